@@ -0,0 +1,260 @@
+//! A spreadsheet evaluated by simple. Each cell of a CSV file is empty, a
+//! label, or a formula in simple, a number or an expression after `=`,
+//! which refers to other cells by name, say `=B2 * C2`.
+//!
+//! Run with `cargo run --example sheet -- examples/sheet.csv C2=10`: the
+//! cells are evaluated in the order their dependencies ask for and the grid
+//! printed, then each `CELL=formula` after the file replaces a cell and
+//! only the cells depending on it are evaluated again.
+
+extern crate proglang;
+
+use proglang::parser;
+use proglang::simple::deps;
+use proglang::simple::machine::{EvalError, Machine};
+use proglang::simple::reduce;
+use proglang::simple::syntax::Node;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::panic;
+use std::process;
+
+/// The fields of a line of CSV, which may be quoted with `"`, a quote in
+/// them doubled.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The name of the column at `index`: A to Z, then AA and on.
+fn column(index: usize) -> String {
+    let mut name = String::new();
+    let mut index = index + 1;
+    while index > 0 {
+        name.insert(0, (b'A' + ((index - 1) % 26) as u8) as char);
+        index = (index - 1) / 26;
+    }
+    name
+}
+
+/// The source of a formula, or `None` for a label.
+fn formula(text: &str) -> Option<&str> {
+    match text.strip_prefix('=') {
+        Some(formula) => Some(formula),
+        None if text.parse::<f64>().is_ok() => Some(text),
+        None => None,
+    }
+}
+
+struct Sheet {
+    /// The names of the cells, by row.
+    rows: Vec<Vec<String>>,
+    /// Each formula as the statement assigning its cell.
+    statements: HashMap<String, Box<Node>>,
+    /// What to show for cells without a value.
+    errors: HashMap<String, String>,
+    machine: Machine,
+    /// The statements evaluated, in order, as the program of the machine.
+    order: Vec<String>,
+}
+
+impl Sheet {
+    fn read(csv: &str) -> Sheet {
+        let mut sheet = Sheet {
+            rows: Vec::new(),
+            statements: HashMap::new(),
+            errors: HashMap::new(),
+            machine: Machine::new_with_empty_env(Node::donothing()),
+            order: Vec::new(),
+        };
+        let mut sources = HashMap::new();
+        for (row, line) in csv.lines().enumerate() {
+            let mut names = Vec::new();
+            for (col, text) in fields(line).into_iter().enumerate() {
+                let name = format!("{}{}", column(col), row + 1);
+                let text = text.trim();
+                match formula(text) {
+                    Some(formula) => { sources.insert(name.clone(), format!("{} = {};", name, formula)); }
+                    None if !text.is_empty() => sheet.machine.set(&name, text),
+                    None => (),
+                }
+                names.push(name);
+            }
+            sheet.rows.push(names);
+        }
+        for (name, source) in &sources {
+            match parser::parse(source) {
+                Ok(statement) => { sheet.statements.insert(name.clone(), statement); }
+                Err(_) => { sheet.errors.insert(name.clone(), "#PARSE".to_string()); }
+            }
+        }
+        let order = sheet.order();
+        let sources: Vec<&str> = order.iter().map(|name| sources[name].as_str()).collect();
+        for (name, result) in order.iter().zip(sheet.machine.eval_many(&sources)) {
+            match result {
+                Ok(_) => sheet.order.push(name.clone()),
+                Err(EvalError::Parse(_)) => { sheet.errors.insert(name.clone(), "#PARSE".to_string()); }
+                Err(EvalError::Runtime(e)) => {
+                    eprintln!("{}: {}", name, e);
+                    sheet.errors.insert(name.clone(), "#ERROR".to_string());
+                }
+            }
+        }
+        sheet.load();
+        sheet
+    }
+
+    /// The cells with formulas, each after those it reads, leaving out
+    /// those on a cycle.
+    fn order(&mut self) -> Vec<String> {
+        let reads: HashMap<&str, Vec<String>> = self.statements.iter().map(|(name, statement)| {
+            let graph = deps::graph(statement);
+            (name.as_str(), graph.definitions[0].reads.clone())
+        }).collect();
+        let mut names: Vec<&str> = reads.keys().cloned().collect();
+        names.sort();
+        let mut order = Vec::new();
+        let mut done = HashSet::new();
+        let mut cycles = Vec::new();
+        for name in names {
+            visit(name, &reads, &mut Vec::new(), &mut done, &mut order, &mut cycles);
+        }
+        for name in cycles {
+            self.errors.insert(name, "#CYCLE".to_string());
+        }
+        order
+    }
+
+    /// Make the statements evaluated the program of the machine, for it
+    /// to know what depends on what.
+    fn load(&mut self) {
+        let statements: Vec<Node> = self.order.iter().map(|name| (*self.statements[name]).clone()).collect();
+        self.machine.load(reduce::sequence(&statements));
+    }
+
+    /// Replace the formula of `name`, then evaluate again what depends on
+    /// it, returning the cells that were.
+    fn update(&mut self, name: &str, text: &str) -> Result<Vec<String>, String> {
+        self.errors.remove(name);
+        match formula(text) {
+            Some(formula) => {
+                let source = format!("{} = {};", name, formula);
+                let statement = parser::parse(&source).map_err(|e| e.to_string())?;
+                if let Some(Err(e)) = self.machine.eval_many(&[&source]).pop() {
+                    return Err(e.to_string());
+                }
+                if self.statements.insert(name.to_string(), statement).is_none() || !self.order.iter().any(|cell| cell == name) {
+                    self.order.push(name.to_string());
+                }
+            }
+            None => {
+                self.machine.set(name, text);
+                self.statements.remove(name);
+                self.order.retain(|cell| cell != name);
+            }
+        }
+        self.load();
+        self.machine.invalidate(name).map_err(|e| e.to_string())
+    }
+
+    fn show(&self, name: &str) -> String {
+        match self.machine.environment.lookup(name) {
+            Some(value) => match *value {
+                Node::Str(ref text) => text.clone(),
+                ref value => value.to_string(),
+            },
+            None => self.errors.get(name).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn print(&self) {
+        let width = self.rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut grid = vec![(0..width).map(column).collect::<Vec<_>>()];
+        grid.extend(self.rows.iter().map(|row| {
+            let mut shown: Vec<String> = row.iter().map(|name| self.show(name)).collect();
+            shown.resize(width, String::new());
+            shown
+        }));
+        let widths: Vec<usize> = (0..width)
+            .map(|col| grid.iter().map(|row| row[col].chars().count()).max().unwrap_or(0))
+            .collect();
+        for (index, row) in grid.iter().enumerate() {
+            let label = if index == 0 { String::new() } else { index.to_string() };
+            let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, &width)| format!("{:>1$}", cell, width)).collect();
+            println!("{:>3} | {}", label, cells.join(" | "));
+        }
+    }
+}
+
+/// Add `name` to `order` after the cells it reads, depth first, noting
+/// the cells on a cycle through `path`.
+fn visit(name: &str, reads: &HashMap<&str, Vec<String>>, path: &mut Vec<String>,
+         done: &mut HashSet<String>, order: &mut Vec<String>, cycles: &mut Vec<String>) {
+    if done.contains(name) {
+        return;
+    }
+    if let Some(start) = path.iter().position(|cell| cell == name) {
+        cycles.extend(path[start..].iter().cloned());
+        return;
+    }
+    path.push(name.to_string());
+    for read in &reads[name] {
+        if reads.contains_key(read.as_str()) {
+            visit(read, reads, path, done, order, cycles);
+        }
+    }
+    path.pop();
+    done.insert(name.to_string());
+    if !cycles.iter().any(|cell| cell == name) {
+        order.push(name.to_string());
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: sheet <file.csv> [CELL=formula]...");
+            process::exit(1);
+        }
+    };
+    let csv = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    // errors in cells are shown in the grid rather than as panics
+    panic::set_hook(Box::new(|_| ()));
+    let mut sheet = Sheet::read(&csv);
+    sheet.print();
+    for change in &args[1..] {
+        let (name, text) = match change.split_once('=') {
+            Some(change) => change,
+            None => {
+                eprintln!("Expect CELL=formula, got {}", change);
+                process::exit(1);
+            }
+        };
+        println!();
+        match sheet.update(name, text) {
+            Ok(cells) => println!("{} = {}, evaluated again: {}", name, text, cells.join(", ")),
+            Err(e) => println!("{} = {}: {}", name, text, e),
+        }
+        sheet.print();
+    }
+}
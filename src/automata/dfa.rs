@@ -0,0 +1,129 @@
+//! Deterministic finite automata: at most one rule for each state and
+//! character, so the machine is in one state at a time. One with no rule
+//! for the character it reads gets stuck, and accepts nothing from then
+//! on.
+
+use super::rule::FaRule;
+
+use std::fmt::Debug;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct DfaRulebook<S> {
+    pub rules: Vec<FaRule<S>>,
+}
+
+impl<S: Clone + PartialEq + Debug> DfaRulebook<S> {
+    pub fn new(rules: Vec<FaRule<S>>) -> DfaRulebook<S> {
+        DfaRulebook { rules }
+    }
+
+    /// The state reading `character` in `state` moves to, if there is a
+    /// rule for it.
+    pub fn next_state(&self, state: &S, character: char) -> Option<S> {
+        self.rule_for(state, character).map(FaRule::follow)
+    }
+
+    fn rule_for(&self, state: &S, character: char) -> Option<&FaRule<S>> {
+        self.rules.iter().find(|rule| rule.applies_to(state, Some(character)))
+    }
+}
+
+/// A DFA part way through reading a string.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Dfa<S> {
+    /// `None` once stuck.
+    pub current_state: Option<S>,
+    pub accept_states: Vec<S>,
+    pub rulebook: DfaRulebook<S>,
+}
+
+impl<S: Clone + PartialEq + Debug> Dfa<S> {
+    pub fn new(current_state: S, accept_states: Vec<S>, rulebook: DfaRulebook<S>) -> Dfa<S> {
+        Dfa { current_state: Some(current_state), accept_states, rulebook }
+    }
+
+    pub fn accepting(&self) -> bool {
+        self.current_state.as_ref().is_some_and(|state| self.accept_states.contains(state))
+    }
+
+    pub fn read_character(&mut self, character: char) {
+        self.current_state = self.current_state.take()
+            .and_then(|state| self.rulebook.next_state(&state, character));
+    }
+
+    pub fn read_string(&mut self, string: &str) {
+        for character in string.chars() {
+            self.read_character(character);
+        }
+    }
+}
+
+/// What a DFA starts from, to make a fresh one for each string read.
+#[derive(Debug,Clone,PartialEq)]
+pub struct DfaDesign<S> {
+    pub start_state: S,
+    pub accept_states: Vec<S>,
+    pub rulebook: DfaRulebook<S>,
+}
+
+impl<S: Clone + PartialEq + Debug> DfaDesign<S> {
+    pub fn new(start_state: S, accept_states: Vec<S>, rulebook: DfaRulebook<S>) -> DfaDesign<S> {
+        DfaDesign { start_state, accept_states, rulebook }
+    }
+
+    pub fn to_dfa(&self) -> Dfa<S> {
+        Dfa::new(self.start_state.clone(), self.accept_states.clone(), self.rulebook.clone())
+    }
+
+    pub fn accepts(&self, string: &str) -> bool {
+        let mut dfa = self.to_dfa();
+        dfa.read_string(string);
+        dfa.accepting()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // accepts the strings with `ab` in them
+    fn rulebook() -> DfaRulebook<u32> {
+        DfaRulebook::new(vec![
+            FaRule::new(1, 'a', 2), FaRule::new(1, 'b', 1),
+            FaRule::new(2, 'a', 2), FaRule::new(2, 'b', 3),
+            FaRule::new(3, 'a', 3), FaRule::new(3, 'b', 3),
+        ])
+    }
+
+    #[test]
+    fn test_rulebook() {
+        let rulebook = rulebook();
+        assert_eq!(Some(2), rulebook.next_state(&1, 'a'));
+        assert_eq!(Some(1), rulebook.next_state(&1, 'b'));
+        assert_eq!(Some(3), rulebook.next_state(&2, 'b'));
+        assert_eq!(None, rulebook.next_state(&1, 'c'));
+    }
+
+    #[test]
+    fn test_dfa() {
+        let mut dfa = Dfa::new(1, vec![3], rulebook());
+        assert!(!dfa.accepting());
+        dfa.read_character('b');
+        assert!(!dfa.accepting());
+        dfa.read_string("aab");
+        assert!(dfa.accepting());
+        // stuck on a character it has no rule for
+        dfa.read_character('c');
+        assert_eq!(None, dfa.current_state);
+        assert!(!dfa.accepting());
+    }
+
+    #[test]
+    fn test_design() {
+        let design = DfaDesign::new(1, vec![3], rulebook());
+        assert!(!design.accepts("a"));
+        assert!(!design.accepts("baa"));
+        assert!(design.accepts("baba"));
+        assert!(!design.accepts(""));
+    }
+}
@@ -0,0 +1,11 @@
+//! Finite automata, from the book's "the simplest computers": machines
+//! with a fixed number of states reading a string a character at a time,
+//! deterministic (`dfa`) or not (`nfa`), and the conversion of one to the
+//! other.
+//!
+//! States may be of any ordered type, say numbers; a DFA simulating an
+//! NFA has sets of the NFA's states for states.
+
+pub mod rule;
+pub mod dfa;
+pub mod nfa;
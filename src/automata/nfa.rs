@@ -0,0 +1,220 @@
+//! Nondeterministic finite automata: any number of rules for a state and
+//! character, and free moves, so the machine is in a set of states at a
+//! time, accepting if any of them is an accept state. Each NFA has a DFA
+//! accepting the same strings, whose states are sets of its states.
+
+use super::rule::FaRule;
+use super::dfa::{DfaDesign, DfaRulebook};
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct NfaRulebook<S> {
+    pub rules: Vec<FaRule<S>>,
+}
+
+impl<S: Clone + Ord + Debug> NfaRulebook<S> {
+    pub fn new(rules: Vec<FaRule<S>>) -> NfaRulebook<S> {
+        NfaRulebook { rules }
+    }
+
+    /// The states reading `character` in any of `states` moves to, or
+    /// with no character, those a free move from one of them leads to.
+    pub fn next_states(&self, states: &BTreeSet<S>, character: Option<char>) -> BTreeSet<S> {
+        states.iter().flat_map(|state| self.follow_rules_for(state, character)).collect()
+    }
+
+    fn follow_rules_for(&self, state: &S, character: Option<char>) -> Vec<S> {
+        self.rules.iter().filter(|rule| rule.applies_to(state, character)).map(FaRule::follow).collect()
+    }
+
+    /// `states` with all the states free moves lead to from them.
+    pub fn follow_free_moves(&self, states: BTreeSet<S>) -> BTreeSet<S> {
+        let mut states = states;
+        loop {
+            let more = self.next_states(&states, None);
+            if more.is_subset(&states) {
+                return states;
+            }
+            states.extend(more);
+        }
+    }
+
+    /// The characters the rules read, sorted.
+    pub fn alphabet(&self) -> Vec<char> {
+        let characters: BTreeSet<char> = self.rules.iter().filter_map(|rule| rule.character).collect();
+        characters.into_iter().collect()
+    }
+}
+
+/// An NFA part way through reading a string.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Nfa<S> {
+    /// The states it may be in, free moves followed.
+    pub current_states: BTreeSet<S>,
+    pub accept_states: Vec<S>,
+    pub rulebook: NfaRulebook<S>,
+}
+
+impl<S: Clone + Ord + Debug> Nfa<S> {
+    pub fn new(current_states: BTreeSet<S>, accept_states: Vec<S>, rulebook: NfaRulebook<S>) -> Nfa<S> {
+        let current_states = rulebook.follow_free_moves(current_states);
+        Nfa { current_states, accept_states, rulebook }
+    }
+
+    pub fn accepting(&self) -> bool {
+        self.current_states.iter().any(|state| self.accept_states.contains(state))
+    }
+
+    pub fn read_character(&mut self, character: char) {
+        let next = self.rulebook.next_states(&self.current_states, Some(character));
+        self.current_states = self.rulebook.follow_free_moves(next);
+    }
+
+    pub fn read_string(&mut self, string: &str) {
+        for character in string.chars() {
+            self.read_character(character);
+        }
+    }
+}
+
+/// What an NFA starts from, to make a fresh one for each string read.
+#[derive(Debug,Clone,PartialEq)]
+pub struct NfaDesign<S> {
+    pub start_state: S,
+    pub accept_states: Vec<S>,
+    pub rulebook: NfaRulebook<S>,
+}
+
+impl<S: Clone + Ord + Debug> NfaDesign<S> {
+    pub fn new(start_state: S, accept_states: Vec<S>, rulebook: NfaRulebook<S>) -> NfaDesign<S> {
+        NfaDesign { start_state, accept_states, rulebook }
+    }
+
+    pub fn to_nfa(&self) -> Nfa<S> {
+        self.to_nfa_from(singleton(self.start_state.clone()))
+    }
+
+    /// An NFA of this design that starts in `current_states` instead.
+    pub fn to_nfa_from(&self, current_states: BTreeSet<S>) -> Nfa<S> {
+        Nfa::new(current_states, self.accept_states.clone(), self.rulebook.clone())
+    }
+
+    pub fn accepts(&self, string: &str) -> bool {
+        let mut nfa = self.to_nfa();
+        nfa.read_string(string);
+        nfa.accepting()
+    }
+
+    /// The set of states an NFA in `states` is in after reading
+    /// `character`.
+    fn next_state(&self, states: &BTreeSet<S>, character: char) -> BTreeSet<S> {
+        let mut nfa = self.to_nfa_from(states.clone());
+        nfa.read_character(character);
+        nfa.current_states
+    }
+
+    /// A DFA accepting the same strings, each of its states the set of
+    /// states the NFA may be in, found by simulating the NFA on every
+    /// character from the states it can reach. The empty set, where the
+    /// NFA is stuck, is a state too.
+    pub fn to_dfa_design(&self) -> DfaDesign<BTreeSet<S>> {
+        let alphabet = self.rulebook.alphabet();
+        let start = self.to_nfa().current_states;
+        let mut states = vec![start.clone()];
+        let mut rules = Vec::new();
+        let mut next = 0;
+        while next < states.len() {
+            let state = states[next].clone();
+            for &character in &alphabet {
+                let to = self.next_state(&state, character);
+                if !states.contains(&to) {
+                    states.push(to.clone());
+                }
+                rules.push(FaRule::new(state.clone(), character, to));
+            }
+            next += 1;
+        }
+        let accept_states = states.into_iter()
+            .filter(|state| state.iter().any(|state| self.accept_states.contains(state)))
+            .collect();
+        DfaDesign::new(start, accept_states, DfaRulebook::new(rules))
+    }
+}
+
+fn singleton<S: Ord>(state: S) -> BTreeSet<S> {
+    let mut states = BTreeSet::new();
+    states.insert(state);
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(states: &[u32]) -> BTreeSet<u32> {
+        states.iter().cloned().collect()
+    }
+
+    // accepts the strings whose third character from the end is `b`
+    fn rulebook() -> NfaRulebook<u32> {
+        NfaRulebook::new(vec![
+            FaRule::new(1, 'a', 1), FaRule::new(1, 'b', 1), FaRule::new(1, 'b', 2),
+            FaRule::new(2, 'a', 3), FaRule::new(2, 'b', 3),
+            FaRule::new(3, 'a', 4), FaRule::new(3, 'b', 4),
+        ])
+    }
+
+    #[test]
+    fn test_nfa() {
+        let rulebook = rulebook();
+        assert_eq!(set(&[1, 2]), rulebook.next_states(&set(&[1]), Some('b')));
+        assert_eq!(set(&[1, 3]), rulebook.next_states(&set(&[1, 2]), Some('a')));
+        let mut nfa = Nfa::new(set(&[1]), vec![4], rulebook.clone());
+        assert!(!nfa.accepting());
+        nfa.read_string("bab");
+        assert!(nfa.accepting());
+        let design = NfaDesign::new(1, vec![4], rulebook);
+        assert!(design.accepts("bab"));
+        assert!(design.accepts("bbbbb"));
+        assert!(!design.accepts("bbabb"));
+    }
+
+    #[test]
+    fn test_free_moves() {
+        // accepts strings of `a` as long as a multiple of two or three
+        let rulebook = NfaRulebook::new(vec![
+            FaRule::free_move(1, 2), FaRule::free_move(1, 4),
+            FaRule::new(2, 'a', 3), FaRule::new(3, 'a', 2),
+            FaRule::new(4, 'a', 5), FaRule::new(5, 'a', 6), FaRule::new(6, 'a', 4),
+        ]);
+        assert_eq!(set(&[1, 2, 4]), rulebook.follow_free_moves(set(&[1])));
+        assert_eq!(vec!['a'], rulebook.alphabet());
+        let design = NfaDesign::new(1, vec![2, 4], rulebook);
+        assert!(design.accepts("aa"));
+        assert!(design.accepts("aaa"));
+        assert!(!design.accepts("aaaaa"));
+        assert!(design.accepts("aaaaaa"));
+    }
+
+    #[test]
+    fn test_to_dfa_design() {
+        let rulebook = NfaRulebook::new(vec![
+            FaRule::new(1, 'a', 1), FaRule::new(1, 'a', 2), FaRule::free_move(1, 2),
+            FaRule::new(2, 'b', 3),
+            FaRule::new(3, 'b', 1), FaRule::free_move(3, 2),
+        ]);
+        let nfa_design = NfaDesign::new(1, vec![3], rulebook);
+        let dfa_design = nfa_design.to_dfa_design();
+        assert_eq!(set(&[1, 2]), dfa_design.start_state);
+        assert_eq!(vec![set(&[2, 3]), set(&[1, 2, 3])], dfa_design.accept_states);
+        // {1, 2}, {2, 3}, the stuck {} and {1, 2, 3}, each with a rule for `a` and `b`
+        assert_eq!(8, dfa_design.rulebook.rules.len());
+        assert_eq!(Some(set(&[])), dfa_design.rulebook.next_state(&set(&[2, 3]), 'a'));
+        for string in &["", "a", "aaa", "aab", "bbbabb", "abba", "ba", "ababb"] {
+            assert_eq!(nfa_design.accepts(string), dfa_design.accepts(string), "{:?}", string);
+        }
+        assert!(!dfa_design.accepts("aaa") && dfa_design.accepts("aab") && dfa_design.accepts("bbbabb"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::{self, Debug, Display, Formatter};
+
+/// A rule of a finite automaton: in `state`, reading `character`, move to
+/// `next_state`. A rule with no character is a free move, taken without
+/// reading anything; only NFAs have those.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct FaRule<S> {
+    pub state: S,
+    pub character: Option<char>,
+    pub next_state: S,
+}
+
+impl<S: Clone + PartialEq> FaRule<S> {
+    pub fn new(state: S, character: char, next_state: S) -> FaRule<S> {
+        FaRule { state, character: Some(character), next_state }
+    }
+
+    pub fn free_move(state: S, next_state: S) -> FaRule<S> {
+        FaRule { state, character: None, next_state }
+    }
+
+    /// Whether the rule is for `character` read in `state`, or, with no
+    /// character, is a free move from `state`.
+    pub fn applies_to(&self, state: &S, character: Option<char>) -> bool {
+        self.state == *state && self.character == character
+    }
+
+    pub fn follow(&self) -> S {
+        self.next_state.clone()
+    }
+}
+
+impl<S: Debug> Display for FaRule<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.character {
+            Some(character) => write!(f, "{:?} --{}--> {:?}", self.state, character, self.next_state),
+            None => write!(f, "{:?} --ε--> {:?}", self.state, self.next_state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule() {
+        let rule = FaRule::new(1, 'a', 2);
+        assert!(rule.applies_to(&1, Some('a')));
+        assert!(!rule.applies_to(&1, Some('b')) && !rule.applies_to(&2, Some('a')));
+        assert_eq!(2, rule.follow());
+        assert_eq!("1 --a--> 2", rule.to_string());
+        let free = FaRule::free_move(1, 3);
+        assert!(free.applies_to(&1, None) && !free.applies_to(&1, Some('a')));
+        assert_eq!("1 --ε--> 3", free.to_string());
+    }
+}
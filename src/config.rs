@@ -0,0 +1,225 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Name of the configuration file looked up in the working directory.
+pub const CONFIG_FILE: &str = "simple.toml";
+/// Environment variable holding default command line flags.
+pub const OPTS_VAR: &str = "SIMPLE_OPTS";
+
+/// Options of the command line runner. Every field is optional so that
+/// options from several sources can be layered with `merge`.
+#[derive(Debug,Default,Clone,PartialEq)]
+pub struct Options {
+    pub time: Option<bool>,
+    pub backend: Option<String>,
+    pub step_limit: Option<usize>,
+    /// Wall-clock limit of a run, in milliseconds.
+    pub timeout: Option<u64>,
+    pub trace: Option<String>,
+    pub color: Option<bool>,
+    /// Report every assignment to this variable.
+    pub break_on_assign: Option<String>,
+    /// Parse and run the program a statement at a time.
+    pub stream: Option<bool>,
+    /// Print the parse tree of the program before running it.
+    pub ast: Option<bool>,
+    /// Print only the result and errors: no parse tree, warnings or trace.
+    pub quiet: Option<bool>,
+    /// Refuse to run code that writes variables around it or does IO.
+    pub pure: Option<bool>,
+    /// Print numbers as this locale writes them, say `en` or `de_DE`, or
+    /// `plain`.
+    pub number_format: Option<String>,
+    /// Pad printed numbers to at least this many characters.
+    pub number_width: Option<usize>,
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("Expect true or false for {}, found {}", key, value)),
+    }
+}
+
+fn parse_number<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Expect a number for {}, found {}", key, value))
+}
+
+impl Options {
+    /// Set the option `key`, spelled as in `simple.toml`, from its textual value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "time" => self.time = Some(parse_bool(key, value)?),
+            "backend" => self.backend = Some(value.to_string()),
+            "step_limit" => self.step_limit = Some(parse_number(key, value)?),
+            "timeout" => self.timeout = Some(parse_number(key, value)?),
+            "trace" => self.trace = Some(value.to_string()),
+            "color" => self.color = Some(parse_bool(key, value)?),
+            "break_on_assign" => self.break_on_assign = Some(value.to_string()),
+            "stream" => self.stream = Some(parse_bool(key, value)?),
+            "ast" => self.ast = Some(parse_bool(key, value)?),
+            "quiet" => self.quiet = Some(parse_bool(key, value)?),
+            "pure" => self.pure = Some(parse_bool(key, value)?),
+            "number_format" => self.number_format = Some(value.to_string()),
+            "number_width" => self.number_width = Some(parse_number(key, value)?),
+            _ => return Err(format!("Unknown option {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Read the flat `key = value` subset of TOML used by `simple.toml`.
+    /// Comments, blank lines and quoted or bare values are accepted.
+    pub fn from_toml(source: &str) -> Result<Options, String> {
+        let mut options = Options::default();
+        for (lineno, line) in source.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos+1..].trim()),
+                None => return Err(format!("line {}: expect key = value, found {}", lineno + 1, line)),
+            };
+            let value = value.trim_matches('"');
+            options.set(&key.replace('-', "_"), value)
+                .map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+        }
+        Ok(options)
+    }
+
+    /// Pick the option flags out of `args`, returning them with the
+    /// remaining positional arguments. Flags are `--time`, `--stream`, `--ast`,
+    /// `--quiet`, `--pure`, `--color`, `--no-color`, `--backend=NAME`, `--step-limit=N`,
+    /// `--timeout=MS`, `--trace[=FORMAT]`, `--break-on-assign[=]NAME`,
+    /// `--number-format=LOCALE` and `--number-width=N`. A bare `--trace` is `--trace=full`.
+    pub fn from_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+        let mut options = Options::default();
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--time" => options.time = Some(true),
+                "--stream" => options.stream = Some(true),
+                "--ast" => options.ast = Some(true),
+                "--quiet" => options.quiet = Some(true),
+                "--pure" => options.pure = Some(true),
+                "--trace" => options.trace = Some("full".to_string()),
+                "--break-on-assign" => match args.next() {
+                    Some(name) => options.break_on_assign = Some(name.clone()),
+                    None => return Err("Expect a variable name after --break-on-assign".to_string()),
+                },
+                "--color" => options.color = Some(true),
+                "--no-color" => options.color = Some(false),
+                _ if arg.starts_with("--") && arg.contains('=') => {
+                    let pos = arg.find('=').unwrap();
+                    options.set(&arg[2..pos].replace('-', "_"), &arg[pos+1..])?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => rest.push(arg.clone()),
+            }
+        }
+        Ok((options, rest))
+    }
+
+    /// Layer `over` on top of `self`: options set in `over` win.
+    pub fn merge(self, over: Options) -> Options {
+        Options {
+            time: over.time.or(self.time),
+            backend: over.backend.or(self.backend),
+            step_limit: over.step_limit.or(self.step_limit),
+            timeout: over.timeout.or(self.timeout),
+            trace: over.trace.or(self.trace),
+            color: over.color.or(self.color),
+            break_on_assign: over.break_on_assign.or(self.break_on_assign),
+            stream: over.stream.or(self.stream),
+            ast: over.ast.or(self.ast),
+            quiet: over.quiet.or(self.quiet),
+            pure: over.pure.or(self.pure),
+            number_format: over.number_format.or(self.number_format),
+            number_width: over.number_width.or(self.number_width),
+        }
+    }
+
+    /// Defaults from `simple.toml` in `dir`, overridden by `SIMPLE_OPTS`.
+    pub fn defaults(dir: &Path) -> Result<Options, String> {
+        let path = dir.join(CONFIG_FILE);
+        let file = if path.exists() {
+            let source = fs::read_to_string(&path)
+                .map_err(|e| format!("Error in reading {}: {}", path.display(), e))?;
+            Options::from_toml(&source).map_err(|e| format!("{}: {}", path.display(), e))?
+        } else {
+            Options::default()
+        };
+        let opts = match env::var(OPTS_VAR) {
+            Ok(value) => {
+                let args: Vec<String> = value.split_whitespace().map(String::from).collect();
+                let (options, rest) = Options::from_args(&args).map_err(|e| format!("{}: {}", OPTS_VAR, e))?;
+                if !rest.is_empty() {
+                    return Err(format!("{}: unexpected argument {}", OPTS_VAR, rest[0]));
+                }
+                options
+            }
+            Err(_) => Options::default(),
+        };
+        Ok(file.merge(opts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let options = Options::from_toml("# classroom defaults\nbackend = \"subst\"\nstep-limit = 1000\n\ncolor = false # no tty\n").unwrap();
+        assert_eq!(Some("subst".to_string()), options.backend);
+        assert_eq!(Some(1000), options.step_limit);
+        assert_eq!(Some(false), options.color);
+        assert_eq!(None, options.time);
+        assert!(Options::from_toml("colour = true").is_err());
+        assert!(Options::from_toml("step_limit = many").is_err());
+        assert!(Options::from_toml("time").is_err());
+        assert!(Options::from_toml("opt_level = 2").is_err());
+    }
+
+    #[test]
+    fn test_from_args() {
+        let (options, rest) = Options::from_args(&args("--time prog.simple --trace=none --no-color --timeout=500 --stream")).unwrap();
+        assert_eq!(Some(500), options.timeout);
+        assert_eq!(Some(true), options.stream);
+        assert_eq!(Some(true), options.time);
+        assert_eq!(Some("none".to_string()), options.trace);
+        assert_eq!(Some(false), options.color);
+        assert_eq!(vec!["prog.simple".to_string()], rest);
+        assert!(Options::from_args(&args("--fast")).is_err());
+        let (options, rest) = Options::from_args(&args("--break-on-assign x prog.simple")).unwrap();
+        assert_eq!(Some("x".to_string()), options.break_on_assign);
+        assert_eq!(vec!["prog.simple".to_string()], rest);
+        let (options, _) = Options::from_args(&args("--break-on-assign=total")).unwrap();
+        assert_eq!(Some("total".to_string()), options.break_on_assign);
+        assert!(Options::from_args(&args("--break-on-assign")).is_err());
+        let (options, _) = Options::from_args(&args("--ast --quiet --trace --pure")).unwrap();
+        assert_eq!((Some(true), Some(true), Some(true)), (options.ast, options.quiet, options.pure));
+        assert_eq!(Some("full".to_string()), options.trace);
+    }
+
+    #[test]
+    fn test_merge() {
+        let file = Options::from_toml("backend = machine\nstep_limit = 10\ncolor = true\nnumber-format = de_DE").unwrap();
+        let (cli, _) = Options::from_args(&args("--backend=subst --no-color --number-width=12")).unwrap();
+        let options = file.merge(cli);
+        assert_eq!(Some("subst".to_string()), options.backend);
+        assert_eq!((Some("de_DE".to_string()), Some(12)), (options.number_format, options.number_width));
+        assert_eq!(Some(10), options.step_limit);
+        assert_eq!(Some(false), options.color);
+    }
+}
@@ -0,0 +1,133 @@
+use parser;
+use simple::environment::Environment;
+use simple::evaluate::Evaluate;
+use simple::syntax::Node;
+
+use std::fmt::{self, Display, Formatter};
+
+/// Marker of an expectation comment, as in `// expect: result == 3628800`.
+pub const MARKER: &str = "// expect:";
+
+/// A condition a program states about its own final environment.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Expectation {
+    /// Line of the comment, counting from 1.
+    pub line: usize,
+    pub source: String,
+    pub condition: Box<Node>,
+}
+
+/// An expectation that does not hold, with what its sides came to.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Failure {
+    pub line: usize,
+    pub source: String,
+    pub found: String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}: expected {}, but {}", self.line, self.source, self.found)
+    }
+}
+
+/// The expectation comments of `source`, each holding a single expression.
+pub fn expectations(source: &str) -> Result<Vec<Expectation>, String> {
+    let mut found = Vec::new();
+    for (lineno, line) in source.lines().enumerate() {
+        let text = match line.trim_start().strip_prefix(MARKER) {
+            Some(text) => text.trim(),
+            None => continue,
+        };
+        let mut stats = parser::parse_statements(text)
+            .map_err(|e| format!("line {}: invalid expectation {}\n{}", lineno + 1, text, e))?;
+        let statement = stats.len() != 1 || matches!(*stats[0], Node::Assign(..) | Node::Global(..) |
+            Node::While(..) | Node::For(..) | Node::Match(..) | Node::Return(_) | Node::Break | Node::Continue);
+        if statement {
+            return Err(format!("line {}: expect a single expression, found {}", lineno + 1, text));
+        }
+        found.push(Expectation { line: lineno + 1, source: text.to_string(), condition: stats.remove(0) });
+    }
+    Ok(found)
+}
+
+impl Expectation {
+    /// Evaluate the condition against a copy of `env`. A failed equality
+    /// reports what each side came to.
+    pub fn check(&self, env: &Environment) -> Result<(), Failure> {
+        let mut env = env.clone();
+        let found = match *self.condition {
+            Node::EQ(ref l, ref r) => {
+                let (l_value, r_value) = (l.evaluate(&mut env), r.evaluate(&mut env));
+                if l_value == r_value {
+                    return Ok(());
+                }
+                format!("{} is {} and {} is {}", l, l_value, r, r_value)
+            }
+            ref condition => match *condition.evaluate(&mut env) {
+                Node::Boolean(true) => return Ok(()),
+                ref value => format!("it is {}", value),
+            },
+        };
+        Err(Failure { line: self.line, source: self.source.clone(), found })
+    }
+}
+
+/// Check every expectation of `source` against `env`, the environment
+/// running it left.
+pub fn check(source: &str, env: &Environment) -> Result<Vec<Failure>, String> {
+    Ok(expectations(source)?.iter().filter_map(|expectation| expectation.check(env).err()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn sources(dir: &Path, found: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                sources(&path, found);
+            } else if path.extension().is_some_and(|ext| ext == "simple") {
+                found.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_expectations() {
+        let source = "x = 6 * 7;\n// expect: x == 42\n  // expect: x < 10\ny = 1; // expect: not here\n// expect: x == 6 * 8\n";
+        let found = expectations(source).unwrap();
+        assert_eq!(vec![2, 3, 5], found.iter().map(|e| e.line).collect::<Vec<_>>());
+        let mut env = Environment::new();
+        parser::parse(source).unwrap().evaluate(&mut env);
+        let failures = check(source, &env).unwrap();
+        assert_eq!(vec!["line 3: expected x < 10, but it is false".to_string(),
+                        "line 5: expected x == 6 * 8, but x is 42 and 6 * 8 is 48".to_string()],
+                   failures.iter().map(|failure| failure.to_string()).collect::<Vec<_>>());
+        assert!(expectations("// expect: x = 1;").is_err());
+        assert!(expectations("// expect: x == 1 y == 2").is_err());
+    }
+
+    #[test]
+    fn test_examples_meet_expectations() {
+        // the examples document themselves: every one of them must run and
+        // meet the expectations written in it
+        let mut paths = Vec::new();
+        sources(&Path::new(env!("CARGO_MANIFEST_DIR")).join("examples"), &mut paths);
+        let mut checked = 0;
+        for path in paths {
+            let source = fs::read_to_string(&path).unwrap();
+            let mut env = Environment::new();
+            parser::parse(&source).unwrap().evaluate(&mut env);
+            let failures = check(&source, &env).unwrap();
+            assert!(failures.is_empty(), "{}: {}", path.display(),
+                    failures.iter().map(|failure| failure.to_string()).collect::<Vec<_>>().join("\n"));
+            checked += expectations(&source).unwrap().len();
+        }
+        assert!(checked > 0);
+    }
+}
@@ -0,0 +1,450 @@
+//! Laying out a program the standard way: a statement per line, blocks
+//! indented by four spaces, spaces around binary operators and after
+//! commas. The program is printed from its parse tree rather than its
+//! syntax tree, so it reads back as written, sugar and parentheses
+//! included. Comments are kept, as are the blank lines between statements;
+//! a comment inside an expression moves to the line after its statement.
+
+use parser::{self, ParseError, Rule};
+
+use pest::iterators::{Pair, Pairs};
+
+const INDENT: &str = "    ";
+
+/// `source` laid out the standard way.
+pub fn format(source: &str) -> Result<String, ParseError> {
+    let stats = parser::parse_tree(source)?;
+    let mut formatter = Formatter::new(source);
+    for stat in stats.into_inner() {
+        formatter.statement(stat, 0);
+    }
+    formatter.comments_before(source.len(), 0);
+    Ok(formatter.out)
+}
+
+/// Where the comments of `source` start and end, skipping strings.
+fn comments(source: &str) -> Vec<(usize, usize)> {
+    let bytes = source.as_bytes();
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'"' => {
+                pos += 1;
+                while pos < bytes.len() && bytes[pos] != b'"' {
+                    pos += if bytes[pos] == b'\\' { 2 } else { 1 };
+                }
+                pos += 1;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'/') => {
+                let end = source[pos..].find('\n').map_or(source.len(), |n| pos + n);
+                found.push((pos, end));
+                pos = end;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                let end = source[pos + 2..].find("*/").map_or(source.len(), |n| pos + n + 4);
+                found.push((pos, end));
+                pos = end;
+            }
+            _ => pos += 1,
+        }
+    }
+    found
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    /// Where the lines of the source end.
+    newlines: Vec<usize>,
+    comments: Vec<(usize, usize)>,
+    /// The first comment not printed yet.
+    next: usize,
+    /// The source line where what was printed last in the current block
+    /// ends, if anything was.
+    last_line: Option<usize>,
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Formatter<'a> {
+        Formatter {
+            source,
+            newlines: source.match_indices('\n').map(|(pos, _)| pos).collect(),
+            comments: comments(source),
+            next: 0,
+            last_line: None,
+            out: String::new(),
+        }
+    }
+
+    fn line(&self, pos: usize) -> usize {
+        self.newlines.partition_point(|&newline| newline < pos)
+    }
+
+    fn source_line(&self, line: usize) -> &'a str {
+        let start = if line == 0 { 0 } else { self.newlines[line - 1] + 1 };
+        let end = self.newlines.get(line).cloned().unwrap_or(self.source.len());
+        &self.source[start..end]
+    }
+
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    /// Start a line for what starts at `pos`, after a blank line if the
+    /// source has one before it.
+    fn start_line(&mut self, pos: usize, indent: usize) {
+        if let Some(last) = self.last_line {
+            if (last + 1..self.line(pos)).any(|line| self.source_line(line).trim().is_empty()) {
+                self.out.push('\n');
+            }
+        }
+        self.push(&INDENT.repeat(indent));
+    }
+
+    /// End the line of what ends at `end`, with the comment right after it
+    /// on the same source line.
+    fn end_line(&mut self, end: usize) {
+        let line = self.line(end);
+        if let Some(&(start, stop)) = self.comments.get(self.next) {
+            if start >= end && self.source[end..start].trim().is_empty() && self.line(start) == line {
+                self.next += 1;
+                self.push("  ");
+                self.push(self.source[start..stop].trim_end());
+            }
+        }
+        self.out.push('\n');
+        self.last_line = Some(line);
+    }
+
+    /// Print the comments that start before `pos`, each on its own line.
+    fn comments_before(&mut self, pos: usize, indent: usize) {
+        while let Some(&(start, end)) = self.comments.get(self.next) {
+            if start >= pos {
+                break;
+            }
+            self.next += 1;
+            self.start_line(start, indent);
+            self.push(self.source[start..end].trim_end());
+            self.out.push('\n');
+            self.last_line = Some(self.line(end));
+        }
+    }
+
+    /// Where the `}` after `pos` is, past whitespace and comments.
+    fn closing(&self, mut pos: usize) -> usize {
+        let bytes = self.source.as_bytes();
+        while bytes[pos] != b'}' {
+            pos = match self.comments.iter().find(|&&(start, _)| start == pos) {
+                Some(&(_, end)) => end,
+                None => pos + 1,
+            };
+        }
+        pos
+    }
+
+    fn statement(&mut self, pair: Pair<Rule>, indent: usize) {
+        let span = pair.as_span();
+        self.comments_before(span.start(), indent);
+        self.start_line(span.start(), indent);
+        self.stat(pair, indent);
+        self.end_line(span.end());
+    }
+
+    fn stat(&mut self, pair: Pair<Rule>, indent: usize) {
+        let rule = pair.as_rule();
+        let mut inner = pair.clone().into_inner();
+        match rule {
+            Rule::stat_assign | Rule::stat_global => {
+                if rule == Rule::stat_global {
+                    self.push("global ");
+                }
+                self.push(inner.next().unwrap().as_str());
+                self.push(" = ");
+                let value = inner.next().unwrap();
+                match value.as_rule() {
+                    Rule::funcdef => self.funcdef(value, indent),
+                    _ => self.expr(value, indent),
+                }
+                self.push(";");
+            }
+            Rule::stat_return => {
+                self.push("return");
+                if let Some(value) = inner.next() {
+                    self.push(" ");
+                    self.expr(value, indent);
+                }
+                self.push(";");
+            }
+            Rule::stat_break => self.push("break;"),
+            Rule::stat_continue => self.push("continue;"),
+            Rule::stat_if => self.if_else(pair, indent),
+            Rule::stat_while => {
+                self.push("while (");
+                self.expr(inner.next().unwrap(), indent);
+                self.push(") ");
+                self.block(inner.next().unwrap(), indent);
+            }
+            Rule::stat_for => {
+                self.push("for (");
+                self.assignment(inner.next().unwrap(), indent);
+                self.push(";");
+                if let Some(cond) = inner.next().unwrap().into_inner().next() {
+                    self.push(" ");
+                    self.expr(cond, indent);
+                }
+                self.push(";");
+                let step = inner.next().unwrap();
+                if step.clone().into_inner().next().is_some() {
+                    self.push(" ");
+                    self.assignment(step, indent);
+                }
+                self.push(") ");
+                self.block(inner.next().unwrap(), indent);
+            }
+            Rule::stat_match => {
+                self.push("match (");
+                self.expr(inner.next().unwrap(), indent);
+                self.push(") {\n");
+                let close = self.closing(pair.as_span().end() - 1);
+                let around = self.last_line.take();
+                for arm in inner {
+                    let span = arm.as_span();
+                    self.comments_before(span.start(), indent + 1);
+                    self.start_line(span.start(), indent + 1);
+                    let mut arm = arm.into_inner();
+                    self.pattern(arm.next().unwrap());
+                    self.push(" => ");
+                    self.inline_block(arm.next().unwrap(), indent + 1);
+                    self.end_line(span.end());
+                }
+                self.comments_before(close, indent + 1);
+                self.last_line = around;
+                self.push(&INDENT.repeat(indent));
+                self.push("}");
+            }
+            Rule::funcdef => self.funcdef(pair, indent),
+            Rule::expr => self.expr(pair, indent),
+            _ => unreachable!("statement {:?}", rule),
+        }
+    }
+
+    fn if_else(&mut self, pair: Pair<Rule>, indent: usize) {
+        let mut inner = pair.into_inner();
+        self.push("if (");
+        self.expr(inner.next().unwrap(), indent);
+        self.push(") ");
+        self.block(inner.next().unwrap(), indent);
+        if let Some(alternative) = inner.next() {
+            self.push(" else ");
+            match alternative.as_rule() {
+                Rule::stat_if => self.if_else(alternative, indent),
+                _ => self.block(alternative, indent),
+            }
+        }
+    }
+
+    /// The `name = value` of a `for` loop, if any.
+    fn assignment(&mut self, pair: Pair<Rule>, indent: usize) {
+        let mut inner = pair.into_inner();
+        if let Some(name) = inner.next() {
+            self.push(name.as_str());
+            self.push(" = ");
+            self.expr(inner.next().unwrap(), indent);
+        }
+    }
+
+    fn funcdef(&mut self, pair: Pair<Rule>, indent: usize) {
+        self.push(if pair.as_str().starts_with("function") { "function " } else { "def " });
+        let mut inner = pair.into_inner();
+        self.push(inner.next().unwrap().as_str());
+        self.params(inner.next().unwrap());
+        self.push(" ");
+        self.block(inner.next().unwrap(), indent);
+    }
+
+    fn params(&mut self, pair: Pair<Rule>) {
+        let params: Vec<_> = pair.into_inner().map(|param| param.as_str()).collect();
+        self.push(&format!("({})", params.join(", ")));
+    }
+
+    /// Print `{`, the statements of `stats` a level deeper, and the `}`
+    /// closing them.
+    fn block(&mut self, stats: Pair<Rule>, indent: usize) {
+        let close = self.closing(stats.as_span().end());
+        let commented = self.comments.get(self.next).is_some_and(|&(start, _)| start < close);
+        let stats: Vec<_> = stats.into_inner().collect();
+        if stats.is_empty() && !commented {
+            self.push("{}");
+            return;
+        }
+        self.push("{\n");
+        let around = self.last_line.take();
+        for stat in stats {
+            self.statement(stat, indent + 1);
+        }
+        self.comments_before(close, indent + 1);
+        self.last_line = around;
+        self.push(&INDENT.repeat(indent));
+        self.push("}");
+    }
+
+    /// Print a block on one line when it is a single expression, as the
+    /// bodies of lambdas and match arms often are.
+    fn inline_block(&mut self, stats: Pair<Rule>, indent: usize) {
+        let close = self.closing(stats.as_span().end());
+        let commented = self.comments.get(self.next).is_some_and(|&(start, _)| start < close);
+        let mut inner = stats.clone().into_inner();
+        match (inner.next(), inner.next()) {
+            (Some(expr), None) if expr.as_rule() == Rule::expr && !commented => {
+                self.push("{ ");
+                self.expr(expr, indent);
+                self.push(" }");
+            }
+            _ => self.block(stats, indent),
+        }
+    }
+
+    fn pattern(&mut self, pair: Pair<Rule>) {
+        match pair.as_rule() {
+            Rule::pat_pair => {
+                let mut inner = pair.into_inner();
+                self.push("(");
+                self.pattern(inner.next().unwrap());
+                self.push(", ");
+                self.pattern(inner.next().unwrap());
+                self.push(")");
+            }
+            Rule::pat_list => {
+                self.push("[");
+                for (n, pattern) in pair.into_inner().enumerate() {
+                    if n > 0 {
+                        self.push(", ");
+                    }
+                    self.pattern(pattern);
+                }
+                self.push("]");
+            }
+            _ => self.push(pair.as_str()),
+        }
+    }
+
+    /// Print expressions separated by commas.
+    fn exprs(&mut self, pairs: Pairs<Rule>, indent: usize) {
+        for (n, expr) in pairs.enumerate() {
+            if n > 0 {
+                self.push(", ");
+            }
+            self.expr(expr, indent);
+        }
+    }
+
+    /// Print an expression, or one of the factors, operators and suffixes
+    /// that make it up.
+    fn expr(&mut self, pair: Pair<Rule>, indent: usize) {
+        match pair.as_rule() {
+            Rule::expr => for part in pair.into_inner() {
+                // a nested expression is one that was parenthesized
+                if part.as_rule() == Rule::expr {
+                    self.push("(");
+                    self.expr(part, indent);
+                    self.push(")");
+                } else {
+                    self.expr(part, indent);
+                }
+            },
+            Rule::op_not => self.push("not "),
+            Rule::op_delay => self.push("delay "),
+            Rule::op_neg => self.push("-"),
+            Rule::op_compose | Rule::op_add | Rule::op_sub | Rule::op_mul | Rule::op_le | Rule::op_ge |
+                Rule::op_lt | Rule::op_gt | Rule::op_eq | Rule::op_ne | Rule::op_and | Rule::op_or => {
+                self.push(&format!(" {} ", pair.as_str()));
+            }
+            Rule::arguments => {
+                self.push("(");
+                self.exprs(pair.into_inner(), indent);
+                self.push(")");
+            }
+            Rule::index | Rule::list => {
+                self.push("[");
+                self.exprs(pair.into_inner(), indent);
+                self.push("]");
+            }
+            Rule::pair => {
+                self.push("(");
+                self.exprs(pair.into_inner(), indent);
+                self.push(")");
+            }
+            Rule::map => {
+                self.push("{");
+                for (n, entry) in pair.into_inner().enumerate() {
+                    if n > 0 {
+                        self.push(", ");
+                    }
+                    let mut entry = entry.into_inner();
+                    self.push(entry.next().unwrap().as_str());
+                    self.push(": ");
+                    self.expr(entry.next().unwrap(), indent);
+                }
+                self.push("}");
+            }
+            Rule::call => {
+                let mut inner = pair.into_inner();
+                self.push(inner.next().unwrap().as_str());
+                self.push("(");
+                self.exprs(inner, indent);
+                self.push(")");
+            }
+            Rule::lambda => {
+                let mut inner = pair.into_inner();
+                self.push("fun");
+                self.params(inner.next().unwrap());
+                self.push(" ");
+                self.inline_block(inner.next().unwrap(), indent);
+            }
+            // a definition in an expression is always parenthesized
+            Rule::funcdef => {
+                self.push("(");
+                self.funcdef(pair, indent);
+                self.push(")");
+            }
+            _ => self.push(pair.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_format() {
+        let source = "// counts\nn=0;def inc(x){ n=n+x; /* by x */ return n;}\n\n\n\
+                      f = fun(x){x*2};   // doubles\nif(n<1){n=(n+1)*2;}else if(n>2){n=-n;}else{}\n\
+                      for(i=0;;i=i+1){ break; }\nmatch (f(n)) { (a,_) => { a } [1, x]=>{ y = x; y } }\nm = {\"a\":[1,2], \"b\":(1,2)}[\"a\"];\n";
+        assert_eq!(
+            "// counts\n\
+             n = 0;\n\
+             def inc(x) {\n    n = n + x;  /* by x */\n    return n;\n}\n\
+             \n\
+             f = fun(x) { x * 2 };  // doubles\n\
+             if (n < 1) {\n    n = (n + 1) * 2;\n} else if (n > 2) {\n    n = -n;\n} else {}\n\
+             for (i = 0;; i = i + 1) {\n    break;\n}\n\
+             match (f(n)) {\n    (a, _) => { a }\n    [1, x] => {\n        y = x;\n        y\n    }\n}\n\
+             m = {\"a\": [1, 2], \"b\": (1, 2)}[\"a\"];\n",
+            format(source).unwrap());
+    }
+
+    #[test]
+    fn test_format_examples() {
+        // laying out keeps the program, and laying it out again changes nothing
+        for entry in fs::read_dir("examples/conformance").unwrap() {
+            let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+            let formatted = format(&source).unwrap();
+            assert_eq!(parser::parse(&source).unwrap(), parser::parse(&formatted).unwrap());
+            assert_eq!(formatted, format(&formatted).unwrap());
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Syntax-highlighted listings of programs, from their tokens: with ANSI
+//! colors for terminals, or as HTML for web pages, each token in a
+//! `<span>` whose class is its kind, for a style sheet to color.
+
+use token::{self, TokenKind};
+
+/// The ANSI color of tokens of `kind`, if they are colored.
+fn color(kind: TokenKind) -> Option<&'static str> {
+    match kind {
+        TokenKind::Keyword => Some("1;35"),
+        TokenKind::Builtin => Some("34"),
+        TokenKind::Number | TokenKind::Float | TokenKind::Boolean => Some("36"),
+        TokenKind::Str => Some("32"),
+        TokenKind::Operator => Some("33"),
+        TokenKind::Comment => Some("90"),
+        TokenKind::Error => Some("4;31"),
+        TokenKind::Identifier | TokenKind::Punctuation | TokenKind::Whitespace => None,
+    }
+}
+
+/// `source` with its tokens colored for a terminal.
+pub fn ansi(source: &str) -> String {
+    let mut out = String::new();
+    for token in token::tokenize(source) {
+        match color(token.kind) {
+            Some(color) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", color, token.text)),
+            None => out.push_str(token.text),
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// `source` as an HTML `<pre>` block, each token but whitespace and
+/// punctuation in a `<span>` of the class named after its kind, say
+/// `keyword` or `string`.
+pub fn html(source: &str) -> String {
+    let mut out = String::from("<pre class=\"simple\"><code>");
+    for token in token::tokenize(source) {
+        match token.kind {
+            TokenKind::Whitespace | TokenKind::Punctuation => out.push_str(&escape(token.text)),
+            kind => out.push_str(&format!("<span class=\"{}\">{}</span>", kind, escape(token.text))),
+        }
+    }
+    out.push_str("</code></pre>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi() {
+        assert_eq!("\x1b[1;35mwhile\x1b[0m (x \x1b[33m<\x1b[0m \x1b[36m3\x1b[0m) {}", ansi("while (x < 3) {}"));
+        assert_eq!("\x1b[34mprint\x1b[0m(\x1b[32m\"hi\"\x1b[0m) \x1b[90m// greet\x1b[0m\n",
+                   ansi("print(\"hi\") // greet\n"));
+    }
+
+    #[test]
+    fn test_html() {
+        assert_eq!("<pre class=\"simple\"><code><span class=\"identifier\">s</span> <span class=\"operator\">=</span> \
+                    <span class=\"string\">&quot;&lt;b&gt; &amp;&quot;</span>;</code></pre>\n",
+                   html("s = \"<b> &\";"));
+    }
+}
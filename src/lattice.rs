@@ -0,0 +1,46 @@
+//! What the static analyses, `typecheck` and `sign`, know of the
+//! variables in scope, and how two such scopes come together where a
+//! program may take either of two ways, say the branches of an `if`.
+
+use std::collections::HashMap;
+
+/// An abstract value, the type or the sign of a variable.
+pub trait Join: Clone {
+    /// What both `self` and `other` are, the least precise of the two.
+    fn join(&self, other: &Self) -> Self;
+}
+
+/// The variables of `left` and `right`, one of which a program may come
+/// out of: those of both are joined, the others kept as they are.
+pub fn merge<T: Join>(left: &HashMap<String, T>, right: &HashMap<String, T>) -> HashMap<String, T> {
+    let mut scope = left.clone();
+    for (name, found) in right {
+        let joined = match left.get(name) {
+            Some(known) => known.join(found),
+            None => found.clone(),
+        };
+        scope.insert(name.clone(), joined);
+    }
+    scope
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Join for i64 {
+        fn join(&self, other: &i64) -> i64 {
+            (*self).max(*other)
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let left: HashMap<String, i64> = vec![("x".to_string(), 1), ("y".to_string(), 5)].into_iter().collect();
+        let right: HashMap<String, i64> = vec![("x".to_string(), 3), ("z".to_string(), 2)].into_iter().collect();
+        let merged = merge(&left, &right);
+        assert_eq!(3, merged.len());
+        assert_eq!((Some(&3), Some(&5), Some(&2)), (merged.get("x"), merged.get("y"), merged.get("z")));
+        assert_eq!(merged, merge(&right, &left));
+    }
+}
@@ -1 +1,33 @@
+// AST nodes are boxed everywhere, including inside argument and element lists
+#![allow(clippy::vec_box)]
+
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
 pub mod simple;
+pub mod parser;
+pub mod timing;
+pub mod config;
+pub mod expect;
+pub mod format;
+pub mod lattice;
+pub mod typecheck;
+pub mod sign;
+pub mod token;
+pub mod highlight;
+pub mod automata;
@@ -1,184 +1,771 @@
 extern crate proglang;
 extern crate pest;
 #[macro_use]
-extern crate pest_derive;
-#[macro_use]
 extern crate lazy_static;
+#[cfg(unix)]
+extern crate libc;
 
 use proglang::simple::syntax::{Node};
-use proglang::simple::machine::{Machine};
+use proglang::simple::environment::Environment;
+use proglang::simple::machine::{Machine, Semantics};
+use proglang::simple::evaluate::{self, Assignment, AssignHook, RuntimeError, Stopped};
+use proglang::simple::trace::{Event, Replay, Trace};
+use proglang::simple::analysis;
+use proglang::simple::subst;
+use proglang::simple::deps;
+use proglang::simple::js;
+use proglang::simple::rust;
+use proglang::simple::wasm;
+use proglang::simple::builtin;
+use proglang::simple::diagnostics::Diagnostics;
+use proglang::simple::numfmt::NumberFormat;
+use proglang::parser::{self, Rule};
+use proglang::timing::Timings;
+use proglang::config::Options;
+use proglang::expect;
+use proglang::format;
+use proglang::typecheck::typecheck;
+use proglang::sign;
+use proglang::highlight;
+use proglang::token;
 
-use pest::Parser;
 use pest::iterators::{Pair};
-use pest::prec_climber::{Assoc, PrecClimber, Operator};
 
 use std::env;
 use std::process;
+use std::panic::{self, AssertUnwindSafe};
 use std::fs::File;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+lazy_static! {
+    /// Raised by Ctrl-C, watched by the machine running the program.
+    static ref INTERRUPT: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// Exit status after Ctrl-C, 128 + SIGINT as shells report it.
+const INTERRUPTED_STATUS: i32 = 130;
+/// Exit status after running out of steps or time, as `timeout` reports.
+const TIMEOUT_STATUS: i32 = 124;
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPT.store(true, Ordering::Relaxed);
+}
+
+/// Restores the previous SIGINT disposition when dropped.
+#[cfg(unix)]
+struct SigintGuard(libc::sighandler_t);
+
+#[cfg(unix)]
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        unsafe { libc::signal(libc::SIGINT, self.0); }
+    }
+}
 
-#[cfg(debug_assertions)]
-const _GRAMMAR: &'static str = include_str!("simple.pest");
+/// Run `f` without the panic hook reporting, for errors `f` reports itself.
+fn quietly<T, F: FnOnce() -> T>(f: F) -> T {
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = f();
+    panic::set_hook(report);
+    result
+}
 
-#[derive(Parser)]
-#[grammar = "simple.pest"]
-struct SimpleParser;
+/// Run `f` with Ctrl-C raising INTERRUPT instead of killing the process.
+#[cfg(unix)]
+fn with_sigint<T, F: FnOnce() -> T>(f: F) -> T {
+    INTERRUPT.store(false, Ordering::Relaxed);
+    let handler = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let _guard = SigintGuard(unsafe { libc::signal(libc::SIGINT, handler) });
+    f()
+}
+
+#[cfg(not(unix))]
+fn with_sigint<T, F: FnOnce() -> T>(f: F) -> T {
+    f()
+}
+
+const USAGE: &str = "Usage: simple [options] [command]\n\
+\n\
+Commands:\n\
+\x20   run <file>            run a program; `simple <file>` for short\n\
+\x20   -e <source>           run the program given on the command line\n\
+\x20   parse <file>          print the parse tree of a program\n\
+\x20   check <file>          report errors and warnings without running\n\
+\x20   signs <file>          print the possible signs of its numeric variables\n\
+\x20   diverge <file>        shrink it to where the backends disagree, if they do\n\
+\x20   fmt <file>            print a program laid out the standard way\n\
+\x20   highlight <file> [--html]\n\
+\x20                         print a program with its syntax colored, or as HTML\n\
+\x20   deps <file> [--json]  print which assignments read which, as DOT or JSON\n\
+\x20   compile <file> [--target js|ruby|rust|wasm]\n\
+\x20                         print the program as a script for another language\n\
+\x20   repl                  run statements as they are typed, the default\n\
+\x20   trace <file> [--json] [--save <trace file>]\n\
+\x20                         record the steps and assignments of a run\n\
+\x20   trace-diff <trace file> <trace file>\n\
+\x20                         compare two recorded runs\n\
+\x20   replay <trace file>   step through a recorded run without running it\n\
+\n\
+A <file> of - is the standard input.\n\
+\n\
+Options:\n\
+\x20   --time --stream --ast --quiet --pure --[no-]color\n\
+\x20   --backend=machine|subst --step-limit=N --timeout=MS\n\
+\x20   --trace[=none|full] --break-on-assign NAME\n\
+\x20   --number-format=plain|<locale> --number-width=N";
+
+/// Names that are commands rather than the program to run.
+const COMMANDS: &[&str] = &["run", "parse", "check", "signs", "diverge", "fmt", "highlight", "deps", "compile", "repl", "trace", "trace-diff", "replay"];
 
 pub fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: ./simple-parser <source file>");
-        process::exit(1);
+    // have syntax errors say what punctuation the grammar would have taken
+    pest::set_error_detail(true);
+    // say where in the program an error was raised, when it is known
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Some(span) = evaluate::span() {
+            eprintln!("error at {}", span);
+        }
+        report(info);
+    }));
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let save = take_value(&mut args, "--save");
+    let inline = take_value(&mut args, "-e");
+    let json = take_flag(&mut args, "--json");
+    let html = take_flag(&mut args, "--html");
+    let target = take_value(&mut args, "--target");
+    let (options, args) = match Options::from_args(&args) {
+        Ok((cli, rest)) => {
+            let dir = env::current_dir().unwrap_or_else(|e| panic!("{}", e));
+            let defaults = Options::defaults(&dir).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            (defaults.merge(cli), rest)
+        }
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] if inline.is_some() => parse_simple(&one_liner(inline.unwrap()), &options),
+        _ if inline.is_some() => {
+            eprintln!("Expect no command with -e\n{}", USAGE);
+            process::exit(1);
+        }
+        [] | ["repl"] => repl(&options),
+        ["run", path] => run(path, &options),
+        ["parse", path] => print_tree(&read_source(path)),
+        ["check", path] => check(&read_source(path), &options),
+        ["signs", path] => print_signs(&read_source(path)),
+        ["diverge", path] => print_divergence(&read_source(path)),
+        ["fmt", path] => format_source(&read_source(path)),
+        ["highlight", path] => print_highlighted(&read_source(path), html, &options),
+        ["deps", path] => print_deps(&read_source(path), json),
+        ["compile", path] => compile(&read_source(path), target.as_deref().unwrap_or("js")),
+        ["trace", path] => record_trace(&read_source(path), &options, save.as_deref(), json),
+        ["trace-diff", left, right] => trace_diff(left, right),
+        ["replay", path] => replay(path),
+        [path] if !COMMANDS.contains(path) => run(path, &options),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(path: &str, options: &Options) {
+    if options.stream == Some(true) {
+        stream_simple(path, options);
+    } else {
+        parse_simple(&read_source(path), options);
     }
-    for arg in env::args().skip(1) {
-        let mut f = File::open(&arg).expect(&format!("file {} not found", arg));
-        let mut content = String::new();
-        f.read_to_string(&mut content).expect(&format!("Error in reading file {}", arg));
-        parse_simple(&content);
+}
+
+/// Remove `flag` and the value following it from `args`, returning the value.
+fn take_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    if pos + 1 == args.len() {
+        eprintln!("Expect a value after {}\n{}", flag, USAGE);
+        process::exit(1);
     }
+    args.remove(pos);
+    Some(args.remove(pos))
 }
 
-fn parse_simple(content: &str) {
-    let pair = SimpleParser::parse(Rule::simple, content)
-        .unwrap_or_else(|e| panic!("{}", e))
-        .next().unwrap();
-    iterate_rules(pair.clone(), 0);
-    let ast = build_stats(pair);
-    let mut machine = Machine::new_with_empty_env(ast);
-    machine.run();
-    println!("{}", machine.get_environment().get("result"));
+/// Remove `flag` from `args`, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
 }
 
-fn build_stats(pair: Pair<Rule>) -> Box<Node> {
-    let inner = pair.into_inner();
-    let nodes : Vec<_> = inner.into_iter().map(|pair| build_stat(pair)).collect();
-    if nodes.is_empty() {
-        Node::donothing()
+/// The program of a `-e` one-liner, which may leave out the `;` ending
+/// its last statement.
+fn one_liner(source: String) -> String {
+    let completed = format!("{};", source.trim_end());
+    if parser::parse(&source).is_err() && parser::parse(&completed).is_ok() {
+        completed
     } else {
-        let first = nodes[0].clone();
-        nodes.iter().skip(1).fold(
-            first, |acc, node| Node::sequence(acc, node.clone()))
+        source
     }
 }
 
-fn build_stat(pair: Pair<Rule>) -> Box<Node> {
-    match pair.as_rule() {
-        Rule::stat_assign => build_assign(pair),
-        Rule::stat_if => build_if(pair),
-        Rule::stat_while => build_while(pair),
-        Rule::expr => climb(pair),
-        Rule::funcdef => build_func(pair),
-        _ => unreachable!(),
+/// The file at `path`, or with `-`, the standard input.
+fn open_source(path: &str) -> Box<dyn Read> {
+    if path == "-" {
+        return Box::new(io::stdin());
     }
+    Box::new(File::open(path).unwrap_or_else(|_| panic!("file {} not found", path)))
 }
 
-fn build_assign(pair: Pair<Rule>) -> Box<Node> {
-    let mut inner = pair.into_inner();
-    let lhs = inner.next().unwrap().as_str();
-    let node = inner.next().unwrap();
-    let rhs = match node.as_rule() {
-        Rule::expr => climb(node),
-        Rule::funcdef => build_func(node),
-        _ => unreachable!(),
+fn read_source(path: &str) -> String {
+    let mut content = String::new();
+    open_source(path).read_to_string(&mut content).unwrap_or_else(|_| panic!("Error in reading file {}", path));
+    content
+}
+
+/// Record a run of the program with the selected backend, into `save` or
+/// on stdout, as JSON with `--json`.
+fn record_trace(content: &str, options: &Options, save: Option<&str>, json: bool) {
+    let ast = parser::parse(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    check_backend(&ast, options);
+    let machine = runner(ast, options);
+    let (trace, result) = with_sigint(|| quietly(|| match options.backend.as_deref() {
+        None | Some("machine") => Trace::record(machine),
+        Some("subst") => Trace::record_subst(machine),
+        Some(backend) => {
+            eprintln!("Unknown backend {}, expect machine or subst", backend);
+            process::exit(1);
+        }
+    }));
+    match save {
+        Some(path) => {
+            let saved = if json { trace.save_json(path) } else { trace.save(path) };
+            saved.unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            eprintln!("saved {} events to {}", trace.events.len(), path);
+        }
+        None if json => print!("{}", trace.to_json()),
+        None => print!("{}", trace),
+    }
+    // the trace up to where the run stopped is kept, the error follows it
+    match result {
+        Ok(_) => (),
+        Err(RuntimeError::Stopped(stopped)) => {
+            eprintln!("{}", stopped);
+            process::exit(match stopped {
+                Stopped::Interrupted(_) => INTERRUPTED_STATUS,
+                Stopped::Timeout(_) => TIMEOUT_STATUS,
+            });
+        }
+        Err(failed) => {
+            eprintln!("{}", failed);
+            process::exit(1);
+        }
+    }
+}
+
+const REPLAY_HELP: &str = "Enter or n: next step, b: previous step, <number>: go to that step, \
+                           env: the variables, q: quit";
+
+/// Step through a saved trace as told on the standard input, showing each
+/// step with the assignments following it.
+fn replay(path: &str) {
+    let trace = Trace::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let mut replay = Replay::new(&trace);
+    if replay.is_empty() {
+        println!("{} has no steps", path);
+        return;
+    }
+    println!("{} steps\n{}", replay.len(), REPLAY_HELP);
+    let stdin = io::stdin();
+    let mut show = true;
+    loop {
+        if show {
+            println!("step {}/{}: {}", replay.position(), replay.len(), replay.step().unwrap_or_default());
+            for (name, value) in replay.assignments() {
+                println!("    {} = {}", name, value);
+            }
+        }
+        print!("replay> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            break;
+        }
+        show = match line.trim() {
+            "" | "n" => replay.forward() || { eprintln!("at the last step"); false },
+            "b" => replay.backward() || { eprintln!("at the first step"); false },
+            "env" => {
+                for (name, value) in replay.variables() {
+                    println!("{} = {}", name, value);
+                }
+                false
+            }
+            "q" => break,
+            command => match command.parse() {
+                Ok(step) => replay.jump(step).map_err(|e| eprintln!("{}", e)).is_ok(),
+                Err(_) => {
+                    eprintln!("{}", REPLAY_HELP);
+                    false
+                }
+            },
+        };
+    }
+}
+
+/// Compare two saved traces, exiting with 1 if they diverge.
+fn trace_diff(left_path: &str, right_path: &str) {
+    let load = |path| Trace::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let (left, right) = (load(left_path), load(right_path));
+    let divergence = match left.diff(&right) {
+        Some(divergence) => divergence,
+        None => {
+            println!("traces agree on every assignment");
+            return;
+        }
     };
-    Node::assign(lhs, rhs)
+    println!("traces diverge after {} matching assignment(s)", divergence.agreed);
+    for &(path, trace, index) in [(left_path, &left, divergence.left), (right_path, &right, divergence.right)].iter() {
+        match index {
+            Some(index) => {
+                if let Event::Assign(ref name, ref value) = trace.events[index] {
+                    println!("{}:{}: {} = {}", path, index + 1, name, value);
+                }
+                if let Some(step) = trace.step_before(index) {
+                    println!("    after evaluating {}", step);
+                }
+            }
+            None => println!("{}: no more assignments", path),
+        }
+    }
+    process::exit(1);
 }
 
-fn build_if(pair: Pair<Rule>) -> Box<Node> {
-    let mut inner = pair.into_inner();
-    let cond = climb(inner.next().unwrap());
-    let then = build_stats(inner.next().unwrap());
-    match inner.next() {
-        Some(stmt) => Node::if_cond_else(cond, then, build_stats(stmt)),
-        None => Node::if_cond_else(cond, then, Node::donothing())
+/// The semantics selected by `--backend`.
+fn semantics(options: &Options) -> Semantics {
+    match options.backend.as_deref() {
+        None | Some("machine") => Semantics::BigStep,
+        Some("subst") => Semantics::SmallStep,
+        Some(backend) => {
+            eprintln!("Unknown backend {}, expect machine or subst", backend);
+            process::exit(1);
+        }
     }
 }
 
-fn build_while(pair: Pair<Rule>) -> Box<Node> {
-    let mut inner = pair.into_inner();
-    let cond = climb(inner.next().unwrap());
-    let stmt = build_stats(inner.next().unwrap());
-    Node::while_node(cond, stmt)
+/// Exit with 1 if `program` cannot run on the backend `--backend` selects.
+fn check_backend(program: &Node, options: &Options) {
+    if semantics(options) == Semantics::SmallStep {
+        if let Err(e) = subst::check(program) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// How `--number-format` and `--number-width` have printed numbers laid out.
+fn number_format(options: &Options) -> NumberFormat {
+    let name = options.number_format.as_deref().unwrap_or("plain");
+    let format = NumberFormat::locale(name).unwrap_or_else(|| {
+        eprintln!("Unknown number format {}, expect plain or a locale such as en or de_DE", name);
+        process::exit(1);
+    });
+    format.with_width(options.number_width.unwrap_or(0))
 }
 
-fn build_func(pair: Pair<Rule>) -> Box<Node> {
-    let mut inner = pair.into_inner();
-    let funcname = inner.next().unwrap().as_str();
-    let mut next = inner.next().unwrap();
-    let mut argname = "";
-    if next.as_rule() == Rule::variable {
-        argname = next.as_str();
-        next = inner.next().unwrap();
+/// A machine for the runner, interruptible by Ctrl-C, limited as asked
+/// with `--step-limit` and `--timeout`, traced with `--trace` unless
+/// `--quiet`, and reporting the assignments asked for with
+/// `--break-on-assign`.
+fn runner(program: Box<Node>, options: &Options) -> Machine {
+    let mut machine = Machine::new_with_empty_env(program)
+        .with_semantics(semantics(options))
+        .with_cancellation(INTERRUPT.clone())
+        .with_number_format(number_format(options));
+    if let Some(max_steps) = options.step_limit {
+        machine = machine.with_step_limit(max_steps);
+    }
+    if let Some(timeout) = options.timeout {
+        machine = machine.with_timeout(Duration::from_millis(timeout));
+    }
+    match options.trace.as_deref() {
+        _ if options.quiet == Some(true) => (),
+        None | Some("none") => (),
+        Some("full") => machine = machine.with_trace(Rc::new(RefCell::new(io::stderr()))),
+        Some(format) => {
+            eprintln!("Unknown trace format {}, expect none or full", format);
+            process::exit(1);
+        }
+    }
+    match options.break_on_assign.clone() {
+        Some(name) => {
+            let hook: AssignHook = Rc::new(move |assignment: &Assignment| if assignment.name == name {
+                let old = assignment.old.as_ref().map_or("unbound".to_string(), |old| old.to_string());
+                let at = evaluate::span().map_or(String::new(), |span| format!(" at {}:{}", span.line, span.col));
+                eprintln!("*** {} = {} (was {}) by `{}`{}", name, assignment.new, old, assignment.statement, at);
+            });
+            machine.with_assign_hook(hook)
+        }
+        None => machine,
     }
-    let body = build_stats(next);
-    Node::fun(funcname, argname, body)
 }
 
-lazy_static! {
-    static ref PREC_CLIMBER: PrecClimber<Rule> = build_precedence_climber();
-}
-
-fn build_precedence_climber() -> PrecClimber<Rule> {
-    PrecClimber::new(vec![
-        Operator::new(Rule::op_mul, Assoc::Left),
-        Operator::new(Rule::op_add, Assoc::Left) |
-        Operator::new(Rule::op_sub, Assoc::Left),
-        Operator::new(Rule::op_lt,  Assoc::Left) |
-        Operator::new(Rule::op_gt,  Assoc::Left),
-        Operator::new(Rule::op_eq,  Assoc::Left),
-    ])
-}
-
-fn infix_rule(lhs: Box<Node>, op: Pair<Rule>, rhs: Box<Node>) -> Box<Node> {
-    match op.as_rule() {
-        Rule::op_add => Node::add(lhs, rhs),
-        Rule::op_sub => Node::subtract(lhs, rhs),
-        Rule::op_mul => Node::multiply(lhs, rhs),
-        Rule::op_lt  => Node::lt(lhs, rhs),
-        Rule::op_gt  => Node::gt(lhs, rhs),
-        Rule::op_eq  => Node::eq(lhs, rhs),
-        _ => unreachable!(),
-    }
-}
-
-fn climb(pair: Pair<Rule>) -> Box<Node> {
-    PREC_CLIMBER.climb(pair.into_inner(), build_factor, infix_rule)
-}
-
-fn build_call(pair: Pair<Rule>) -> Box<Node> {
-    let mut inner = pair.into_inner();
-    let var = inner.next().unwrap().as_str();
-    match var.as_ref() {
-        "pair" => Node::pair(climb(inner.next().unwrap()), climb(inner.next().unwrap())),
-        "fst"  => Node::fst(climb(inner.next().unwrap())),
-        "snd"  => Node::snd(climb(inner.next().unwrap())),
-        "nothing"   => Node::donothing(),
-        "isnothing" => Node::isdonothing(climb(inner.next().unwrap())),
-        &_     => {
-            let arg = match inner.next() {
-                Some(pair) => climb(pair),
-                None => Node::donothing(),
-            };
-            Node::call(Node::variable(var), arg)
-        }
-    }
-}
-
-fn build_list(pair: Pair<Rule>) -> Box<Node> {
-    pair.into_inner()
-        .map(|pair| climb(pair))
-        .rev()
-        .fold(Node::donothing(), |cdr, car| Node::pair(car, cdr))
-}
-
-fn build_factor(pair: Pair<Rule>) -> Box<Node> {
-    match pair.as_rule() {
-        Rule::variable => Node::variable(pair.as_str()),
-        Rule::number => Node::number(pair.as_str().parse::<i64>().unwrap()),
-        Rule::expr => climb(pair),
-        Rule::call => build_call(pair),
-        Rule::list => build_list(pair),
-        _ => unreachable!(),
+/// Under `--pure`, why `node` may not run in `env`: the effects it has,
+/// and those of the functions it defines.
+fn impure(node: &Node, env: &Environment, options: &Options) -> Option<String> {
+    if options.pure != Some(true) {
+        return None;
+    }
+    let effects = analysis::effects_in_env(node, env);
+    if effects.is_pure() {
+        return None;
+    }
+    let mut reason = format!("refusing to run code with effects ({}) under --pure", effects);
+    for (name, effects) in analysis::function_effects(node) {
+        if !effects.is_pure() {
+            reason.push_str(&format!("\n  function `{}`: {}", name, effects));
+        }
+    }
+    Some(reason)
+}
+
+/// Report what the analyses have to say about a program.
+fn lint(program: &Node, diagnostics: &Diagnostics) {
+    let mut warnings = analysis::ambiguous_assignments(program);
+    warnings.extend(analysis::unstable_kinds(program));
+    for warning in warnings {
+        diagnostics.warning(&warning, None);
+    }
+}
+
+/// Write out the diagnostics collected so far, after what the program
+/// printed.
+fn emit(diagnostics: &Diagnostics) {
+    io::stdout().flush().unwrap();
+    diagnostics.emit(&mut io::stderr()).unwrap();
+}
+
+/// Report what can be told of a program without running it: syntax
+/// errors, type errors, warnings, malformed expectations and, with
+/// `--pure`, effects. Exits with 1 if the program could not run.
+fn check(content: &str, options: &Options) {
+    let spanned = parser::parse_spanned(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let ast = spanned.without_spans();
+    let diagnostics = Diagnostics::new();
+    lint(&ast, &diagnostics);
+    // too noisy to report on every run
+    let checked = expect::expectations(content).unwrap_or_default().iter()
+        .flat_map(|expectation| evaluate::get_free_vars(&expectation.condition))
+        .collect();
+    let mut warnings = analysis::unassigned_reads(&ast);
+    warnings.extend(analysis::unused_assignments_with(&ast, &checked));
+    for warning in warnings {
+        diagnostics.warning(&warning, None);
+    }
+    if let Err(errors) = typecheck(&spanned) {
+        for error in errors {
+            diagnostics.error(&error.message, error.span);
+        }
+    }
+    if let Err(e) = expect::expectations(content) {
+        diagnostics.error(&e.to_string(), None);
+    }
+    if let Some(reason) = impure(&ast, &Environment::new(), options) {
+        diagnostics.error(&reason, None);
+    }
+    let runs = !diagnostics.has_errors();
+    emit(&diagnostics);
+    if !runs {
+        process::exit(1);
+    }
+}
+
+fn print_signs(content: &str) {
+    let ast = parser::parse(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    for (name, sign) in sign::analyze(&ast).variables {
+        println!("{}: {}", name, sign);
+    }
+}
+
+/// Print the smallest program on which the backends disagree where they
+/// disagree on the program `content`, and exit with 1.
+fn print_divergence(content: &str) {
+    let ast = parser::parse(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Some(program) = quietly(|| subst::divergence(&ast)) {
+        println!("{}", program);
+        process::exit(1);
+    }
+    println!("The backends agree");
+}
+
+fn format_source(content: &str) {
+    match format::format(content) {
+        Ok(formatted) => print!("{}", formatted),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print `content` highlighted as HTML, or for the terminal unless
+/// `--no-color` says otherwise.
+fn print_highlighted(content: &str, html: bool, options: &Options) {
+    if html {
+        print!("{}", highlight::html(content));
+    } else if options.color == Some(false) {
+        print!("{}", content);
+    } else {
+        print!("{}", highlight::ansi(content));
+    }
+}
+
+fn print_deps(content: &str, json: bool) {
+    let ast = parser::parse_spanned(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let graph = deps::graph(&ast);
+    print!("{}", if json { graph.to_json() } else { graph.to_dot() });
+}
+
+fn compile(content: &str, target: &str) {
+    let ast = parser::parse(content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    match target {
+        "js" => print!("{}", js::compile(&ast)),
+        // the book's lambda from an environment, for a host to call
+        "ruby" => println!("{}", ast.to_ruby()),
+        "rust" => print!("{}", rust::compile(&ast)),
+        // a binary module, for a runtime to load
+        "wasm" => match wasm::compile(&ast) {
+            Ok(module) => io::stdout().write_all(&module).unwrap(),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("Unknown target {}, expect js, ruby, rust or wasm", target);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_tree(content: &str) {
+    match parser::parse_tree(content) {
+        Ok(tree) => iterate_rules(tree, 0),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_simple(content: &str, options: &Options) {
+    let mut timings = Timings::new();
+    let ast = timings.time("parse", || parser::parse_spanned(content)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let quiet = options.quiet == Some(true);
+    if options.ast == Some(true) && !quiet {
+        print_tree(content);
+    }
+    // quiet runs leave out the lints, not the warnings the program makes
+    let diagnostics = Diagnostics::new();
+    let lints = if quiet { Diagnostics::new() } else { diagnostics.clone() };
+    timings.time("analysis", || lint(&ast.without_spans(), &lints));
+    let expectations = expect::expectations(content).unwrap_or_else(|e| {
+        diagnostics.error(&e.to_string(), None);
+        emit(&diagnostics);
+        process::exit(1);
+    });
+    check_backend(&ast, options);
+    let mut machine = runner(ast, options).with_diagnostics(diagnostics.clone());
+    if let Some(reason) = impure(&machine.program().without_spans(), &machine.get_environment(), options) {
+        diagnostics.error(&reason, None);
+        emit(&diagnostics);
+        process::exit(1);
+    }
+    let run = timings.time("execution", || with_sigint(|| quietly(|| machine.run())));
+    let value = match run {
+        Ok(value) => value,
+        Err(RuntimeError::Stopped(stopped)) => {
+            emit(&diagnostics);
+            eprintln!("{}", stopped);
+            eprintln!("{}", machine.get_environment());
+            process::exit(match stopped {
+                Stopped::Interrupted(_) => INTERRUPTED_STATUS,
+                Stopped::Timeout(_) => TIMEOUT_STATUS,
+            });
+        }
+        // the error carries where it was raised
+        Err(failed) => {
+            emit(&diagnostics);
+            eprintln!("{}", failed);
+            process::exit(1);
+        }
+    };
+    let env = machine.get_environment();
+    for expectation in &expectations {
+        if let Err(failure) = expectation.check(&env) {
+            diagnostics.error(&format!("expectation failed at {}", failure), None);
+        }
+    }
+    if diagnostics.has_errors() {
+        emit(&diagnostics);
+        process::exit(1);
+    }
+    print_result(&env, &value, machine.number_format());
+    emit(&diagnostics);
+    if options.time == Some(true) {
+        eprintln!("{}", timings);
+    }
+}
+
+/// Parse and run a program a chunk of statements at a time, for programs
+/// too large to hold whole. Analysis warnings and expectations need the
+/// whole program and are skipped; step limits and timeouts apply to each
+/// statement, and the warnings of the program are printed as they come.
+fn stream_simple(path: &str, options: &Options) {
+    let diagnostics = Diagnostics::streaming(|diagnostic| {
+        io::stdout().flush().unwrap();
+        eprintln!("{}", diagnostic);
+    });
+    let mut machine = runner(Node::donothing(), options).with_diagnostics(diagnostics);
+    let mut value = Node::donothing();
+    for stat in parser::statements(io::BufReader::new(open_source(path))) {
+        let stat = stat.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        check_backend(&stat, options);
+        if let Some(reason) = impure(&stat, &machine.get_environment(), options) {
+            eprintln!("{}", reason);
+            process::exit(1);
+        }
+        value = with_sigint(|| machine.try_execute(&stat)).unwrap_or_else(|stopped| {
+            eprintln!("{}", stopped);
+            eprintln!("{}", machine.get_environment());
+            process::exit(match stopped {
+                Stopped::Interrupted(_) => INTERRUPTED_STATUS,
+                Stopped::Timeout(_) => TIMEOUT_STATUS,
+            });
+        });
+    }
+    print_result(&machine.get_environment(), &value, machine.number_format());
+}
+
+/// Print what a program came to: its `result` variable, or else the value
+/// of its last statement, as scripting languages do, unless that is
+/// do-nothing.
+fn print_result(env: &Environment, value: &Node, format: NumberFormat) {
+    match env.lookup("result") {
+        Some(result) => println!("{}", format.value(&result)),
+        None if *value == Node::DoNothing => (),
+        None => println!("{}", format.value(value)),
+    }
+}
+
+fn repl(options: &Options) {
+    let stdin = io::stdin();
+    let mut machine = runner(Node::donothing(), options);
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { ">> " } else { ".. " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            break;
+        }
+        if buffer.is_empty() {
+            match line.trim() {
+                "" => continue,
+                ":quit" | ":q" => break,
+                ":env" => {
+                    println!("{}", machine.get_environment());
+                    println!("builtins: {}", builtin::names().join(", "));
+                    continue;
+                }
+                command if command.starts_with(":type") => {
+                    match parser::parse(command[":type".len()..].trim()) {
+                        Ok(ref expr) if **expr == Node::DoNothing => eprintln!("Usage: :type <expression>"),
+                        Ok(expr) => println!("{}", analysis::type_of(&expr, &machine.get_environment())),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        buffer.push_str(&line);
+        // keep reading until every opened block and comment is closed
+        if token::is_open(&buffer) {
+            continue;
+        }
+        let source = buffer.split_off(0);
+        let stats = match parser::parse_statements(&source) {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        for stat in stats {
+            if let Some(reason) = impure(&stat, &machine.get_environment(), options) {
+                eprintln!("{}", reason);
+                break;
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| with_sigint(|| machine.try_execute(&stat))));
+            match result {
+                Ok(Ok(value)) => {
+                    if *value != Node::DoNothing {
+                        println!("{}", machine.number_format().value(&value));
+                    }
+                }
+                Ok(Err(stopped)) => {
+                    eprintln!("{}", stopped);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
     }
 }
 
@@ -0,0 +1,1362 @@
+use simple::syntax::{Node, Pattern, Span};
+use simple::builtin;
+
+use pest::Parser;
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
+use pest::iterators::Pair;
+use pest::pratt_parser::{Assoc, PrattParser, Op};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead};
+use std::mem;
+use std::vec;
+
+#[cfg(debug_assertions)]
+const _GRAMMAR: &str = include_str!("simple.pest");
+
+#[derive(Parser)]
+#[grammar = "simple.pest"]
+pub struct SimpleParser;
+
+#[derive(Debug)]
+pub enum ParseError {
+    Syntax(SyntaxError),
+    Internal(InternalParserError),
+    Arity(ArityError),
+    Param(ParamError),
+    Literal(LiteralError),
+    /// The source could not be read, when parsing from a reader.
+    Io(io::Error),
+}
+
+/// A rule the grammar produced where no builder expects it, which means
+/// the grammar and the builders have drifted apart.
+#[derive(Debug,Clone,PartialEq)]
+pub struct InternalParserError {
+    pub rule: Rule,
+    /// What was being built: a statement, a factor, a pattern or an operator.
+    pub expected: &'static str,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
+
+impl InternalParserError {
+    fn at(pair: &Pair<Rule>, expected: &'static str) -> ParseError {
+        let (line, col) = pair.as_span().start_pos().line_col();
+        ParseError::Internal(InternalParserError {
+            rule: pair.as_rule(), expected, line, col, text: pair.as_str().to_string(),
+        })
+    }
+}
+
+/// A call of a builtin or special form with a number of arguments it does
+/// not take, which would otherwise only fail once evaluated.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ArityError {
+    pub name: String,
+    pub arity: Arity,
+    pub found: usize,
+    pub line: usize,
+    pub col: usize,
+    /// The line of source the call is on.
+    pub source_line: String,
+}
+
+/// A parameter named a second time in the same function, which one of
+/// the arguments would otherwise silently be lost to.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ParamError {
+    pub name: String,
+    pub line: usize,
+    pub col: usize,
+    /// The line of source the parameter is on.
+    pub source_line: String,
+}
+
+/// A literal the grammar accepts but no value can hold: a number past
+/// the range of 64-bit integers.
+#[derive(Debug,Clone,PartialEq)]
+pub struct LiteralError {
+    pub text: String,
+    pub line: usize,
+    pub col: usize,
+    /// The line of source the literal is on.
+    pub source_line: String,
+}
+
+impl LiteralError {
+    fn at(pair: &Pair<Rule>) -> ParseError {
+        let start = pair.as_span().start_pos();
+        let (line, col) = start.line_col();
+        ParseError::Literal(LiteralError {
+            text: pair.as_str().to_string(), line, col, source_line: start.line_of().trim_end().to_string(),
+        })
+    }
+}
+
+/// How many arguments a builtin or special form takes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exactly(arity) => count == arity,
+            Arity::AtLeast(arity) => count >= arity,
+        }
+    }
+
+    /// The one number of arguments taken, if there is only one.
+    pub fn exact(self) -> Option<usize> {
+        match self {
+            Arity::Exactly(arity) => Some(arity),
+            Arity::AtLeast(_) => None,
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Arity::Exactly(1) => write!(f, "1 argument"),
+            Arity::Exactly(arity) => write!(f, "{} arguments", arity),
+            Arity::AtLeast(arity) => write!(f, "at least {} argument{}", arity, if arity == 1 { "" } else { "s" }),
+        }
+    }
+}
+
+/// The arguments the builtin or special form `name` takes, or `None` if
+/// it is neither.
+pub fn arity(name: &str) -> Option<Arity> {
+    match name {
+        "nothing" => Some(Arity::Exactly(0)),
+        "isnothing" => Some(Arity::Exactly(1)),
+        "cons_stream" => Some(Arity::Exactly(2)),
+        name => builtin::lookup(name).map(|builtin| builtin.arity),
+    }
+}
+
+/// Write the line `source_line`, numbered `line`, with a caret under
+/// column `col`.
+fn caret(f: &mut Formatter, line: usize, col: usize, source_line: &str) -> fmt::Result {
+    let number = line.to_string();
+    let margin = " ".repeat(number.len());
+    // keep tabs so that the caret lines up under them
+    let indent: String = source_line.chars().take(col - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    writeln!(f, "{} | {}", number, source_line)?;
+    write!(f, "{} | {}^", margin, indent)
+}
+
+/// Source that does not follow the grammar, described for people: where,
+/// what the grammar expected there and what it found instead.
+#[derive(Debug,Clone,PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub col: usize,
+    /// The line of source the error is on.
+    pub source_line: String,
+    /// The token found, or `None` at the end of the input.
+    pub found: Option<String>,
+    /// Tokens in backquotes, then kinds of syntax such as "an expression".
+    pub expected: Vec<String>,
+}
+
+/// Punctuation worth suggesting when the grammar would take it; the other
+/// literals it tries are whitespace, comments and parts of operators and
+/// numbers.
+const PUNCTUATION: &[&str] = &[";", ")", "]", "}", ",", "{", "=", "=>", ":"];
+
+/// What a rule the grammar tried stands for, or `None` for rules that
+/// could follow nearly anything.
+fn describe(rule: Rule, rules: &[Rule]) -> Option<&'static str> {
+    Some(match rule {
+        Rule::arguments | Rule::index => return None,
+        Rule::op_add | Rule::op_sub | Rule::op_mul | Rule::op_lt | Rule::op_gt | Rule::op_eq |
+            Rule::op_le | Rule::op_ge | Rule::op_ne | Rule::op_compose | Rule::op_and | Rule::op_or => "an operator",
+        // a lone variable is a name to bind, as after `def`
+        Rule::variable if rules.len() == 1 => "a name",
+        Rule::number | Rule::float | Rule::boolean | Rule::variable | Rule::string | Rule::call |
+            Rule::pair | Rule::list | Rule::map | Rule::lambda | Rule::op_not | Rule::op_neg |
+            Rule::op_delay | Rule::expr => "an expression",
+        Rule::EOI => "end of input",
+        Rule::stat_assign | Rule::stat_return | Rule::stat_break | Rule::stat_continue | Rule::stat_global |
+            Rule::stat_match | Rule::stat_while | Rule::stat_for | Rule::stat_if | Rule::funcdef | Rule::stats => "a statement",
+        Rule::pat_wildcard | Rule::pat_number | Rule::pat_pair | Rule::pat_list | Rule::arm => "a pattern",
+        Rule::entry => "a map entry",
+        Rule::params => "parameters",
+        _ => return None,
+    })
+}
+
+/// The token starting at byte `pos` of `source`: a word, or else a
+/// single character.
+fn token_at(source: &str, pos: usize) -> Option<String> {
+    let rest = source.get(pos..)?;
+    let first = rest.chars().next()?;
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    Some(if word(first) {
+        rest.chars().take_while(|&c| word(c)).collect()
+    } else {
+        first.to_string()
+    })
+}
+
+impl SyntaxError {
+    /// Describe `error`, raised parsing `source`. The punctuation the
+    /// grammar would have taken is only known to pest, and so listed here,
+    /// once the program has switched on `pest::set_error_detail`.
+    fn new(source: &str, error: &pest::error::Error<Rule>) -> SyntaxError {
+        let (line, col) = match error.line_col {
+            LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+        };
+        let pos = match error.location {
+            InputLocation::Pos(pos) | InputLocation::Span((pos, _)) => pos,
+        };
+        let mut expected: Vec<String> = Vec::new();
+        if let Some(attempts) = error.parse_attempts() {
+            for token in attempts.expected_tokens() {
+                let token = token.to_string();
+                if PUNCTUATION.contains(&token.as_str()) {
+                    expected.push(format!("`{}`", token));
+                }
+            }
+        }
+        if let ErrorVariant::ParsingError { ref positives, .. } = error.variant {
+            for description in positives.iter().filter_map(|&rule| describe(rule, positives)) {
+                if !expected.iter().any(|known| known == description) {
+                    expected.push(description.to_string());
+                }
+            }
+        }
+        SyntaxError { line, col, source_line: error.line().trim_end().to_string(), found: token_at(source, pos), expected }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "syntax error at line {}, column {}: ", self.line, self.col)?;
+        match self.expected.split_last() {
+            Some((last, [])) => write!(f, "expected {}, ", last)?,
+            Some((last, rest)) => write!(f, "expected {} or {}, ", rest.join(", "), last)?,
+            None => (),
+        }
+        match self.found {
+            Some(ref token) => writeln!(f, "found `{}`", token)?,
+            None => writeln!(f, "found end of input")?,
+        }
+        caret(f, self.line, self.col, &self.source_line)
+    }
+}
+
+impl Display for ArityError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "arity error at line {}, column {}: {} takes {}, found {}",
+                 self.line, self.col, self.name, self.arity, self.found)?;
+        caret(f, self.line, self.col, &self.source_line)
+    }
+}
+
+impl Display for ParamError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "parameter error at line {}, column {}: {} is already a parameter",
+                 self.line, self.col, self.name)?;
+        caret(f, self.line, self.col, &self.source_line)
+    }
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "literal error at line {}, column {}: {} is out of the range of integers",
+                 self.line, self.col, self.text)?;
+        caret(f, self.line, self.col, &self.source_line)
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Syntax(ref e) => write!(f, "{}", e),
+            ParseError::Arity(ref e) => write!(f, "{}", e),
+            ParseError::Param(ref e) => write!(f, "{}", e),
+            ParseError::Literal(ref e) => write!(f, "{}", e),
+            ParseError::Io(ref e) => write!(f, "Error in reading the source: {}", e),
+            ParseError::Internal(ref e) => write!(f, "internal parser error: no builder for rule {:?} as {} at {}:{}: {}",
+                                                  e.rule, e.expected, e.line, e.col, e.text),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl ParseError {
+    /// The error, raised parsing source that starts after `lines` lines of
+    /// the program, placed in the program.
+    fn below(mut self, lines: usize) -> ParseError {
+        match self {
+            ParseError::Syntax(ref mut e) => e.line += lines,
+            ParseError::Internal(ref mut e) => e.line += lines,
+            ParseError::Arity(ref mut e) => e.line += lines,
+            ParseError::Param(ref mut e) => e.line += lines,
+            ParseError::Literal(ref mut e) => e.line += lines,
+            ParseError::Io(_) => (),
+        }
+        self
+    }
+}
+
+/// Without the source at hand, the token found is only known if it is on
+/// the line of the error.
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(e: pest::error::Error<Rule>) -> ParseError {
+        let line = e.line().to_string();
+        let mut error = SyntaxError::new(&line, &e);
+        let col = line.char_indices().nth(error.col - 1).map(|(pos, _)| pos);
+        error.found = col.and_then(|pos| token_at(&line, pos));
+        ParseError::Syntax(error)
+    }
+}
+
+/// Builds the syntax tree of one grammar rule, using the registry for the
+/// rules nested in it.
+pub type Builder = fn(&Registry, Pair<Rule>) -> Result<Box<Node>, ParseError>;
+
+/// The builders of statement and factor rules. `Registry::new` holds those
+/// of the grammar as it is; a builder registered later replaces the one
+/// for the same rule.
+#[derive(Clone)]
+pub struct Registry {
+    statements: HashMap<Rule, Builder>,
+    factors: HashMap<Rule, Builder>,
+    spans: bool,
+}
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref SPANNED: Registry = Registry::new().with_spans();
+}
+
+/// Where `pair` was written. The start comes from the line index of the
+/// parse; the end is counted from there over the text of the pair.
+fn span(pair: &Pair<Rule>) -> Span {
+    let (line, col) = pair.line_col();
+    let text = pair.as_str();
+    let (end_line, end_col) = match text.rfind('\n') {
+        Some(last) => (line + text.matches('\n').count(), text[last + 1..].chars().count() + 1),
+        None => (line, col + text.chars().count()),
+    };
+    Span { line, col, end_line, end_col }
+}
+
+impl Registry {
+    /// A registry without any builder.
+    pub fn empty() -> Registry {
+        Registry { statements: HashMap::new(), factors: HashMap::new(), spans: false }
+    }
+
+    /// Wrap statements, calls and variables in `Node::Spanned`, so that
+    /// errors running them can tell where they are in the source.
+    pub fn with_spans(mut self) -> Registry {
+        self.spans = true;
+        self
+    }
+
+    pub fn new() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register_statement(Rule::stat_assign, build_assign);
+        registry.register_statement(Rule::stat_global, build_global);
+        registry.register_statement(Rule::stat_return, build_return);
+        registry.register_statement(Rule::stat_break, |_, _| Ok(Node::break_node()));
+        registry.register_statement(Rule::stat_continue, |_, _| Ok(Node::continue_node()));
+        registry.register_statement(Rule::stat_if, build_if);
+        registry.register_statement(Rule::stat_while, build_while);
+        registry.register_statement(Rule::stat_for, build_for);
+        registry.register_statement(Rule::stat_match, build_match);
+        registry.register_statement(Rule::expr, |registry, pair| registry.climb(pair));
+        registry.register_statement(Rule::funcdef, build_def);
+        registry.register_factor(Rule::variable, |_, pair| Ok(Node::variable(pair.as_str())));
+        registry.register_factor(Rule::number, |_, pair| match pair.as_str().parse::<i64>() {
+            Ok(number) => Ok(Node::number(number)),
+            Err(_) => Err(LiteralError::at(&pair)),
+        });
+        registry.register_factor(Rule::float, |_, pair| Ok(Node::float(pair.as_str().parse::<f64>().unwrap())));
+        registry.register_factor(Rule::boolean, |_, pair| Ok(Node::boolean(pair.as_str() == "true")));
+        registry.register_factor(Rule::string, |_, pair| Ok(Node::string(&unescape(pair.as_str()))));
+        registry.register_factor(Rule::expr, |registry, pair| registry.climb(pair));
+        registry.register_factor(Rule::call, build_call);
+        registry.register_factor(Rule::lambda, build_lambda);
+        // in parentheses, a definition is the function, named for recursion
+        registry.register_factor(Rule::funcdef, build_func);
+        registry.register_factor(Rule::pair, |registry, pair| {
+            let mut inner = pair.into_inner();
+            Ok(Node::pair(registry.climb(inner.next().unwrap())?, registry.climb(inner.next().unwrap())?))
+        });
+        registry.register_factor(Rule::list, build_list);
+        registry.register_factor(Rule::map, build_map);
+        registry
+    }
+
+    pub fn register_statement(&mut self, rule: Rule, builder: Builder) {
+        self.statements.insert(rule, builder);
+    }
+
+    pub fn register_factor(&mut self, rule: Rule, builder: Builder) {
+        self.factors.insert(rule, builder);
+    }
+
+    /// Parse a whole program into a single `Sequence` of its statements.
+    pub fn parse(&self, source: &str) -> Result<Box<Node>, ParseError> {
+        let pair = SimpleParser::parse(Rule::simple, source)
+            .map_err(|e| ParseError::Syntax(SyntaxError::new(source, &e)))?.next().unwrap();
+        self.stats(pair)
+    }
+
+    /// Parse a program and keep its top-level statements apart.
+    pub fn parse_statements(&self, source: &str) -> Result<Vec<Box<Node>>, ParseError> {
+        let pair = SimpleParser::parse(Rule::simple, source)
+            .map_err(|e| ParseError::Syntax(SyntaxError::new(source, &e)))?.next().unwrap();
+        pair.into_inner().map(|pair| self.stat(pair)).collect()
+    }
+
+    pub fn stat(&self, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+        let span = if self.spans { Some(span(&pair)) } else { None };
+        let node = match self.statements.get(&pair.as_rule()) {
+            Some(builder) => builder(self, pair)?,
+            None => return Err(InternalParserError::at(&pair, "a statement")),
+        };
+        Ok(match span {
+            Some(span) => Node::spanned(span, node),
+            None => node,
+        })
+    }
+
+    pub fn factor(&self, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+        let span = match pair.as_rule() {
+            Rule::variable | Rule::call if self.spans => Some(span(&pair)),
+            _ => None,
+        };
+        let node = match self.factors.get(&pair.as_rule()) {
+            Some(builder) => builder(self, pair)?,
+            None => return Err(InternalParserError::at(&pair, "a factor")),
+        };
+        Ok(match span {
+            Some(span) => Node::spanned(span, node),
+            None => node,
+        })
+    }
+
+    /// A block of statements chained into a `Sequence`.
+    pub fn stats(&self, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+        let nodes = pair.into_inner().map(|pair| self.stat(pair)).collect::<Result<Vec<_>, _>>()?;
+        let mut nodes = nodes.into_iter();
+        Ok(match nodes.next() {
+            Some(first) => nodes.fold(first, Node::sequence),
+            None => Node::donothing(),
+        })
+    }
+
+    /// An expression, its operators applied by precedence.
+    pub fn climb(&self, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+        PRATT_PARSER
+            .map_primary(|primary| self.factor(primary))
+            .map_infix(|lhs, op, rhs| infix_rule(lhs?, op, rhs?))
+            .map_prefix(|op, rhs| match rhs {
+                // the one literal only written negated
+                Err(ParseError::Literal(ref e)) if op.as_rule() == Rule::op_neg && e.text == MIN_MAGNITUDE =>
+                    Ok(Node::number(i64::MIN)),
+                rhs => prefix_rule(op, rhs?),
+            })
+            .map_postfix(|lhs, op| self.postfix_rule(lhs?, op))
+            .parse(pair.into_inner())
+    }
+
+    /// Argument lists after an arbitrary expression apply its value, as in
+    /// `make_adder(1)(2)` or `(fun(x) { x })(3)`; brackets index into it.
+    fn postfix_rule(&self, lhs: Box<Node>, op: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+        match op.as_rule() {
+            Rule::arguments => Ok(Node::call_args(lhs, self.climb_all(op)?)),
+            Rule::index => Ok(Node::index(lhs, self.climb(op.into_inner().next().unwrap())?)),
+            _ => Err(InternalParserError::at(&op, "an operator")),
+        }
+    }
+
+    /// Every expression nested in `pair`.
+    fn climb_all(&self, pair: Pair<Rule>) -> Result<Vec<Box<Node>>, ParseError> {
+        pair.into_inner().map(|expr| self.climb(expr)).collect()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+/// Parse a whole program into a single `Sequence` of its statements.
+pub fn parse(source: &str) -> Result<Box<Node>, ParseError> {
+    REGISTRY.parse(source)
+}
+
+/// Parse a whole program like `parse`, keeping where its statements,
+/// calls and variables were written for the errors of running it.
+pub fn parse_spanned(source: &str) -> Result<Box<Node>, ParseError> {
+    SPANNED.parse(source)
+}
+
+/// Parse a program and keep its top-level statements apart, for callers
+/// such as the REPL that evaluate them one at a time.
+pub fn parse_statements(source: &str) -> Result<Vec<Box<Node>>, ParseError> {
+    REGISTRY.parse_statements(source)
+}
+
+/// The parse tree of a program: its `stats`, for tools that keep to the
+/// program as written, such as the formatter.
+pub fn parse_tree(source: &str) -> Result<Pair<'_, Rule>, ParseError> {
+    let pair = SimpleParser::parse(Rule::simple, source)
+        .map_err(|e| ParseError::Syntax(SyntaxError::new(source, &e)))?.next().unwrap();
+    Ok(pair)
+}
+
+/// Where a chunk of source lines may be cut: outside strings and comments,
+/// with every block closed, after a statement's `;` or `}`.
+#[derive(Debug,Default)]
+struct Cut {
+    depth: i64,
+    in_string: bool,
+    in_comment: bool,
+    last: Option<char>,
+}
+
+impl Cut {
+    fn scan(&mut self, line: &str) {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.in_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    self.in_comment = false;
+                }
+                continue;
+            }
+            if self.in_string {
+                match c {
+                    '\\' => { chars.next(); }
+                    '"' => self.in_string = false,
+                    _ => (),
+                }
+                continue;
+            }
+            match (c, chars.peek()) {
+                ('/', Some(&'/')) => break,
+                ('/', Some(&'*')) => {
+                    chars.next();
+                    self.in_comment = true;
+                    continue;
+                }
+                ('"', _) => self.in_string = true,
+                ('{', _) => self.depth += 1,
+                ('}', _) => self.depth -= 1,
+                _ => (),
+            }
+            if !c.is_whitespace() {
+                self.last = Some(c);
+            }
+        }
+    }
+
+    fn here(&self) -> bool {
+        self.depth <= 0 && !self.in_string && !self.in_comment && matches!(self.last, Some(';') | Some('}'))
+    }
+}
+
+/// The top-level statements of a program read from `reader`, parsed a
+/// chunk of at least `chunk` bytes at a time, so that neither the source
+/// nor the syntax tree of the whole program is ever held at once. A chunk
+/// ends after a line closing a statement, unless the next line carries on
+/// with `else`; a statement continuing on the next line without either is
+/// cut in two and fails to parse.
+pub struct Statements<R> {
+    reader: R,
+    chunk: usize,
+    buffer: String,
+    /// The lines of the program before those in `buffer`.
+    line: usize,
+    cut: Cut,
+    parsed: vec::IntoIter<Box<Node>>,
+    done: bool,
+}
+
+/// Bytes of source parsed at once by `statements`.
+pub const CHUNK: usize = 64 * 1024;
+
+pub fn statements<R: BufRead>(reader: R) -> Statements<R> {
+    Statements::new(reader, CHUNK)
+}
+
+impl<R: BufRead> Statements<R> {
+    pub fn new(reader: R, chunk: usize) -> Statements<R> {
+        Statements { reader, chunk, buffer: String::new(), line: 0, cut: Cut::default(),
+                     parsed: Vec::new().into_iter(), done: false }
+    }
+
+    /// Read lines until a chunk is complete, and parse it.
+    fn fill(&mut self) -> Result<(), ParseError> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line).map_err(ParseError::Io)? == 0 {
+                self.done = true;
+                break;
+            }
+            let full = self.buffer.len() >= self.chunk && self.cut.here();
+            if full && !line.trim_start().starts_with("else") {
+                let chunk = mem::replace(&mut self.buffer, line.clone());
+                self.cut = Cut::default();
+                self.cut.scan(&line);
+                return self.parse(&chunk);
+            }
+            self.cut.scan(&line);
+            self.buffer.push_str(&line);
+        }
+        let chunk = mem::take(&mut self.buffer);
+        self.parse(&chunk)
+    }
+
+    /// Parse `chunk`, the lines following those parsed so far.
+    fn parse(&mut self, chunk: &str) -> Result<(), ParseError> {
+        let line = self.line;
+        self.parsed = REGISTRY.parse_statements(chunk).map_err(|e| e.below(line))?.into_iter();
+        self.line += chunk.matches('\n').count();
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for Statements<R> {
+    type Item = Result<Box<Node>, ParseError>;
+
+    fn next(&mut self) -> Option<Result<Box<Node>, ParseError>> {
+        loop {
+            if let Some(stat) = self.parsed.next() {
+                return Some(Ok(stat));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                // a chunk that fails to parse ends the program
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// The name and value of an assignment, the value a function definition
+/// or an expression.
+fn build_binding(registry: &Registry, pair: Pair<Rule>) -> Result<(String, Box<Node>), ParseError> {
+    let mut inner = pair.into_inner();
+    let lhs = inner.next().unwrap().as_str().to_string();
+    let node = inner.next().unwrap();
+    let rhs = match node.as_rule() {
+        Rule::expr => registry.climb(node)?,
+        Rule::funcdef => build_func(registry, node)?,
+        _ => return Err(InternalParserError::at(&node, "an assigned value")),
+    };
+    Ok((lhs, rhs))
+}
+
+fn build_assign(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let (name, rhs) = build_binding(registry, pair)?;
+    Ok(Node::assign(&name, rhs))
+}
+
+fn build_global(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let (name, rhs) = build_binding(registry, pair)?;
+    Ok(Node::global(&name, rhs))
+}
+
+fn build_return(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    Ok(match pair.into_inner().next() {
+        Some(expr) => Node::return_node(registry.climb(expr)?),
+        None => Node::return_node(Node::donothing()),
+    })
+}
+
+fn build_if(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let cond = registry.climb(inner.next().unwrap())?;
+    let then = registry.stats(inner.next().unwrap())?;
+    Ok(match inner.next() {
+        Some(stmt) if stmt.as_rule() == Rule::stat_if => Node::if_cond_else(cond, then, build_if(registry, stmt)?),
+        Some(stmt) => Node::if_cond_else(cond, then, registry.stats(stmt)?),
+        None => Node::if_cond_else(cond, then, Node::donothing())
+    })
+}
+
+fn build_while(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let cond = registry.climb(inner.next().unwrap())?;
+    let stmt = registry.stats(inner.next().unwrap())?;
+    Ok(Node::while_node(cond, stmt))
+}
+
+/// Every clause of a for loop may be left out: a missing initialisation
+/// or step does nothing and a missing condition is always true.
+fn build_for(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let mut clause = |default: Box<Node>| -> Result<Box<Node>, ParseError> {
+        let mut clause = inner.next().unwrap().into_inner();
+        Ok(match (clause.next(), clause.next()) {
+            (Some(name), Some(expr)) => Node::assign(name.as_str(), registry.climb(expr)?),
+            (Some(expr), None) => registry.climb(expr)?,
+            _ => default,
+        })
+    };
+    let init = clause(Node::donothing())?;
+    let cond = clause(Node::boolean(true))?;
+    let step = clause(Node::donothing())?;
+    let body = registry.stats(inner.next().unwrap())?;
+    Ok(Node::for_node(init, cond, step, body))
+}
+
+fn build_pattern(pair: Pair<Rule>) -> Result<Pattern, ParseError> {
+    Ok(match pair.as_rule() {
+        Rule::pat_wildcard => Pattern::Wildcard,
+        Rule::variable => Pattern::Bind(pair.as_str().to_string()),
+        Rule::pat_number => Pattern::Number(pair.as_str().parse::<i64>().map_err(|_| LiteralError::at(&pair))?),
+        Rule::boolean => Pattern::Boolean(pair.as_str() == "true"),
+        Rule::string => Pattern::Str(unescape(pair.as_str())),
+        Rule::pat_pair => {
+            let mut inner = pair.into_inner();
+            Pattern::Pair(Box::new(build_pattern(inner.next().unwrap())?),
+                          Box::new(build_pattern(inner.next().unwrap())?))
+        }
+        Rule::pat_list => Pattern::List(pair.into_inner().map(build_pattern).collect::<Result<_, _>>()?),
+        _ => return Err(InternalParserError::at(&pair, "a pattern")),
+    })
+}
+
+fn build_match(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let value = registry.climb(inner.next().unwrap())?;
+    let arms = inner.map(|arm| {
+        let mut arm = arm.into_inner();
+        let pattern = build_pattern(arm.next().unwrap())?;
+        let body = binding(&pattern.bindings(), || registry.stats(arm.next().unwrap()))?;
+        Ok((pattern, body))
+    }).collect::<Result<_, ParseError>>()?;
+    Ok(Node::match_node(value, arms))
+}
+
+/// The names of the parameters `pair`, each only once.
+fn build_params(pair: Pair<'_, Rule>) -> Result<Vec<&str>, ParseError> {
+    let mut params: Vec<&str> = Vec::new();
+    for param in pair.into_inner() {
+        if params.contains(&param.as_str()) {
+            let start = param.as_span().start_pos();
+            let (line, col) = start.line_col();
+            return Err(ParseError::Param(ParamError {
+                name: param.as_str().to_string(), line, col,
+                source_line: start.line_of().trim_end().to_string(),
+            }));
+        }
+        params.push(param.as_str());
+    }
+    Ok(params)
+}
+
+fn build_lambda(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let params = build_params(inner.next().unwrap())?;
+    let body = binding(&params, || registry.stats(inner.next().unwrap()))?;
+    Ok(Node::fun("", &params, body))
+}
+
+fn build_func(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let mut inner = pair.into_inner();
+    let funcname = inner.next().unwrap().as_str();
+    let params = build_params(inner.next().unwrap())?;
+    let body = binding(&params, || registry.stats(inner.next().unwrap()))?;
+    Ok(Node::fun(funcname, &params, body))
+}
+
+thread_local! {
+    /// The names bound around what is being built, by the parameters of
+    /// the functions and the patterns of the match arms it is in.
+    static LOCALS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Build with `names` bound, as the body of a function or a match arm is.
+fn binding<T, F>(names: &[&str], build: F) -> T
+    where F: FnOnce() -> T {
+    struct Unbind(usize);
+    impl Drop for Unbind {
+        fn drop(&mut self) {
+            LOCALS.with(|locals| locals.borrow_mut().truncate(self.0));
+        }
+    }
+    let _unbind = Unbind(LOCALS.with(|locals| {
+        let mut locals = locals.borrow_mut();
+        let depth = locals.len();
+        locals.extend(names.iter().map(|name| name.to_string()));
+        depth
+    }));
+    build()
+}
+
+/// Whether `name` is bound by a function or match arm around it, so that
+/// a call by that name may be to anything.
+fn is_local(name: &str) -> bool {
+    LOCALS.with(|locals| locals.borrow().iter().any(|local| local == name))
+}
+
+/// A function definition used as a statement binds the function to its name.
+fn build_def(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let name = pair.clone().into_inner().next().unwrap().as_str().to_string();
+    Ok(Node::assign(&name, build_func(registry, pair)?))
+}
+
+lazy_static! {
+    static ref PRATT_PARSER: PrattParser<Rule> = build_pratt_parser();
+}
+
+fn build_pratt_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        // `delay` takes everything after it, as a call's argument would
+        .op(Op::prefix(Rule::op_delay))
+        .op(Op::infix(Rule::op_compose, Assoc::Left))
+        .op(Op::infix(Rule::op_or,  Assoc::Left))
+        .op(Op::infix(Rule::op_and, Assoc::Left))
+        .op(Op::prefix(Rule::op_not))
+        .op(Op::infix(Rule::op_eq,  Assoc::Left) |
+            Op::infix(Rule::op_ne,  Assoc::Left))
+        .op(Op::infix(Rule::op_lt,  Assoc::Left) |
+            Op::infix(Rule::op_gt,  Assoc::Left) |
+            Op::infix(Rule::op_le,  Assoc::Left) |
+            Op::infix(Rule::op_ge,  Assoc::Left))
+        .op(Op::infix(Rule::op_add, Assoc::Left) |
+            Op::infix(Rule::op_sub, Assoc::Left))
+        .op(Op::infix(Rule::op_mul, Assoc::Left))
+        .op(Op::prefix(Rule::op_neg))
+        .op(Op::postfix(Rule::arguments) | Op::postfix(Rule::index))
+}
+
+fn infix_rule(lhs: Box<Node>, op: Pair<Rule>, rhs: Box<Node>) -> Result<Box<Node>, ParseError> {
+    Ok(match op.as_rule() {
+        Rule::op_add => Node::add(lhs, rhs),
+        Rule::op_sub => Node::subtract(lhs, rhs),
+        Rule::op_mul => Node::multiply(lhs, rhs),
+        Rule::op_lt  => Node::lt(lhs, rhs),
+        Rule::op_gt  => Node::gt(lhs, rhs),
+        Rule::op_eq  => Node::eq(lhs, rhs),
+        Rule::op_le  => Node::le(lhs, rhs),
+        Rule::op_ge  => Node::ge(lhs, rhs),
+        Rule::op_ne  => Node::ne(lhs, rhs),
+        Rule::op_and => Node::and(lhs, rhs),
+        // `f >> g` applies f first, then g
+        Rule::op_compose => Node::compose(rhs, lhs),
+        Rule::op_or  => Node::or(lhs, rhs),
+        _ => return Err(InternalParserError::at(&op, "an operator")),
+    })
+}
+
+/// The digits of `i64::MIN`, a literal out of range until negated.
+const MIN_MAGNITUDE: &str = "9223372036854775808";
+
+fn prefix_rule(op: Pair<Rule>, rhs: Box<Node>) -> Result<Box<Node>, ParseError> {
+    Ok(match op.as_rule() {
+        Rule::op_not => Node::not(rhs),
+        Rule::op_neg => Node::negate(rhs),
+        Rule::op_delay => Node::delay(rhs),
+        _ => return Err(InternalParserError::at(&op, "an operator")),
+    })
+}
+
+/// A call by name; builtins and special forms are checked against the
+/// arguments they take, unless the name is bound locally.
+fn build_call(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    let start = pair.as_span().start_pos();
+    let mut inner = pair.into_inner();
+    let var = inner.next().unwrap().as_str();
+    let mut args = inner.map(|arg| registry.climb(arg)).collect::<Result<Vec<_>, _>>()?;
+    if is_local(var) {
+        return Ok(Node::call_args(Node::variable(var), args));
+    }
+    match arity(var) {
+        Some(arity) if !arity.accepts(args.len()) => {
+            let (line, col) = start.line_col();
+            return Err(ParseError::Arity(ArityError {
+                name: var.to_string(), arity, found: args.len(), line, col,
+                source_line: start.line_of().trim_end().to_string(),
+            }));
+        }
+        _ => (),
+    }
+    Ok(match var {
+        "nothing"   => Node::donothing(),
+        "isnothing" => Node::isdonothing(args.remove(0)),
+        // the tail of a stream is only evaluated once it is needed
+        "cons_stream" => {
+            let tail = args.pop().unwrap();
+            let head = args.pop().unwrap();
+            Node::pair(head, Node::delay(tail))
+        }
+        &_     => Node::call_args(Node::variable(var), args),
+    })
+}
+
+fn build_list(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    Ok(Node::list(registry.climb_all(pair)?))
+}
+
+/// Later entries of a map literal replace earlier ones with the same key.
+fn build_map(registry: &Registry, pair: Pair<Rule>) -> Result<Box<Node>, ParseError> {
+    Ok(Node::map(pair.into_inner().map(|entry| {
+        let mut inner = entry.into_inner();
+        let key = unescape(inner.next().unwrap().as_str());
+        Ok((key, registry.climb(inner.next().unwrap())?))
+    }).collect::<Result<_, ParseError>>()?))
+}
+
+/// Strip the quotes of a string literal and resolve its escapes.
+fn unescape(literal: &str) -> String {
+    let mut value = String::new();
+    let mut chars = literal[1..literal.len()-1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some(c) => value.push(c),
+            None => (),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_assign() {
+        let ast = parse("x = 1 + 2 * 3;").unwrap();
+        assert_eq!(Node::assign("x", Node::add(Node::number(1),
+            Node::multiply(Node::number(2), Node::number(3)))), ast);
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let stats = parse_statements("x = 1; y = x; x < y").unwrap();
+        assert_eq!(3, stats.len());
+        assert_eq!(Node::lt(Node::variable("x"), Node::variable("y")), stats[2]);
+    }
+
+    #[test]
+    fn test_parse_string() {
+        let ast = parse(r#"s = "say \"hi\"\n" + "!";"#).unwrap();
+        assert_eq!(Node::assign("s", Node::add(Node::string("say \"hi\"\n"), Node::string("!"))), ast);
+    }
+
+    #[test]
+    fn test_parse_logical() {
+        let ast = parse("not a == b and c or true").unwrap();
+        assert_eq!(Node::or(
+            Node::and(Node::not(Node::eq(Node::variable("a"), Node::variable("b"))), Node::variable("c")),
+            Node::boolean(true)), ast);
+        assert_eq!(Node::variable("android"), parse("android").unwrap());
+    }
+
+    #[test]
+    fn test_parse_global() {
+        assert_eq!(Node::global("x", Node::number(1)), parse("global x = 1;").unwrap());
+        assert_eq!(Node::assign("globalx", Node::number(1)), parse("globalx = 1;").unwrap());
+    }
+
+    #[test]
+    fn test_parse_comparison() {
+        let ast = parse("a <= b != c >= d").unwrap();
+        assert_eq!(Node::ne(Node::le(Node::variable("a"), Node::variable("b")),
+                            Node::ge(Node::variable("c"), Node::variable("d"))), ast);
+    }
+
+    #[test]
+    fn test_parse_compose() {
+        let (f, g, h) = (Node::variable("f"), Node::variable("g"), Node::variable("h"));
+        assert_eq!(Node::compose(h.clone(), Node::compose(g.clone(), f.clone())), parse("f >> g >> h").unwrap());
+        // compose is a builtin, called like any other
+        assert_eq!(Node::call_args(Node::variable("compose"), vec![f, g, h]), parse("compose(f, g, h)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let (a, b, c) = (Node::variable("a"), Node::variable("b"), Node::variable("c"));
+        assert_eq!(Node::subtract(Node::subtract(a.clone(), b.clone()), c.clone()), parse("a - b - c").unwrap());
+        assert_eq!(Node::subtract(Node::add(a.clone(), Node::multiply(b.clone(), c.clone())), Node::number(1)),
+                   parse("a + b * c - 1").unwrap());
+        assert_eq!(Node::eq(Node::lt(a.clone(), Node::add(b.clone(), Node::number(1))), c.clone()),
+                   parse("a < b + 1 == c").unwrap());
+        assert_eq!(Node::or(a.clone(), Node::and(b.clone(), c.clone())), parse("a or b and c").unwrap());
+        assert_eq!(Node::or(Node::and(Node::not(a.clone()), Node::not(b.clone())), c.clone()),
+                   parse("not a and not b or c").unwrap());
+        // calls and indexing bind tighter than prefix operators
+        let called = Node::index(Node::call_args(Node::variable("f"), vec![a.clone()]), Node::number(0));
+        assert_eq!(Node::multiply(Node::negate(called), b.clone()), parse("-f(a)[0] * b").unwrap());
+        assert_eq!(Node::delay(Node::multiply(a.clone(), Node::number(2))), parse("delay a * 2").unwrap());
+        assert_eq!(Node::delay(Node::or(Node::add(a.clone(), b.clone()), c.clone())), parse("delay a + b or c").unwrap());
+        assert_eq!(Node::add(a.clone(), Node::delay(Node::multiply(b.clone(), c.clone()))), parse("a + delay b * c").unwrap());
+        assert_eq!(Node::multiply(Node::delay(a), b), parse("(delay a) * b").unwrap());
+    }
+
+    #[test]
+    fn test_parse_negate() {
+        let ast = parse("-x * 2 - -(a + 0)").unwrap();
+        assert_eq!(Node::subtract(
+            Node::multiply(Node::negate(Node::variable("x")), Node::number(2)),
+            Node::negate(Node::add(Node::variable("a"), Node::number(0)))), ast);
+    }
+
+    #[test]
+    fn test_parse_float() {
+        assert_eq!(Node::add(Node::float(1.5), Node::number(2)), parse("1.5 + 2").unwrap());
+        assert_eq!(Node::negate(Node::float(0.25)), parse("-0.25").unwrap());
+        assert_eq!(Node::float(2.5e-3), parse("2.5e-3").unwrap());
+        assert_eq!(Node::float(1e10), parse("1E10").unwrap());
+        assert_eq!(Node::list(vec![Node::float(1.0), Node::number(2)]), parse("[1.0, 2]").unwrap());
+        assert!(parse("1.").is_err());
+        assert!(parse(".5").is_err());
+        // floats print in a form that reads back as a float
+        assert_eq!("1.0", Node::float(1.0).to_string());
+        assert_eq!(Node::float(1e20), parse(&Node::float(1e20).to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let source = "// leading comment\nx = /* inline */ 1;\n/* block\n   comment */\ny = \"// not a comment\"; // trailing";
+        let stats = parse_statements(source).unwrap();
+        assert_eq!(vec![Node::assign("x", Node::number(1)), Node::assign("y", Node::string("// not a comment"))], stats);
+        assert!(parse("x = 1; /* unterminated").is_err());
+    }
+
+    #[test]
+    fn test_parse_funcdef() {
+        let body = Node::add(Node::variable("x"), Node::variable("y"));
+        assert_eq!(Node::assign("add", Node::fun("add", &["x", "y"], body.clone())),
+                   parse("def add(x, y) { x + y }").unwrap());
+        assert_eq!(Node::assign("f", Node::fun("g", &[], body.clone())),
+                   parse("f = function g() { x + y };").unwrap());
+        assert_eq!(Node::call_args(Node::variable("add"), vec![Node::number(1), Node::number(2)]),
+                   parse("add(1, 2)").unwrap());
+        assert_eq!(Node::call_args(Node::variable("g"), vec![]), parse("g()").unwrap());
+        assert_eq!(Node::call_args(Node::call_args(Node::variable("g"), vec![]), vec![]), parse("g()()").unwrap());
+        assert_eq!("call g", parse("g()").unwrap().to_string());
+        assert_eq!(Node::assign("f", Node::fun("g", &[], body.clone())), parse("f = (def g() { x + y });").unwrap());
+        assert_eq!(Node::call(Node::fun("g", &["x"], body), Node::number(1)),
+                   parse("(function g(x) { x + y })(1)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_nested_funcdef() {
+        let inner = Node::fun("inner", &["c"], Node::variable("a"));
+        let middle = Node::fun("middle", &["b"], Node::sequence(
+            Node::assign("inner", inner.clone()), Node::return_node(Node::variable("inner"))));
+        let outer = Node::fun("outer", &["a"], Node::sequence(
+            Node::assign("middle", middle), Node::return_node(Node::variable("middle"))));
+        assert_eq!(Node::assign("outer", outer),
+                   parse("def outer(a) { def middle(b) { def inner(c) { a } return inner; } return middle; }").unwrap());
+        let nested = Node::fun("outer", &["a"], Node::return_node(Node::fun("middle", &["b"], Node::return_node(inner))));
+        assert_eq!(Node::assign("f", nested),
+                   parse("f = def outer(a) { return (def middle(b) { return (def inner(c) { a }); }); };").unwrap());
+    }
+
+    #[test]
+    fn test_parse_pair_literal() {
+        let pair = Node::pair(Node::number(1), Node::add(Node::variable("x"), Node::number(2)));
+        assert_eq!(pair.clone(), parse("(1, x + 2)").unwrap());
+        assert_eq!(Node::call(Node::variable("fst"), pair.clone()), parse("fst((1, x + 2))").unwrap());
+        assert_eq!(Node::pair(Node::number(1), pair.clone()), parse("(1, (1, x + 2))").unwrap());
+        assert_eq!(Node::multiply(Node::add(Node::number(1), Node::number(2)), Node::number(3)),
+                   parse("(1 + 2) * 3").unwrap());
+        assert_eq!(pair.to_string(), "(1, x + 2)");
+    }
+
+    #[test]
+    fn test_parse_pair_call() {
+        let pair = Node::call_args(Node::variable("pair"), vec![Node::number(1), Node::number(2)]);
+        assert_eq!(Node::call(Node::variable("fst"), pair), parse("fst(pair(1, 2))").unwrap());
+        assert_eq!(Node::assign("f", Node::variable("snd")), parse("f = snd;").unwrap());
+    }
+
+    #[test]
+    fn test_parse_lambda() {
+        let inc = Node::fun("", &["x"], Node::add(Node::variable("x"), Node::number(1)));
+        assert_eq!(Node::assign("inc", inc.clone()), parse("inc = fun(x) { x + 1 };").unwrap());
+        assert_eq!(Node::call(inc.clone(), Node::number(2)), parse("(fun(x) { x + 1 })(2)").unwrap());
+        assert_eq!(Node::call_args(Node::variable("apply"), vec![inc, Node::number(2)]),
+                   parse("apply(fun(x) { x + 1 }, 2)").unwrap());
+        assert_eq!(Node::call(Node::call(Node::variable("f"), Node::number(1)), Node::number(2)),
+                   parse("f(1)(2)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_return() {
+        assert_eq!(Node::return_node(Node::variable("x")), parse("return x;").unwrap());
+        assert_eq!(Node::return_node(Node::donothing()), parse("return;").unwrap());
+        assert_eq!(Node::assign("returned", Node::number(1)), parse("returned = 1;").unwrap());
+        assert!(parse("def f(x) { returnx; } f(1)").is_err());
+        assert!(parse("def f(x) { return(x); } f(1)").is_ok());
+    }
+
+    #[test]
+    fn test_parse_break_continue() {
+        let body = Node::sequence(
+            Node::if_cond_else(Node::variable("done"), Node::break_node(), Node::donothing()),
+            Node::continue_node());
+        assert_eq!(Node::while_node(Node::boolean(true), body), parse("while (true) { if (done) { break; } continue; }").unwrap());
+        assert_eq!(Node::assign("breaking", Node::number(1)), parse("breaking = 1;").unwrap());
+        assert!(parse("while (true) { breakx; }").is_err());
+        assert!(parse("while (true) { continue_; }").is_err());
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(Node::assign("t", Node::delay(Node::call(Node::variable("f"), Node::number(1)))),
+                   parse("t = delay f(1);").unwrap());
+        assert_eq!(Node::delay(Node::add(Node::number(1), Node::number(2))), parse("delay 1 + 2").unwrap());
+        assert_eq!(Node::variable("delayed"), parse("delayed").unwrap());
+    }
+
+    #[test]
+    fn test_parse_cons_stream() {
+        let tail = Node::delay(Node::call(Node::variable("f"), Node::number(2)));
+        assert_eq!(Node::pair(Node::number(1), tail), parse("cons_stream(1, f(2))").unwrap());
+        assert_eq!(Node::variable("not_done"), parse("not_done").unwrap());
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let xs = Node::list(vec![Node::number(1), Node::add(Node::number(2), Node::number(3))]);
+        assert_eq!(xs.clone(), parse("[1, 2 + 3]").unwrap());
+        assert_eq!(Node::list(vec![]), parse("[]").unwrap());
+        assert_eq!(Node::index(xs, Node::number(0)), parse("[1, 2 + 3][0]").unwrap());
+        assert_eq!(Node::index(Node::index(Node::variable("m"), Node::variable("i")), Node::number(1)),
+                   parse("m[i][1]").unwrap());
+        assert_eq!(Node::call(Node::variable("len"), Node::variable("xs")), parse("len(xs)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), Node::number(1));
+        entries.insert("b c".to_string(), Node::add(Node::variable("x"), Node::number(2)));
+        assert_eq!(Node::map(entries), parse("{\"a\": 1, \"b c\": x + 2}").unwrap());
+        assert_eq!(Node::assign("m", Node::map(HashMap::new())), parse("m = {};").unwrap());
+        assert_eq!(Node::while_node(Node::variable("x"), Node::map(HashMap::new())), parse("while (x) { {} }").unwrap());
+    }
+
+    #[test]
+    fn test_parse_else_if() {
+        let branch = |n| Node::assign("x", Node::number(n));
+        let cond = |n| Node::lt(Node::variable("y"), Node::number(n));
+        assert_eq!(Node::if_cond_else(cond(1), branch(1), Node::if_cond_else(cond(2), branch(2), branch(3))),
+                   parse("if (y < 1) { x = 1; } else if (y < 2) { x = 2; } else { x = 3; }").unwrap());
+        assert_eq!(Node::if_cond_else(cond(1), branch(1), Node::if_cond_else(cond(2), branch(2), Node::donothing())),
+                   parse("if (y < 1) { x = 1; } else if (y < 2) { x = 2; }").unwrap());
+    }
+
+    #[test]
+    fn test_parse_match() {
+        let source = "match (p) { (0, _) => { x = 1; } ([a, -2], \"s\") => { a } true => { } n => { n } }";
+        let arms = vec![
+            (Pattern::Pair(Box::new(Pattern::Number(0)), Box::new(Pattern::Wildcard)), Node::assign("x", Node::number(1))),
+            (Pattern::Pair(Box::new(Pattern::List(vec![Pattern::Bind("a".to_string()), Pattern::Number(-2)])),
+                           Box::new(Pattern::Str("s".to_string()))), Node::variable("a")),
+            (Pattern::Boolean(true), Node::donothing()),
+            (Pattern::Bind("n".to_string()), Node::variable("n")),
+        ];
+        assert_eq!(Node::match_node(Node::variable("p"), arms), parse(source).unwrap());
+        assert_eq!(Node::call(Node::variable("matches"), Node::number(1)), parse("matches(1)").unwrap());
+        let min = Node::match_node(Node::variable("p"), vec![(Pattern::Number(i64::MIN), Node::donothing())]);
+        assert_eq!(min, parse("match (p) { -9223372036854775808 => { } }").unwrap());
+        match parse("match (p) {\n  (1, 99999999999999999999) => { }\n}") {
+            Err(ParseError::Literal(e)) => assert_eq!(("99999999999999999999", 2, 7), (&e.text[..], e.line, e.col)),
+            other => panic!("not a literal error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_for() {
+        let step = Node::assign("i", Node::add(Node::variable("i"), Node::number(1)));
+        assert_eq!(Node::for_node(Node::assign("i", Node::number(0)), Node::lt(Node::variable("i"), Node::number(3)),
+                                  step.clone(), Node::call(Node::variable("f"), Node::variable("i"))),
+                   parse("for (i = 0; i < 3; i = i + 1) { f(i) }").unwrap());
+        assert_eq!(Node::for_node(Node::donothing(), Node::boolean(true), Node::donothing(), Node::break_node()),
+                   parse("for (;;) { break; }").unwrap());
+        assert_eq!(Node::assign("format", Node::number(1)), parse("format = 1;").unwrap());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse("x = ;").is_err());
+    }
+
+    #[test]
+    fn test_statements() {
+        let mut source = String::new();
+        for i in 0..200 {
+            source.push_str(&format!("x{0} = {0}; /* {{ */ s = \"}}{{\";\nif (x{0} < 100) {{\n  y = 1;\n}}\nelse {{ y = 2; }} // }}\n", i));
+        }
+        let expected = parse_statements(&source).unwrap();
+        for chunk in [1, 100, CHUNK] {
+            let streamed: Vec<_> = Statements::new(source.as_bytes(), chunk).map(|stat| stat.unwrap()).collect();
+            assert_eq!(expected, streamed);
+        }
+        let mut failing = Statements::new("x = 1;\ny = ;\nz = 2;\n".as_bytes(), 1);
+        assert!(failing.next().unwrap().is_ok());
+        match failing.next().unwrap() {
+            Err(ParseError::Syntax(e)) => assert_eq!((2, 5), (e.line, e.col)),
+            other => panic!("not a syntax error: {:?}", other),
+        }
+        assert!(failing.next().is_none());
+        // an error past the first chunk is placed in the whole program
+        let source = format!("{}y = ;\n", "x = 1;\n".repeat(20000));
+        match statements(source.as_bytes()).find_map(Result::err) {
+            Some(ParseError::Syntax(e)) => assert_eq!((20001, 5, "y = ;"), (e.line, e.col, &e.source_line[..])),
+            other => panic!("not a syntax error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        // a rule without a builder is reported, not a panic
+        let mut registry = Registry::empty();
+        registry.register_statement(Rule::stat_assign, build_assign);
+        registry.register_factor(Rule::number, |_, pair| Ok(Node::number(pair.as_str().parse().unwrap())));
+        assert_eq!(Node::assign("x", Node::number(1)), registry.parse("x = 1;").unwrap());
+        match registry.parse("x = 1;\ny = \"s\";") {
+            Err(ParseError::Internal(e)) => {
+                assert_eq!((Rule::string, "a factor", 2, 5, "\"s\""), (e.rule, e.expected, e.line, e.col, &e.text[..]));
+            }
+            other => panic!("expect an internal error, found {:?}", other),
+        }
+        // a builder registered later replaces the grammar's own
+        let mut registry = Registry::new();
+        registry.register_factor(Rule::string, |_, pair| Ok(Node::string(&pair.as_str().to_uppercase())));
+        assert_eq!(Node::assign("s", Node::string("\"HI\"")), registry.parse("s = \"hi\";").unwrap());
+        assert!(registry.parse("x = ;").is_err());
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "x = 1;\nif (x > 0) {\n  y = f(x);\n}";
+        let spanned = parse_spanned(source).unwrap();
+        assert_eq!(parse(source).unwrap(), spanned.without_spans());
+        let at = |line, col, end_line, end_col| Span { line, col, end_line, end_col };
+        let call = match *spanned {
+            Node::Sequence(ref first, ref second) => {
+                assert_eq!(Node::Spanned(at(1, 1, 1, 7), Node::assign("x", Node::number(1))), **first);
+                match **second {
+                    Node::Spanned(span, ref node) => {
+                        assert_eq!(at(2, 1, 4, 2), span);
+                        match **node {
+                            Node::If(_, ref then, _) => then.clone(),
+                            _ => panic!("not an if: {}", node),
+                        }
+                    }
+                    _ => panic!("not spanned: {:?}", second),
+                }
+            }
+            _ => panic!("not a sequence: {:?}", spanned),
+        };
+        let variable = Node::spanned(at(3, 9, 3, 10), Node::variable("x"));
+        assert_eq!(Node::spanned(at(3, 3, 3, 12), Node::assign("y", Node::spanned(at(3, 7, 3, 11),
+            Node::call(Node::variable("f"), variable)))), call);
+    }
+
+    #[test]
+    fn test_arity_error() {
+        let error = |source| match parse(source) {
+            Err(ParseError::Arity(e)) => e,
+            other => panic!("not an arity error: {:?}", other),
+        };
+        let e = error("x = 1;\ny = fst(pair(1));");
+        assert_eq!(("pair", Arity::Exactly(2), 1, 2, 9), (&e.name[..], e.arity, e.found, e.line, e.col));
+        assert_eq!("arity error at line 2, column 9: pair takes 2 arguments, found 1\n\
+                    2 | y = fst(pair(1));\n  |         ^", e.to_string());
+        assert!(error("fst(a, b)").to_string().starts_with("arity error at line 1, column 1: fst takes 1 argument, found 2\n"));
+        assert_eq!(Arity::AtLeast(1), error("f = compose();").arity);
+        assert_eq!(3, error("s = cons_stream(1, 2, 3);").found);
+        assert_eq!(0, error("isnothing()").found);
+        // names not of builtins take whatever they are given
+        assert!(parse("f(1, 2, 3) nothing() fst(p) compose(f, g, h)").is_ok());
+        // nor are names bound by a parameter or a pattern, whatever they shadow
+        let local = Node::call_args(Node::variable("min"), vec![Node::number(1)]);
+        assert_eq!(Node::fun("", &["min"], local.clone()), parse("fun(min) { min(1) }").unwrap());
+        assert!(parse("def f(g) { match (g) { (pair, _) => { pair(1) } } }").is_ok());
+        assert!(parse("def f(g) { return g; } x = min(1);").is_err());
+    }
+
+    #[test]
+    fn test_param_error() {
+        let error = |source| match parse(source) {
+            Err(ParseError::Param(e)) => e,
+            other => panic!("not a parameter error: {:?}", other),
+        };
+        let e = error("x = 1;\ndef f(x, y, x) { x }");
+        assert_eq!(("x", 2, 13), (&e.name[..], e.line, e.col));
+        assert_eq!("parameter error at line 2, column 13: x is already a parameter\n\
+                    2 | def f(x, y, x) { x }\n  |             ^", e.to_string());
+        let e = error("f = fun(a, a) { a };");
+        assert_eq!((1, 12), (e.line, e.col));
+        // a parameter may still shadow one of an outer function
+        assert!(parse("def f(x) { return fun(x) { x }; }").is_ok());
+    }
+
+    #[test]
+    fn test_literal_error() {
+        let e = match parse("x = 1;\ny = 2 + 99999999999999999999;") {
+            Err(ParseError::Literal(e)) => e,
+            other => panic!("not a literal error: {:?}", other),
+        };
+        assert_eq!(("99999999999999999999", 2, 9), (&e.text[..], e.line, e.col));
+        assert_eq!("literal error at line 2, column 9: 99999999999999999999 is out of the range of integers\n\
+                    2 | y = 2 + 99999999999999999999;\n  |         ^", e.to_string());
+        assert!(parse("x = 9223372036854775808;").is_err());
+        assert_eq!(Node::assign("x", Node::number(i64::MAX)), parse("x = 9223372036854775807;").unwrap());
+        assert_eq!(Node::assign("x", Node::number(i64::MIN)), parse("x = -9223372036854775808;").unwrap());
+        assert!(parse("x = --9223372036854775808;").is_ok());
+        assert!(parse("x = !9223372036854775808;").is_err());
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        // as the binary does, for the punctuation expected
+        pest::set_error_detail(true);
+        let error = |source| match parse(source) {
+            Err(ParseError::Syntax(e)) => e,
+            other => panic!("not a syntax error: {:?}", other),
+        };
+        let e = error("x = 1;\ny = (1, 2;");
+        assert_eq!((2, 10), (e.line, e.col));
+        assert_eq!(Some(";".to_string()), e.found);
+        assert_eq!(vec!["`)`", "an operator"], e.expected);
+        assert_eq!("syntax error at line 2, column 10: expected `)` or an operator, found `;`\n\
+                    2 | y = (1, 2;\n  |          ^", e.to_string());
+        let e = error("x = 1");
+        assert_eq!((None, vec!["`;`".to_string(), "an operator".to_string()]), (e.found, e.expected));
+        let e = error("f = fun(1) { 1 };");
+        // `fun(1)` reads as a call, so the block after it is unexpected
+        assert_eq!((Some("{".to_string()), vec!["`;`".to_string(), "an operator".to_string()]), (e.found, e.expected));
+        assert_eq!(Some("foo".to_string()), error("x = 1 foo;").found);
+    }
+}
@@ -0,0 +1,438 @@
+//! Abstract interpretation, as in the book's "programming in toyland":
+//! evaluate a program over the signs of numbers rather than the numbers
+//! themselves, to tell without running it whether each variable ends up
+//! negative, zero or positive.
+//!
+//! The analysis is sound but not precise: a sign it reports is one every
+//! run agrees with, integer overflow and NaN aside, and where runs may
+//! disagree, or it cannot tell, the sign is `Unknown`. Conditions are not
+//! decided, so both branches of an `if` are taken, and a loop is followed
+//! until the signs of its variables settle.
+
+use simple::syntax::Node;
+use lattice::{merge, Join};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem;
+use std::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Sign {
+    Negative,
+    Zero,
+    Positive,
+    /// Any sign at all.
+    Unknown,
+}
+
+impl Sign {
+    pub fn of(value: i64) -> Sign {
+        match value {
+            0 => Sign::Zero,
+            value if value < 0 => Sign::Negative,
+            _ => Sign::Positive,
+        }
+    }
+
+    pub fn of_float(value: f64) -> Sign {
+        if value == 0.0 {
+            Sign::Zero
+        } else if value < 0.0 {
+            Sign::Negative
+        } else if value > 0.0 {
+            Sign::Positive
+        } else {
+            Sign::Unknown
+        }
+    }
+
+    /// The sign of a number of either sign, the book's `|`.
+    pub fn join(self, other: Sign) -> Sign {
+        if self == other { self } else { Sign::Unknown }
+    }
+
+    /// Whether every number of sign `other` is of this sign, the book's
+    /// `<=` the other way round.
+    pub fn covers(self, other: Sign) -> bool {
+        self == other || self == Sign::Unknown
+    }
+}
+
+impl Add for Sign {
+    type Output = Sign;
+
+    fn add(self, other: Sign) -> Sign {
+        if self == other || other == Sign::Zero {
+            self
+        } else if self == Sign::Zero {
+            other
+        } else {
+            Sign::Unknown
+        }
+    }
+}
+
+impl Neg for Sign {
+    type Output = Sign;
+
+    fn neg(self) -> Sign {
+        match self {
+            Sign::Negative => Sign::Positive,
+            Sign::Positive => Sign::Negative,
+            other => other,
+        }
+    }
+}
+
+impl Sub for Sign {
+    type Output = Sign;
+
+    fn sub(self, other: Sign) -> Sign {
+        self + -other
+    }
+}
+
+impl Mul for Sign {
+    type Output = Sign;
+
+    fn mul(self, other: Sign) -> Sign {
+        if self == Sign::Zero || other == Sign::Zero {
+            Sign::Zero
+        } else if self == Sign::Unknown || other == Sign::Unknown {
+            Sign::Unknown
+        } else if self == other {
+            Sign::Positive
+        } else {
+            Sign::Negative
+        }
+    }
+}
+
+impl fmt::Display for Sign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Sign::Negative => "negative",
+            Sign::Zero => "zero",
+            Sign::Positive => "positive",
+            Sign::Unknown => "unknown",
+        })
+    }
+}
+
+/// The abstract value of an expression: the sign of the number it comes
+/// to, or `None` when it may not be a number at all.
+type Value = Option<Sign>;
+
+/// The abstract values of the variables in scope.
+type Scope = HashMap<String, Value>;
+
+fn join(left: Value, right: Value) -> Value {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left.join(right)),
+        _ => None,
+    }
+}
+
+impl Join for Value {
+    fn join(&self, other: &Value) -> Value {
+        join(*self, *other)
+    }
+}
+
+fn merge_into(into: &mut Option<Scope>, scope: &Scope) {
+    *into = Some(match into.take() {
+        Some(known) => merge(&known, scope),
+        None => scope.clone(),
+    });
+}
+
+struct Analyzer {
+    scope: Scope,
+    /// The top-level variables a call may assign with `global`, and what.
+    clobbers: Scope,
+    /// Inside a function body, the values it assigns with `global`.
+    globals: Option<Scope>,
+    /// Where the innermost loop is left by `break`, or gone round again by
+    /// `continue`, from.
+    breaks: Option<Scope>,
+    continues: Option<Scope>,
+}
+
+impl Analyzer {
+    fn new(clobbers: Scope) -> Analyzer {
+        Analyzer { scope: Scope::new(), clobbers, globals: None, breaks: None, continues: None }
+    }
+
+    /// The sign of an operand of arithmetic, which is a number if the
+    /// program gets past it.
+    fn number(&mut self, node: &Node) -> Sign {
+        self.value(node).unwrap_or(Sign::Unknown)
+    }
+
+    /// Follow a loop until the variables it assigns settle, leaving the
+    /// scope as it may be after the loop.
+    fn repeat(&mut self, cond: &Node, body: &Node, step: Option<&Node>) {
+        let breaks = self.breaks.take();
+        let continues = self.continues.take();
+        let mut head = self.scope.clone();
+        let exit = loop {
+            self.scope = head.clone();
+            self.value(cond);
+            let exit = self.scope.clone();
+            self.value(body);
+            if let Some(continued) = self.continues.take() {
+                self.scope = merge(&self.scope, &continued);
+            }
+            if let Some(step) = step {
+                self.value(step);
+            }
+            let next = merge(&head, &self.scope);
+            if next == head {
+                break exit;
+            }
+            head = next;
+        };
+        self.scope = match mem::replace(&mut self.breaks, breaks) {
+            Some(broken) => merge(&exit, &broken),
+            None => exit,
+        };
+        self.continues = continues;
+    }
+
+    fn value(&mut self, node: &Node) -> Value {
+        match *node {
+            Node::Number(value) => Some(Sign::of(value)),
+            Node::Float(value) => Some(Sign::of_float(value)),
+            Node::Add(ref l, ref r) => match (self.value(l), self.value(r)) {
+                (Some(l), Some(r)) => Some(l + r),
+                // strings are added too, so one side must be a number
+                (None, None) => None,
+                _ => Some(Sign::Unknown),
+            },
+            Node::Subtract(ref l, ref r) => Some(self.number(l) - self.number(r)),
+            Node::Multiply(ref l, ref r) => Some(self.number(l) * self.number(r)),
+            Node::Negate(ref inner) => Some(-self.number(inner)),
+            Node::Len(ref inner) => { self.value(inner); Some(Sign::Unknown) }
+            Node::Variable(ref name) => self.scope.get(name).cloned().unwrap_or(None),
+            Node::Assign(ref name, ref value) => {
+                let found = self.value(value);
+                self.scope.insert(name.clone(), found);
+                None
+            }
+            Node::Global(ref name, ref value) => {
+                let found = self.value(value);
+                if let Some(ref mut globals) = self.globals {
+                    let joined = globals.get(name).map_or(found, |&known| join(known, found));
+                    globals.insert(name.clone(), joined);
+                }
+                self.scope.insert(name.clone(), found);
+                None
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                self.value(cond);
+                let before = self.scope.clone();
+                let consequence = self.value(consequence);
+                let after = mem::replace(&mut self.scope, before);
+                let alternative = self.value(alternative);
+                self.scope = merge(&after, &self.scope);
+                join(consequence, alternative)
+            }
+            Node::Sequence(ref head, ref more) => {
+                self.value(head);
+                self.value(more)
+            }
+            Node::While(ref cond, ref body) => { self.repeat(cond, body, None); None }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.value(init);
+                self.repeat(cond, body, Some(step));
+                None
+            }
+            Node::Break => { merge_into(&mut self.breaks, &self.scope.clone()); None }
+            Node::Continue => { merge_into(&mut self.continues, &self.scope.clone()); None }
+            Node::Match(ref value, ref arms) => {
+                self.value(value);
+                let before = self.scope.clone();
+                let mut found: Option<(Value, Scope)> = None;
+                for (pattern, body) in arms {
+                    self.scope = before.clone();
+                    for name in pattern.bindings() {
+                        self.scope.insert(name.to_string(), None);
+                    }
+                    let value = self.value(body);
+                    // the names a pattern binds go out of scope with its arm
+                    for name in pattern.bindings() {
+                        match before.get(name) {
+                            Some(&known) => self.scope.insert(name.to_string(), known),
+                            None => self.scope.remove(name),
+                        };
+                    }
+                    found = Some(match found {
+                        Some((other, scope)) => (join(other, value), merge(&scope, &self.scope)),
+                        None => (value, self.scope.clone()),
+                    });
+                }
+                match found {
+                    Some((value, scope)) => { self.scope = scope; value }
+                    None => { self.scope = before; None }
+                }
+            }
+            Node::Return(ref value) => self.value(value),
+            Node::Call(ref closure, ref args) => {
+                self.value(closure);
+                for arg in args {
+                    self.value(arg);
+                }
+                // whichever function is called may assign top-level variables
+                let clobbers = self.clobbers.clone();
+                self.scope = merge(&self.scope, &clobbers);
+                match *closure.unspanned() {
+                    // the builtin, unless a variable hides it
+                    Node::Variable(ref name) if name == "len" && !self.scope.contains_key(name) => Some(Sign::Unknown),
+                    _ => None,
+                }
+            }
+            // function bodies are only followed for what they assign with
+            // `global`, found before the analysis starts
+            Node::Fun(..) | Node::Closure(..) | Node::Thunk(..) => None,
+            Node::Spanned(_, ref inner) => self.value(inner),
+            _ => {
+                for child in node.children() {
+                    self.value(child);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// What each function in `node` may assign with `global`, followed with
+/// every variable it reads unknown, as it may be called from anywhere.
+fn clobbers(node: &Node, found: &mut Scope) {
+    if let Node::Fun(_, _, ref body) = *node {
+        let mut analyzer = Analyzer::new(Scope::new());
+        analyzer.globals = Some(Scope::new());
+        analyzer.value(body);
+        *found = merge(found, &analyzer.globals.unwrap());
+    }
+    for child in node.children() {
+        clobbers(child, found);
+    }
+}
+
+/// What sign analysis tells of a program.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Analysis {
+    /// The sign of the value of its last statement, if that is a number.
+    pub value: Option<Sign>,
+    /// The possible signs of the variables it leaves holding numbers, by
+    /// name; those that may hold anything else are left out.
+    pub variables: BTreeMap<String, Sign>,
+}
+
+/// Analyze `program` over signs, without running it.
+pub fn analyze(program: &Node) -> Analysis {
+    let mut found = Scope::new();
+    clobbers(program, &mut found);
+    let mut analyzer = Analyzer::new(found);
+    let value = analyzer.value(program);
+    let variables = analyzer.scope.into_iter()
+        .filter_map(|(name, value)| value.map(|sign| (name, sign)))
+        .collect();
+    Analysis { value, variables }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::machine::Machine;
+
+    fn signs(source: &str) -> Vec<(String, Sign)> {
+        analyze(&parser::parse(source).unwrap()).variables.into_iter().collect()
+    }
+
+    fn sign(source: &str, name: &str) -> Option<Sign> {
+        analyze(&parser::parse(source).unwrap()).variables.get(name).cloned()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(Sign::Negative, Sign::Positive * Sign::Negative);
+        assert_eq!(Sign::Zero, Sign::Unknown * Sign::Zero);
+        assert_eq!(Sign::Positive, Sign::Negative * Sign::Negative);
+        assert_eq!(Sign::Positive, Sign::Positive + Sign::Zero);
+        assert_eq!(Sign::Unknown, Sign::Positive + Sign::Negative);
+        assert_eq!(Sign::Negative, Sign::Negative - Sign::Positive);
+        assert_eq!(Sign::Unknown, Sign::of_float(f64::NAN));
+        assert!(Sign::Unknown.covers(Sign::Zero) && !Sign::Zero.covers(Sign::Unknown));
+        let analysis = analyze(&parser::parse("(0 - 3) * 6").unwrap());
+        assert_eq!(Some(Sign::Negative), analysis.value);
+        assert_eq!(Some(Sign::Positive), analyze(&parser::parse("-2.5 * -4").unwrap()).value);
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(vec![("x".to_string(), Sign::Positive), ("y".to_string(), Sign::Negative),
+                        ("z".to_string(), Sign::Zero)],
+                   signs("x = 3; y = x * -2; z = y * 0; s = \"a\"; p = (1, 2);"));
+        // both branches are taken
+        assert_eq!(Some(Sign::Positive), sign("if (input() == \"a\") { x = 1; } else { x = 2; }", "x"));
+        assert_eq!(Some(Sign::Unknown), sign("if (true) { x = 1; } else { x = -1; }", "x"));
+        assert_eq!(None, sign("if (true) { x = 1; } else { x = \"one\"; }", "x"));
+        // adding to what may be a string is still a number
+        assert_eq!(Some(Sign::Unknown), sign("x = input() + 1;", "x"));
+        assert_eq!(Some(Sign::Unknown), sign("x = len([1]);", "x"));
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = "total = 0; i = 1; while (i < 10) { total = total + i; i = i + 1; }";
+        assert_eq!(vec![("i".to_string(), Sign::Positive), ("total".to_string(), Sign::Unknown)], signs(source));
+        assert_eq!(Some(Sign::Positive), sign("n = 1; for (i = 0; i < 5; i = i + 1) { n = n * 2; }", "n"));
+        assert_eq!(Some(Sign::Unknown), sign("n = 1; while (n < 100) { n = n * -2; }", "n"));
+        // leaving the loop midway
+        assert_eq!(Some(Sign::Unknown), sign("x = 1; while (true) { x = -1; break; x = 1; }", "x"));
+        assert_eq!(Some(Sign::Unknown),
+                   sign("x = 1; for (i = 0; i < 3; i = i + 1) { x = 0; if (i < 1) { continue; } x = 1; }", "x"));
+    }
+
+    #[test]
+    fn test_functions() {
+        // what a call returns is not followed, nor are the locals of functions
+        assert_eq!(None, sign("def f(a) { return a; } x = f(1);", "x"));
+        assert_eq!(None, sign("def f(a) { x = 1; return a; } f(1)", "x"));
+        // but a call may change a top-level variable with `global`
+        let source = "n = 1; def reset() { global n = 0; } reset()";
+        assert_eq!(Some(Sign::Unknown), sign(source, "n"));
+        assert_eq!(Some(Sign::Positive), sign("n = 1; def reset() { global n = 0; }", "n"));
+        assert_eq!(None, sign("n = 1; def f(v) { global n = v; } f(2)", "n"));
+    }
+
+    #[test]
+    fn test_spans() {
+        let program = parser::parse_spanned("x = -1;\ny = x * x;").unwrap();
+        assert_eq!(Some(&Sign::Positive), analyze(&program).variables.get("y"));
+    }
+
+    #[test]
+    fn test_sound() {
+        // every run agrees with the signs the analysis reports
+        let source = "a = 5; b = 0 - a; c = 0;
+                      for (i = 0; i < 4; i = i + 1) { c = c + a * b; if (c < -50) { break; } }
+                      d = c * c; e = b * 2.5 - 1;";
+        let program = parser::parse(source).unwrap();
+        let analysis = analyze(&program);
+        let mut machine = Machine::new_with_empty_env(program);
+        machine.run().unwrap();
+        for (name, sign) in &analysis.variables {
+            let found = match *machine.environment.get(name) {
+                Node::Number(value) => Sign::of(value),
+                Node::Float(value) => Sign::of_float(value),
+                ref other => panic!("{} is {}, not a number", name, other),
+            };
+            assert!(sign.covers(found), "{} is {}, but the analysis says {}", name, found, sign);
+        }
+        assert_eq!(6, analysis.variables.len());
+    }
+}
@@ -0,0 +1,272 @@
+use super::syntax::Node;
+
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+/// Pairs of names bound by enclosing functions on the two sides, innermost last.
+type Scope = Vec<(String, String)>;
+
+/// Whether `left` and `right` refer to the same binder: both bound by the
+/// same enclosing function, or both free with the same name.
+fn same_binding(left: &str, right: &str, scope: &Scope) -> bool {
+    let bound_left = scope.iter().rposition(|(l, _)| l == left);
+    let bound_right = scope.iter().rposition(|(_, r)| r == right);
+    match (bound_left, bound_right) {
+        (None, None) => left == right,
+        (l, r) => l == r,
+    }
+}
+
+/// Compare everything about two nodes except their sub-expressions.
+fn shallow_eq(left: &Node, right: &Node) -> bool {
+    if mem::discriminant(left) != mem::discriminant(right) {
+        return false;
+    }
+    match (left, right) {
+        (&Node::Number(l), &Node::Number(r)) => l == r,
+        (&Node::Float(l), &Node::Float(r)) => l.to_bits() == r.to_bits(),
+        (&Node::Boolean(l), &Node::Boolean(r)) => l == r,
+        (Node::Str(l), Node::Str(r)) | (Node::Builtin(l), Node::Builtin(r)) |
+            (Node::Global(l, _), Node::Global(r, _)) => l == r,
+        (Node::Map(l), Node::Map(r)) => l.len() == r.len() && l.keys().all(|key| r.contains_key(key)),
+        (Node::Closure(l, _), Node::Closure(r, _)) => l == r,
+        (Node::Native(l), Node::Native(r)) => l == r,
+        // names bound by patterns must match exactly
+        (Node::Match(_, l), Node::Match(_, r)) =>
+            l.len() == r.len() && l.iter().zip(r).all(|((l, _), (r, _))| l == r),
+        (Node::Thunk(lenv, _, lmemo, lread), Node::Thunk(renv, _, rmemo, rread)) =>
+            lenv == renv && lmemo == rmemo && lread == rread,
+        _ => true,
+    }
+}
+
+fn alpha_eq_in(left: &Node, right: &Node, scope: &mut Scope) -> bool {
+    match (left, right) {
+        (Node::Variable(l), Node::Variable(r)) => same_binding(l, r, scope),
+        (Node::Assign(l, lexpr), Node::Assign(r, rexpr)) =>
+            same_binding(l, r, scope) && alpha_eq_in(lexpr, rexpr, scope),
+        (Node::Fun(lname, lparams, lbody), Node::Fun(rname, rparams, rbody)) => {
+            if lparams.len() != rparams.len() {
+                return false;
+            }
+            let depth = scope.len();
+            scope.push((lname.clone(), rname.clone()));
+            scope.extend(lparams.iter().cloned().zip(rparams.iter().cloned()));
+            let equal = alpha_eq_in(lbody, rbody, scope);
+            scope.truncate(depth);
+            equal
+        }
+        _ => {
+            let (lchildren, rchildren) = (left.children(), right.children());
+            shallow_eq(left, right) && lchildren.len() == rchildren.len() &&
+                lchildren.into_iter().zip(rchildren).all(|(l, r)| alpha_eq_in(l, r, scope))
+        }
+    }
+}
+
+/// Hash what `shallow_eq` compares. Closures, thunks and natives hash
+/// by their kind alone: their environments and host functions have no
+/// stable content to hash, and equal nodes need only equal hashes.
+fn shallow_hash<H: Hasher>(node: &Node, state: &mut H) {
+    mem::discriminant(node).hash(state);
+    match *node {
+        Node::Number(value) => value.hash(state),
+        Node::Float(value) => value.to_bits().hash(state),
+        Node::Boolean(value) => value.hash(state),
+        Node::Str(ref name) | Node::Builtin(ref name) | Node::Global(ref name, _) => name.hash(state),
+        Node::Map(ref entries) => {
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            keys.hash(state);
+        }
+        Node::Native(ref native) => native.name.hash(state),
+        Node::Match(_, ref arms) => for (pattern, _) in arms {
+            pattern.hash(state);
+        },
+        _ => {}
+    }
+}
+
+/// Hash a name the way `same_binding` compares it: bound names by the
+/// binder they refer to, free names by themselves.
+fn hash_binding<H: Hasher>(name: &str, scope: &[String], state: &mut H) {
+    match scope.iter().rposition(|bound| bound == name) {
+        Some(index) => (0u8, index).hash(state),
+        None => (1u8, name).hash(state),
+    }
+}
+
+fn alpha_hash_in<H: Hasher>(node: &Node, scope: &mut Vec<String>, state: &mut H) {
+    mem::discriminant(node).hash(state);
+    match *node {
+        Node::Variable(ref name) => hash_binding(name, scope, state),
+        Node::Assign(ref name, ref expr) => {
+            hash_binding(name, scope, state);
+            alpha_hash_in(expr, scope, state);
+        }
+        Node::Fun(ref name, ref params, ref body) => {
+            params.len().hash(state);
+            let depth = scope.len();
+            scope.push(name.clone());
+            scope.extend(params.iter().cloned());
+            alpha_hash_in(body, scope, state);
+            scope.truncate(depth);
+        }
+        _ => {
+            shallow_hash(node, state);
+            let children = node.children();
+            children.len().hash(state);
+            for child in children {
+                alpha_hash_in(child, scope, state);
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Structural equality up to a consistent renaming of the names bound
+    /// by functions: their own name and their parameters. Free variables
+    /// must match by name, so `fun(x) { x + y }` equals `fun(z) { z + y }`
+    /// but not `fun(y) { y + y }`.
+    pub fn alpha_eq(&self, other: &Node) -> bool {
+        alpha_eq_in(self, other, &mut Vec::new())
+    }
+
+    /// Feed a hash of this node to `state` that agrees with `alpha_eq`:
+    /// nodes equal up to renaming hash the same.
+    pub fn alpha_hash<H: Hasher>(&self, state: &mut H) {
+        alpha_hash_in(self, &mut Vec::new(), state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpha_eq_rename() {
+        let id = |x| Node::fun("", &[x], Node::variable(x));
+        assert!(id("x").alpha_eq(&id("y")));
+        let add_y = |x| Node::fun("f", &[x], Node::add(Node::variable(x), Node::variable("y")));
+        assert!(add_y("x").alpha_eq(&add_y("z")));
+        // renaming the parameter to a free name captures it
+        assert!(!add_y("x").alpha_eq(&add_y("y")));
+        assert!(!Node::variable("x").alpha_eq(&Node::variable("y")));
+        assert!(!Node::number(1).alpha_eq(&Node::number(2)));
+    }
+
+    #[test]
+    fn test_alpha_eq_shadowing() {
+        // fun(x) { fun(x) { x } } is fun(a) { fun(b) { b } }, not fun(a) { fun(b) { a } }
+        let inner = |x, y, z| Node::fun("", &[x], Node::fun("", &[y], Node::variable(z)));
+        assert!(inner("x", "x", "x").alpha_eq(&inner("a", "b", "b")));
+        assert!(!inner("x", "x", "x").alpha_eq(&inner("a", "b", "a")));
+    }
+
+    /// A small deterministic generator, enough to drive property tests
+    /// without pulling in a crate for it.
+    struct Gen(u64);
+
+    impl Gen {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+
+        fn term(&mut self, bound: &mut Vec<String>, depth: u32) -> Box<Node> {
+            let choice = if depth == 0 { self.below(3) } else { self.below(7) };
+            match choice {
+                0 => Node::number(self.below(10) as i64),
+                1 if !bound.is_empty() => {
+                    let i = self.below(bound.len() as u64) as usize;
+                    Node::variable(&bound[i])
+                }
+                1 | 2 => Node::variable(["free", "g"][self.below(2) as usize]),
+                3 => Node::add(self.term(bound, depth - 1), self.term(bound, depth - 1)),
+                4 => Node::call(self.term(bound, depth - 1), self.term(bound, depth - 1)),
+                5 => Node::if_cond_else(Node::lt(self.term(bound, depth - 1), self.term(bound, depth - 1)),
+                                        self.term(bound, depth - 1), self.term(bound, depth - 1)),
+                _ => {
+                    let param = format!("v{}", bound.len());
+                    bound.push(param.clone());
+                    let body = self.term(bound, depth - 1);
+                    bound.pop();
+                    Node::fun("", &[&param], body)
+                }
+            }
+        }
+    }
+
+    /// Give every parameter a fresh name, renaming its uses to match.
+    fn rename_bound(node: &Node, renames: &mut Vec<(String, String)>, fresh: &mut usize) -> Box<Node> {
+        match *node {
+            Node::Variable(ref name) => match renames.iter().rev().find(|(from, _)| from == name) {
+                Some((_, to)) => Node::variable(to),
+                None => Node::variable(name),
+            },
+            Node::Fun(ref funname, ref params, ref body) => {
+                let depth = renames.len();
+                let mut fresh_params = Vec::new();
+                for param in params {
+                    *fresh += 1;
+                    fresh_params.push(format!("renamed{}", fresh));
+                    renames.push((param.clone(), fresh_params.last().unwrap().clone()));
+                }
+                let body = rename_bound(body, renames, fresh);
+                renames.truncate(depth);
+                Box::new(Node::Fun(funname.clone(), fresh_params, body))
+            }
+            Node::Add(ref l, ref r) => Node::add(rename_bound(l, renames, fresh), rename_bound(r, renames, fresh)),
+            Node::LT(ref l, ref r) => Node::lt(rename_bound(l, renames, fresh), rename_bound(r, renames, fresh)),
+            Node::Call(ref closure, ref args) => Node::call_args(rename_bound(closure, renames, fresh),
+                args.iter().map(|arg| rename_bound(arg, renames, fresh)).collect()),
+            Node::If(ref c, ref t, ref e) => Node::if_cond_else(
+                rename_bound(c, renames, fresh), rename_bound(t, renames, fresh), rename_bound(e, renames, fresh)),
+            _ => Box::new(node.clone()),
+        }
+    }
+
+    #[test]
+    fn test_alpha_eq_renaming_property() {
+        let mut gen = Gen(2519);
+        for _ in 0..500 {
+            let term = gen.term(&mut Vec::new(), 5);
+            let renamed = rename_bound(&term, &mut Vec::new(), &mut 0);
+            assert!(term.alpha_eq(&term), "{}", term);
+            assert!(term.alpha_eq(&renamed), "{} vs {}", term, renamed);
+            assert!(renamed.alpha_eq(&term), "{} vs {}", renamed, term);
+        }
+    }
+
+    fn hash(node: &Node) -> u64 {
+        let mut state = ::std::collections::hash_map::DefaultHasher::new();
+        node.alpha_hash(&mut state);
+        state.finish()
+    }
+
+    #[test]
+    fn test_alpha_hash_property() {
+        let mut gen = Gen(7);
+        for _ in 0..500 {
+            let term = gen.term(&mut Vec::new(), 5);
+            let renamed = rename_bound(&term, &mut Vec::new(), &mut 0);
+            assert_eq!(hash(&term), hash(&renamed), "{} vs {}", term, renamed);
+        }
+        let add_y = |x| Node::fun("f", &[x], Node::add(Node::variable(x), Node::variable("y")));
+        assert_ne!(hash(&add_y("x")), hash(&add_y("y")));
+    }
+
+    #[test]
+    fn test_alpha_eq_distinguishes_property() {
+        // changing a literal is never an alpha-renaming
+        let mut gen = Gen(31);
+        for _ in 0..500 {
+            let term = Node::add(gen.term(&mut Vec::new(), 4), Node::number(0));
+            let changed = match *term {
+                Node::Add(ref l, _) => Node::add(rename_bound(l, &mut Vec::new(), &mut 0), Node::number(1)),
+                _ => unreachable!(),
+            };
+            assert!(!term.alpha_eq(&changed), "{} vs {}", term, changed);
+        }
+    }
+}
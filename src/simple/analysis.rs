@@ -0,0 +1,756 @@
+use super::syntax::Node;
+use super::environment::Environment;
+use super::evaluate::get_free_vars;
+use super::builtin;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+/// Names assigned outside of any function body.
+fn toplevel_assignments(node: &Node, names: &mut HashSet<String>) {
+    match *node {
+        Node::Assign(ref name, _) | Node::Global(ref name, _) => { names.insert(name.clone()); }
+        Node::Fun(..) | Node::Closure(..) => return,
+        _ => (),
+    }
+    for child in node.children() {
+        toplevel_assignments(child, names);
+    }
+}
+
+fn check_function_assignments(node: &Node, funname: Option<&str>,
+                              toplevel: &HashSet<String>, warnings: &mut Vec<String>) {
+    match *node {
+        Node::Assign(ref name, _) => {
+            if let Some(funname) = funname {
+                if toplevel.contains(name) {
+                    warnings.push(format!(
+                        "assignment to `{0}` in function `{1}` only changes a local variable; \
+                         write `global {0} = ...` to update the top-level `{0}`",
+                        name, funname));
+                }
+            }
+        }
+        Node::Fun(ref name, _, ref body) => {
+            check_function_assignments(body, Some(name), toplevel, warnings);
+            return;
+        }
+        _ => (),
+    }
+    for child in node.children() {
+        check_function_assignments(child, funname, toplevel, warnings);
+    }
+}
+
+/// Warn about plain assignments inside functions to names that are also
+/// assigned at the top level: they create a local variable, which may not
+/// be what the author meant when the program predates `global`.
+pub fn ambiguous_assignments(program: &Node) -> Vec<String> {
+    let mut toplevel = HashSet::new();
+    toplevel_assignments(program, &mut toplevel);
+    let mut warnings = Vec::new();
+    check_function_assignments(program, None, &toplevel, &mut warnings);
+    warnings
+}
+
+fn describe_function(name: &str) -> String {
+    if name.is_empty() { "an anonymous function".to_string() } else { format!("function `{}`", name) }
+}
+
+/// Names that may be read outside the function reading them: those
+/// assigned at the top level, and with `global`, anywhere in `node`.
+fn outer_names(node: &Node, names: &mut HashSet<String>) {
+    if let Node::Global(ref name, _) = *node {
+        names.insert(name.clone());
+    }
+    for child in node.children() {
+        outer_names(child, names);
+    }
+}
+
+struct Reads<'a> {
+    /// Names assigned on every path to where the walk is.
+    assigned: HashSet<String>,
+    outer: &'a HashSet<String>,
+    warnings: Vec<String>,
+}
+
+impl<'a> Reads<'a> {
+    fn warn(&mut self, warning: String) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    /// Follow `node` at the top level, in the order it runs.
+    fn walk(&mut self, node: &Node) {
+        match *node {
+            Node::Variable(ref name) => {
+                if !self.assigned.contains(name) && builtin::lookup(name).is_none() {
+                    self.warn(format!("`{}` may be read before it is assigned", name));
+                }
+            }
+            Node::Assign(ref name, ref value) | Node::Global(ref name, ref value) => {
+                self.walk(value);
+                self.assigned.insert(name.clone());
+            }
+            Node::Fun(ref name, _, _) => {
+                // a function may run after anything at the top level is assigned
+                let mut free: Vec<String> = get_free_vars(node).into_iter()
+                    .filter(|free| !self.outer.contains(free) && builtin::lookup(free).is_none())
+                    .collect();
+                free.sort();
+                for free in free {
+                    self.warn(format!("`{}` is read in {} but never assigned", free, describe_function(name)));
+                }
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                self.walk(cond);
+                let before = self.assigned.clone();
+                self.walk(consequence);
+                let after = mem::replace(&mut self.assigned, before);
+                self.walk(alternative);
+                self.assigned = self.assigned.intersection(&after).cloned().collect();
+            }
+            Node::While(ref cond, ref body) => {
+                self.walk(cond);
+                // the body may not run at all
+                let before = self.assigned.clone();
+                self.walk(body);
+                self.assigned = before;
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.walk(init);
+                self.walk(cond);
+                let before = self.assigned.clone();
+                self.walk(body);
+                self.walk(step);
+                self.assigned = before;
+            }
+            Node::Match(ref value, ref arms) => {
+                self.walk(value);
+                let before = self.assigned.clone();
+                let mut after: Option<HashSet<String>> = None;
+                for (pattern, body) in arms {
+                    self.assigned = before.clone();
+                    self.assigned.extend(pattern.bindings().into_iter().map(str::to_string));
+                    self.walk(body);
+                    after = Some(match after {
+                        Some(after) => after.intersection(&self.assigned).cloned().collect(),
+                        None => self.assigned.clone(),
+                    });
+                }
+                self.assigned = after.unwrap_or(before);
+            }
+            _ => {
+                for child in node.children() {
+                    self.walk(child);
+                }
+            }
+        }
+    }
+}
+
+/// Warn about variables that may be read before any assignment to them:
+/// at the top level, those not assigned on every path before the read,
+/// and in a function, those it does not bind that are assigned nowhere
+/// it could see them. Builtins are always assigned.
+pub fn unassigned_reads(program: &Node) -> Vec<String> {
+    let mut outer = HashSet::new();
+    toplevel_assignments(program, &mut outer);
+    outer_names(program, &mut outer);
+    let mut reads = Reads { assigned: HashSet::new(), outer: &outer, warnings: Vec::new() };
+    reads.walk(program);
+    reads.warnings
+}
+
+/// Names read anywhere in `node`.
+fn read_names(node: &Node, names: &mut HashSet<String>) {
+    if let Node::Variable(ref name) = *node {
+        names.insert(name.clone());
+    }
+    for child in node.children() {
+        read_names(child, names);
+    }
+}
+
+/// Names assigned with `=` in `node`, in order, the functions it defines
+/// and the bodies of those left out.
+fn plain_assignments(node: &Node, names: &mut Vec<String>) {
+    match *node {
+        Node::Assign(_, ref value) if matches!(**value, Node::Fun(..)) => return,
+        Node::Assign(ref name, _) if !names.contains(name) => names.push(name.clone()),
+        Node::Fun(..) | Node::Closure(..) => return,
+        _ => (),
+    }
+    for child in node.children() {
+        plain_assignments(child, names);
+    }
+}
+
+fn check_unused_locals(node: &Node, warnings: &mut Vec<String>) {
+    if let Node::Fun(ref name, _, ref body) = *node {
+        let mut assigned = Vec::new();
+        plain_assignments(body, &mut assigned);
+        let mut read = HashSet::new();
+        read_names(body, &mut read);
+        for local in assigned.into_iter().filter(|local| !read.contains(local)) {
+            warnings.push(format!("`{}` is assigned in {} but never read", local, describe_function(name)));
+        }
+    }
+    for child in node.children() {
+        check_unused_locals(child, warnings);
+    }
+}
+
+/// Warn about variables assigned but never read, at the top level or in
+/// a function. Functions defined and never called are not warned about,
+/// nor is `result`, which the program is run for.
+pub fn unused_assignments(program: &Node) -> Vec<String> {
+    unused_assignments_with(program, &HashSet::new())
+}
+
+/// Like `unused_assignments`, with `outside` the top-level variables read
+/// from outside the program, say by its expectations.
+pub fn unused_assignments_with(program: &Node, outside: &HashSet<String>) -> Vec<String> {
+    let mut assigned = Vec::new();
+    plain_assignments(program, &mut assigned);
+    let mut read = outside.clone();
+    read_names(program, &mut read);
+    let mut warnings: Vec<String> = assigned.into_iter()
+        .filter(|name| name != "result" && !read.contains(name))
+        .map(|name| format!("`{}` is assigned but never read", name))
+        .collect();
+    check_unused_locals(program, &mut warnings);
+    warnings
+}
+
+/// What kind of value an expression evaluates to, as far as can be told
+/// from its shape.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Kind {
+    Number,
+    Boolean,
+    String,
+    Pair,
+    List,
+    Map,
+    Function,
+    Nothing,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Number => "a number",
+            Kind::Boolean => "a boolean",
+            Kind::String => "a string",
+            Kind::Pair => "a pair",
+            Kind::List => "a list",
+            Kind::Map => "a map",
+            Kind::Function => "a function",
+            Kind::Nothing => "nothing",
+        }
+    }
+
+    fn type_name(self) -> &'static str {
+        match self {
+            Kind::Number => "number",
+            Kind::Boolean => "boolean",
+            Kind::String => "string",
+            Kind::Pair => "pair",
+            Kind::List => "list",
+            Kind::Map => "map",
+            Kind::Function => "function",
+            Kind::Nothing => "nothing",
+        }
+    }
+}
+
+/// Kinds of the variables of one scope; `None` when unknown or when the
+/// assignments to a variable disagree.
+type Kinds = HashMap<String, Option<Kind>>;
+
+fn kind(expr: &Node, vars: &Kinds) -> Option<Kind> {
+    match *expr {
+        Node::Number(_) | Node::Float(_) | Node::Subtract(..) | Node::Multiply(..) |
+            Node::Negate(_) | Node::Len(_) => Some(Kind::Number),
+        Node::Boolean(_) | Node::LT(..) | Node::GT(..) | Node::LE(..) | Node::GE(..) |
+            Node::EQ(..) | Node::NE(..) | Node::And(..) | Node::Or(..) | Node::Not(_) |
+            Node::IsDoNothing(_) => Some(Kind::Boolean),
+        Node::Str(_) => Some(Kind::String),
+        Node::Pair(..) => Some(Kind::Pair),
+        Node::List(_) => Some(Kind::List),
+        Node::Map(_) => Some(Kind::Map),
+        Node::Fun(..) | Node::Closure(..) | Node::Compose(..) | Node::Builtin(_) | Node::Native(_) => Some(Kind::Function),
+        Node::DoNothing => Some(Kind::Nothing),
+        Node::Add(ref l, ref r) => match (kind(l, vars), kind(r, vars)) {
+            (Some(Kind::Number), Some(Kind::Number)) => Some(Kind::Number),
+            (Some(Kind::String), Some(Kind::String)) => Some(Kind::String),
+            _ => None,
+        },
+        Node::Variable(ref name) => vars.get(name).cloned().unwrap_or(None),
+        Node::If(_, ref consequence, ref alternative) => match (kind(consequence, vars), kind(alternative, vars)) {
+            (Some(l), Some(r)) if l == r => Some(l),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The kinds of the variables assigned in a scope, not looking into the
+/// functions defined in it.
+fn assigned_kinds(node: &Node, vars: &mut Kinds) {
+    if let Node::Assign(ref name, ref value) = *node {
+        assigned_kinds(value, vars);
+        let found = kind(value, vars);
+        let known = vars.entry(name.clone()).or_insert(found);
+        if *known != found {
+            *known = None;
+        }
+        return;
+    }
+    if let Node::Fun(..) = *node {
+        return;
+    }
+    for child in node.children() {
+        assigned_kinds(child, vars);
+    }
+}
+
+/// The known kinds `expr` may come to along each branch of the `if`
+/// expressions in it, with the branch that comes to each.
+fn value_kinds(expr: &Node, vars: &Kinds, found: &mut Vec<(Kind, String)>) {
+    match *expr {
+        Node::If(_, ref consequence, ref alternative) => {
+            value_kinds(consequence, vars, found);
+            value_kinds(alternative, vars, found);
+        }
+        _ => if let Some(kind) = kind(expr, vars) {
+            found.push((kind, expr.to_string()));
+        },
+    }
+}
+
+/// The values `name` is assigned in a scope, or with `name` `None`, the
+/// values returned from it, not looking into the functions defined in it.
+fn scope_values(node: &Node, name: Option<&str>, vars: &Kinds, found: &mut Vec<(Kind, String)>) {
+    match (node, name) {
+        (Node::Assign(assigned, value), Some(name)) if assigned == name => value_kinds(value, vars, found),
+        (Node::Return(value), None) => value_kinds(value, vars, found),
+        (Node::Fun(..), _) => return,
+        _ => (),
+    }
+    for child in node.children() {
+        scope_values(child, name, vars, found);
+    }
+}
+
+/// The distinct kinds in `found`, if there are several.
+fn unstable(found: &[(Kind, String)]) -> Option<String> {
+    let mut kinds: Vec<Kind> = Vec::new();
+    let mut examples = Vec::new();
+    for &(kind, ref value) in found {
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+            examples.push(format!("{} ({})", kind.name(), value));
+        }
+    }
+    if kinds.len() < 2 {
+        return None;
+    }
+    let last = examples.pop().unwrap();
+    Some(format!("{} or {}", examples.join(", "), last))
+}
+
+fn check_function_kinds(node: &Node, warnings: &mut Vec<String>) {
+    if let Node::Fun(ref name, ref params, ref body) = *node {
+        let mut vars: Kinds = params.iter().map(|param| (param.clone(), None)).collect();
+        assigned_kinds(body, &mut vars);
+        let mut found = Vec::new();
+        // a body that is a single expression is the value of the function
+        match **body {
+            Node::Sequence(..) | Node::Assign(..) | Node::Global(..) | Node::While(..) |
+                Node::For(..) | Node::Match(..) | Node::Return(_) => (),
+            ref expr => value_kinds(expr, &vars, &mut found),
+        }
+        scope_values(body, None, &vars, &mut found);
+        if let Some(kinds) = unstable(&found) {
+            let function = if name.is_empty() { "an anonymous function".to_string() } else { format!("function `{}`", name) };
+            warnings.push(format!("{} may return {} depending on the path taken", function, kinds));
+        }
+    }
+    for child in node.children() {
+        check_function_kinds(child, warnings);
+    }
+}
+
+/// Warn when `result` may be assigned values of different kinds, say a
+/// number along one path and a boolean along another, and likewise for
+/// the values a function returns. Only kinds evident from the program text
+/// are compared, so a warning is never about a value that is unknown.
+pub fn unstable_kinds(program: &Node) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut vars = Kinds::new();
+    assigned_kinds(program, &mut vars);
+    let mut found = Vec::new();
+    scope_values(program, Some("result"), &vars, &mut found);
+    if let Some(kinds) = unstable(&found) {
+        warnings.push(format!("`result` may hold {} depending on the path taken", kinds));
+    }
+    check_function_kinds(program, &mut warnings);
+    warnings
+}
+
+/// Builtins that act on the world rather than compute from their
+/// arguments.
+const IO_BUILTINS: &[&str] = &["print", "warn", "input"];
+
+/// Names assigned in a function body, outside the functions nested in it.
+fn function_assignments(node: &Node, names: &mut HashSet<String>) {
+    match *node {
+        Node::Assign(ref name, _) => { names.insert(name.clone()); }
+        Node::Fun(..) | Node::Closure(..) => return,
+        _ => (),
+    }
+    for child in node.children() {
+        function_assignments(child, names);
+    }
+}
+
+/// The scope of the function a node is in: its parameters and name, and
+/// the names assigned in its body.
+struct Function {
+    own: HashSet<String>,
+    locals: HashSet<String>,
+}
+
+/// What evaluating an expression may do besides computing a value: bind
+/// or update variables bound around it, or act on the world. A function
+/// carries the effects of its body, taken on wherever it is used.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Effects {
+    pub writes_env: bool,
+    pub io: bool,
+}
+
+impl Effects {
+    pub const PURE: Effects = Effects { writes_env: false, io: false };
+    pub const WRITES_ENV: Effects = Effects { writes_env: true, io: false };
+    pub const IO: Effects = Effects { writes_env: false, io: true };
+
+    /// The effects of doing both.
+    pub fn join(self, other: Effects) -> Effects {
+        Effects { writes_env: self.writes_env || other.writes_env, io: self.io || other.io }
+    }
+
+    pub fn is_pure(self) -> bool {
+        self == Effects::PURE
+    }
+}
+
+impl fmt::Display for Effects {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.writes_env, self.io) {
+            (false, false) => write!(f, "pure"),
+            (true, false) => write!(f, "writes-env"),
+            (false, true) => write!(f, "io"),
+            (true, true) => write!(f, "writes-env, io"),
+        }
+    }
+}
+
+/// What is known of the effects of the functions of a program, by name.
+type Known = HashMap<String, Effects>;
+
+/// The effects of `node`, given the names bound outside the function it
+/// is in, if any, and the effects of the functions it may call. The
+/// effects of the named functions in it are added to `functions`.
+fn effects_in(node: &Node, outer: &HashSet<String>, function: Option<&Function>,
+              known: &Known, functions: &mut Vec<(String, Effects)>) -> Effects {
+    match *node {
+        Node::Global(_, ref expr) => Effects::WRITES_ENV.join(effects_in(expr, outer, function, known, functions)),
+        // natives are opaque, so may do anything
+        Node::Native(_) => Effects::IO.join(Effects::WRITES_ENV),
+        // a local hides the builtins and functions of the program
+        Node::Variable(ref name) if function.is_some_and(|function| {
+            function.own.contains(name) || function.locals.contains(name)
+        }) => Effects::PURE,
+        Node::Variable(ref name) | Node::Builtin(ref name) if IO_BUILTINS.contains(&name.as_str()) => Effects::IO,
+        Node::Variable(ref name) => known.get(name).cloned().unwrap_or(Effects::PURE),
+        // a function assigning a name bound around it updates that
+        // binding when scopes are shared
+        Node::Assign(ref name, ref expr) => {
+            let effects = effects_in(expr, outer, function, known, functions);
+            match function {
+                Some(function) if !function.own.contains(name) && outer.contains(name) => effects.join(Effects::WRITES_ENV),
+                _ => effects,
+            }
+        }
+        Node::Fun(ref funname, ref params, ref body) => {
+            let mut around = outer.clone();
+            if let Some(function) = function {
+                around.extend(function.own.iter().cloned());
+                around.extend(function.locals.iter().cloned());
+            }
+            let mut own: HashSet<String> = params.iter().cloned().collect();
+            own.insert(funname.clone());
+            let mut locals = HashSet::new();
+            function_assignments(body, &mut locals);
+            let effects = effects_in(body, &around, Some(&Function { own, locals }), known, functions);
+            if !funname.is_empty() {
+                functions.push((funname.clone(), effects));
+            }
+            effects
+        }
+        Node::Closure(_, ref fun) | Node::Thunk(_, ref fun, _, _) => effects_in(fun, outer, function, known, functions),
+        _ => node.children().into_iter()
+            .fold(Effects::PURE, |effects, child| effects.join(effects_in(child, outer, function, known, functions))),
+    }
+}
+
+fn effects_and_functions(node: &Node, outer: &HashSet<String>) -> (Effects, Vec<(String, Effects)>) {
+    let mut around = outer.clone();
+    around.extend(get_free_vars(node));
+    toplevel_assignments(node, &mut around);
+    // what calls do is known once what the functions called do is, so
+    // go again until nothing more is learned
+    let mut known = Known::new();
+    loop {
+        let mut functions = Vec::new();
+        let effects = effects_in(node, &around, None, &known, &mut functions);
+        let mut learned = Known::new();
+        for &(ref name, effects) in &functions {
+            let entry = learned.entry(name.clone()).or_default();
+            *entry = entry.join(effects);
+        }
+        if learned == known {
+            return (effects, functions);
+        }
+        known = learned;
+    }
+}
+
+/// The effects of evaluating `node` besides binding the names it assigns
+/// at its own top level: calling an IO builtin is `io`, assigning with
+/// `global` or, in a function, to a name bound around it is `writes-env`,
+/// and a native may be either. Calling a function of the program has the
+/// effects of its body. The names in `outer` are taken as bound
+/// around `node`; names the functions assign that are bound nowhere else
+/// are their locals. Values of free variables, such as functions from the
+/// environment, are not looked into.
+pub fn effects_with(node: &Node, outer: &HashSet<String>) -> Effects {
+    effects_and_functions(node, outer).0
+}
+
+/// The effects of evaluating `node` (see `effects_with`), knowing nothing
+/// of the scope it runs in.
+pub fn effects(node: &Node) -> Effects {
+    effects_with(node, &HashSet::new())
+}
+
+/// The effects inferred for each named function of `program`, in the
+/// order their definitions end.
+pub fn function_effects(program: &Node) -> Vec<(String, Effects)> {
+    effects_and_functions(program, &HashSet::new()).1
+}
+
+/// Whether evaluating `node` can only compute a value and bind the names
+/// it assigns at its own top level (see `effects_with`).
+pub fn is_pure_with(node: &Node, outer: &HashSet<String>) -> bool {
+    effects_with(node, outer).is_pure()
+}
+
+/// Whether evaluating `node` is pure, knowing nothing of the scope it
+/// runs in.
+pub fn is_pure(node: &Node) -> bool {
+    effects(node).is_pure()
+}
+
+/// The effects of evaluating `node` in `env`, those of the functions it
+/// reaches in `env` included.
+pub fn effects_in_env(node: &Node, env: &Environment) -> Effects {
+    env_effects(node, env, &mut Vec::new())
+}
+
+fn env_effects(node: &Node, env: &Environment, seen: &mut Vec<*const Node>) -> Effects {
+    get_free_vars(node).iter().fold(effects_with(node, &env.names()), |effects, name| match env.lookup(name) {
+        Some(value) => effects.join(value_effects(&value, seen)),
+        None => effects,
+    })
+}
+
+/// The effects of using a value from the environment.
+fn value_effects(value: &Node, seen: &mut Vec<*const Node>) -> Effects {
+    match *value {
+        Node::Closure(ref env, ref fun) | Node::Thunk(ref env, ref fun, _, _) => {
+            if seen.contains(&Rc::as_ptr(fun)) {
+                return Effects::PURE;
+            }
+            seen.push(Rc::as_ptr(fun));
+            env_effects(fun, env, seen)
+        }
+        _ => value.children().into_iter()
+            .fold(effects(value), |effects, child| effects.join(value_effects(child, seen))),
+    }
+}
+
+/// The kind and effects of `expr` evaluated in `env`, as `:type` shows
+/// them, say `function ! io`.
+pub fn type_of(expr: &Node, env: &Environment) -> String {
+    let vars: Kinds = env.iter().map(|(name, value)| {
+        let known = kind(&value, &Kinds::new());
+        (name, known)
+    }).collect();
+    let kind = kind(expr, &vars).map_or("unknown", Kind::type_name);
+    format!("{} ! {}", kind, effects_in_env(expr, env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    #[test]
+    fn test_ambiguous_assignments() {
+        let program = Node::sequence(
+            Node::assign("count", Node::number(0)),
+            Node::assign("f", Node::fun("f", &["x"], Node::sequence(
+                Node::assign("count", Node::variable("x")),
+                Node::sequence(
+                    Node::global("count", Node::variable("x")),
+                    Node::assign("tmp", Node::variable("x")))))));
+        let warnings = ambiguous_assignments(&program);
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("`count` in function `f`"));
+    }
+
+    #[test]
+    fn test_unassigned_reads() {
+        let reads = |source| unassigned_reads(&parser::parse(source).unwrap());
+        assert!(reads("x = 1; print(x + len([x]))").is_empty());
+        assert_eq!(vec!["`y` may be read before it is assigned".to_string()], reads("x = y + 1; y = 2;"));
+        // assigned on one path only, or in a loop that may not run
+        assert_eq!(vec!["`x` may be read before it is assigned".to_string()],
+                   reads("if (input() == \"a\") { x = 1; } print(x)"));
+        assert!(reads("if (true) { x = 1; } else { x = 2; } print(x)").is_empty());
+        assert_eq!(vec!["`total` may be read before it is assigned".to_string()],
+                   reads("for (i = 0; i < 3; i = i + 1) { total = i; } print(total)"));
+        assert!(reads("match ((1, 2)) { (a, b) => { c = a + b; } _ => { c = 0; } } print(c)").is_empty());
+        // a function may read what is assigned after it is defined, but not what is never assigned
+        assert!(reads("def fetch() { return n + helper(); } def helper() { return 1; } n = 1; fetch()").is_empty());
+        assert!(reads("def make() { n = 0; def inc() { n = n + 1; return n; } return inc; } make()").is_empty());
+        assert!(reads("def mark() { global seen = 1; } def fetch() { return seen; }").is_empty());
+        assert_eq!(vec!["`missing` is read in function `f` but never assigned".to_string()],
+                   reads("def f(a) { return a + missing; }"));
+    }
+
+    #[test]
+    fn test_unused_assignments() {
+        let unused = |source| unused_assignments(&parser::parse(source).unwrap());
+        assert!(unused("x = 1; def f(a) { return a * x; } result = f(2);").is_empty());
+        assert_eq!(vec![
+            "`y` is assigned but never read".to_string(),
+            "`tmp` is assigned in function `f` but never read".to_string(),
+        ], unused("x = 1; y = x; def f(a) { tmp = a; return a; } print(f(x))"));
+        // a variable read in a loop that assigns it is read
+        assert!(unused("i = 0; while (i < 3) { i = i + 1; }").is_empty());
+        assert_eq!(vec!["`step` is assigned in an anonymous function but never read".to_string()],
+                   unused("g = fun(n) { step = 1; n }; print(g(1))"));
+        let outside: HashSet<String> = ["y".to_string()].iter().cloned().collect();
+        let program = parser::parse("x = 1; y = x;").unwrap();
+        assert!(unused_assignments_with(&program, &outside).is_empty());
+    }
+
+    #[test]
+    fn test_unstable_kinds() {
+        let program = parser::parse("def sign(x) { if (x < 0) { -1 } else { if (x == 0) { false } else { 1 } } }
+            def lookup(xs, i) { if (i < len(xs)) { return xs[i]; } return nothing(); }
+            def fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }
+            def clamp(x) { y = x; if (y > 10) { return 10; } return y; }
+            flag = 1 < 2;
+            if (flag) { result = 3; } else { result = flag and false; }").unwrap();
+        let warnings = unstable_kinds(&program);
+        assert_eq!(vec![
+            "`result` may hold a number (3) or a boolean (flag and false) depending on the path taken".to_string(),
+            "function `sign` may return a number (-(1)) or a boolean (false) depending on the path taken".to_string(),
+        ], warnings);
+        let consistent = parser::parse("x = 1; result = if_else(x); result = x + 2;").unwrap();
+        assert!(unstable_kinds(&consistent).is_empty());
+    }
+
+    #[test]
+    fn test_is_pure() {
+        let pure = |source| is_pure(&parser::parse(source).unwrap());
+        assert!(pure("x = 1; y = x + 1; [x, y]"));
+        assert!(pure("n = 5; def add(x) { return x + n; } add(1)"));
+        // parameters and names bound nowhere else are the function's own
+        assert!(pure("n = 0; def f(n) { n = n + 1; return n; } f(1)"));
+        assert!(pure("def f() { tmp = 1; return tmp; } f()"));
+        assert!(!pure("print(1)"));
+        assert!(!pure("log = print; log(1)"));
+        assert!(!pure("def f() { return input(); }"));
+        assert!(!pure("def f() { global count = 1; }"));
+        // writes to bindings around the function
+        assert!(!pure("y = 0; def f() { y = 1; }"));
+        assert!(!pure("def f() { total = total + 1; }"));
+    }
+
+    #[test]
+    fn test_is_pure_closures() {
+        let pure = |source| is_pure(&parser::parse(source).unwrap());
+        // a counter updates the variable it captured
+        assert!(!pure("def make() { n = 0; def inc() { n = n + 1; return n; } return inc; }"));
+        assert!(!pure("make = fun(n) { fun() { n = n + 1; } };"));
+        // the inner function shadows what it captured
+        assert!(pure("def make() { n = 0; def get(n) { n = n * 2; return n; } return get; }"));
+        assert!(pure("make = fun(n) { fun(m) { m + n } };"));
+        // an effect is one even when only captured, never called here
+        assert!(!pure("def make() { out = print; return fun(x) { out(x) }; }"));
+        // the scope around the program counts as outer
+        let outer: HashSet<String> = ["acc".to_string()].iter().cloned().collect();
+        let program = parser::parse("def keep(x) { acc = x; }").unwrap();
+        assert!(is_pure(&program));
+        assert!(!is_pure_with(&program, &outer));
+    }
+
+    #[test]
+    fn test_effects() {
+        let program = parser::parse("count = 0;
+            def bump() { count = count + 1; }
+            def show(x) { print(x) }
+            def both() { done = bump(); show(count) }
+            def sq(x) { return x * x; }").unwrap();
+        assert_eq!(vec![
+            ("bump".to_string(), Effects::WRITES_ENV),
+            ("show".to_string(), Effects::IO),
+            ("both".to_string(), Effects::WRITES_ENV.join(Effects::IO)),
+            ("sq".to_string(), Effects::PURE),
+        ], function_effects(&program));
+        assert_eq!("writes-env, io", effects(&program).to_string());
+        assert_eq!("pure", effects(&parser::parse("x = 1;").unwrap()).to_string());
+        // recursion, and parameters hiding a function
+        let program = parser::parse("def loop(n) { if (n > 0) { loop(n - 1) } else { print(n) } }
+            def apply(print, x) { print(x) }").unwrap();
+        assert_eq!(vec![
+            ("loop".to_string(), Effects::IO),
+            ("apply".to_string(), Effects::PURE),
+        ], function_effects(&program));
+    }
+
+    #[test]
+    fn test_type_of() {
+        use simple::machine::Machine;
+        let mut machine = Machine::new_with_empty_env(Node::donothing());
+        machine.execute(&parser::parse("n = 2; log = print; def twice(x) { first = log(x); log(x) }").unwrap());
+        let env = machine.get_environment();
+        let type_of = |source| type_of(&parser::parse(source).unwrap(), &env);
+        assert_eq!("number ! pure", type_of("n + 1"));
+        // the effects of functions from the environment are looked into
+        assert_eq!("function ! io", type_of("twice"));
+        assert_eq!("unknown ! io", type_of("twice(n)"));
+        assert_eq!("boolean ! pure", type_of("n < 3"));
+    }
+}
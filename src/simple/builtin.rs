@@ -0,0 +1,669 @@
+use super::syntax::Node;
+use super::evaluate;
+use super::convert::FromValue;
+use parser::Arity::{self, AtLeast, Exactly};
+
+use std::convert::TryFrom;
+
+/// A function implemented in Rust and callable from simple code.
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub fun: fn(&[Box<Node>]) -> Box<Node>,
+}
+
+/// Define a builtin from a Rust function over Rust values:
+///
+/// ```
+/// # #[macro_use] extern crate proglang;
+/// # fn main() {
+/// let max = builtin!(fn max(a: i64, b: i64) -> i64 { a.max(b) });
+/// # use proglang::parser::Arity;
+/// assert_eq!(("max", Arity::Exactly(2)), (max.name, max.arity));
+/// # }
+/// ```
+///
+/// is the `Builtin` named `max` taking two arguments. Calls with another
+/// number of arguments, or with arguments that do not convert to the
+/// parameter types (see `convert::FromValue`), panic with the same
+/// messages for every builtin; the result is converted back with
+/// `convert::IntoValue`. It registers with a machine like a native, with
+/// `Machine::register_builtin`.
+#[macro_export]
+macro_rules! builtin {
+    (fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty $body:block) => {
+        $crate::simple::builtin::Builtin {
+            name: stringify!($name),
+            arity: $crate::parser::Arity::Exactly(<[&str]>::len(&[$(stringify!($arg)),*])),
+            fun: |args: &[Box<$crate::simple::syntax::Node>]| {
+                // values are boxed as the machine holds them
+                #[allow(clippy::boxed_local)]
+                fn implementation($($arg: $ty),*) -> $ret $body
+                $crate::simple::builtin::check_arity(stringify!($name),
+                    $crate::parser::Arity::Exactly(<[&str]>::len(&[$(stringify!($arg)),*])), args);
+                let _args = &mut args.iter().enumerate();
+                $crate::simple::convert::IntoValue::into_value(implementation($({
+                    let (index, value) = _args.next().unwrap();
+                    $crate::simple::builtin::argument::<$ty>(stringify!($name), index, value)
+                }),*))
+            },
+        }
+    };
+}
+
+static BUILTINS: &[Builtin] = &[
+    Builtin { name: "abs",  arity: Exactly(1), fun: builtin_abs },
+    Builtin { name: "sign", arity: Exactly(1), fun: builtin_sign },
+    Builtin { name: "min",  arity: Exactly(2), fun: builtin_min },
+    Builtin { name: "max",  arity: Exactly(2), fun: builtin_max },
+    builtin!(fn pow(base: i64, exp: i64) -> i64 { pow(base, exp) }),
+    builtin!(fn gcd(a: i64, b: i64) -> i64 { gcd(a, b) }),
+    builtin!(fn lcm(a: i64, b: i64) -> i64 { lcm(a, b) }),
+    Builtin { name: "divmod", arity: Exactly(2), fun: builtin_divmod },
+    builtin!(fn pair(fst: Box<Node>, snd: Box<Node>) -> Box<Node> { Node::pair(fst, snd) }),
+    Builtin { name: "fst",  arity: Exactly(1), fun: builtin_fst },
+    Builtin { name: "snd",  arity: Exactly(1), fun: builtin_snd },
+    Builtin { name: "len",  arity: Exactly(1), fun: builtin_len },
+    Builtin { name: "push", arity: Exactly(2), fun: builtin_push },
+    Builtin { name: "head", arity: Exactly(1), fun: builtin_head },
+    Builtin { name: "tail", arity: Exactly(1), fun: builtin_tail },
+    builtin!(fn force(value: Box<Node>) -> Box<Node> { evaluate::force(value) }),
+    Builtin { name: "stream_head", arity: Exactly(1), fun: builtin_stream_head },
+    Builtin { name: "stream_tail", arity: Exactly(1), fun: builtin_stream_tail },
+    Builtin { name: "take", arity: Exactly(2), fun: builtin_take },
+    Builtin { name: "get",  arity: Exactly(2), fun: builtin_get },
+    Builtin { name: "set",  arity: Exactly(3), fun: builtin_set },
+    Builtin { name: "contains", arity: Exactly(2), fun: builtin_contains },
+    Builtin { name: "print", arity: Exactly(1), fun: builtin_print },
+    Builtin { name: "warn", arity: Exactly(1), fun: builtin_warn },
+    Builtin { name: "input", arity: Exactly(0), fun: builtin_input },
+    Builtin { name: "arity", arity: Exactly(1), fun: builtin_arity },
+    Builtin { name: "name", arity: Exactly(1), fun: builtin_name },
+    builtin!(fn is_function(value: Box<Node>) -> bool { value.is_function() }),
+    builtin!(fn is_number(value: Box<Node>) -> bool { matches!(*value, Node::Number(_) | Node::Float(_)) }),
+    builtin!(fn is_boolean(value: Box<Node>) -> bool { matches!(*value, Node::Boolean(_)) }),
+    builtin!(fn is_string(value: Box<Node>) -> bool { matches!(*value, Node::Str(_)) }),
+    builtin!(fn is_pair(value: Box<Node>) -> bool { matches!(*value, Node::Pair(..)) }),
+    builtin!(fn is_list(value: Box<Node>) -> bool { matches!(*value, Node::List(_)) }),
+    builtin!(fn is_map(value: Box<Node>) -> bool { matches!(*value, Node::Map(_)) }),
+    Builtin { name: "to_number", arity: Exactly(2), fun: builtin_to_number },
+    Builtin { name: "to_boolean", arity: Exactly(2), fun: builtin_to_boolean },
+    Builtin { name: "compose", arity: AtLeast(1), fun: builtin_compose },
+];
+
+/// Names the parser turns into dedicated nodes rather than calls. They
+/// are not values, but share the protected namespace with the builtins.
+static SPECIAL_FORMS: &[&str] = &["nothing", "isnothing", "cons_stream"];
+
+/// Find the builtin registered under `name`.
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// All registered builtins, in registration order.
+pub fn all() -> &'static [Builtin] {
+    BUILTINS
+}
+
+/// Names in the builtin namespace, special forms first. User code cannot
+/// assign to them or take them as function parameters.
+pub fn names() -> Vec<&'static str> {
+    SPECIAL_FORMS.iter().cloned().chain(BUILTINS.iter().map(|builtin| builtin.name)).collect()
+}
+
+/// Whether `name` belongs to the builtin namespace.
+pub fn is_reserved(name: &str) -> bool {
+    SPECIAL_FORMS.contains(&name) || lookup(name).is_some()
+}
+
+/// Apply the builtin `name` to already evaluated arguments.
+pub fn call(name: &str, args: &[Box<Node>]) -> Box<Node> {
+    let builtin = lookup(name).unwrap_or_else(|| panic!("Unknown builtin: {}", name));
+    check_arity(name, builtin.arity, args);
+    (builtin.fun)(args)
+}
+
+/// Panic unless the builtin `name` is given `arity` arguments.
+pub fn check_arity(name: &str, arity: Arity, args: &[Box<Node>]) {
+    if !arity.accepts(args.len()) {
+        panic!("Builtin {} takes {}, found {}", name, arity, args.len());
+    }
+}
+
+/// The argument at `index` of the builtin `name` as the Rust value its
+/// implementation takes, or a panic saying what was expected.
+pub fn argument<T: FromValue>(name: &str, index: usize, value: &Node) -> T {
+    T::from_value(value).unwrap_or_else(|e| {
+        panic!("Builtin {} expects {} as argument {}, got {}", name, e.expected, index + 1, e.found)
+    })
+}
+
+fn builtin_abs(args: &[Box<Node>]) -> Box<Node> {
+    if let Node::Float(value) = *args[0] {
+        return Node::float(value.abs());
+    }
+    let value = args[0].value();
+    Node::number(value.checked_abs().unwrap_or_else(|| panic!("abs({}) overflows", value)))
+}
+
+/// -1, 0 or 1 as an integer, for floats as well as integers.
+fn builtin_sign(args: &[Box<Node>]) -> Box<Node> {
+    if let Node::Float(value) = *args[0] {
+        if value.is_nan() {
+            panic!("sign({}) is undefined", args[0]);
+        }
+        return Node::number(if value > 0.0 { 1 } else if value < 0.0 { -1 } else { 0 });
+    }
+    Node::number(args[0].value().signum())
+}
+
+/// The smaller of two numbers; mixing an integer with a float gives a float.
+fn builtin_min(args: &[Box<Node>]) -> Box<Node> {
+    match (&*args[0], &*args[1]) {
+        (&Node::Number(a), &Node::Number(b)) => Node::number(a.min(b)),
+        (a, b) => Node::float(a.float_value().min(b.float_value())),
+    }
+}
+
+/// The larger of two numbers; mixing an integer with a float gives a float.
+fn builtin_max(args: &[Box<Node>]) -> Box<Node> {
+    match (&*args[0], &*args[1]) {
+        (&Node::Number(a), &Node::Number(b)) => Node::number(a.max(b)),
+        (a, b) => Node::float(a.float_value().max(b.float_value())),
+    }
+}
+
+fn pow(base: i64, exp: i64) -> i64 {
+    if exp < 0 {
+        panic!("pow({}, {}) has a negative exponent", base, exp);
+    }
+    let result = if exp > u32::MAX as i64 { None } else { base.checked_pow(exp as u32) };
+    result.unwrap_or_else(|| panic!("pow({}, {}) overflows", base, exp))
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    i64::try_from(a).unwrap_or_else(|_| panic!("gcd result {} overflows", a))
+}
+
+fn lcm(a: i64, b: i64) -> i64 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let lcm = (a / gcd(a, b)).checked_mul(b).and_then(i64::checked_abs);
+    lcm.unwrap_or_else(|| panic!("lcm({}, {}) overflows", a, b))
+}
+
+/// Floored division and remainder as a pair, so the remainder takes the
+/// sign of the divisor: divmod(-7, 2) is pair(-4, 1).
+fn builtin_divmod(args: &[Box<Node>]) -> Box<Node> {
+    let (a, b) = (args[0].value(), args[1].value());
+    if b == 0 {
+        panic!("divmod({}, {}) divides by zero", a, b);
+    }
+    let (q, r) = match (a.checked_div(b), a.checked_rem(b)) {
+        (Some(q), Some(r)) => (q, r),
+        _ => panic!("divmod({}, {}) overflows", a, b),
+    };
+    if r != 0 && (r < 0) != (b < 0) {
+        Node::pair(Node::number(q - 1), Node::number(r + b))
+    } else {
+        Node::pair(Node::number(q), Node::number(r))
+    }
+}
+
+fn builtin_fst(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Pair(ref l, _) => l.clone(),
+        _ => panic!("Apply fst on non-pair type: {}", args[0]),
+    }
+}
+
+fn builtin_snd(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Pair(_, ref r) => r.clone(),
+        _ => panic!("Apply snd on non-pair type: {}", args[0]),
+    }
+}
+
+/// The number of characters of a string, items of a list or entries of a map.
+fn builtin_len(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Str(ref value) => Node::number(value.chars().count() as i64),
+        Node::List(ref items) => Node::number(items.len() as i64),
+        Node::Map(ref entries) => Node::number(entries.len() as i64),
+        _ => panic!("Apply len on non-string, non-list, non-map type: {}", args[0]),
+    }
+}
+
+/// Lists are values: push returns a new list with the item appended.
+fn builtin_push(args: &[Box<Node>]) -> Box<Node> {
+    let mut items = args[0].list_value().to_vec();
+    items.push(args[1].clone());
+    Node::list(items)
+}
+
+fn builtin_head(args: &[Box<Node>]) -> Box<Node> {
+    match args[0].list_value().first() {
+        Some(item) => item.clone(),
+        None => panic!("Apply head on empty list"),
+    }
+}
+
+fn builtin_tail(args: &[Box<Node>]) -> Box<Node> {
+    match args[0].list_value().split_first() {
+        Some((_, rest)) => Node::list(rest.to_vec()),
+        None => panic!("Apply tail on empty list"),
+    }
+}
+
+/// A stream is do-nothing when empty, or a pair of its head and a thunk
+/// of the rest of the stream.
+fn builtin_stream_head(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Pair(ref head, _) => head.clone(),
+        _ => panic!("Apply stream_head on non-stream type: {}", args[0]),
+    }
+}
+
+fn builtin_stream_tail(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Pair(_, ref tail) => evaluate::force(tail.clone()),
+        _ => panic!("Apply stream_tail on non-stream type: {}", args[0]),
+    }
+}
+
+/// The first n items of a stream as a list, fewer if the stream ends.
+fn builtin_take(args: &[Box<Node>]) -> Box<Node> {
+    let mut stream = args[0].clone();
+    let mut items = Vec::new();
+    for _ in 0..args[1].value() {
+        if *stream == Node::DoNothing {
+            break;
+        }
+        items.push(builtin_stream_head(&[stream.clone()]));
+        stream = builtin_stream_tail(&[stream]);
+    }
+    Node::list(items)
+}
+
+fn builtin_get(args: &[Box<Node>]) -> Box<Node> {
+    let key = args[1].string_value();
+    match args[0].map_value().get(key) {
+        Some(value) => value.clone(),
+        None => panic!("Key {:?} not found in {}", key, args[0]),
+    }
+}
+
+/// Maps are values like lists: set returns a new map with the entry changed.
+fn builtin_set(args: &[Box<Node>]) -> Box<Node> {
+    let mut entries = args[0].map_value().clone();
+    entries.insert(args[1].string_value().to_string(), args[2].clone());
+    Node::map(entries)
+}
+
+fn builtin_contains(args: &[Box<Node>]) -> Box<Node> {
+    Node::boolean(args[0].map_value().contains_key(args[1].string_value()))
+}
+
+/// Write a value and a newline to the machine's output. Strings are
+/// written as they are, without quotes.
+fn builtin_print(args: &[Box<Node>]) -> Box<Node> {
+    let text = match *args[0] {
+        Node::Str(ref value) => format!("{}\n", value),
+        ref value => format!("{}\n", evaluate::number_format().value(value)),
+    };
+    evaluate::write_output(&text).unwrap_or_else(|e| panic!("Error in printing {}: {}", args[0], e));
+    Node::donothing()
+}
+
+/// Report a warning, apart from what the program prints.
+fn builtin_warn(args: &[Box<Node>]) -> Box<Node> {
+    match *args[0] {
+        Node::Str(ref value) => evaluate::warn(value),
+        ref value => evaluate::warn(&value.to_string()),
+    }
+    Node::donothing()
+}
+
+/// Read a line of input: a number if it reads as one, a string otherwise,
+/// and do-nothing at the end of the input.
+fn builtin_input(_args: &[Box<Node>]) -> Box<Node> {
+    let line = match evaluate::read_input() {
+        Ok(Some(line)) => line,
+        Ok(None) => return Node::donothing(),
+        Err(e) => panic!("Error in reading input: {}", e),
+    };
+    let text = line.trim();
+    parse_number(text).unwrap_or_else(|| Node::string(&line))
+}
+
+/// The integer or else the float `text` reads as, if any.
+fn parse_number(text: &str) -> Option<Box<Node>> {
+    if let Ok(value) = text.parse::<i64>() {
+        Some(Node::number(value))
+    } else {
+        text.parse::<f64>().ok().map(Node::float)
+    }
+}
+
+/// `compose(f, g, h)` is the function x -> f(g(h(x))), as `h >> g >> f`.
+fn builtin_compose(args: &[Box<Node>]) -> Box<Node> {
+    let (last, rest) = args.split_last().unwrap();
+    rest.iter().rev().fold(last.clone(), |inner, outer| evaluate::compose(outer.clone(), inner))
+}
+
+fn builtin_arity(args: &[Box<Node>]) -> Box<Node> {
+    match args[0].arity() {
+        Some(arity) => Node::number(arity as i64),
+        None => match *args[0] {
+            Node::Builtin(ref name) => panic!("Builtin {} takes {}", name, lookup(name).unwrap().arity),
+            _ if args[0].is_function() => panic!("Function {} takes any number of arguments", args[0]),
+            _ => panic!("Apply arity on non-function type: {}", args[0]),
+        },
+    }
+}
+
+/// The name of a function, or do-nothing for an anonymous one.
+fn builtin_name(args: &[Box<Node>]) -> Box<Node> {
+    if !args[0].is_function() {
+        panic!("Apply name on non-function type: {}", args[0]);
+    }
+    args[0].function_name().map_or_else(Node::donothing, Node::string)
+}
+
+/// Convert a value to a number, or return the default when it has none:
+/// numbers are kept, booleans give 1 or 0, strings are read like
+/// `input()` reads a line (surrounding spaces ignored), and every other
+/// value gives the default.
+fn builtin_to_number(args: &[Box<Node>]) -> Box<Node> {
+    let converted = match *args[0] {
+        Node::Number(_) | Node::Float(_) => Some(args[0].clone()),
+        Node::Boolean(value) => Some(Node::number(value as i64)),
+        Node::Str(ref value) => parse_number(value.trim()),
+        _ => None,
+    };
+    converted.unwrap_or_else(|| args[1].clone())
+}
+
+/// Convert a value to a boolean, or return the default when it has none:
+/// booleans are kept, numbers are true unless zero (NaN gives the
+/// default), the strings "true" and "false" give their value, and every
+/// other value gives the default.
+fn builtin_to_boolean(args: &[Box<Node>]) -> Box<Node> {
+    let converted = match *args[0] {
+        Node::Boolean(value) => Some(value),
+        Node::Number(value) => Some(value != 0),
+        Node::Float(value) if !value.is_nan() => Some(value != 0.0),
+        Node::Str(ref value) => match value.trim() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    };
+    converted.map_or_else(|| args[1].clone(), Node::boolean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple::environment::Environment;
+    use simple::evaluate::Evaluate;
+    use parser;
+    use std::collections::HashMap;
+
+    fn eval(name: &str, args: Vec<Box<Node>>) -> Box<Node> {
+        Node::call_args(Node::variable(name), args).evaluate(&mut Environment::new())
+    }
+
+    #[test]
+    fn test_builtin_math() {
+        let minus_two = || Node::subtract(Node::number(1), Node::number(3));
+        assert_eq!(2, eval("abs", vec![minus_two()]).value());
+        assert_eq!(-1, eval("sign", vec![minus_two()]).value());
+        assert_eq!(0, eval("sign", vec![Node::subtract(Node::number(1), Node::number(1))]).value());
+        assert_eq!(-2, eval("min", vec![minus_two(), Node::number(4)]).value());
+        assert_eq!(4, eval("max", vec![minus_two(), Node::number(4)]).value());
+        assert_eq!(1024, eval("pow", vec![Node::number(2), Node::number(10)]).value());
+    }
+
+    #[test]
+    fn test_builtin_math_float() {
+        let minus = |v| Node::negate(Node::float(v));
+        assert_eq!(Node::float(2.5), eval("abs", vec![minus(2.5)]));
+        assert_eq!(Node::number(-1), eval("sign", vec![minus(0.1)]));
+        assert_eq!(Node::number(0), eval("sign", vec![Node::float(0.0)]));
+        assert_eq!(Node::float(-2.5), eval("min", vec![minus(2.5), Node::number(4)]));
+        assert_eq!(Node::float(4.0), eval("max", vec![minus(2.5), Node::number(4)]));
+    }
+
+    #[test]
+    fn test_builtin_number_theory() {
+        let n = |v: i64| if v < 0 { Node::negate(Node::number(-v)) } else { Node::number(v) };
+        assert_eq!(6, eval("gcd", vec![n(-12), n(18)]).value());
+        assert_eq!(5, eval("gcd", vec![n(0), n(5)]).value());
+        assert_eq!(36, eval("lcm", vec![n(12), n(-18)]).value());
+        assert_eq!(0, eval("lcm", vec![n(0), n(7)]).value());
+        let pair = |q, r| Node::pair(Node::number(q), Node::number(r));
+        assert_eq!(pair(3, 1), eval("divmod", vec![n(7), n(2)]));
+        assert_eq!(pair(-4, 1), eval("divmod", vec![n(-7), n(2)]));
+        assert_eq!(pair(-4, -1), eval("divmod", vec![n(7), n(-2)]));
+    }
+
+    #[test]
+    fn test_builtin_pair_as_value() {
+        // f = fst; p = pair(1, 2); f(p)
+        let program = Node::sequence(
+            Node::assign("f", Node::variable("fst")),
+            Node::assign("p", Node::call_args(Node::variable("pair"), vec![Node::number(1), Node::number(2)])));
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        assert_eq!(1, Node::call(Node::variable("f"), Node::variable("p")).evaluate(&mut env).value());
+        assert_eq!(2, eval("snd", vec![env.get("p")]).value());
+    }
+
+    #[test]
+    fn test_builtin_function_introspection() {
+        let program = parser::parse("def add(x, y) { x + y }
+            inc = fun(x) { x + 1 };
+            composed = inc >> inc;
+            arities = [arity(add), arity(inc), arity(composed), arity(len), arity(input)];
+            names = [name(add), name(inc), name(len)];
+            functions = [is_function(add), is_function(len), is_function(1), is_function(\"add\")];").unwrap();
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        let numbers = |items: &[i64]| Node::list(items.iter().map(|&item| Node::number(item)).collect());
+        assert_eq!(numbers(&[2, 1, 1, 1, 0]), env.get("arities"));
+        assert_eq!(Node::list(vec![Node::string("add"), Node::donothing(), Node::string("len")]), env.get("names"));
+        let booleans = Node::list([true, true, false, false].iter().map(|&item| Node::boolean(item)).collect());
+        assert_eq!(booleans, env.get("functions"));
+    }
+
+    #[test]
+    fn test_builtin_type_predicates() {
+        let program = parser::parse("def safe_fst(v, default) {
+                if (is_pair(v)) { return fst(v); }
+                return default;
+            }
+            values = [1, 2.5, true, \"s\", (1, 2), [1], {}, nothing()];
+            picked = [safe_fst((7, 8), 0), safe_fst([7, 8], 0)];").unwrap();
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        assert_eq!(Node::list(vec![Node::number(7), Node::number(0)]), env.get("picked"));
+        let values = env.get("values");
+        let kinds = |predicate: &str| -> Vec<bool> {
+            values.list_value().iter().map(|value| eval(predicate, vec![value.clone()]).condition()).collect()
+        };
+        assert_eq!(vec![true, true, false, false, false, false, false, false], kinds("is_number"));
+        assert_eq!(vec![false, false, true, false, false, false, false, false], kinds("is_boolean"));
+        assert_eq!(vec![false, false, false, true, false, false, false, false], kinds("is_string"));
+        assert_eq!(vec![false, false, false, false, true, false, false, false], kinds("is_pair"));
+        assert_eq!(vec![false, false, false, false, false, true, false, false], kinds("is_list"));
+        assert_eq!(vec![false, false, false, false, false, false, true, false], kinds("is_map"));
+    }
+
+    #[test]
+    fn test_builtin_conversions() {
+        let to_number = |value| eval("to_number", vec![value, Node::number(-1)]);
+        assert_eq!(Node::number(3), to_number(Node::number(3)));
+        assert_eq!(Node::float(2.5), to_number(Node::float(2.5)));
+        assert_eq!(Node::number(1), to_number(Node::boolean(true)));
+        assert_eq!(Node::number(42), to_number(Node::string(" 42 ")));
+        assert_eq!(Node::float(0.5), to_number(Node::string("0.5")));
+        assert_eq!(Node::number(-1), to_number(Node::string("forty-two")));
+        assert_eq!(Node::number(-1), to_number(Node::list(vec![])));
+        assert_eq!(Node::number(-1), to_number(Node::donothing()));
+
+        let to_boolean = |value| eval("to_boolean", vec![value, Node::string("neither")]);
+        assert_eq!(Node::boolean(false), to_boolean(Node::boolean(false)));
+        assert_eq!(Node::boolean(true), to_boolean(Node::number(-2)));
+        assert_eq!(Node::boolean(false), to_boolean(Node::number(0)));
+        assert_eq!(Node::boolean(false), to_boolean(Node::float(0.0)));
+        assert_eq!(Node::string("neither"), to_boolean(Node::float(f64::NAN)));
+        assert_eq!(Node::boolean(true), to_boolean(Node::string("true")));
+        assert_eq!(Node::string("neither"), to_boolean(Node::string("yes")));
+        assert_eq!(Node::string("neither"), to_boolean(Node::pair(Node::number(1), Node::number(2))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Apply arity on non-function type: 3")]
+    fn test_builtin_arity_non_function() {
+        eval("arity", vec![Node::number(3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Apply snd on non-pair type: 3")]
+    fn test_builtin_snd_non_pair() {
+        eval("snd", vec![Node::number(3)]);
+    }
+
+    #[test]
+    fn test_builtin_list() {
+        let xs = || Node::list(vec![Node::number(1), Node::number(2)]);
+        assert_eq!(2, eval("len", vec![xs()]).value());
+        assert_eq!(3, eval("len", vec![Node::string("abc")]).value());
+        assert_eq!(Node::list(vec![Node::number(1), Node::number(2), Node::number(3)]),
+                   eval("push", vec![xs(), Node::number(3)]));
+        assert_eq!(1, eval("head", vec![xs()]).value());
+        assert_eq!(Node::list(vec![Node::number(2)]), eval("tail", vec![xs()]));
+    }
+
+    #[test]
+    fn test_builtin_streams_example() {
+        let program = parser::parse(include_str!("../../examples/streams.simple")).unwrap();
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        let list = |items: &[i64]| Node::list(items.iter().map(|&item| Node::number(item)).collect());
+        assert_eq!(list(&[0, 1, 2, 3, 4]), env.get("first_naturals"));
+        assert_eq!(list(&[0, 1, 1, 2, 3, 5, 8, 13, 21, 34]), env.get("first_fibs"));
+        assert_eq!(list(&[0, 1, 4, 9, 16]), env.get("first_squares"));
+        assert_eq!(1, env.get("result").value());
+    }
+
+    #[test]
+    fn test_builtin_take_finite() {
+        // a stream of a single item ends after it
+        let stream = Node::pair(Node::number(7), Node::thunk(Environment::new(), Node::donothing(), true, false));
+        assert_eq!(Node::list(vec![Node::number(7)]), eval("take", vec![stream, Node::number(3)]));
+    }
+
+    #[test]
+    fn test_builtin_map() {
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), Node::number(1));
+        let m = || Node::map(entries.clone());
+        assert_eq!(1, eval("get", vec![m(), Node::string("a")]).value());
+        assert_eq!(Node::boolean(false), eval("contains", vec![m(), Node::string("b")]));
+        let m2 = eval("set", vec![m(), Node::string("b"), Node::number(2)]);
+        assert_eq!("{\"a\": 1, \"b\": 2}", m2.to_string());
+        assert_eq!(2, eval("len", vec![m2]).value());
+    }
+
+    #[test]
+    #[should_panic(expected = "Key \"b\" not found in {\"a\": 1}")]
+    fn test_builtin_map_missing_key() {
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), Node::number(1));
+        eval("get", vec![Node::map(entries), Node::string("b")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Apply head on empty list")]
+    fn test_builtin_head_empty() {
+        eval("head", vec![Node::list(vec![])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "divides by zero")]
+    fn test_builtin_divmod_zero() {
+        eval("divmod", vec![Node::number(1), Node::number(0)]);
+    }
+
+    #[test]
+    fn test_builtin_shadowed_by_user() {
+        let mut env = Environment::new();
+        env.add("max", Node::number(3));
+        assert_eq!(3, Node::variable("max").evaluate(&mut env).value());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot assign to builtin max")]
+    fn test_builtin_assign_rejected() {
+        Node::assign("max", Node::number(3)).evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot assign to builtin fst")]
+    fn test_special_form_global_rejected() {
+        Node::global("fst", Node::number(3)).evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot bind builtin min as a parameter")]
+    fn test_builtin_param_rejected() {
+        assert!(is_reserved("min") && is_reserved("pair") && !is_reserved("x"));
+        // refused when the function is made, before it is ever called
+        Node::fun("", &["x", "min"], Node::variable("min")).evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "pow(2, 64) overflows")]
+    fn test_builtin_overflow() {
+        eval("pow", vec![Node::number(2), Node::number(64)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Builtin gcd expects an integer as argument 2, got 1.5")]
+    fn test_builtin_argument_type() {
+        eval("gcd", vec![Node::number(3), Node::float(1.5)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Builtin min takes 2 arguments, found 1")]
+    fn test_builtin_arity() {
+        eval("min", vec![Node::number(2)]);
+    }
+
+    #[test]
+    fn test_builtin_compose() {
+        let mut env = Environment::new();
+        parser::parse("def inc(x) { return x + 1; } def dbl(x) { return x * 2; }
+            c = compose; f = c(inc, dbl, inc); g = compose(dbl); h = inc >> dbl;
+            r = [f(3), g(5), h(1)];").unwrap().evaluate(&mut env);
+        assert_eq!(Node::list(vec![Node::number(9), Node::number(10), Node::number(4)]), env.get("r"));
+        assert_eq!(Arity::AtLeast(1), lookup("compose").unwrap().arity);
+        assert!(!is_reserved("cons") && is_reserved("compose"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Builtin compose takes at least 1 argument, found 0")]
+    fn test_builtin_compose_arity() {
+        parser::parse("c = compose; f = c();").unwrap().evaluate(&mut Environment::new());
+    }
+}
@@ -0,0 +1,250 @@
+//! Remembering what programs evaluated to. A program is keyed by a hash
+//! of its syntax up to the names bound by its functions, and of the
+//! bindings it starts from, so evaluating an identical program against
+//! identical variables again returns at once.
+//!
+//! What is cached, and for how long:
+//! - only programs that are pure, functions they reach in the
+//!   environment included (see `analysis::effects_in_env`);
+//! - only results that carry no environment, so no closure or thunk in
+//!   the value or among the bindings the program changed;
+//! - entries depend on the content of the bindings, so changing any
+//!   binding simply misses; past the capacity the oldest entry goes, and
+//!   `clear` drops them all.
+
+use super::syntax::Node;
+use super::environment::Environment;
+use super::analysis;
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+type Bindings = Vec<(String, Box<Node>)>;
+
+/// A cache shared by the machines of a pool.
+pub type SharedCache = Rc<RefCell<ResultCache>>;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Runs that could not be looked up or stored, having effects or
+    /// leaving closures behind.
+    pub uncacheable: usize,
+    pub evictions: usize,
+}
+
+/// A program and the bindings it starts from, found missing in a cache.
+pub struct Key {
+    hash: u64,
+    input: Bindings,
+}
+
+pub enum Lookup {
+    /// The value of the program; the environment has its bindings.
+    Hit(Box<Node>),
+    Miss(Key),
+    Uncacheable,
+}
+
+struct Entry {
+    program: Box<Node>,
+    input: Bindings,
+    /// Bindings the program added or changed.
+    output: Bindings,
+    value: Box<Node>,
+}
+
+pub struct ResultCache {
+    capacity: usize,
+    entries: HashMap<u64, Vec<Entry>>,
+    /// Hashes of the entries, oldest first.
+    order: VecDeque<u64>,
+    stats: CacheStats,
+}
+
+/// Hash `program` with the bindings of `env` it starts from. Programs
+/// equal up to renaming the names their functions bind hash the same.
+pub fn program_hash(program: &Node, env: &Environment) -> u64 {
+    hash(program, &snapshot(env))
+}
+
+/// The bindings of `env` a cached program may depend on. Natives are
+/// left out: a program reaching one is never cached, and they compare by
+/// identity, which would keep machines set up alike from sharing results.
+fn snapshot(env: &Environment) -> Bindings {
+    env.iter().filter(|(_, value)| !matches!(**value, Node::Native(_))).collect()
+}
+
+fn hash(program: &Node, input: &[(String, Box<Node>)]) -> u64 {
+    let mut state = DefaultHasher::new();
+    program.alpha_hash(&mut state);
+    for (name, value) in input {
+        name.hash(&mut state);
+        value.alpha_hash(&mut state);
+    }
+    state.finish()
+}
+
+/// Whether `node` holds an environment, and so cannot outlive the run
+/// that made it.
+fn holds_environment(node: &Node) -> bool {
+    match *node {
+        Node::Closure(..) | Node::Thunk(..) | Node::Native(_) => true,
+        _ => node.children().into_iter().any(holds_environment),
+    }
+}
+
+impl ResultCache {
+    /// A cache keeping the results of at most `capacity` runs.
+    pub fn new(capacity: usize) -> ResultCache {
+        ResultCache { capacity, entries: HashMap::new(), order: VecDeque::new(), stats: CacheStats::default() }
+    }
+
+    pub fn shared(capacity: usize) -> SharedCache {
+        Rc::new(RefCell::new(ResultCache::new(capacity)))
+    }
+
+    /// Look `program` up as run in `env`. On a hit the bindings it made
+    /// are added to `env`; on a miss the key to `insert` its result under
+    /// is returned.
+    pub fn lookup(&mut self, program: &Node, env: &mut Environment) -> Lookup {
+        if !analysis::effects_in_env(program, env).is_pure() {
+            self.stats.uncacheable += 1;
+            return Lookup::Uncacheable;
+        }
+        let input = snapshot(env);
+        let hash = hash(program, &input);
+        let found = self.entries.get(&hash).and_then(|entries| entries.iter().find(|entry| {
+            entry.input == input && entry.program.alpha_eq(program)
+        }));
+        match found {
+            Some(entry) => {
+                self.stats.hits += 1;
+                for (name, value) in &entry.output {
+                    env.add(name, value.clone());
+                }
+                Lookup::Hit(entry.value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                Lookup::Miss(Key { hash, input })
+            }
+        }
+    }
+
+    /// Remember that `program`, missed under `key`, came to `value` and
+    /// left `env`.
+    pub fn insert(&mut self, key: Key, program: &Node, env: &Environment, value: &Node) {
+        let output: Bindings = snapshot(env).into_iter()
+            .filter(|(name, value)| !key.input.iter().any(|(n, v)| n == name && v == value))
+            .collect();
+        if holds_environment(value) || output.iter().any(|(_, value)| holds_environment(value)) {
+            self.stats.uncacheable += 1;
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.len() == self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            let bucket = self.entries.get_mut(&oldest).unwrap();
+            bucket.remove(0);
+            if bucket.is_empty() {
+                self.entries.remove(&oldest);
+            }
+            self.stats.evictions += 1;
+        }
+        self.order.push_back(key.hash);
+        self.entries.entry(key.hash).or_default().push(Entry {
+            program: Box::new(program.clone()),
+            input: key.input,
+            output,
+            value: Box::new(value.clone()),
+        });
+    }
+
+    /// Number of results kept.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Drop every result kept; the statistics stay.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::machine::Machine;
+
+    fn run(cache: &SharedCache, env: &Environment, source: &str) -> Box<Node> {
+        let mut machine = Machine::new(parser::parse(source).unwrap(), env.clone()).with_result_cache(cache.clone());
+        machine.run().unwrap()
+    }
+
+    #[test]
+    fn test_program_hash() {
+        let mut env = Environment::new();
+        env.add("n", Node::number(5));
+        let hash = |source, env: &Environment| program_hash(&parser::parse(source).unwrap(), env);
+        assert_eq!(hash("(fun(x) { x + n })(1)", &env), hash("(fun(y) { y + n })(1)", &env));
+        assert_ne!(hash("(fun(x) { x + n })(1)", &env), hash("(fun(x) { x + n })(2)", &env));
+        let before = hash("n + 1", &env);
+        env.add("n", Node::number(6));
+        assert_ne!(before, hash("n + 1", &env));
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let cache = ResultCache::shared(2);
+        let mut env = Environment::new();
+        env.add("n", Node::number(5));
+        assert_eq!(Node::number(6), run(&cache, &env, "(fun(x) { x + 1 })(n)"));
+        // the same program up to renaming is answered from the cache
+        assert_eq!(Node::number(6), run(&cache, &env, "(fun(y) { y + 1 })(n)"));
+        assert_eq!(CacheStats { hits: 1, misses: 1, uncacheable: 0, evictions: 0 }, cache.borrow().stats());
+        // a changed binding misses
+        env.add("n", Node::number(7));
+        assert_eq!(Node::number(8), run(&cache, &env, "(fun(x) { x + 1 })(n)"));
+        assert_eq!(2, cache.borrow().stats().misses);
+        // effects and closures are never kept
+        run(&cache, &env, "print(n)");
+        run(&cache, &env, "fun(x) { x }");
+        assert_eq!(2, cache.borrow().stats().uncacheable);
+        // the oldest result goes past the capacity
+        run(&cache, &env, "n * 2");
+        assert_eq!((2, 1), (cache.borrow().len(), cache.borrow().stats().evictions));
+        cache.borrow_mut().clear();
+        assert!(cache.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_cache_bindings() {
+        let cache = ResultCache::shared(4);
+        let env = Environment::new();
+        let source = "x = 2; y = x * 21;";
+        let mut machine = Machine::new(parser::parse(source).unwrap(), env.clone()).with_result_cache(cache.clone());
+        machine.run().unwrap();
+        let mut machine = Machine::new(parser::parse(source).unwrap(), env.clone()).with_result_cache(cache.clone());
+        machine.run().unwrap();
+        assert_eq!(1, cache.borrow().stats().hits);
+        assert_eq!(0, machine.steps_taken());
+        assert_eq!(Node::number(42), machine.environment.get("y"));
+    }
+}
@@ -0,0 +1,441 @@
+//! A compact binary encoding of syntax trees, for storing many programs.
+//! A tree is written in prefix order, a tag byte per node followed by its
+//! fields; integers are varints, zigzagged when signed, and every name or
+//! string is an index into a table written once in front of the tree.
+//!
+//! Only syntax is encoded, without spans. Closures, thunks and native
+//! functions hold environments or host code and are refused, so nothing
+//! else is stored this way: a `MachineState`, full of closures, goes
+//! through serde, and a `Trace` keeps the nodes it steps through as text.
+
+use super::syntax::{Node, Pattern};
+
+use std::collections::HashMap;
+
+const VERSION: u8 = 1;
+
+// node tags
+const NUMBER: u8 = 0;
+const FLOAT: u8 = 1;
+const STR: u8 = 2;
+const BOOLEAN: u8 = 3;
+const VARIABLE: u8 = 4;
+const DONOTHING: u8 = 5;
+const BREAK: u8 = 6;
+const CONTINUE: u8 = 7;
+const BUILTIN: u8 = 8;
+const NEGATE: u8 = 9;
+const NOT: u8 = 10;
+const ISDONOTHING: u8 = 11;
+const RETURN: u8 = 12;
+const FST: u8 = 13;
+const SND: u8 = 14;
+const LEN: u8 = 15;
+const DELAY: u8 = 16;
+const ADD: u8 = 17;
+const SUBTRACT: u8 = 18;
+const MULTIPLY: u8 = 19;
+const LT: u8 = 20;
+const EQ: u8 = 21;
+const GT: u8 = 22;
+const LE: u8 = 23;
+const GE: u8 = 24;
+const NE: u8 = 25;
+const AND: u8 = 26;
+const OR: u8 = 27;
+const SEQUENCE: u8 = 28;
+const WHILE: u8 = 29;
+const PAIR: u8 = 30;
+const INDEX: u8 = 31;
+const COMPOSE: u8 = 32;
+const ASSIGN: u8 = 33;
+const GLOBAL: u8 = 34;
+const IF: u8 = 35;
+const FOR: u8 = 36;
+const MATCH: u8 = 37;
+const LIST: u8 = 38;
+const MAP: u8 = 39;
+const FUN: u8 = 40;
+const CALL: u8 = 41;
+
+// pattern tags
+const PAT_WILDCARD: u8 = 0;
+const PAT_BIND: u8 = 1;
+const PAT_NUMBER: u8 = 2;
+const PAT_BOOLEAN: u8 = 3;
+const PAT_STR: u8 = 4;
+const PAT_PAIR: u8 = 5;
+const PAT_LIST: u8 = 6;
+
+struct Encoder {
+    strings: Vec<String>,
+    index: HashMap<String, usize>,
+    body: Vec<u8>,
+}
+
+/// Write `value` as an unsigned LEB128 varint.
+pub(crate) fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+impl Encoder {
+    fn varint(&mut self, value: usize) {
+        put_varint(&mut self.body, value as u64);
+    }
+
+    fn string(&mut self, value: &str) {
+        let next = self.strings.len();
+        let index = *self.index.entry(value.to_string()).or_insert(next);
+        if index == next {
+            self.strings.push(value.to_string());
+        }
+        self.varint(index);
+    }
+
+    fn unary(&mut self, tag: u8, node: &Node) -> Result<(), String> {
+        self.body.push(tag);
+        self.node(node)
+    }
+
+    fn binary(&mut self, tag: u8, l: &Node, r: &Node) -> Result<(), String> {
+        self.body.push(tag);
+        self.node(l)?;
+        self.node(r)
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match *pattern {
+            Pattern::Wildcard => self.body.push(PAT_WILDCARD),
+            Pattern::Bind(ref name) => { self.body.push(PAT_BIND); self.string(name); }
+            Pattern::Number(n) => { self.body.push(PAT_NUMBER); put_varint(&mut self.body, zigzag(n)); }
+            Pattern::Boolean(b) => { self.body.push(PAT_BOOLEAN); self.body.push(b as u8); }
+            Pattern::Str(ref s) => { self.body.push(PAT_STR); self.string(s); }
+            Pattern::Pair(ref l, ref r) => {
+                self.body.push(PAT_PAIR);
+                self.pattern(l);
+                self.pattern(r);
+            }
+            Pattern::List(ref items) => {
+                self.body.push(PAT_LIST);
+                self.varint(items.len());
+                for item in items {
+                    self.pattern(item);
+                }
+            }
+        }
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), String> {
+        match *node {
+            Node::Number(n) => { self.body.push(NUMBER); put_varint(&mut self.body, zigzag(n)); }
+            Node::Float(f) => { self.body.push(FLOAT); self.body.extend_from_slice(&f.to_le_bytes()); }
+            Node::Str(ref s) => { self.body.push(STR); self.string(s); }
+            Node::Boolean(b) => { self.body.push(BOOLEAN); self.body.push(b as u8); }
+            Node::Variable(ref name) => { self.body.push(VARIABLE); self.string(name); }
+            Node::DoNothing => self.body.push(DONOTHING),
+            Node::Break => self.body.push(BREAK),
+            Node::Continue => self.body.push(CONTINUE),
+            Node::Builtin(ref name) => { self.body.push(BUILTIN); self.string(name); }
+            Node::Negate(ref n) => self.unary(NEGATE, n)?,
+            Node::Not(ref n) => self.unary(NOT, n)?,
+            Node::IsDoNothing(ref n) => self.unary(ISDONOTHING, n)?,
+            Node::Return(ref n) => self.unary(RETURN, n)?,
+            Node::Fst(ref n) => self.unary(FST, n)?,
+            Node::Snd(ref n) => self.unary(SND, n)?,
+            Node::Len(ref n) => self.unary(LEN, n)?,
+            Node::Delay(ref n) => self.unary(DELAY, n)?,
+            Node::Add(ref l, ref r) => self.binary(ADD, l, r)?,
+            Node::Subtract(ref l, ref r) => self.binary(SUBTRACT, l, r)?,
+            Node::Multiply(ref l, ref r) => self.binary(MULTIPLY, l, r)?,
+            Node::LT(ref l, ref r) => self.binary(LT, l, r)?,
+            Node::EQ(ref l, ref r) => self.binary(EQ, l, r)?,
+            Node::GT(ref l, ref r) => self.binary(GT, l, r)?,
+            Node::LE(ref l, ref r) => self.binary(LE, l, r)?,
+            Node::GE(ref l, ref r) => self.binary(GE, l, r)?,
+            Node::NE(ref l, ref r) => self.binary(NE, l, r)?,
+            Node::And(ref l, ref r) => self.binary(AND, l, r)?,
+            Node::Or(ref l, ref r) => self.binary(OR, l, r)?,
+            Node::Sequence(ref l, ref r) => self.binary(SEQUENCE, l, r)?,
+            Node::While(ref l, ref r) => self.binary(WHILE, l, r)?,
+            Node::Pair(ref l, ref r) => self.binary(PAIR, l, r)?,
+            Node::Index(ref l, ref r) => self.binary(INDEX, l, r)?,
+            Node::Compose(ref l, ref r) => self.binary(COMPOSE, l, r)?,
+            Node::Assign(ref name, ref value) | Node::Global(ref name, ref value) => {
+                self.body.push(if let Node::Assign(..) = *node { ASSIGN } else { GLOBAL });
+                self.string(name);
+                self.node(value)?;
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                self.body.push(IF);
+                self.node(cond)?;
+                self.node(consequence)?;
+                self.node(alternative)?;
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.body.push(FOR);
+                for part in [init, cond, step, body] {
+                    self.node(part)?;
+                }
+            }
+            Node::Match(ref value, ref arms) => {
+                self.body.push(MATCH);
+                self.node(value)?;
+                self.varint(arms.len());
+                for (pattern, body) in arms {
+                    self.pattern(pattern);
+                    self.node(body)?;
+                }
+            }
+            Node::List(ref items) => {
+                self.body.push(LIST);
+                self.varint(items.len());
+                for item in items {
+                    self.node(item)?;
+                }
+            }
+            Node::Map(ref entries) => {
+                self.body.push(MAP);
+                self.varint(entries.len());
+                // sorted, so that equal maps encode the same
+                let mut keys: Vec<&String> = entries.keys().collect();
+                keys.sort();
+                for key in keys {
+                    self.string(key);
+                    self.node(&entries[key])?;
+                }
+            }
+            Node::Fun(ref name, ref params, ref body) => {
+                self.body.push(FUN);
+                self.string(name);
+                self.varint(params.len());
+                for param in params {
+                    self.string(param);
+                }
+                self.node(body)?;
+            }
+            Node::Call(ref closure, ref args) => {
+                self.body.push(CALL);
+                self.node(closure)?;
+                self.varint(args.len());
+                for arg in args {
+                    self.node(arg)?;
+                }
+            }
+            // positions in the source are not kept
+            Node::Spanned(_, ref n) => self.node(n)?,
+            Node::Closure(..) | Node::Thunk(..) | Node::Native(_) =>
+                return Err(format!("cannot encode the runtime value {}", node)),
+        }
+        Ok(())
+    }
+}
+
+/// Encode the syntax tree `node`.
+pub fn encode(node: &Node) -> Result<Vec<u8>, String> {
+    let mut encoder = Encoder { strings: Vec::new(), index: HashMap::new(), body: Vec::new() };
+    encoder.node(node)?;
+    let mut out = vec![VERSION];
+    put_varint(&mut out, encoder.strings.len() as u64);
+    for string in &encoder.strings {
+        put_varint(&mut out, string.len() as u64);
+        out.extend_from_slice(string.as_bytes());
+    }
+    out.extend(encoder.body);
+    Ok(out)
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("unexpected end of input")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Err(format!("varint too long at byte {}", self.pos))
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let len = self.varint()? as usize;
+        // every item takes at least a byte, which bounds what a corrupt
+        // length can make us allocate
+        if len > self.bytes.len() - self.pos {
+            return Err(format!("length {} past the end of input at byte {}", len, self.pos));
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let index = self.varint()? as usize;
+        self.strings.get(index).cloned().ok_or_else(|| format!("no string {} in the table", index))
+    }
+
+    fn node(&mut self) -> Result<Box<Node>, String> {
+        let tag = self.byte()?;
+        let node = match tag {
+            NUMBER => Node::Number(unzigzag(self.varint()?)),
+            FLOAT => {
+                let end = self.pos + 8;
+                let bytes = self.bytes.get(self.pos..end).ok_or("unexpected end of input")?;
+                let mut float = [0; 8];
+                float.copy_from_slice(bytes);
+                self.pos = end;
+                Node::Float(f64::from_le_bytes(float))
+            }
+            STR => Node::Str(self.string()?),
+            BOOLEAN => Node::Boolean(self.byte()? != 0),
+            VARIABLE => Node::Variable(self.string()?),
+            DONOTHING => Node::DoNothing,
+            BREAK => Node::Break,
+            CONTINUE => Node::Continue,
+            BUILTIN => Node::Builtin(self.string()?),
+            NEGATE => Node::Negate(self.node()?),
+            NOT => Node::Not(self.node()?),
+            ISDONOTHING => Node::IsDoNothing(self.node()?),
+            RETURN => Node::Return(self.node()?),
+            FST => Node::Fst(self.node()?),
+            SND => Node::Snd(self.node()?),
+            LEN => Node::Len(self.node()?),
+            DELAY => Node::Delay(self.node()?),
+            ADD => Node::Add(self.node()?, self.node()?),
+            SUBTRACT => Node::Subtract(self.node()?, self.node()?),
+            MULTIPLY => Node::Multiply(self.node()?, self.node()?),
+            LT => Node::LT(self.node()?, self.node()?),
+            EQ => Node::EQ(self.node()?, self.node()?),
+            GT => Node::GT(self.node()?, self.node()?),
+            LE => Node::LE(self.node()?, self.node()?),
+            GE => Node::GE(self.node()?, self.node()?),
+            NE => Node::NE(self.node()?, self.node()?),
+            AND => Node::And(self.node()?, self.node()?),
+            OR => Node::Or(self.node()?, self.node()?),
+            SEQUENCE => Node::Sequence(self.node()?, self.node()?),
+            WHILE => Node::While(self.node()?, self.node()?),
+            PAIR => Node::Pair(self.node()?, self.node()?),
+            INDEX => Node::Index(self.node()?, self.node()?),
+            COMPOSE => Node::Compose(self.node()?, self.node()?),
+            ASSIGN => Node::Assign(self.string()?, self.node()?),
+            GLOBAL => Node::Global(self.string()?, self.node()?),
+            IF => Node::If(self.node()?, self.node()?, self.node()?),
+            FOR => Node::For(self.node()?, self.node()?, self.node()?, self.node()?),
+            MATCH => {
+                let value = self.node()?;
+                let arms = (0..self.length()?).map(|_| Ok((self.pattern()?, self.node()?)))
+                    .collect::<Result<_, String>>()?;
+                Node::Match(value, arms)
+            }
+            LIST => Node::List((0..self.length()?).map(|_| self.node()).collect::<Result<_, _>>()?),
+            MAP => Node::Map((0..self.length()?).map(|_| Ok((self.string()?, self.node()?)))
+                                 .collect::<Result<_, String>>()?),
+            FUN => {
+                let name = self.string()?;
+                let params = (0..self.length()?).map(|_| self.string()).collect::<Result<_, _>>()?;
+                Node::Fun(name, params, self.node()?)
+            }
+            CALL => {
+                let closure = self.node()?;
+                Node::Call(closure, (0..self.length()?).map(|_| self.node()).collect::<Result<_, _>>()?)
+            }
+            _ => return Err(format!("unknown node tag {} at byte {}", tag, self.pos - 1)),
+        };
+        Ok(Box::new(node))
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, String> {
+        let tag = self.byte()?;
+        Ok(match tag {
+            PAT_WILDCARD => Pattern::Wildcard,
+            PAT_BIND => Pattern::Bind(self.string()?),
+            PAT_NUMBER => Pattern::Number(unzigzag(self.varint()?)),
+            PAT_BOOLEAN => Pattern::Boolean(self.byte()? != 0),
+            PAT_STR => Pattern::Str(self.string()?),
+            PAT_PAIR => Pattern::Pair(Box::new(self.pattern()?), Box::new(self.pattern()?)),
+            PAT_LIST => Pattern::List((0..self.length()?).map(|_| self.pattern()).collect::<Result<_, _>>()?),
+            _ => return Err(format!("unknown pattern tag {} at byte {}", tag, self.pos - 1)),
+        })
+    }
+}
+
+/// Decode a syntax tree written by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Box<Node>, String> {
+    let mut decoder = Decoder { bytes, pos: 0, strings: Vec::new() };
+    let version = decoder.byte()?;
+    if version != VERSION {
+        return Err(format!("unknown encoding version {}", version));
+    }
+    for _ in 0..decoder.length()? {
+        let len = decoder.length()?;
+        let text = decoder.pos.checked_add(len).and_then(|end| bytes.get(decoder.pos..end))
+            .ok_or_else(|| format!("string of {} bytes past the end of input at byte {}", len, decoder.pos))?;
+        decoder.pos += len;
+        decoder.strings.push(String::from_utf8(text.to_vec()).map_err(|e| e.to_string())?);
+    }
+    let node = decoder.node()?;
+    if decoder.pos != bytes.len() {
+        return Err(format!("{} bytes left after the tree", bytes.len() - decoder.pos));
+    }
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let program = parser::parse("def f(x, y) { match ((x, [y])) { (0, [_]) => { return -1.5; } (n, [\"s\"]) => { n } _ => { false } } }
+            m = {\"a\": [1, 2], \"b\": nothing()};
+            for (i = 0; i < 3; i = i + 1) { if (i == 1) { continue; } global g = f(i, \"s\")[0] >> len; }
+            while (not true or 1 != 2) { break; }
+            s = delay fst((1, 2)) + snd((3, -4611686018427387904));").unwrap();
+        let bytes = encode(&program).unwrap();
+        assert_eq!(program, decode(&bytes).unwrap());
+        assert!(encode(&Node::native("f", |_| Node::donothing())).is_err());
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[VERSION, 0, 200]).is_err());
+        assert!(decode(&[VERSION, 100]).is_err());
+        assert!(decode(&[VERSION, 1, 5]).is_err());
+        assert!(decode(&[VERSION, 2, 1, b'a', 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_compact_size() {
+        use serde_json;
+        use std::fs;
+        use std::path::Path;
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/conformance/functions.simple");
+        let program = parser::parse(&fs::read_to_string(path).unwrap()).unwrap();
+        let bytes = encode(&program).unwrap();
+        let json = serde_json::to_string(&program).unwrap().len();
+        assert!(bytes.len() * 4 < json, "{} bytes encoded, {} as JSON", bytes.len(), json);
+        assert_eq!(program, decode(&bytes).unwrap());
+    }
+}
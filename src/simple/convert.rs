@@ -0,0 +1,318 @@
+//! Converting between values of simple code and Rust values, for
+//! builtins written in Rust and for programs embedding the machine:
+//! integers and floats, booleans, strings, `Option` (do-nothing is
+//! `None`), `Vec` (lists) and 2-tuples (pairs), nested as deep as wanted.
+
+use super::syntax::Node;
+use super::builtin;
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+
+/// A value of simple code, as host functions take and return it.
+pub type Value = Box<Node>;
+
+/// A value that is not of the Rust type asked for.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ConversionError {
+    /// What was expected, say "an integer".
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl ConversionError {
+    pub fn new(expected: &'static str, found: &Node) -> ConversionError {
+        ConversionError { expected, found: found.to_string() }
+    }
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl error::Error for ConversionError {}
+
+/// Why a variable could not be read as a Rust value.
+#[derive(Debug,Clone,PartialEq)]
+pub enum GetError {
+    Unbound(String),
+    Conversion(ConversionError),
+}
+
+impl Display for GetError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            GetError::Unbound(ref name) => write!(f, "Variable {} not found", name),
+            GetError::Conversion(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for GetError {}
+
+impl From<ConversionError> for GetError {
+    fn from(e: ConversionError) -> GetError {
+        GetError::Conversion(e)
+    }
+}
+
+/// A Rust value a value of simple code converts to.
+pub trait FromValue: Sized {
+    fn from_value(value: &Node) -> Result<Self, ConversionError>;
+}
+
+/// A Rust value that converts to a value of simple code.
+pub trait IntoValue {
+    fn into_value(self) -> Box<Node>;
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Node) -> Result<i64, ConversionError> {
+        match *value {
+            Node::Number(value) => Ok(value),
+            _ => Err(ConversionError::new("an integer", value)),
+        }
+    }
+}
+
+/// Integers of other widths, converted when they fit.
+macro_rules! integer {
+    ($($int:ty),*) => {$(
+        impl FromValue for $int {
+            fn from_value(value: &Node) -> Result<$int, ConversionError> {
+                i64::from_value(value).ok().and_then(|int| <$int>::try_from(int).ok())
+                    .ok_or_else(|| ConversionError::new(concat!("an integer that fits in ", stringify!($int)), value))
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, isize, u8, u16, u32, u64, usize);
+
+/// Integers are promoted, as arithmetic mixing them with floats does.
+impl FromValue for f64 {
+    fn from_value(value: &Node) -> Result<f64, ConversionError> {
+        match *value {
+            Node::Number(value) => Ok(value as f64),
+            Node::Float(value) => Ok(value),
+            _ => Err(ConversionError::new("a number", value)),
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Node) -> Result<f32, ConversionError> {
+        f64::from_value(value).map(|value| value as f32)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Node) -> Result<bool, ConversionError> {
+        match *value {
+            Node::Boolean(value) => Ok(value),
+            _ => Err(ConversionError::new("a boolean", value)),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Node) -> Result<String, ConversionError> {
+        match *value {
+            Node::Str(ref value) => Ok(value.clone()),
+            _ => Err(ConversionError::new("a string", value)),
+        }
+    }
+}
+
+/// Any value, as it is.
+impl FromValue for Box<Node> {
+    fn from_value(value: &Node) -> Result<Box<Node>, ConversionError> {
+        Ok(Box::new(value.clone()))
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Box<Node> {
+        Node::number(self)
+    }
+}
+
+macro_rules! lossless {
+    ($($int:ty),*) => {$(
+        impl IntoValue for $int {
+            fn into_value(self) -> Box<Node> {
+                Node::number(i64::from(self))
+            }
+        }
+    )*};
+}
+
+lossless!(i8, i16, i32, u8, u16, u32);
+
+/// Lengths and indices; those past the largest integer panic.
+impl IntoValue for usize {
+    fn into_value(self) -> Box<Node> {
+        Node::number(i64::try_from(self).unwrap_or_else(|_| panic!("{} does not fit in an integer", self)))
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Box<Node> {
+        Node::float(self)
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Box<Node> {
+        Node::float(f64::from(self))
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Box<Node> {
+        Node::boolean(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Box<Node> {
+        Node::string(&self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Box<Node> {
+        Node::string(self)
+    }
+}
+
+/// Nothing to return is do-nothing.
+impl IntoValue for () {
+    fn into_value(self) -> Box<Node> {
+        Node::donothing()
+    }
+}
+
+impl IntoValue for Box<Node> {
+    fn into_value(self) -> Box<Node> {
+        self
+    }
+}
+
+/// Do-nothing is `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Node) -> Result<Option<T>, ConversionError> {
+        match *value {
+            Node::DoNothing => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Box<Node> {
+        self.map_or_else(Node::donothing, T::into_value)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Node) -> Result<Vec<T>, ConversionError> {
+        match *value {
+            Node::List(ref items) => items.iter().map(|item| T::from_value(item)).collect(),
+            _ => Err(ConversionError::new("a list", value)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Box<Node> {
+        Node::list(self.into_iter().map(T::into_value).collect())
+    }
+}
+
+impl<A: FromValue, B: FromValue> FromValue for (A, B) {
+    fn from_value(value: &Node) -> Result<(A, B), ConversionError> {
+        match *value {
+            Node::Pair(ref fst, ref snd) => Ok((A::from_value(fst)?, B::from_value(snd)?)),
+            _ => Err(ConversionError::new("a pair", value)),
+        }
+    }
+}
+
+impl<A: IntoValue, B: IntoValue> IntoValue for (A, B) {
+    fn into_value(self) -> Box<Node> {
+        Node::pair(self.0.into_value(), self.1.into_value())
+    }
+}
+
+/// A Rust closure callable from simple code, its arguments converted with
+/// `FromValue` and its result with `IntoValue`; `Args` is the tuple of its
+/// argument types. Closures of up to four arguments are host functions.
+pub trait HostFn<Args>: 'static {
+    fn arity(&self) -> usize;
+
+    /// Call the closure with the arguments of a call to `name`, panicking
+    /// as builtins do when they do not convert.
+    fn call(&self, name: &str, args: &[Value]) -> Value;
+}
+
+macro_rules! host_fn {
+    ($($ty:ident $arg:ident),*) => {
+        impl<F, R, $($ty),*> HostFn<($($ty,)*)> for F
+            where F: Fn($($ty),*) -> R + 'static, R: IntoValue, $($ty: FromValue),* {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+
+            // a closure of no arguments has none to name in errors
+            #[allow(unused_variables)]
+            fn call(&self, name: &str, args: &[Value]) -> Value {
+                let _args = &mut args.iter().enumerate();
+                $(let $arg = {
+                    let (index, value) = _args.next().unwrap();
+                    builtin::argument::<$ty>(name, index, value)
+                };)*
+                self($($arg),*).into_value()
+            }
+        }
+    };
+}
+
+host_fn!();
+host_fn!(A a);
+host_fn!(A a, B b);
+host_fn!(A a, B b, C c);
+host_fn!(A a, B b, C c, D d);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: IntoValue + FromValue + Clone + PartialEq + fmt::Debug>(value: T) {
+        assert_eq!(Ok(value.clone()), T::from_value(&value.into_value()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(-3i64);
+        round_trip(7u8);
+        round_trip(2.5f64);
+        round_trip(true);
+        round_trip("text".to_string());
+        round_trip(vec![Some((1i32, "a".to_string())), None]);
+        assert_eq!("[(1, 2.5), (2, 0.5)]", vec![(1, 2.5), (2, 0.5)].into_value().to_string());
+        // integers are promoted to floats, not the other way
+        assert_eq!(Ok(3.0), f64::from_value(&Node::number(3)));
+    }
+
+    #[test]
+    fn test_conversion_errors() {
+        assert_eq!("expected an integer that fits in u8, found 256", u8::from_value(&Node::number(256)).unwrap_err().to_string());
+        assert_eq!("expected a list, found 1", Vec::<i64>::from_value(&Node::number(1)).unwrap_err().to_string());
+        let list = vec![1.into_value(), Node::boolean(true)].into_value();
+        assert_eq!("expected an integer, found true", Vec::<i64>::from_value(&list).unwrap_err().to_string());
+    }
+}
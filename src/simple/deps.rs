@@ -0,0 +1,223 @@
+//! Which assignments of a program read which variables: a graph with an
+//! edge from every assignment to the assignments whose values it reads,
+//! for tools to draw and for hosts that want to know what to evaluate
+//! again when a variable changes.
+//!
+//! A read depends on the last assignment of the variable before it in the
+//! program, so edges only point back and the graph has no cycles, even
+//! where a loop feeds a variable to its next iteration. An assignment in
+//! the branch of an `if`, the body of a loop or the arm of a `match` also
+//! reads the variables deciding whether it runs. Calls are followed to the
+//! definition of the function, whose own reads are those of its body;
+//! what a function assigns with `global` is not followed.
+
+use super::syntax::{Node, Span};
+use super::evaluate;
+use super::builtin;
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// One assignment of the program.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Definition {
+    pub name: String,
+    /// Where the assignment was written, if the program has spans.
+    pub span: Option<Span>,
+    /// The variables it reads, sorted.
+    pub reads: Vec<String>,
+    /// The indices of the assignments whose values it reads.
+    pub depends_on: Vec<usize>,
+}
+
+/// The dependency graph of a program.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Graph {
+    /// The assignments in the order they are written.
+    pub definitions: Vec<Definition>,
+    /// Variables read before any assignment to them, which the host has
+    /// to provide, sorted. Builtins are not among them.
+    pub inputs: Vec<String>,
+}
+
+/// The dependency graph of `program`.
+pub fn graph(program: &Node) -> Graph {
+    let mut builder = Builder { graph: Graph::default(), inputs: HashSet::new() };
+    builder.collect(program, &[], &[], None);
+    let mut graph = builder.graph;
+    graph.inputs = builder.inputs.into_iter().collect();
+    graph.inputs.sort();
+    graph
+}
+
+struct Builder {
+    graph: Graph,
+    inputs: HashSet<String>,
+}
+
+impl Builder {
+    /// Add the assignments of `node`, each also reading `control`. The
+    /// names in `bound` are bound by the patterns of enclosing arms, and
+    /// are the matched value rather than variables.
+    fn collect(&mut self, node: &Node, control: &[String], bound: &[String], span: Option<Span>) {
+        let within = |expr: &Node| -> Vec<String> {
+            let mut reads: Vec<String> = control.to_vec();
+            reads.extend(evaluate::get_free_vars(expr).into_iter().filter(|name| !bound.contains(name)));
+            reads
+        };
+        match *node {
+            Node::Spanned(span, ref inner) => self.collect(inner, control, bound, Some(span)),
+            Node::Sequence(ref head, ref more) => {
+                self.collect(head, control, bound, span);
+                self.collect(more, control, bound, span);
+            }
+            Node::Assign(ref name, ref expr) | Node::Global(ref name, ref expr) => self.define(name, within(expr), span),
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let control = within(cond);
+                self.collect(consequence, &control, bound, span);
+                self.collect(alternative, &control, bound, span);
+            }
+            Node::While(ref cond, ref body) => {
+                let control = within(cond);
+                self.collect(body, &control, bound, span);
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.collect(init, control, bound, span);
+                let control = within(cond);
+                self.collect(body, &control, bound, span);
+                self.collect(step, &control, bound, span);
+            }
+            Node::Match(ref matched, ref arms) => {
+                let control = within(matched);
+                for (pattern, body) in arms {
+                    let mut bound = bound.to_vec();
+                    bound.extend(pattern.bindings().into_iter().map(|name| name.to_string()));
+                    self.collect(body, &control, &bound, span);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Add an assignment of `name` reading `reads`, builtins left out
+    /// unless the program assigned them.
+    fn define(&mut self, name: &str, reads: Vec<String>, span: Option<Span>) {
+        let mut kept = Vec::new();
+        let mut depends_on = Vec::new();
+        for read in reads {
+            match self.graph.definitions.iter().rposition(|definition| definition.name == read) {
+                Some(index) => depends_on.push(index),
+                None if builtin::lookup(&read).is_some() => continue,
+                None => {
+                    self.inputs.insert(read.clone());
+                }
+            }
+            kept.push(read);
+        }
+        kept.sort();
+        kept.dedup();
+        depends_on.sort();
+        depends_on.dedup();
+        self.graph.definitions.push(Definition { name: name.to_string(), span, reads: kept, depends_on });
+    }
+}
+
+/// `text` as a quoted string of JSON or DOT, which escape alike.
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Graph {
+    /// The graph in the DOT language of Graphviz, values flowing along
+    /// the edges: from an assignment, or an input, to those reading it.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph deps {\n");
+        for input in &self.inputs {
+            writeln!(dot, "    {} [label={}, shape=box];", quote(&format!("input {}", input)), quote(input)).unwrap();
+        }
+        for (index, definition) in self.definitions.iter().enumerate() {
+            let label = match definition.span {
+                Some(span) => format!("{}\nline {}", definition.name, span.line),
+                None => definition.name.clone(),
+            };
+            writeln!(dot, "    d{} [label={}];", index, quote(&label)).unwrap();
+        }
+        for (index, definition) in self.definitions.iter().enumerate() {
+            for &from in &definition.depends_on {
+                writeln!(dot, "    d{} -> d{};", from, index).unwrap();
+            }
+            for read in definition.reads.iter().filter(|read| self.inputs.contains(read)) {
+                if !definition.depends_on.iter().any(|&from| self.definitions[from].name == *read) {
+                    writeln!(dot, "    {} -> d{};", quote(&format!("input {}", read)), index).unwrap();
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as JSON: `{"definitions": [...], "inputs": [...]}`, each
+    /// definition with its `name`, `line` (or null), `reads` and the
+    /// indices it `depends_on`.
+    pub fn to_json(&self) -> String {
+        let strings = |names: &[String]| names.iter().map(|name| quote(name)).collect::<Vec<_>>().join(", ");
+        let definitions: Vec<String> = self.definitions.iter().map(|definition| {
+            let line = definition.span.map_or("null".to_string(), |span| span.line.to_string());
+            let depends_on: Vec<String> = definition.depends_on.iter().map(|index| index.to_string()).collect();
+            format!("{{\"name\": {}, \"line\": {}, \"reads\": [{}], \"depends_on\": [{}]}}",
+                    quote(&definition.name), line, strings(&definition.reads), depends_on.join(", "))
+        }).collect();
+        format!("{{\"definitions\": [{}], \"inputs\": [{}]}}\n", definitions.join(", "), strings(&self.inputs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn edges(graph: &Graph) -> Vec<String> {
+        graph.definitions.iter().map(|definition| {
+            let from: Vec<&str> = definition.depends_on.iter().map(|&index| graph.definitions[index].name.as_str()).collect();
+            format!("{} <- {}", definition.name, from.join(" "))
+        }).collect()
+    }
+
+    #[test]
+    fn test_graph() {
+        let program = parser::parse("a = rate * 2; b = a + 1; a = b; def f(x) { return x + b; }
+            c = f(a); if (c > 10) { d = 1; } else { d = len([]); }").unwrap();
+        let deps = graph(&program);
+        assert_eq!(vec!["a <- ", "b <- a", "a <- b", "f <- b", "c <- a f", "d <- c", "d <- c"], edges(&deps));
+        assert_eq!(vec!["rate".to_string()], deps.inputs);
+        // a loop reads what was assigned before it, and its condition
+        let deps = graph(&parser::parse("i = 0; s = 0; while (i < n) { s = s + i; i = i + 1; }").unwrap());
+        assert_eq!(vec!["i <- ", "s <- ", "s <- i s", "i <- i"], edges(&deps));
+        assert_eq!(vec!["i", "n", "s"], deps.definitions[2].reads);
+        // names bound by a pattern are the matched value
+        let deps = graph(&parser::parse("x = 0; p = (1, 2); match (p) { (x, y) => { q = x + y; } }").unwrap());
+        assert_eq!(vec!["x <- ", "p <- ", "q <- p"], edges(&deps));
+        assert!(deps.inputs.is_empty());
+    }
+
+    #[test]
+    fn test_export() {
+        let deps = graph(&parser::parse_spanned("x = 1;\ny = x + z;").unwrap());
+        assert_eq!("{\"definitions\": [{\"name\": \"x\", \"line\": 1, \"reads\": [], \"depends_on\": []}, \
+                    {\"name\": \"y\", \"line\": 2, \"reads\": [\"x\", \"z\"], \"depends_on\": [0]}], \"inputs\": [\"z\"]}\n",
+                   deps.to_json());
+        assert_eq!("digraph deps {\n    \"input z\" [label=\"z\", shape=box];\n    d0 [label=\"x\\nline 1\"];\n    \
+                    d1 [label=\"y\\nline 2\"];\n    d0 -> d1;\n    \"input z\" -> d1;\n}\n", deps.to_dot());
+    }
+}
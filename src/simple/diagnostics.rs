@@ -0,0 +1,155 @@
+//! Warnings and errors about a program, from parsing, analysis and
+//! running it, kept apart from what the program prints. A `Diagnostics`
+//! sink collects them to be emitted together once the program is done,
+//! or hands each on as it is reported.
+//!
+//! A sink is a handle: clones report to and read from the same
+//! diagnostics, so a host keeps one while a machine reports to another.
+
+use super::syntax::Span;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where in the source it is about, when that is known.
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at {}: {}", self.severity, span, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// Called with each diagnostic as it is reported.
+pub type Stream = Rc<dyn Fn(&Diagnostic)>;
+
+#[derive(Default)]
+struct Sink {
+    collected: Vec<Diagnostic>,
+    stream: Option<Stream>,
+}
+
+#[derive(Clone,Default)]
+pub struct Diagnostics {
+    sink: Rc<RefCell<Sink>>,
+}
+
+impl Diagnostics {
+    /// A sink collecting diagnostics until they are taken.
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    /// A sink handing each diagnostic to `stream` as it is reported, say
+    /// to print it at once, as well as collecting it.
+    pub fn streaming<F>(stream: F) -> Diagnostics
+        where F: Fn(&Diagnostic) + 'static {
+        let sink = Sink { collected: Vec::new(), stream: Some(Rc::new(stream)) };
+        Diagnostics { sink: Rc::new(RefCell::new(sink)) }
+    }
+
+    pub fn report(&self, severity: Severity, message: &str, span: Option<Span>) {
+        let diagnostic = Diagnostic { severity, message: message.to_string(), span };
+        // cloned out so the stream may itself report
+        let stream = self.sink.borrow().stream.clone();
+        if let Some(stream) = stream {
+            stream(&diagnostic);
+        }
+        self.sink.borrow_mut().collected.push(diagnostic);
+    }
+
+    pub fn warning(&self, message: &str, span: Option<Span>) {
+        self.report(Severity::Warning, message, span);
+    }
+
+    pub fn error(&self, message: &str, span: Option<Span>) {
+        self.report(Severity::Error, message, span);
+    }
+
+    /// The diagnostics reported so far, in the order they were.
+    pub fn all(&self) -> Vec<Diagnostic> {
+        self.sink.borrow().collected.clone()
+    }
+
+    /// Take the diagnostics reported so far, leaving none.
+    pub fn take(&self) -> Vec<Diagnostic> {
+        let mut sink = self.sink.borrow_mut();
+        sink.collected.drain(..).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sink.borrow().collected.is_empty()
+    }
+
+    /// Whether an error was reported.
+    pub fn has_errors(&self) -> bool {
+        self.sink.borrow().collected.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    /// Take the diagnostics reported so far and write them to `out`, a
+    /// line each.
+    pub fn emit<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for diagnostic in self.take() {
+            writeln!(out, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let diagnostics = Diagnostics::new();
+        let handle = diagnostics.clone();
+        handle.warning("unused `x`", Some(Span { line: 2, col: 1, end_line: 2, end_col: 6 }));
+        assert!(!diagnostics.has_errors());
+        handle.error("no value", None);
+        assert!(diagnostics.has_errors());
+        let mut out = Vec::new();
+        diagnostics.emit(&mut out).unwrap();
+        assert_eq!("warning at line 2, column 1: unused `x`\nerror: no value\n", String::from_utf8(out).unwrap());
+        assert!(diagnostics.is_empty() && handle.is_empty());
+    }
+
+    #[test]
+    fn test_streaming() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let diagnostics = Diagnostics::streaming(move |diagnostic| log.borrow_mut().push(diagnostic.to_string()));
+        diagnostics.report(Severity::Note, "first", None);
+        assert_eq!(vec!["note: first".to_string()], *seen.borrow());
+        diagnostics.warning("second", None);
+        assert_eq!(2, seen.borrow().len());
+        assert_eq!(2, diagnostics.all().len());
+    }
+}
@@ -1,36 +1,221 @@
+use std::fmt::Debug;
 use std::fmt::Display;
 use std::fmt::Result;
 use std::fmt::Formatter;
 
 use super::syntax::Node;
+use super::builtin;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-#[derive(Debug,PartialEq,Clone)]
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The variables bound directly in one scope.
+pub type Vars = Rc<RefCell<HashMap<String, Box<Node>>>>;
+
+/// How a function value captures the environment it is defined in.
+#[derive(Debug,PartialEq,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Capture {
+    /// Copy the whole defining environment into the closure.
+    Value,
+    /// Capture nothing; free variables are read from, and assignments to
+    /// them written back to, the live environment at call time. Bindings
+    /// a native closure was built with still take precedence.
+    Reference,
+    /// Copy the whole defining environment, but only bind the free
+    /// variables of the function when it is called.
+    Minimal,
+    /// Share the defining scopes themselves: later assignments to them are
+    /// seen by the closure, and assigning to a variable of an enclosing
+    /// scope updates it there instead of shadowing it.
+    Shared,
+}
+
+/// When the arguments of a call to a user function are evaluated.
+#[derive(Debug,PartialEq,Clone,Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Strategy {
+    /// Evaluate every argument before the call.
+    Value,
+    /// Pass arguments as thunks, evaluated again on every use.
+    Name,
+    /// Pass arguments as thunks, evaluated on first use and remembered.
+    Need,
+}
+
+/// A scope of variables. Names not bound here are looked up in the
+/// parent scope, then its parent, and finally among the builtins.
+///
+/// Cloning copies the variables, except with `Capture::Shared`, where
+/// the clone is another handle on the same scope.
+///
+/// With the `serde` feature, scopes shared by several environments or
+/// closures are written out once for each, and come back apart.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Environment {
-    pub vars: HashMap<String, Box<Node>>
+    pub vars: Vars,
+    /// The scope this one is nested in: for a call frame, the environment
+    /// the function was defined in. Only written through with
+    /// `Capture::Shared`.
+    pub parent: Option<Rc<Environment>>,
+    pub capture: Capture,
+    pub strategy: Strategy,
+    /// Whether this is the top-level environment rather than a call frame.
+    pub toplevel: bool,
+    /// Writes made with `global` inside a call frame, waiting to reach the
+    /// top-level environment when the call returns.
+    pub globals: HashMap<String, Box<Node>>,
 }
 
 impl Environment {
     pub fn new() -> Environment {
-        Environment{ vars: HashMap::new() }
+        Environment::with_capture(Capture::Minimal)
+    }
+
+    pub fn with_capture(capture: Capture) -> Environment {
+        Environment{ vars: Vars::default(), parent: None, capture, strategy: Strategy::Value,
+                     toplevel: true, globals: HashMap::new() }
+    }
+
+    /// An empty call frame nested in `parent`, with its capture policy
+    /// and evaluation strategy. Assignments bind in the frame and shadow
+    /// the parent's bindings rather than changing them.
+    pub fn frame(parent: Rc<Environment>) -> Environment {
+        Environment{ vars: Vars::default(), capture: parent.capture, strategy: parent.strategy,
+                     toplevel: false, globals: HashMap::new(), parent: Some(parent) }
+    }
+
+    /// An empty top-level environment with the same capture policy and
+    /// evaluation strategy as this one.
+    pub fn fresh(&self) -> Environment {
+        Environment{ strategy: self.strategy, ..Environment::with_capture(self.capture) }
+    }
+
+    /// Turn a copy of an environment into a fresh call frame.
+    pub fn into_frame(mut self) -> Environment {
+        self.toplevel = false;
+        self.globals.clear();
+        self
+    }
+
+    /// Write a variable of the top-level environment. Inside a call frame
+    /// the binding is also visible locally until the call returns.
+    pub fn add_global(&mut self, name: &str, node: Box<Node>) {
+        if !self.toplevel {
+            self.globals.insert(name.to_string(), node.clone());
+        }
+        self.add(name, node);
+    }
+
+    /// Pass the global writes of a finished call frame on to its caller.
+    pub fn merge_globals(&mut self, frame: Environment) {
+        for (name, node) in frame.globals {
+            if self.toplevel {
+                self.add(&name, node);
+            } else {
+                self.globals.insert(name, node);
+            }
+        }
     }
 
     pub fn add(&mut self, name: &str, node: Box<Node>) {
-        self.vars.insert(name.to_string(), node);
+        self.insert(name, node);
+    }
+
+    /// Bind `name` in this scope, returning what it was bound to here.
+    pub fn insert(&mut self, name: &str, node: Box<Node>) -> Option<Box<Node>> {
+        self.vars.borrow_mut().insert(name.to_string(), node)
+    }
+
+    /// Unbind `name` from this scope, returning what it was bound to.
+    pub fn remove(&mut self, name: &str) -> Option<Box<Node>> {
+        self.vars.borrow_mut().remove(name)
+    }
+
+    /// Whether `name` is bound in this scope, ignoring enclosing ones.
+    pub fn contains(&self, name: &str) -> bool {
+        self.vars.borrow().contains_key(name)
+    }
+
+    /// A copy of the bindings of this scope, ignoring enclosing ones.
+    pub fn bindings(&self) -> Vec<(String, Box<Node>)> {
+        self.vars.borrow().iter().map(|(name, node)| (name.clone(), node.clone())).collect()
+    }
+
+    /// Assign a variable. With `Capture::Shared` this updates the nearest
+    /// scope that already binds `name`; otherwise, or if no scope does,
+    /// `name` is bound in this one.
+    pub fn assign(&mut self, name: &str, node: Box<Node>) {
+        if self.capture == Capture::Shared && !self.contains(name) {
+            let mut scope = self.parent.as_ref();
+            while let Some(env) = scope {
+                if let Some(slot) = env.vars.borrow_mut().get_mut(name) {
+                    *slot = node;
+                    return;
+                }
+                scope = env.parent.as_ref();
+            }
+        }
+        self.add(name, node);
+    }
+
+    /// The value bound to `name` in this scope or the nearest enclosing one.
+    pub fn lookup(&self, name: &str) -> Option<Box<Node>> {
+        let mut scope = self;
+        loop {
+            if let Some(node) = scope.vars.borrow().get(name) {
+                return Some(node.clone());
+            }
+            scope = scope.parent.as_ref()?;
+        }
+    }
+
+    /// What `name` refers to here, a variable or else a builtin, or `None`
+    /// if it is unbound.
+    pub fn try_get(&self, name: &str) -> Option<Box<Node>> {
+        self.lookup(name).or_else(|| builtin::lookup(name).map(|_| Node::builtin(name)))
     }
 
     pub fn get(&self, name: &str) -> Box<Node> {
-        match self.vars.get(name) {
-            Some(node) => node.clone(),
-            None => panic!("Variable {} not found", name),
+        self.try_get(name).unwrap_or_else(|| panic!("Variable {} not found", name))
+    }
+
+    /// Every name bound in this scope or an enclosing one.
+    pub fn names(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        let mut scope = Some(self);
+        while let Some(env) = scope {
+            names.extend(env.vars.borrow().keys().cloned());
+            scope = env.parent.as_deref();
         }
+        names
+    }
+
+    /// Number of bindings in this scope, ignoring enclosing ones.
+    pub fn len(&self) -> usize {
+        self.vars.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.borrow().is_empty()
+    }
+
+    /// The bindings of this scope sorted by name, copied so that the
+    /// environment may change while they are walked.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<Node>)> {
+        let mut bindings = self.bindings();
+        bindings.sort_by(|(l, _), (r, _)| l.cmp(r));
+        bindings.into_iter()
     }
 
     pub fn prettyprint(&self, indent: usize) -> String {
         let prefix = " ".repeat(indent);
         let mut parts = Vec::new();
-        for (key, val) in self.vars.iter() {
+        for (key, val) in self.vars.borrow().iter() {
             parts.push(format!("{0}{1} = {2}\n",
                                prefix, key, val))
         };
@@ -39,8 +224,127 @@ impl Environment {
     }
 }
 
+impl Clone for Environment {
+    fn clone(&self) -> Environment {
+        let vars = match self.capture {
+            Capture::Shared => self.vars.clone(),
+            _ => Rc::new(RefCell::new(self.vars.borrow().clone())),
+        };
+        Environment{ vars, parent: self.parent.clone(), capture: self.capture, strategy: self.strategy,
+                     toplevel: self.toplevel, globals: self.globals.clone() }
+    }
+}
+
+impl PartialEq for Environment {
+    fn eq(&self, other: &Environment) -> bool {
+        // shared scopes may hold closures over themselves, so compare
+        // them by identity rather than recurse into them
+        let same_vars = match (self.capture, other.capture) {
+            (Capture::Shared, Capture::Shared) => Rc::ptr_eq(&self.vars, &other.vars),
+            _ => self.vars == other.vars,
+        };
+        same_vars && self.parent == other.parent && self.capture == other.capture &&
+            self.strategy == other.strategy && self.toplevel == other.toplevel && self.globals == other.globals
+    }
+}
+
+impl Debug for Environment {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let mut debug = f.debug_struct("Environment");
+        if self.capture == Capture::Shared {
+            let mut names: Vec<String> = self.vars.borrow().keys().cloned().collect();
+            names.sort();
+            debug.field("vars", &names);
+        } else {
+            debug.field("vars", &self.vars);
+        }
+        debug.field("parent", &self.parent)
+            .field("capture", &self.capture)
+            .field("strategy", &self.strategy)
+            .field("toplevel", &self.toplevel)
+            .field("globals", &self.globals)
+            .finish()
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
+
 impl Display for Environment {
     fn fmt(&self, f: &mut Formatter) -> Result {
         write!(f, "{}", self.prettyprint(0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_chain() {
+        let mut toplevel = Environment::with_capture(Capture::Value);
+        toplevel.strategy = Strategy::Need;
+        toplevel.add("x", Node::number(1));
+        toplevel.add("y", Node::number(2));
+        let mut frame = Environment::frame(Rc::new(toplevel));
+        frame.add("x", Node::number(10));
+        assert_eq!(Node::number(10), frame.get("x"));
+        assert_eq!(Node::number(2), frame.get("y"));
+        assert_eq!(Node::builtin("len"), frame.get("len"));
+        assert_eq!(None, frame.lookup("z"));
+        // shadowing leaves the enclosing scope alone
+        assert_eq!(Some(Node::number(1)), frame.parent.as_ref().unwrap().lookup("x"));
+        assert_eq!((Capture::Value, Strategy::Need, false), (frame.capture, frame.strategy, frame.toplevel));
+        let inner = Environment::frame(Rc::new(frame));
+        assert_eq!(Node::number(10), inner.get("x"));
+        assert_eq!(Node::number(2), inner.get("y"));
+        assert_eq!(Some(Node::number(2)), inner.try_get("y"));
+        assert_eq!(Some(Node::builtin("len")), inner.try_get("len"));
+        assert_eq!(None, inner.try_get("z"));
+        assert!(inner.is_empty());
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut env = Environment::new();
+        env.add("b", Node::number(2));
+        env.add("a", Node::number(1));
+        assert_eq!(2, env.len());
+        let names: Vec<String> = env.iter().map(|(name, _)| name).collect();
+        assert_eq!(vec!["a", "b"], names);
+        // the bindings are copied, so the environment can change meanwhile
+        for (name, value) in env.iter() {
+            env.add(&name, Node::multiply(value, Node::number(10)));
+        }
+        assert_eq!(Some(Node::multiply(Node::number(1), Node::number(10))), env.remove("a"));
+        assert!(!env.contains("a"));
+        assert_eq!(1, env.len());
+    }
+
+    #[test]
+    fn test_shared_scope() {
+        let mut toplevel = Environment::with_capture(Capture::Shared);
+        toplevel.add("x", Node::number(1));
+        let alias = toplevel.clone();
+        let mut frame = Environment::frame(Rc::new(toplevel.clone()));
+        frame.assign("x", Node::number(2));
+        frame.assign("y", Node::number(3));
+        assert_eq!(Node::number(2), toplevel.get("x"));
+        assert_eq!(Node::number(2), alias.get("x"));
+        assert!(!frame.contains("x"));
+        assert_eq!(None, toplevel.lookup("y"));
+        // other capture policies copy on clone and shadow on assignment
+        let mut toplevel = Environment::with_capture(Capture::Value);
+        toplevel.add("x", Node::number(1));
+        let copy = toplevel.clone();
+        let mut frame = Environment::frame(Rc::new(toplevel.clone()));
+        frame.assign("x", Node::number(2));
+        toplevel.add("x", Node::number(4));
+        assert_eq!(Node::number(1), copy.get("x"));
+        assert_eq!(Node::number(2), frame.get("x"));
+        assert_eq!(Node::number(1), frame.parent.as_ref().unwrap().get("x"));
+    }
+}
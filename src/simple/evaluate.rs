@@ -1,30 +1,339 @@
-use super::syntax::{Node};
-use super::environment::{Environment};
+use super::syntax::{self, Node, Pattern, Span};
+use super::environment::{Environment, Capture, Strategy};
+use super::builtin;
+use super::diagnostics::Diagnostics;
+use super::numfmt::NumberFormat;
+use parser::Arity;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::fmt;
+use std::convert::TryFrom;
 use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::panic;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static STEPS: Cell<usize> = const { Cell::new(0) };
+    static CANCEL: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+    static OUTPUT: RefCell<Option<Output>> = const { RefCell::new(None) };
+    static TRACE: RefCell<Option<Output>> = const { RefCell::new(None) };
+    static INPUT: RefCell<Option<Input>> = const { RefCell::new(None) };
+    static DIAGNOSTICS: RefCell<Option<Diagnostics>> = const { RefCell::new(None) };
+    static NUMBER_FORMAT: Cell<NumberFormat> = const { Cell::new(NumberFormat::plain()) };
+    static ON_ASSIGN: RefCell<Option<AssignHook>> = const { RefCell::new(None) };
+    static ON_STEP: RefCell<Option<StepHook>> = const { RefCell::new(None) };
+    static BUDGET: Cell<Budget> = const { Cell::new(Budget { steps: None, deadline: None }) };
+    static SPAN: Cell<Option<Span>> = const { Cell::new(None) };
+}
+
+/// Make `span` where the current thread is in the source, returning where
+/// it was before.
+pub fn set_span(span: Option<Span>) -> Option<Span> {
+    SPAN.with(|current| current.replace(span))
+}
+
+/// Where in the source evaluation on the current thread is: the innermost
+/// spanned node being evaluated, or, after an error, the one it was
+/// raised in.
+pub fn span() -> Option<Span> {
+    SPAN.with(|current| current.get())
+}
+
+/// Where programs print to.
+pub type Output = Rc<RefCell<dyn Write>>;
+
+/// Send what programs print on the current thread to `output`, or to
+/// stdout if `None`, returning the output used before.
+pub fn set_output(output: Option<Output>) -> Option<Output> {
+    OUTPUT.with(|current| current.replace(output))
+}
+
+/// Lay out the numbers programs print on the current thread in `format`,
+/// returning the format used before.
+pub fn set_number_format(format: NumberFormat) -> NumberFormat {
+    NUMBER_FORMAT.with(|current| current.replace(format))
+}
+
+/// How numbers printed on the current thread are laid out.
+pub fn number_format() -> NumberFormat {
+    NUMBER_FORMAT.with(|current| current.get())
+}
+
+/// Where programs read input from.
+pub type Input = Rc<RefCell<dyn BufRead>>;
+
+/// Read what programs input on the current thread from `input`, or from
+/// stdin if `None`, returning the input used before.
+pub fn set_input(input: Option<Input>) -> Option<Input> {
+    INPUT.with(|current| current.replace(input))
+}
+
+/// Read a line from the current input, without its line ending, or
+/// `None` at the end of the input.
+pub fn read_input() -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = match INPUT.with(|current| current.borrow().clone()) {
+        Some(input) => input.borrow_mut().read_line(&mut line)?,
+        None => io::stdin().lock().read_line(&mut line)?,
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+/// Write `text` to the current output.
+pub fn write_output(text: &str) -> io::Result<()> {
+    match OUTPUT.with(|current| current.borrow().clone()) {
+        Some(output) => output.borrow_mut().write_all(text.as_bytes()),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+}
+
+/// Report the warnings of programs on the current thread to
+/// `diagnostics`, or to stderr if `None`, returning the sink used before.
+pub fn set_diagnostics(diagnostics: Option<Diagnostics>) -> Option<Diagnostics> {
+    DIAGNOSTICS.with(|current| current.replace(diagnostics))
+}
+
+/// Report a warning from the running program, where it is in the source
+/// if that is known, to the current diagnostics.
+pub fn warn(message: &str) {
+    match DIAGNOSTICS.with(|current| current.borrow().clone()) {
+        Some(diagnostics) => diagnostics.warning(message, span()),
+        None => match span() {
+            Some(span) => eprintln!("warning at {}: {}", span, message),
+            None => eprintln!("warning: {}", message),
+        },
+    }
+}
+
+/// A variable about to be assigned, as reported to an `AssignHook`.
+pub struct Assignment<'a> {
+    pub name: &'a str,
+    /// The value of `name` before the assignment as seen from the scope
+    /// assigning it, if any. A `global` in a call frame may not see one.
+    pub old: Option<Box<Node>>,
+    pub new: &'a Node,
+    /// The assignment statement, `global` included.
+    pub statement: &'a Node,
+    /// Whether the assignment is made in the top-level environment
+    /// rather than in a call frame.
+    pub toplevel: bool,
+}
+
+/// Called before every assignment evaluated on the current thread.
+pub type AssignHook = Rc<dyn Fn(&Assignment)>;
+
+/// Call `hook` before every assignment evaluated on the current thread,
+/// or no hook if `None`, returning the hook used before.
+pub fn set_assign_hook(hook: Option<AssignHook>) -> Option<AssignHook> {
+    ON_ASSIGN.with(|current| current.replace(hook))
+}
+
+fn notify_assign(env: &Environment, name: &str, new: &Node, statement: &Node) {
+    // cloned out so the hook may itself install another one
+    if let Some(hook) = ON_ASSIGN.with(|current| current.borrow().clone()) {
+        hook(&Assignment { name, old: env.lookup(name), new, statement, toplevel: env.toplevel });
+    }
+}
+
+/// Called with every node about to be evaluated on the current thread.
+pub type StepHook = Rc<dyn Fn(&Node)>;
+
+/// Call `hook` with every node evaluated on the current thread, or no
+/// hook if `None`, returning the hook used before.
+pub fn set_step_hook(hook: Option<StepHook>) -> Option<StepHook> {
+    ON_STEP.with(|current| current.replace(hook))
+}
+
+/// The panic payload evaluation unwinds with once its cancellation flag
+/// is raised, carrying the node that was about to be evaluated, printed
+/// (a payload has to be `Send`, which nodes are not).
+#[derive(Debug,Clone,PartialEq)]
+pub struct Interrupted {
+    pub redex: String,
+}
+
+/// Watch `flag` while evaluating on the current thread, returning the flag
+/// watched before. Once the flag is set, the next node evaluated unwinds
+/// with an `Interrupted` payload instead of running.
+pub fn set_cancellation(flag: Option<Arc<AtomicBool>>) -> Option<Arc<AtomicBool>> {
+    CANCEL.with(|cancel| cancel.replace(flag))
+}
+
+fn cancelled() -> bool {
+    CANCEL.with(|cancel| cancel.borrow().as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed)))
+}
+
+/// What evaluation ran out of.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Limit {
+    Steps(usize),
+    Time(Duration),
+}
+
+/// The panic payload evaluation unwinds with once it runs out of its
+/// budget, carrying the limit reached and the node that was about to be
+/// evaluated, printed.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Timeout {
+    pub limit: Limit,
+    pub redex: String,
+}
+
+/// Why evaluation stopped before it finished.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Stopped {
+    Interrupted(Interrupted),
+    Timeout(Timeout),
+}
+
+impl Stopped {
+    /// The node that was about to be evaluated, printed.
+    pub fn redex(&self) -> &str {
+        match *self {
+            Stopped::Interrupted(ref interrupted) => &interrupted.redex,
+            Stopped::Timeout(ref timeout) => &timeout.redex,
+        }
+    }
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stopped::Interrupted(ref interrupted) => write!(f, "Interrupted while evaluating {}", interrupted.redex),
+            Stopped::Timeout(Timeout { limit: Limit::Steps(max), ref redex }) =>
+                write!(f, "Step limit of {} reached while evaluating {}", max, redex),
+            Stopped::Timeout(Timeout { limit: Limit::Time(timeout), ref redex }) =>
+                write!(f, "Timeout of {:?} reached while evaluating {}", timeout, redex),
+        }
+    }
+}
+
+/// Why running a program failed: it was stopped, or it went wrong, like
+/// looking up an unbound variable or adding a string to a number.
+#[derive(Debug,Clone,PartialEq)]
+pub enum RuntimeError {
+    Stopped(Stopped),
+    Failed(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::Stopped(ref stopped) => write!(f, "{}", stopped),
+            RuntimeError::Failed(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<Stopped> for RuntimeError {
+    fn from(stopped: Stopped) -> RuntimeError {
+        RuntimeError::Stopped(stopped)
+    }
+}
+
+/// How long evaluation on the current thread may still run.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Budget {
+    /// The step count to stop at, and the limit it came from.
+    steps: Option<(usize, usize)>,
+    deadline: Option<(Instant, Duration)>,
+}
+
+/// Reading the clock on every node would dominate evaluating it.
+const CLOCK_INTERVAL: usize = 1024;
+
+/// Let evaluation on the current thread take at most `max_steps` more
+/// steps and run for at most `timeout` from now, returning the budget in
+/// force before. Past either, the next node evaluated unwinds with a
+/// `Timeout` payload instead of running.
+pub fn set_budget(max_steps: Option<usize>, timeout: Option<Duration>) -> Budget {
+    let budget = Budget {
+        steps: max_steps.map(|max| (steps().saturating_add(max), max)),
+        deadline: timeout.map(|timeout| (Instant::now() + timeout, timeout)),
+    };
+    BUDGET.with(|current| current.replace(budget))
+}
+
+/// Put back a budget returned by `set_budget`.
+pub fn restore_budget(budget: Budget) {
+    BUDGET.with(|current| current.set(budget));
+}
+
+fn check_budget(node: &Node) {
+    let budget = BUDGET.with(|current| current.get());
+    let steps = steps();
+    let limit = match budget {
+        Budget { steps: Some((end, max)), .. } if steps >= end => Limit::Steps(max),
+        Budget { deadline: Some((deadline, timeout)), .. }
+            if steps.is_multiple_of(CLOCK_INTERVAL) && Instant::now() >= deadline => Limit::Time(timeout),
+        _ => return,
+    };
+    panic::resume_unwind(Box::new(Timeout { limit, redex: node.to_string() }));
+}
+
+/// Number of nodes evaluated so far on the current thread.
+pub fn steps() -> usize {
+    STEPS.with(|steps| steps.get())
+}
+
+/// Write every node evaluated on the current thread, with the environment
+/// it is evaluated in, to `sink`, or trace nothing if `None` (the default),
+/// returning the sink used before.
+pub fn set_trace(sink: Option<Output>) -> Option<Output> {
+    TRACE.with(|current| current.replace(sink))
+}
 
 pub trait Evaluate {
     fn evaluate(&self, environment: &mut Environment) -> Box<Node>;
 }
 
-fn get_free_vars_helper(node: &Box<Node>, varlist: &mut HashSet<String>, free_vars: &mut HashSet<String>) {
-    match **node {
-        Node::IsDoNothing(ref node) | Node::Fst(ref node) | Node::Snd(ref node) => {
+fn get_free_vars_helper(node: &Node, varlist: &mut HashSet<String>, free_vars: &mut HashSet<String>) {
+    match *node {
+        Node::IsDoNothing(ref node) | Node::Fst(ref node) | Node::Snd(ref node) |
+            Node::Len(ref node) | Node::Not(ref node) | Node::Negate(ref node) |
+            Node::Return(ref node) | Node::Delay(ref node) | Node::Spanned(_, ref node) => {
             get_free_vars_helper(node, varlist, free_vars);
         }
         Node::Add(ref l, ref r) | Node::Subtract(ref l, ref r) |
             Node::Multiply(ref l, ref r) | Node::LT(ref l, ref r) |
             Node::EQ(ref l, ref r) | Node::GT(ref l, ref r) |
+            Node::LE(ref l, ref r) | Node::GE(ref l, ref r) | Node::NE(ref l, ref r) |
+            Node::And(ref l, ref r) | Node::Or(ref l, ref r) |
             Node::Sequence(ref l, ref r) | Node::While(ref l, ref r) |
-            Node::Pair(ref l, ref r) => {
+            Node::Pair(ref l, ref r) | Node::Compose(ref l, ref r) |
+            Node::Index(ref l, ref r) => {
                 get_free_vars_helper(l, varlist, free_vars);
                 get_free_vars_helper(r, varlist, free_vars);
         }
-        Node::Variable(ref name) => { 
-            if !varlist.contains(name) {
-                free_vars.insert(name.clone());
+        Node::List(ref items) => {
+            for item in items {
+                get_free_vars_helper(item, varlist, free_vars);
             }
         }
-        Node::Assign(ref name, ref expr) => {
+        Node::Map(ref entries) => {
+            for value in entries.values() {
+                get_free_vars_helper(value, varlist, free_vars);
+            }
+        }
+        Node::Call(ref closure, ref args) => {
+            get_free_vars_helper(closure, varlist, free_vars);
+            for arg in args {
+                get_free_vars_helper(arg, varlist, free_vars);
+            }
+        }
+        Node::Variable(ref name) if !varlist.contains(name) => {
+            free_vars.insert(name.clone());
+        }
+        Node::Assign(ref name, ref expr) | Node::Global(ref name, ref expr) => {
             get_free_vars_helper(expr, varlist, free_vars);
             varlist.insert(name.clone());
         }
@@ -33,31 +342,218 @@ fn get_free_vars_helper(node: &Box<Node>, varlist: &mut HashSet<String>, free_va
             get_free_vars_helper(consequence, varlist, free_vars);
             get_free_vars_helper(alternative, varlist, free_vars);
         }
-        Node::Fun(ref funname, ref argname, ref body) => {
+        Node::Match(ref value, ref arms) => {
+            get_free_vars_helper(value, varlist, free_vars);
+            for (pattern, body) in arms {
+                varlist.extend(pattern.bindings().into_iter().map(|name| name.to_string()));
+                get_free_vars_helper(body, varlist, free_vars);
+            }
+        }
+        Node::For(ref init, ref cond, ref step, ref body) => {
+            get_free_vars_helper(init, varlist, free_vars);
+            get_free_vars_helper(cond, varlist, free_vars);
+            get_free_vars_helper(body, varlist, free_vars);
+            get_free_vars_helper(step, varlist, free_vars);
+        }
+        Node::Fun(ref funname, ref params, ref body) => {
             varlist.insert(funname.clone());
-            varlist.insert(argname.clone());
+            varlist.extend(params.iter().cloned());
             get_free_vars_helper(body, varlist, free_vars);
         }
         Node::Closure(ref _env, ref fun) => {
             get_free_vars_helper(fun, varlist, free_vars);
         }
-        // Number, Boolean, DoNothing
+        // Number, Float, Str, Boolean, DoNothing, Builtin, Native
         _ => (),
     }
 }
 
-fn get_free_vars(node: &Box<Node>) -> HashSet<String> {
+/// User code lives in its own namespace: the names of builtins and
+/// special forms cannot be rebound by assignment.
+fn check_assignable(name: &str) {
+    if builtin::is_reserved(name) {
+        panic!("Cannot assign to builtin {}", name);
+    }
+}
+
+/// Nor bound as parameters, which is checked when the function is made
+/// rather than on every call.
+pub(crate) fn check_params(params: &[String]) {
+    if let Some(name) = params.iter().find(|param| builtin::is_reserved(param)) {
+        panic!("Cannot bind builtin {} as a parameter", name);
+    }
+}
+
+/// Test `value` against `pattern`, collecting the variables it binds.
+pub fn match_pattern(pattern: &Pattern, value: &Node, bindings: &mut Vec<(String, Box<Node>)>) -> bool {
+    match (pattern, value) {
+        (&Pattern::Wildcard, _) => true,
+        (Pattern::Bind(name), _) => {
+            bindings.push((name.clone(), Box::new(value.clone())));
+            true
+        }
+        (&Pattern::Number(p), &Node::Number(v)) => p == v,
+        (&Pattern::Boolean(p), &Node::Boolean(v)) => p == v,
+        (Pattern::Str(p), Node::Str(v)) => p == v,
+        (Pattern::Pair(pl, pr), Node::Pair(vl, vr)) =>
+            match_pattern(pl, vl, bindings) && match_pattern(pr, vr, bindings),
+        (Pattern::List(patterns), Node::List(items)) =>
+            patterns.len() == items.len() &&
+                patterns.iter().zip(items).all(|(pattern, item)| match_pattern(pattern, item, bindings)),
+        _ => false,
+    }
+}
+
+/// Wrap a call argument for lazy evaluation in the caller's environment.
+/// Literals are already values and are passed as they are.
+fn delay(arg: &Node, env: &Environment) -> Box<Node> {
+    match *arg {
+        Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Boolean(_) | Node::DoNothing => Box::new(arg.clone()),
+        _ => Node::thunk(env.clone(), Box::new(arg.clone()), env.strategy == Strategy::Need, true),
+    }
+}
+
+/// Evaluate a thunk, reusing the remembered value of a memoized one.
+/// Any other value is returned as it is.
+pub fn force(value: Box<Node>) -> Box<Node> {
+    match *value {
+        Node::Thunk(ref tenv, ref expr, ref memo, _) => {
+            if let Some(ref memo) = *memo {
+                if let Some(ref value) = *memo.borrow() {
+                    return value.clone();
+                }
+            }
+            let value = expr.evaluate(&mut (**tenv).clone());
+            if let Some(ref memo) = *memo {
+                *memo.borrow_mut() = Some(value.clone());
+            }
+            value
+        }
+        _ => value,
+    }
+}
+
+/// Call the function value `function` with already evaluated `args`, as a
+/// host function calling back into simple code does. The call is part of
+/// the evaluation in progress on the current thread: its steps count
+/// against the same limits, and it prints to the same output. What it
+/// assigns with `global` is dropped, there being no caller's scope to keep
+/// it in.
+pub fn call_value(function: &Node, args: Vec<Box<Node>>) -> Box<Node> {
+    let mut env = match *function {
+        Node::Closure(ref cenv, _) => Environment::with_capture(cenv.capture),
+        _ => Environment::new(),
+    };
+    force(apply(Box::new(function.clone()), args, &mut env))
+}
+
+/// The function x -> outer(inner(x)) of two function values.
+pub fn compose(outer: Box<Node>, inner: Box<Node>) -> Box<Node> {
+    compose_in(Environment::new(), outer, inner)
+}
+
+/// A closure over `cenv`; the `$` names cannot be written in source, so
+/// they never clash.
+fn compose_in(mut cenv: Environment, outer: Box<Node>, inner: Box<Node>) -> Box<Node> {
+    cenv.add("$outer", outer);
+    cenv.add("$inner", inner);
+    Node::closure(cenv, Node::fun("compose", &["$x"], Node::call(
+        Node::variable("$outer"),
+        Node::call(Node::variable("$inner"), Node::variable("$x")))))
+}
+
+pub fn get_free_vars(node: &Node) -> HashSet<String> {
     let mut vars: HashSet<String> = HashSet::new();
     let mut free_vars: HashSet<String> = HashSet::new();
     get_free_vars_helper(node, &mut vars, &mut free_vars);
     free_vars
 }
 
+/// Apply the arithmetic operator `op`. Two integers give an integer, and
+/// fail when it overflows; if either side is a float the other is promoted
+/// and the result is a float.
+fn arithmetic(l: &Node, r: &Node, op: &str, int_op: fn(i64, i64) -> Option<i64>,
+              float_op: fn(f64, f64) -> f64) -> Box<Node> {
+    match (l, r) {
+        (&Node::Number(l), &Node::Number(r)) =>
+            Node::number(int_op(l, r).unwrap_or_else(|| panic!("{} {} {} overflows", l, op, r))),
+        _ => Node::float(float_op(l.float_value(), r.float_value())),
+    }
+}
+
+/// Order two numbers, promoting an integer compared with a float. NaN is
+/// unordered, so every comparison involving it is false.
+fn compare(l: &Node, r: &Node) -> Option<cmp::Ordering> {
+    match (l, r) {
+        (&Node::Number(l), &Node::Number(r)) => Some(l.cmp(&r)),
+        _ => l.float_value().partial_cmp(&r.float_value()),
+    }
+}
+
+/// Whether two values are equal: numbers by value, an integer promoted to
+/// compare with a float, and pairs, lists and maps item by item. Values
+/// of different kinds, or functions, cannot be compared.
+fn equal(l: &Node, r: &Node) -> bool {
+    match (l, r) {
+        (Node::Str(l), Node::Str(r)) => l == r,
+        (Node::Boolean(l), Node::Boolean(r)) => l == r,
+        (Node::DoNothing, Node::DoNothing) => true,
+        (Node::Pair(lfst, lsnd), Node::Pair(rfst, rsnd)) => equal(lfst, rfst) && equal(lsnd, rsnd),
+        (Node::List(l), Node::List(r)) => l.len() == r.len() && l.iter().zip(r).all(|(l, r)| equal(l, r)),
+        (Node::Map(l), Node::Map(r)) =>
+            l.len() == r.len() && l.iter().all(|(key, l)| r.get(key).is_some_and(|r| equal(l, r))),
+        (Node::Number(_) | Node::Float(_), Node::Number(_) | Node::Float(_)) => compare(l, r) == Some(cmp::Ordering::Equal),
+        _ => panic!("Cannot compare {} and {}", l, r),
+    }
+}
+
+/// Check the budget and cancellation before a step and count it.
+fn begin(node: &Node) {
+    check_budget(node);
+    STEPS.with(|steps| steps.set(steps.get() + 1));
+    if cancelled() {
+        // resume_unwind skips the panic hook: this is not an error to report
+        panic::resume_unwind(Box::new(Interrupted { redex: node.to_string() }));
+    }
+}
+
+/// Count a step of a semantics other than this evaluator, reducing
+/// `node`, so that the budget, cancellation and step hook of the current
+/// thread apply to it too.
+pub fn count_step(node: &Node) {
+    begin(node);
+    if let Some(hook) = ON_STEP.with(|current| current.borrow().clone()) {
+        hook(node);
+    }
+}
+
+/// Count, trace and check for cancellation a node about to be evaluated.
+/// Public for evaluators that take some nodes apart themselves.
+pub fn enter(node: &Node, env: &Environment) {
+    begin(node);
+    if let Some(sink) = TRACE.with(|current| current.borrow().clone()) {
+        // a trace that cannot be written is not worth stopping the program for
+        let _ = write!(sink.borrow_mut(), "evaluate {} with environment \n{}\n\n", node, env.prettyprint(0));
+    }
+    if let Some(hook) = ON_STEP.with(|current| current.borrow().clone()) {
+        hook(node);
+    }
+}
+
 impl Evaluate for Node {
     fn evaluate(&self, env: &mut Environment) -> Box<Node> {
-        println!("evaluate {} with environment \n{}\n", self, env.prettyprint(0));
+        // a span is no step of the program; it is left in place on errors
+        if let Node::Spanned(span, ref node) = *self {
+            let outer = set_span(Some(span));
+            let value = node.evaluate(env);
+            set_span(outer);
+            return value;
+        }
+        enter(self, env);
         match *self {
             Node::Number(v) => { Node::number(v) }
+            Node::Float(v) => { Node::float(v) }
+            Node::Str(ref v) => { Node::string(v) }
             Node::Boolean(v) => { Node::boolean(v) }
             Node::DoNothing => { Node::donothing() }
             Node::IsDoNothing(ref node) => {
@@ -68,27 +564,75 @@ impl Evaluate for Node {
                 }
             }
             Node::Add(ref l, ref r) => {
-                Node::number(l.evaluate(env).value() + r.evaluate(env).value())
+                let l = l.evaluate(env);
+                let r = r.evaluate(env);
+                match (&*l, &*r) {
+                    (Node::Str(l), Node::Str(r)) => Node::string(&format!("{}{}", l, r)),
+                    _ => arithmetic(&l, &r, "+", i64::checked_add, |l, r| l + r),
+                }
             }
             Node::Subtract(ref l, ref r) => {
-                Node::number(l.evaluate(env).value() - r.evaluate(env).value())
+                arithmetic(&l.evaluate(env), &r.evaluate(env), "-", i64::checked_sub, |l, r| l - r)
             }
             Node::Multiply(ref l, ref r) => {
-                Node::number(l.evaluate(env).value() * r.evaluate(env).value())
+                arithmetic(&l.evaluate(env), &r.evaluate(env), "*", i64::checked_mul, |l, r| l * r)
+            }
+            Node::Negate(ref node) => {
+                match *node.evaluate(env) {
+                    Node::Float(value) => Node::float(-value),
+                    ref value => {
+                        let value = value.value();
+                        Node::number(value.checked_neg().unwrap_or_else(|| panic!("-({}) overflows", value)))
+                    }
+                }
             }
             Node::LT(ref l, ref r) => {
-                Node::boolean(l.evaluate(env).value() < r.evaluate(env).value())
+                Node::boolean(compare(&l.evaluate(env), &r.evaluate(env)) == Some(cmp::Ordering::Less))
             }
             Node::EQ(ref l, ref r) => {
-                Node::boolean(l.evaluate(env).value() == r.evaluate(env).value())
+                Node::boolean(equal(&l.evaluate(env), &r.evaluate(env)))
             }
             Node::GT(ref l, ref r) => {
                 Node::lt(r.clone(), l.clone()).evaluate(env)
             }
-            Node::Variable(ref name) => { env.get(&name) }
+            Node::LE(ref l, ref r) => {
+                let ordering = compare(&l.evaluate(env), &r.evaluate(env));
+                Node::boolean(ordering == Some(cmp::Ordering::Less) || ordering == Some(cmp::Ordering::Equal))
+            }
+            Node::GE(ref l, ref r) => {
+                Node::le(r.clone(), l.clone()).evaluate(env)
+            }
+            Node::NE(ref l, ref r) => {
+                Node::not(Node::eq(l.clone(), r.clone())).evaluate(env)
+            }
+            Node::And(ref l, ref r) => {
+                Node::boolean(l.evaluate(env).condition() && r.evaluate(env).condition())
+            }
+            Node::Or(ref l, ref r) => {
+                Node::boolean(l.evaluate(env).condition() || r.evaluate(env).condition())
+            }
+            Node::Not(ref node) => {
+                Node::boolean(!node.evaluate(env).condition())
+            }
+            Node::Variable(ref name) => {
+                let value = env.get(name);
+                match *value {
+                    Node::Thunk(_, _, _, true) => force(value),
+                    _ => value,
+                }
+            }
             Node::Assign(ref name, ref expr) => {
+                check_assignable(name);
+                let reduce = expr.evaluate(env);
+                notify_assign(env, name, &reduce, self);
+                env.assign(name, reduce);
+                Node::donothing()
+            }
+            Node::Global(ref name, ref expr) => {
+                check_assignable(name);
                 let reduce = expr.evaluate(env);
-                env.add(name, reduce.clone());
+                notify_assign(env, name, &reduce, self);
+                env.add_global(name, reduce);
                 Node::donothing()
             }
             Node::If(ref condition, ref consequence, ref alternative) => {
@@ -99,18 +643,80 @@ impl Evaluate for Node {
                 }
             }
             Node::Sequence(ref head, ref more) => {
-                head.evaluate(env);
-                more.evaluate(env);
-                Node::donothing()
+                // a program is a chain of sequences as long as it is, so walk
+                // the chain with a stack of its own rather than the host's
+                let mut pending: Vec<&Node> = vec![more, head];
+                // the value of a block is the value of its last statement
+                let mut last = Node::donothing();
+                while let Some(node) = pending.pop() {
+                    match *node {
+                        Node::Sequence(ref head, ref more) => {
+                            STEPS.with(|steps| steps.set(steps.get() + 1));
+                            pending.push(more);
+                            pending.push(head);
+                        }
+                        _ => {
+                            last = node.evaluate(env);
+                            if last.is_signal() {
+                                return last;
+                            }
+                        }
+                    }
+                }
+                last
             }
             Node::While(ref cond, ref body) => {
-                if cond.evaluate(env).condition() {
-                    body.evaluate(env);
-                    self.evaluate(env)
-                } else {
-                    Node::donothing()
+                while cond.evaluate(env).condition() {
+                    match *body.evaluate(env) {
+                        Node::Break => break,
+                        Node::Continue => continue,
+                        ref value if value.is_signal() => return Box::new(value.clone()),
+                        _ => (),
+                    }
                 }
+                Node::donothing()
             }
+            Node::Match(ref value, ref arms) => {
+                let value = value.evaluate(env);
+                for (pattern, body) in arms {
+                    let mut bindings = Vec::new();
+                    if match_pattern(pattern, &value, &mut bindings) {
+                        // the arm is a block scope for the names its pattern binds
+                        let mut shadowed = Vec::new();
+                        for (name, node) in bindings {
+                            check_assignable(&name);
+                            shadowed.push((name.clone(), env.insert(&name, node)));
+                        }
+                        let value = body.evaluate(env);
+                        for (name, node) in shadowed.into_iter().rev() {
+                            match node {
+                                Some(node) => env.add(&name, node),
+                                None => { env.remove(&name); }
+                            }
+                        }
+                        return value;
+                    }
+                }
+                panic!("No match arm for value {}", value)
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                init.evaluate(env);
+                while cond.evaluate(env).condition() {
+                    match *body.evaluate(env) {
+                        Node::Break => break,
+                        // unlike a hand-written while loop, continue still runs the step
+                        Node::Continue => (),
+                        ref value if value.is_signal() => return Box::new(value.clone()),
+                        _ => (),
+                    }
+                    step.evaluate(env);
+                }
+                Node::donothing()
+            }
+            Node::Return(ref value) => {
+                Node::return_node(value.evaluate(env))
+            }
+            Node::Break | Node::Continue => { Box::new(self.clone()) }
             Node::Pair(ref fst, ref snd) => {
                 Node::pair(fst.evaluate(env).clone(), snd.evaluate(env).clone())
             }
@@ -130,43 +736,204 @@ impl Evaluate for Node {
                     _ => panic!("Apply snd on non-pair type: {}", pair)
                 }
             }
-            Node::Fun(ref _funname, ref _argname, ref _body) => {
-                Node::closure(env.clone(), Box::new(self.clone()))
+            Node::Len(ref node) => {
+                builtin::call("len", &[node.evaluate(env)])
             }
-            Node::Closure(ref env, ref fun) => {
-                Node::closure(env.clone(), fun.clone())
-            }
-            Node::Call(ref closure, ref arg) => {
-                let arg = arg.evaluate(env);
-                let clsr = closure.evaluate(env);
-                match *clsr {
-                    Node::Closure(ref env, ref fun) => {
-                        if let Node::Fun(funname, argname, body) = *fun.clone() {
-                            let freevars = get_free_vars(&fun);
-                            let mut newenv = Environment::new();
-                            for var in freevars {
-                                newenv.add(&var, env.get(&var));
-                            }
-                            newenv.add(&funname, clsr.clone());
-                            if !argname.is_empty() {
-                                newenv.add(&argname, arg.clone());
+            Node::List(ref items) => {
+                Node::list(items.iter().map(|item| item.evaluate(env)).collect())
+            }
+            Node::Map(ref entries) => {
+                Node::map(syntax::sorted(entries).into_iter()
+                          .map(|(key, value)| (key.to_string(), value.evaluate(env))).collect())
+            }
+            Node::Index(ref list, ref index) => {
+                let list = list.evaluate(env);
+                let items = list.list_value();
+                let index = index.evaluate(env).value();
+                match usize::try_from(index).ok().and_then(|i| items.get(i)) {
+                    Some(item) => item.clone(),
+                    None => panic!("Index {} out of bounds for list of length {}", index, items.len()),
+                }
+            }
+            Node::Fun(_, ref params, _) => {
+                check_params(params);
+                let cenv = match env.capture {
+                    // with Shared the clone is a handle on the live scope
+                    Capture::Value | Capture::Shared => env.clone(),
+                    Capture::Reference => env.fresh(),
+                    Capture::Minimal => {
+                        // bind the free variables once here rather than on every call
+                        let mut cenv = env.fresh();
+                        for var in get_free_vars(self) {
+                            if let Some(node) = env.lookup(&var) {
+                                cenv.add(&var, node);
                             }
-                            body.evaluate(&mut newenv)
-                        } else {
-                            panic!("Closure not contain function: {}", fun)
                         }
+                        cenv
                     }
-                    _ => panic!("Call on non-closure type: {:?}", closure)
+                };
+                Node::closure(cenv, Box::new(self.clone()))
+            }
+            Node::Closure(ref env, ref fun) => {
+                Box::new(Node::Closure(env.clone(), fun.clone()))
+            }
+            Node::Thunk(_, _, _, true) => { force(Box::new(self.clone())) }
+            Node::Thunk(..) => { Box::new(self.clone()) }
+            Node::Delay(ref expr) => { Node::thunk(env.clone(), expr.clone(), true, false) }
+            Node::Compose(ref outer, ref inner) => {
+                let (outer, inner) = (outer.evaluate(env), inner.evaluate(env));
+                compose_in(env.fresh(), outer, inner)
+            }
+            Node::Builtin(ref name) => { Node::builtin(name) }
+            Node::Native(_) => { Box::new(self.clone()) }
+            Node::Call(ref closure, ref args) => {
+                let (clsr, args) = callee(closure, args, env);
+                apply(clsr, args, env)
+            }
+            Node::Spanned(..) => unreachable!("spans are evaluated before entering"),
+        }
+    }
+}
+
+/// Evaluate the function and the arguments of a call.
+fn callee(closure: &Node, args: &[Box<Node>], env: &mut Environment) -> (Box<Node>, Vec<Box<Node>>) {
+    if env.strategy == Strategy::Value {
+        let args: Vec<Box<Node>> = args.iter().map(|arg| arg.evaluate(env)).collect();
+        (closure.evaluate(env), args)
+    } else {
+        // builtins are strict in their arguments whatever the strategy
+        let clsr = closure.evaluate(env);
+        let args = match *clsr {
+            Node::Closure(..) => args.iter().map(|arg| delay(arg, env)).collect(),
+            _ => args.iter().map(|arg| arg.evaluate(env)).collect(),
+        };
+        (clsr, args)
+    }
+}
+
+/// Where evaluating a function body in tail position leaves off.
+enum Tail {
+    Value(Box<Node>),
+    /// A call left to make in place of the current one.
+    Call(Box<Node>, Vec<Box<Node>>),
+}
+
+/// Evaluate `node` as the body of a function, or part of it, stopping
+/// short of a call whose value would be the value of the function. The
+/// caller makes that call in place of the current one, so that tail
+/// recursion runs in constant host stack. The last statement of a body
+/// is in tail position, its value being that of the body.
+fn tail(node: &Node, env: &mut Environment) -> Tail {
+    match *node {
+        Node::Call(ref closure, ref args) => {
+            enter(node, env);
+            let (clsr, args) = callee(closure, args, env);
+            Tail::Call(clsr, args)
+        }
+        Node::Return(ref expr) => {
+            enter(node, env);
+            match tail(expr, env) {
+                Tail::Value(result) => Tail::Value(Node::return_node(result)),
+                call => call,
+            }
+        }
+        Node::If(ref condition, ref consequence, ref alternative) => {
+            enter(node, env);
+            if condition.evaluate(env).condition() {
+                tail(consequence, env)
+            } else {
+                tail(alternative, env)
+            }
+        }
+        Node::Spanned(span, ref node) => {
+            let outer = set_span(Some(span));
+            let result = tail(node, env);
+            set_span(outer);
+            result
+        }
+        Node::Sequence(ref head, ref more) => {
+            enter(node, env);
+            let head = head.evaluate(env);
+            if head.is_signal() {
+                return Tail::Value(head);
+            }
+            tail(more, env)
+        }
+        _ => Tail::Value(node.evaluate(env)),
+    }
+}
+
+/// Call `clsr` with `args` from `env`.
+fn apply(mut clsr: Box<Node>, mut args: Vec<Box<Node>>, env: &mut Environment) -> Box<Node> {
+    loop {
+        let (cenv, fun) = match *clsr {
+            Node::Builtin(ref name) => return builtin::call(name, &args),
+            Node::Native(ref native) => return (native.fun)(&args),
+            Node::Closure(ref cenv, ref fun) => (cenv.clone(), fun.clone()),
+            _ => panic!("Call on non-closure type: {:?}", clsr),
+        };
+        let (funname, params, body) = match *fun {
+            Node::Fun(ref funname, ref params, ref body) => (funname, params, body),
+            _ => panic!("Closure not contain function: {}", fun),
+        };
+        if args.len() != params.len() {
+            panic!("Function {} takes {}, found {}", funname, Arity::Exactly(params.len()), args.len());
+        }
+        let mut newenv = match env.capture {
+            // the frame shares the defining environment instead of copying it
+            Capture::Value | Capture::Minimal | Capture::Shared => Environment::frame(cenv.clone()),
+            Capture::Reference => {
+                let mut newenv = env.clone();
+                for (name, node) in cenv.bindings() {
+                    newenv.add(&name, node);
                 }
+                newenv.into_frame()
             }
-            _ => panic!("Non evaluate type found: {}", *self)
+        };
+        // anonymous functions have no name to recurse through
+        if !funname.is_empty() {
+            newenv.add(funname, clsr.clone());
         }
+        for (param, arg) in params.iter().zip(args) {
+            newenv.add(param, arg);
+        }
+        let result = if env.capture == Capture::Reference {
+            // the frame is written back to the caller when the call returns,
+            // so it cannot be given up early for a tail call
+            Tail::Value(body.evaluate(&mut newenv))
+        } else {
+            tail(body, &mut newenv)
+        };
+        let value = match result {
+            Tail::Call(next, next_args) => {
+                env.merge_globals(newenv);
+                clsr = next;
+                args = next_args;
+                continue;
+            }
+            Tail::Value(value) => match *value {
+                Node::Return(value) => value,
+                Node::Break | Node::Continue => panic!("break or continue outside of a loop in {}", fun),
+                value => Box::new(value),
+            },
+        };
+        if env.capture == Capture::Reference {
+            for (name, node) in newenv.bindings() {
+                if name != *funname && !params.contains(&name) &&
+                    !cenv.contains(&name) && env.contains(&name) {
+                    env.add(&name, node);
+                }
+            }
+        }
+        env.merge_globals(newenv);
+        return value;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use parser;
 
     #[test]
     fn test_simple_big_number() {
@@ -190,6 +957,56 @@ mod tests {
         assert_eq!(42, n.evaluate(&mut env).value());
     }
 
+    #[test]
+    #[should_panic(expected = "9223372036854775807 + 1 overflows")]
+    fn test_simple_big_overflow() {
+        let n = Node::add(Node::number(i64::MAX), Node::number(1));
+        n.evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "-(-9223372036854775808) overflows")]
+    fn test_simple_big_negate_overflow() {
+        let n = Node::negate(Node::number(i64::MIN));
+        n.evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    fn test_simple_big_negate() {
+        let n = Node::negate(Node::add(Node::variable("x"), Node::number(2)));
+        let mut env = Environment::new();
+        env.add("x", Node::number(3));
+        assert_eq!(-5, n.evaluate(&mut env).value());
+        assert_eq!(5, Node::negate(n).evaluate(&mut env).value());
+    }
+
+    #[test]
+    fn test_simple_big_float() {
+        let mut env = Environment::new();
+        env.add("x", Node::float(1.5));
+        let n = Node::multiply(Node::variable("x"), Node::number(2));
+        assert_eq!(Node::float(3.0), n.evaluate(&mut env));
+        assert_eq!(Node::float(0.5), Node::negate(Node::subtract(Node::number(1), Node::variable("x"))).evaluate(&mut env));
+        // two integers stay integers
+        assert_eq!(Node::number(3), Node::add(Node::number(1), Node::number(2)).evaluate(&mut env));
+        assert!(Node::lt(Node::number(1), Node::variable("x")).evaluate(&mut env).condition());
+        assert!(Node::eq(Node::number(3), n.clone()).evaluate(&mut env).condition());
+        assert!(Node::ge(n, Node::number(3)).evaluate(&mut env).condition());
+        let nan = Node::float(f64::NAN);
+        assert!(!Node::le(nan.clone(), Node::number(0)).evaluate(&mut env).condition());
+        assert!(!Node::eq(nan.clone(), nan).evaluate(&mut env).condition());
+    }
+
+    #[test]
+    fn test_simple_big_string() {
+        let n = Node::add(Node::string("hello, "), Node::variable("name"));
+        let mut env = Environment::new();
+        env.add("name", Node::string("world"));
+        assert_eq!("hello, world", n.evaluate(&mut env).string_value());
+        assert!(Node::eq(n.clone(), Node::string("hello, world")).evaluate(&mut env).condition());
+        assert_eq!(12, Node::len(n).evaluate(&mut env).value());
+    }
+
     #[test]
     fn test_simple_big_lessthan() {
         let n = Node::lt(Node::add(Node::variable("x"), Node::number(2)), Node::variable("y"));
@@ -199,6 +1016,46 @@ mod tests {
         assert!(n.evaluate(&mut env).condition());
     }
 
+    #[test]
+    fn test_simple_big_comparison() {
+        let mut env = Environment::new();
+        env.add("x", Node::number(3));
+        assert!(Node::le(Node::variable("x"), Node::number(3)).evaluate(&mut env).condition());
+        assert!(!Node::ge(Node::variable("x"), Node::number(4)).evaluate(&mut env).condition());
+        assert!(Node::ne(Node::variable("x"), Node::number(4)).evaluate(&mut env).condition());
+        assert!(!Node::ne(Node::string("a"), Node::string("a")).evaluate(&mut env).condition());
+    }
+
+    #[test]
+    fn test_simple_big_equality() {
+        let mut env = Environment::new();
+        env.add("x", Node::boolean(true));
+        let holds = |source: &str, env: &mut Environment| parser::parse(source).unwrap().evaluate(env).condition();
+        assert!(holds("x != false", &mut env));
+        assert!(!holds("true == false", &mut env));
+        assert!(holds("(1, \"a\") == (1, \"a\")", &mut env));
+        assert!(holds("[1, 2.0] == [1, 2] and [1] != [1, 2]", &mut env));
+        assert!(holds("{\"a\": [true]} == {\"a\": [true]} and {\"a\": 1} != {\"b\": 1}", &mut env));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot compare true and 1")]
+    fn test_simple_big_equality_kinds() {
+        parser::parse("true == 1").unwrap().evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    fn test_simple_big_logical() {
+        let mut env = Environment::new();
+        // the right operand would panic if it were evaluated
+        let n = Node::and(Node::boolean(false), Node::fst(Node::number(1)));
+        assert!(!n.evaluate(&mut env).condition());
+        let n = Node::or(Node::boolean(true), Node::fst(Node::number(1)));
+        assert!(n.evaluate(&mut env).condition());
+        let n = Node::not(Node::and(Node::boolean(true), Node::lt(Node::number(1), Node::number(2))));
+        assert!(!n.evaluate(&mut env).condition());
+    }
+
     #[test]
     fn test_simple_big_sequence() {
         let statement = Node::sequence(
@@ -223,6 +1080,82 @@ mod tests {
         assert_eq!(9, env.get("x").value());
     }
 
+    #[test]
+    fn test_simple_big_while_long() {
+        // a million iterations must not grow the host stack
+        let handle = std::thread::Builder::new().stack_size(64 * 1024).spawn(|| {
+            let statement = Node::while_node(
+                Node::lt(Node::variable("x"), Node::number(1_000_000)),
+                Node::assign("x", Node::add(Node::variable("x"), Node::number(1))),
+            );
+            let mut env = Environment::new();
+            env.add("x", Node::number(0));
+            statement.evaluate(&mut env);
+            env.get("x").value()
+        }).unwrap();
+        assert_eq!(1_000_000, handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_simple_big_tail_call() {
+        let handle = std::thread::Builder::new().stack_size(256 * 1024).spawn(|| {
+            let program = parser::parse("def countdown(n, total) {
+                    if (n == 0) { return total; }
+                    return countdown(n - 1, total + n);
+                }
+                total = countdown(20000, 0);
+                last = (fun(n) { return len([n]); })(7);").unwrap();
+            let mut results = Vec::new();
+            for &capture in [Capture::Value, Capture::Minimal, Capture::Shared].iter() {
+                let mut env = Environment::with_capture(capture);
+                program.evaluate(&mut env);
+                results.push((env.get("total").value(), env.get("last").value()));
+            }
+            // mutual recursion through the shared top-level scope
+            let program = parser::parse("def is_even(n) { if (n == 0) { true } else { is_odd(n - 1) } }
+                def is_odd(n) { if (n == 0) { false } else { is_even(n - 1) } }
+                even = is_even(20001);").unwrap();
+            let mut env = Environment::with_capture(Capture::Shared);
+            program.evaluate(&mut env);
+            (results, env.get("even").condition())
+        }).unwrap();
+        let (results, even) = handle.join().unwrap();
+        assert_eq!(vec![(200010000, 1); 3], results);
+        assert!(!even);
+    }
+
+    #[test]
+    fn test_simple_big_sequence_long() {
+        // a long program nests its sequences as deep as it has statements
+        let handle = std::thread::Builder::new().stack_size(256 * 1024).spawn(|| {
+            let increment = Node::assign("x", Node::add(Node::variable("x"), Node::number(1)));
+            let program = (1..2000).fold(increment.clone(), |acc, _| Node::sequence(acc, increment.clone()));
+            let mut env = Environment::new();
+            env.add("x", Node::number(0));
+            program.evaluate(&mut env);
+            env.get("x").value()
+        }).unwrap();
+        assert_eq!(2000, handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_simple_big_for() {
+        // sum = 0; for (i = 0; i < 10; i = i + 1) { if (i == 3) { continue; } sum = sum + i; }
+        let statement = Node::sequence(
+            Node::assign("sum", Node::number(0)),
+            Node::for_node(
+                Node::assign("i", Node::number(0)),
+                Node::lt(Node::variable("i"), Node::number(10)),
+                Node::assign("i", Node::add(Node::variable("i"), Node::number(1))),
+                Node::sequence(
+                    Node::if_cond_else(Node::eq(Node::variable("i"), Node::number(3)), Node::continue_node(), Node::donothing()),
+                    Node::assign("sum", Node::add(Node::variable("sum"), Node::variable("i"))))));
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(10, env.get("i").value());
+        assert_eq!(42, env.get("sum").value());
+    }
+
     #[test]
     fn test_simple_big_pair() {
         let statement = Node::sequence(
@@ -239,11 +1172,111 @@ mod tests {
         assert_eq!(30, env.get("z").value());
     }
 
+    #[test]
+    fn test_simple_big_delay_force() {
+        // t = delay 2 * 3; u = delay fst(0)
+        let statement = Node::sequence(
+            Node::assign("t", Node::delay(Node::multiply(Node::number(2), Node::number(3)))),
+            Node::assign("u", Node::delay(Node::call(Node::variable("fst"), Node::number(0)))));
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!("thunk (2 * 3)", env.get("t").to_string());
+        let force = || Node::call(Node::variable("force"), Node::variable("t"));
+        let start = steps();
+        assert_eq!(6, force().evaluate(&mut env).value());
+        let first = steps() - start;
+        assert_eq!(6, force().evaluate(&mut env).value());
+        // the second force reuses the remembered value
+        assert_eq!(first - 3, steps() - start - first);
+        assert_eq!("thunk (2 * 3) => 6", env.get("t").to_string());
+        assert_eq!(5, Node::call(Node::variable("force"), Node::number(5)).evaluate(&mut env).value());
+    }
+
+    #[test]
+    fn test_simple_big_match() {
+        // def describe(v) { match (v) { 0 => { "zero" } (a, b) => { a + b } [x, _] => { x } _ => { "other" } } }
+        let describe = Node::fun("describe", &["v"], Node::match_node(Node::variable("v"), vec![
+            (Pattern::Number(0), Node::string("zero")),
+            (Pattern::Pair(Box::new(Pattern::Bind("a".to_string())), Box::new(Pattern::Bind("b".to_string()))),
+             Node::add(Node::variable("a"), Node::variable("b"))),
+            (Pattern::List(vec![Pattern::Bind("x".to_string()), Pattern::Wildcard]), Node::variable("x")),
+            (Pattern::Wildcard, Node::string("other")),
+        ]));
+        let mut env = Environment::new();
+        Node::assign("describe", describe).evaluate(&mut env);
+        let mut describe = |arg| Node::call(Node::variable("describe"), arg).evaluate(&mut env);
+        assert_eq!(Node::string("zero"), describe(Node::number(0)));
+        assert_eq!(7, describe(Node::pair(Node::number(3), Node::number(4))).value());
+        assert_eq!(5, describe(Node::list(vec![Node::number(5), Node::number(6)])).value());
+        assert_eq!(Node::string("other"), describe(Node::list(vec![Node::number(5)])));
+        assert_eq!(Node::string("other"), describe(Node::boolean(true)));
+    }
+
+    #[test]
+    fn test_simple_big_match_scope() {
+        // x = 1; match ((5, 6)) { (x, y) => { x = x + y; z = x } }
+        let statement = Node::sequence(Node::assign("x", Node::number(1)), Node::match_node(
+            Node::pair(Node::number(5), Node::number(6)), vec![
+                (Pattern::Pair(Box::new(Pattern::Bind("x".to_string())), Box::new(Pattern::Bind("y".to_string()))),
+                 Node::sequence(Node::assign("x", Node::add(Node::variable("x"), Node::variable("y"))),
+                                Node::assign("z", Node::variable("x"))))]));
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        // the arm's bindings end with the arm; other assignments outlive it
+        assert_eq!(1, env.get("x").value());
+        assert_eq!(None, env.lookup("y"));
+        assert_eq!(11, env.get("z").value());
+    }
+
+    #[test]
+    fn test_simple_big_frame_shadowing() {
+        // n = 1; def f(x) { n = x; g = fun() { n }; return g() }; r = f(5)
+        let statement = Node::sequence(Node::assign("n", Node::number(1)), Node::sequence(
+            Node::assign("f", Node::fun("f", &["x"], Node::sequence(
+                Node::assign("n", Node::variable("x")), Node::sequence(
+                Node::assign("g", Node::fun("", &[], Node::variable("n"))),
+                Node::return_node(Node::call_args(Node::variable("g"), vec![])))))),
+            Node::assign("r", Node::call(Node::variable("f"), Node::number(5)))));
+        for &capture in [Capture::Value, Capture::Minimal].iter() {
+            let mut env = Environment::with_capture(capture);
+            statement.evaluate(&mut env);
+            assert_eq!(5, env.get("r").value());
+            assert_eq!(1, env.get("n").value());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "No match arm for value 2")]
+    fn test_simple_big_match_no_arm() {
+        Node::match_node(Node::number(2), vec![(Pattern::Number(1), Node::number(1))]).evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    fn test_simple_big_list() {
+        // xs = [1, 2 * 3]; y = xs[1] + len(xs)
+        let statement = Node::sequence(
+            Node::assign("xs", Node::list(vec![Node::number(1), Node::multiply(Node::number(2), Node::number(3))])),
+            Node::assign("y", Node::add(
+                Node::index(Node::variable("xs"), Node::number(1)),
+                Node::call(Node::variable("len"), Node::variable("xs")))));
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(Node::list(vec![Node::number(1), Node::number(6)]), env.get("xs"));
+        assert_eq!(8, env.get("y").value());
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 2 out of bounds for list of length 2")]
+    fn test_simple_big_list_out_of_bounds() {
+        let list = Node::list(vec![Node::number(1), Node::number(2)]);
+        Node::index(list, Node::number(2)).evaluate(&mut Environment::new());
+    }
+
     #[test]
     fn test_simple_big_function() {
-        let statement = Node::assign("x", Node::call(
-            Node::fun("const", "", Node::number(42)),
-            Node::donothing()
+        let statement = Node::assign("x", Node::call_args(
+            Node::fun("const", &[], Node::number(42)),
+            vec![]
             )
         );
         let mut env = Environment::new();
@@ -253,7 +1286,7 @@ mod tests {
 
     #[test]
     fn test_simple_big_function_var() {
-        let add1 = Node::fun("add1", "x", Node::add(Node::variable("x"), Node::number(1)));
+        let add1 = Node::fun("add1", &["x"], Node::add(Node::variable("x"), Node::number(1)));
         let statement = Node::sequence(
             Node::assign("f", add1),
             Node::assign("result", Node::call(Node::variable("f"), Node::number(4)))
@@ -263,9 +1296,22 @@ mod tests {
         assert_eq!(5, env.get("result").value());
     }
 
+    #[test]
+    fn test_simple_big_function_last_value() {
+        let program = parser::parse("def f(x) { y = x; y }
+            def counter() { c = 0; return fun() { c = c + 1; c }; }
+            def g(x) { if (x > 0) { y = x; y } else { 0 } }
+            a = counter();
+            a()
+            result = [f(3), a(), g(4)];").unwrap();
+        let mut env = Environment::with_capture(Capture::Shared);
+        program.evaluate(&mut env);
+        assert_eq!(Node::list(vec![Node::number(3), Node::number(2), Node::number(4)]), env.get("result"));
+    }
+
     #[test]
     fn test_simple_big_function_env() {
-        let x_add_y = Node::fun("add1", "y", Node::add(Node::variable("x"), Node::variable("y")));
+        let x_add_y = Node::fun("add1", &["y"], Node::add(Node::variable("x"), Node::variable("y")));
         let statement = Node::sequence(
             Node::assign("x", Node::number(3)),
             Node::sequence(
@@ -281,18 +1327,238 @@ mod tests {
         assert_eq!(7, env.get("result").value());
     }
 
+    #[test]
+    fn test_simple_big_capture_policy() {
+        let x_add_y = Node::fun("add1", &["y"], Node::add(Node::variable("x"), Node::variable("y")));
+        let statement = Node::sequence(
+            Node::assign("x", Node::number(3)),
+            Node::sequence(
+                Node::assign("addx", x_add_y),
+                Node::sequence(
+                    Node::assign("x", Node::number(5)),
+                    Node::assign("result", Node::call(Node::variable("addx"), Node::number(4)))
+                )
+            )
+        );
+        for &(capture, expect) in [(Capture::Value, 7), (Capture::Minimal, 7), (Capture::Reference, 9),
+                                   (Capture::Shared, 9)].iter() {
+            let mut env = Environment::with_capture(capture);
+            statement.evaluate(&mut env);
+            assert_eq!(expect, env.get("result").value());
+        }
+    }
+
+    #[test]
+    fn test_simple_big_capture_reference_writeback() {
+        let incr = Node::fun("incr", &[], Node::assign("n", Node::add(Node::variable("n"), Node::number(1))));
+        let statement = Node::sequence(
+            Node::assign("n", Node::number(0)),
+            Node::sequence(
+                Node::assign("incr", incr),
+                Node::sequence(
+                    Node::call_args(Node::variable("incr"), vec![]),
+                    Node::call_args(Node::variable("incr"), vec![])
+                )
+            )
+        );
+        for &(capture, expect) in [(Capture::Reference, 2), (Capture::Shared, 2), (Capture::Value, 0)].iter() {
+            let mut env = Environment::with_capture(capture);
+            statement.evaluate(&mut env);
+            assert_eq!(expect, env.get("n").value());
+        }
+    }
+
+    #[test]
+    fn test_simple_big_capture_shared() {
+        let program = parser::parse("def counter() {
+                count = 0;
+                return fun() { count = count + 1; return count; };
+            }
+            def is_even(n) { if (n == 0) { return true; } return is_odd(n - 1); }
+            def is_odd(n) { if (n == 0) { return false; } return is_even(n - 1); }
+            a = counter();
+            b = counter();
+            a() a() b()
+            counts = [a(), b()];
+            even = is_even(10);").unwrap();
+        let mut env = Environment::with_capture(Capture::Shared);
+        program.evaluate(&mut env);
+        // each call of counter makes its own upvalue, kept across calls of the closure
+        assert_eq!(Node::list(vec![Node::number(3), Node::number(2)]), env.get("counts"));
+        // is_even sees is_odd though it was defined first
+        assert_eq!(Node::boolean(true), env.get("even"));
+        assert_eq!(None, env.lookup("count"));
+        assert!(env.get("a").to_string().contains("shared env"));
+    }
+
+    #[test]
+    fn test_simple_big_nested_functions() {
+        let program = parser::parse("def outer(a) {
+                def middle(b) {
+                    def inner(c) { a * 100 + b * 10 + c }
+                    return inner;
+                }
+                f = middle(a + 1);
+                a = 0;
+                return pair(middle, f);
+            }
+            first = outer(1);
+            second = outer(5);
+            results = [fst(first)(2)(3), snd(first)(4), fst(second)(6)(7), snd(second)(8)];
+            fact = (def fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } })(5);").unwrap();
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        // each level keeps the locals it closed over, as they were when it was defined
+        let results = [123, 124, 567, 568].iter().map(|&n| Node::number(n)).collect();
+        assert_eq!(Node::list(results), env.get("results"));
+        assert_eq!(120, env.get("fact").value());
+        assert_eq!(None, env.lookup("a"));
+        assert_eq!(None, env.lookup("middle"));
+    }
+
+    #[test]
+    fn test_simple_big_global() {
+        // function bump(x) { counter = 100; global total = x + 1; }
+        let bump = Node::fun("bump", &["x"], Node::sequence(
+            Node::assign("counter", Node::number(100)),
+            Node::global("total", Node::add(Node::variable("x"), Node::number(1)))));
+        let statement = Node::sequence(
+            Node::sequence(Node::assign("total", Node::number(0)), Node::assign("counter", Node::number(0))),
+            Node::sequence(
+                Node::assign("bump", bump),
+                Node::assign("result", Node::call(Node::variable("bump"), Node::number(1)))
+            )
+        );
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(2, env.get("total").value());
+        assert_eq!(0, env.get("counter").value());
+    }
+
     #[test]
     fn test_simple_big_function_twoarg() {
-        let x_add_y = Node::fun("addx", "x", Node::fun("addy", "y", Node::add(Node::variable("x"), Node::variable("y"))));
+        let x_add_y = Node::fun("addx", &["x"], Node::fun("addy", &["y"], Node::add(Node::variable("x"), Node::variable("y"))));
         let statement = Node::assign("result", Node::call(Node::call(x_add_y, Node::number(17)), Node::number(31)));
         let mut env = Environment::new();
         println!("{}", statement.evaluate(&mut env));
         assert_eq!(48, env.get("result").value());
     }
 
+    #[test]
+    fn test_simple_big_compose() {
+        let statement = Node::sequence(
+            Node::sequence(
+                Node::assign("add1", Node::fun("add1", &["x"], Node::add(Node::variable("x"), Node::number(1)))),
+                Node::assign("double", Node::fun("double", &["x"], Node::multiply(Node::variable("x"), Node::number(2))))),
+            Node::sequence(
+                Node::assign("square", Node::fun("square", &["x"], Node::multiply(Node::variable("x"), Node::variable("x")))),
+                Node::assign("f", Node::compose(Node::variable("add1"),
+                                                Node::compose(Node::variable("double"), Node::variable("square"))))));
+        for &capture in [Capture::Value, Capture::Minimal, Capture::Reference].iter() {
+            let mut env = Environment::with_capture(capture);
+            statement.evaluate(&mut env);
+            let result = Node::call(Node::variable("f"), Node::number(3)).evaluate(&mut env);
+            assert_eq!(19, result.value());
+            let twice = Node::compose(Node::variable("f"), Node::variable("f"));
+            assert_eq!(723, Node::call(twice, Node::number(3)).evaluate(&mut env).value());
+        }
+    }
+
+    #[test]
+    fn test_simple_big_function_multiarg() {
+        let sub = Node::fun("sub", &["x", "y"], Node::subtract(Node::variable("x"), Node::variable("y")));
+        let statement = Node::sequence(
+            Node::assign("sub", sub),
+            Node::assign("result", Node::call_args(Node::variable("sub"), vec![Node::number(17), Node::number(31)]))
+        );
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(-14, env.get("result").value());
+    }
+
+    #[test]
+    #[should_panic(expected = "Function sub takes 2 arguments, found 1")]
+    fn test_simple_big_function_arity() {
+        let sub = Node::fun("sub", &["x", "y"], Node::subtract(Node::variable("x"), Node::variable("y")));
+        Node::call(sub, Node::number(1)).evaluate(&mut Environment::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Function answer takes 0 arguments, found 1")]
+    fn test_simple_big_function_nullary_arity() {
+        // do-nothing is a value like any other, not a stand-in for no arguments
+        let answer = Node::fun("answer", &[], Node::number(42));
+        let mut env = Environment::new();
+        assert_eq!(42, Node::call_args(answer.clone(), vec![]).evaluate(&mut env).value());
+        Node::call(answer, Node::donothing()).evaluate(&mut env);
+    }
+
+    #[test]
+    fn test_simple_big_lambda() {
+        // apply(fun(y) { y * x }, 21) with x captured from the outer scope
+        let apply = Node::fun("apply", &["f", "v"], Node::call(Node::variable("f"), Node::variable("v")));
+        let lambda = Node::fun("", &["y"], Node::multiply(Node::variable("y"), Node::variable("x")));
+        let statement = Node::sequence(
+            Node::sequence(Node::assign("x", Node::number(2)), Node::assign("apply", apply)),
+            Node::assign("result", Node::call_args(Node::variable("apply"), vec![lambda, Node::number(21)]))
+        );
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(42, env.get("result").value());
+    }
+
+    #[test]
+    fn test_simple_big_return() {
+        // function find(limit) { i = 0; while (1 == 1) { if (i * i > limit) { return i; } i = i + 1; } }
+        let find = Node::fun("find", &["limit"], Node::sequence(
+            Node::assign("i", Node::number(0)),
+            Node::while_node(
+                Node::eq(Node::number(1), Node::number(1)),
+                Node::sequence(
+                    Node::if_cond_else(
+                        Node::gt(Node::multiply(Node::variable("i"), Node::variable("i")), Node::variable("limit")),
+                        Node::return_node(Node::variable("i")),
+                        Node::donothing()),
+                    Node::assign("i", Node::add(Node::variable("i"), Node::number(1)))))));
+        let statement = Node::sequence(
+            Node::assign("find", find),
+            Node::sequence(
+                Node::assign("result", Node::call(Node::variable("find"), Node::number(50))),
+                Node::assign("after", Node::number(1))));
+        let mut env = Environment::new();
+        statement.evaluate(&mut env);
+        assert_eq!(8, env.get("result").value());
+        assert_eq!(1, env.get("after").value());
+    }
+
+    #[test]
+    fn test_simple_big_break_continue() {
+        // i = 0; sum = 0; while (true) { i = i + 1; if (i > 6) { break; } if (i == 3) { continue; } sum = sum + i; }
+        let statement = Node::sequence(
+            Node::sequence(Node::assign("i", Node::number(0)), Node::assign("sum", Node::number(0))),
+            Node::while_node(Node::boolean(true), Node::sequence(
+                Node::sequence(
+                    Node::assign("i", Node::add(Node::variable("i"), Node::number(1))),
+                    Node::if_cond_else(Node::gt(Node::variable("i"), Node::number(6)), Node::break_node(), Node::donothing())),
+                Node::sequence(
+                    Node::if_cond_else(Node::eq(Node::variable("i"), Node::number(3)), Node::continue_node(), Node::donothing()),
+                    Node::assign("sum", Node::add(Node::variable("sum"), Node::variable("i")))))));
+        let mut env = Environment::new();
+        assert_eq!(Node::donothing(), statement.evaluate(&mut env));
+        assert_eq!(7, env.get("i").value());
+        assert_eq!(18, env.get("sum").value());
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a loop")]
+    fn test_simple_big_break_outside_loop() {
+        let statement = Node::call_args(Node::fun("f", &[], Node::break_node()), vec![]);
+        statement.evaluate(&mut Environment::new());
+    }
+
     #[test]
     fn test_simple_big_function_recursive() {
-        let factor = Node::fun("factor", "x", Node::if_cond_else(
+        let factor = Node::fun("factor", &["x"], Node::if_cond_else(
                 Node::gt(Node::variable("x"), Node::number(1)),
                 Node::multiply(Node::variable("x"),
                                Node::call(Node::variable("factor"), Node::subtract(Node::variable("x"), Node::number(1)))),
@@ -308,11 +1574,11 @@ mod tests {
 
     #[test]
     fn test_get_free_vars() {
-        let x_add_y = Node::fun("addx", "x", Node::fun("addy", "y", Node::add(Node::variable("x"), Node::variable("y"))));
+        let x_add_y = Node::fun("addx", &["x"], Node::fun("addy", &["y"], Node::add(Node::variable("x"), Node::variable("y"))));
         let freevars = get_free_vars(&x_add_y).iter().cloned().collect::<Vec<String>>();
         assert!(freevars.is_empty());
 
-        let add_y = Node::fun("addy", "x", Node::add(Node::variable("x"), Node::variable("y")));
+        let add_y = Node::fun("addy", &["x"], Node::add(Node::variable("x"), Node::variable("y")));
         let freevars = get_free_vars(&add_y).iter().cloned().collect::<Vec<String>>();
         assert!(!freevars.is_empty());
         assert_eq!("y", &freevars[0]);
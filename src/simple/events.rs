@@ -0,0 +1,158 @@
+//! What a running machine does, as structured events for tools that show
+//! it, say a GUI stepping through a program or a visualizer of its
+//! reductions, so that they need not scrape the text of a trace.
+//!
+//! The events are a stable integration surface, versioned by `VERSION`.
+//! Within a version, events and fields may be added, which is why the
+//! enum is `non_exhaustive`, but none are removed, renamed or given a
+//! different meaning, and the rule names of `Reduced` stay the same.
+//! Anything else bumps the version. With the `serde` feature, an event
+//! serializes as an object tagged with its name under `event`.
+
+use super::syntax::{Node, Span};
+use super::subst;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+
+/// The version of the events, bumped on any change beyond an addition.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug,Clone,PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event"))]
+#[non_exhaustive]
+pub enum MachineEvent {
+    /// The machine starts a step: a top-level statement, the condition or
+    /// an iteration of a top-level loop, or a reduction when small-step.
+    /// `step` counts the evaluation steps taken before it.
+    StepStarted { step: usize },
+    /// A node is reduced by the rule of its kind, say `add` or `call`,
+    /// where it was written if the program has spans.
+    Reduced { rule: String, span: Option<Span> },
+    /// A variable is assigned, just before its new value is stored.
+    Assigned { name: String },
+    /// A function is applied, named if it has a name, else printed.
+    Called { fun: String },
+    /// The program has finished, with its value printed.
+    Completed { value: String },
+}
+
+/// Called with each event of a machine, as it happens.
+pub type EventSink = Rc<dyn Fn(&MachineEvent)>;
+
+/// A sink sending the events to the receiver returned with it, which
+/// another thread may read from while the machine runs.
+pub fn channel() -> (EventSink, Receiver<MachineEvent>) {
+    let (sender, receiver) = mpsc::channel();
+    // a receiver dropped by the tool is no reason to stop the program
+    (Rc::new(move |event: &MachineEvent| { let _ = sender.send(event.clone()); }), receiver)
+}
+
+/// The name of the rule reducing `node`.
+pub fn rule(node: &Node) -> &'static str {
+    match *node {
+        Node::Number(_) => "number",
+        Node::Float(_) => "float",
+        Node::Str(_) => "string",
+        Node::Add(..) => "add",
+        Node::Subtract(..) => "subtract",
+        Node::Multiply(..) => "multiply",
+        Node::Negate(_) => "negate",
+        Node::Boolean(_) => "boolean",
+        Node::LT(..) => "less-than",
+        Node::EQ(..) => "equal",
+        Node::GT(..) => "greater-than",
+        Node::LE(..) => "less-or-equal",
+        Node::GE(..) => "greater-or-equal",
+        Node::NE(..) => "not-equal",
+        Node::And(..) => "and",
+        Node::Or(..) => "or",
+        Node::Not(_) => "not",
+        Node::Variable(_) => "variable",
+        Node::DoNothing => "do-nothing",
+        Node::IsDoNothing(_) => "is-do-nothing",
+        Node::Assign(..) => "assign",
+        Node::Global(..) => "global",
+        Node::If(..) => "if",
+        Node::Sequence(..) => "sequence",
+        Node::While(..) => "while",
+        Node::Match(..) => "match",
+        Node::For(..) => "for",
+        Node::Return(_) => "return",
+        Node::Break => "break",
+        Node::Continue => "continue",
+        Node::Pair(..) => "pair",
+        Node::Fst(_) => "fst",
+        Node::Snd(_) => "snd",
+        Node::Len(_) => "len",
+        Node::List(_) => "list",
+        Node::Index(..) => "index",
+        Node::Map(_) => "map",
+        Node::Fun(..) => "function",
+        Node::Closure(..) => "closure",
+        Node::Thunk(..) => "thunk",
+        Node::Delay(_) => "delay",
+        Node::Call(..) => "call",
+        Node::Builtin(_) => "builtin",
+        Node::Native(_) => "native",
+        Node::Compose(..) => "compose",
+        Node::Spanned(..) => "spanned",
+    }
+}
+
+/// The events of evaluating `node`, where the evaluation is at `span`.
+/// Small-step, a call is applied only once its function and arguments
+/// are values, rather than when it is first reduced.
+pub fn reduced(node: &Node, span: Option<Span>, small_step: bool) -> Vec<MachineEvent> {
+    // a spanned node only says where the node inside it was written
+    if let Node::Spanned(..) = *node {
+        return Vec::new();
+    }
+    let mut events = vec![MachineEvent::Reduced { rule: rule(node).to_string(), span }];
+    if let Node::Call(ref closure, ref args) = *node {
+        if !small_step || (subst::is_value(closure) && args.iter().all(|arg| subst::is_value(arg))) {
+            let fun = match *closure.unspanned() {
+                Node::Variable(ref name) => name.clone(),
+                ref other => other.function_name().map_or_else(|| other.to_string(), str::to_string),
+            };
+            events.push(MachineEvent::Called { fun });
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel() {
+        let (sink, receiver) = channel();
+        sink(&MachineEvent::Assigned { name: "x".to_string() });
+        assert_eq!(Ok(MachineEvent::Assigned { name: "x".to_string() }), receiver.try_recv());
+        drop(receiver);
+        sink(&MachineEvent::StepStarted { step: 0 });
+    }
+
+    #[test]
+    fn test_reduced() {
+        let call = Node::Call(Node::variable("f"), vec![Node::add(Node::number(1), Node::number(2))]);
+        assert_eq!(vec![MachineEvent::Reduced { rule: "call".to_string(), span: None },
+                        MachineEvent::Called { fun: "f".to_string() }],
+                   reduced(&call, None, false));
+        // not applied yet while its arguments are reduced
+        assert_eq!(1, reduced(&call, None, true).len());
+        assert!(reduced(&Node::Spanned(Span { line: 1, col: 1, end_line: 1, end_col: 2 }, Node::number(1)), None, false).is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let event = MachineEvent::Called { fun: "fact".to_string() };
+        assert_eq!(r#"{"event":"Called","fun":"fact"}"#, ::serde_json::to_string(&event).unwrap());
+    }
+}
@@ -0,0 +1,139 @@
+//! Evaluation as a `Future`, for embedders running on an async executor.
+//! The program is stepped a bounded number of steps per poll, so that a
+//! long program yields to the other tasks of the executor instead of
+//! holding its thread. Small-step, a step is a reduction; big-step, it is
+//! a top-level statement or loop iteration, so a long call holds the
+//! thread until it returns.
+//!
+//! Nodes are reference counted, so the future is not `Send`: spawn it on
+//! a local task set.
+
+use super::syntax::Node;
+use super::machine::{self, Machine};
+use super::evaluate::RuntimeError;
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Steps taken per poll unless set with `with_steps_per_poll`.
+pub const STEPS_PER_POLL: usize = 1000;
+
+/// A program being evaluated; resolves to its value.
+pub struct Evaluation {
+    machine: Machine,
+    steps_per_poll: usize,
+}
+
+impl Evaluation {
+    /// Evaluate the program of `machine` with the semantics it was built
+    /// with. Its limits, cancellation and hooks apply.
+    pub fn new(machine: Machine) -> Evaluation {
+        Evaluation { machine, steps_per_poll: STEPS_PER_POLL }
+    }
+
+    pub fn with_steps_per_poll(mut self, steps: usize) -> Evaluation {
+        self.steps_per_poll = steps.max(1);
+        self
+    }
+
+    /// The machine, with the environment the program has built so far.
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+/// Evaluate `program` in a fresh machine.
+pub fn evaluate(program: Box<Node>) -> Evaluation {
+    Evaluation::new(Machine::new_with_empty_env(program))
+}
+
+impl Future for Evaluation {
+    type Output = Result<Box<Node>, RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Box<Node>, RuntimeError>> {
+        let this = self.get_mut();
+        for _ in 0..this.steps_per_poll {
+            // a program going wrong fails the future, as it fails `run`
+            let machine = &mut this.machine;
+            match panic::catch_unwind(AssertUnwindSafe(|| machine.try_step())) {
+                Ok(Ok(Some(_))) => (),
+                Ok(Ok(None)) => return Poll::Ready(Ok(this.machine.stepped_value())),
+                Ok(Err(stopped)) => return Poll::Ready(Err(stopped.into())),
+                Err(payload) => return Poll::Ready(Err(machine::failure(payload))),
+            }
+        }
+        // there is more to do: ask to be polled again right away
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::evaluate::Stopped;
+    use simple::machine::Semantics;
+
+    use std::task::Waker;
+
+    /// Poll `future` to completion, counting the polls.
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return (output, polls);
+            }
+        }
+    }
+
+    #[test]
+    fn test_future() {
+        let program = parser::parse("def fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }
+            x = 0; while (x < 50) { x = x + 1; }
+            y = fact(5);").unwrap();
+        let mut evaluation = evaluate(program.clone()).with_steps_per_poll(10);
+        let (value, polls) = block_on(&mut evaluation);
+        assert_eq!(Node::donothing(), value.unwrap());
+        assert!(polls > 10);
+        assert_eq!((Node::number(50), Node::number(120)),
+                   (evaluation.machine().environment.get("x"), evaluation.machine().environment.get("y")));
+        let (value, polls) = block_on(evaluate(Node::number(3)));
+        assert_eq!((Node::number(3), 1), (value.unwrap(), polls));
+        let (value, _) = block_on(evaluate(Node::multiply(Node::number(6), Node::add(Node::number(3), Node::number(4)))));
+        assert_eq!(Node::number(42), value.unwrap());
+        let machine = Machine::new_with_empty_env(program.clone()).with_step_limit(100);
+        let (value, _) = block_on(Evaluation::new(machine));
+        assert!(matches!(value, Err(RuntimeError::Stopped(Stopped::Timeout(_)))));
+        // small-step, a poll takes reductions rather than statements
+        let machine = Machine::new_with_empty_env(program).with_semantics(Semantics::SmallStep);
+        let (value, small_polls) = block_on(Evaluation::new(machine).with_steps_per_poll(10));
+        assert_eq!(Node::donothing(), value.unwrap());
+        assert!(small_polls > polls);
+    }
+
+    #[test]
+    fn test_future_error() {
+        let (value, _) = block_on(evaluate(parser::parse("x = 1; y = x + z;").unwrap()));
+        match value {
+            Err(RuntimeError::Failed(message)) => assert!(message.contains('z'), "{}", message),
+            other => panic!("not a failure: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_future_loop() {
+        let program = parser::parse("s = 0; i = 0;
+            while (true) { i = i + 1; if (i > 10) { break; } if (i == 5) { continue; } s = s + i; }
+            t = delay (s * 2);
+            s").unwrap();
+        let mut evaluation = evaluate(program).with_steps_per_poll(3);
+        let (value, polls) = block_on(&mut evaluation);
+        assert_eq!(Node::number(50), value.unwrap());
+        assert!(polls > 3);
+    }
+}
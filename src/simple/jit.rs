@@ -0,0 +1,495 @@
+//! Running the part of the language a machine word holds as native code,
+//! with the `jit` feature: integers, booleans, arithmetic, comparisons and
+//! logic, assignments, `if`, `while` and `for` with `break` and `continue`,
+//! the part `wasm` compiles. The program is lowered to Cranelift IR as a
+//! function over an array of slots, two per variable: its value and
+//! whether it is bound. Variables the environment binds to integers or
+//! booleans are read from it first, and those the program binds are
+//! written back to it at the end.
+//!
+//! Integer overflow fails the run, as it does in the interpreter, but
+//! leaves the environment as it was. The limits, cancellation and hooks
+//! of a machine do not apply.
+
+use super::syntax::Node;
+use super::environment::Environment;
+use super::reduce;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, Function, InstBuilder, MemFlags, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+
+/// What the compiled function returns when an operation overflowed.
+const OVERFLOW: i64 = 1;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Type {
+    Int,
+    Bool,
+}
+
+impl Type {
+    fn ir(self) -> types::Type {
+        match self {
+            Type::Int => types::I64,
+            Type::Bool => types::I8,
+        }
+    }
+
+    fn node(self, slot: i64) -> Box<Node> {
+        match self {
+            Type::Int => Node::number(slot),
+            Type::Bool => Node::boolean(slot != 0),
+        }
+    }
+}
+
+fn unsupported(node: &Node) -> String {
+    format!("Cannot compile `{}` to native code, only integers, booleans and loops", node)
+}
+
+/// The names `node` reads or assigns.
+fn names(node: &Node, names: &mut BTreeSet<String>) {
+    match *node {
+        Node::Variable(ref name) | Node::Assign(ref name, _) => { names.insert(name.clone()); }
+        _ => (),
+    }
+    for child in node.children() {
+        self::names(child, names);
+    }
+}
+
+/// Where the compiled function keeps what: the value of the program in
+/// slot 0, then the value and bound flag of each variable.
+struct Layout {
+    variables: Vec<(String, Type)>,
+    value: Option<Type>,
+}
+
+struct Lowering<'a, 'b> {
+    builder: &'b mut FunctionBuilder<'a>,
+    slots: Value,
+    names: HashMap<String, usize>,
+    variables: Vec<(String, Type)>,
+    env: &'b Environment,
+    /// The blocks `break` and `continue` jump to, of each loop around.
+    loops: Vec<(Block, Block)>,
+    overflow: Option<Block>,
+}
+
+impl<'a, 'b> Lowering<'a, 'b> {
+    fn value_var(index: usize) -> Variable {
+        Variable::from_u32(2 * index as u32)
+    }
+
+    fn bound_var(index: usize) -> Variable {
+        Variable::from_u32(2 * index as u32 + 1)
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) -> usize {
+        let index = self.variables.len();
+        self.builder.declare_var(Lowering::value_var(index), ty.ir());
+        self.builder.declare_var(Lowering::bound_var(index), types::I8);
+        self.variables.push((name.to_string(), ty));
+        self.names.insert(name.to_string(), index);
+        index
+    }
+
+    fn offset(slot: usize) -> i32 {
+        (slot * mem::size_of::<i64>()) as i32
+    }
+
+    /// Read the variables of `node` the environment holds integers or
+    /// booleans for.
+    fn inputs(&mut self, node: &Node) {
+        let mut mentioned = BTreeSet::new();
+        names(node, &mut mentioned);
+        for name in mentioned {
+            let ty = match self.env.lookup(&name).map(|value| *value) {
+                Some(Node::Number(_)) => Type::Int,
+                Some(Node::Boolean(_)) => Type::Bool,
+                _ => continue,
+            };
+            let index = self.declare(&name, ty);
+            let value = self.builder.ins().load(types::I64, MemFlags::trusted(), self.slots, Lowering::offset(1 + 2 * index));
+            let value = if ty == Type::Bool { self.builder.ins().ireduce(types::I8, value) } else { value };
+            let bound = self.builder.ins().iconst(types::I8, 1);
+            self.builder.def_var(Lowering::value_var(index), value);
+            self.builder.def_var(Lowering::bound_var(index), bound);
+        }
+    }
+
+    /// Store the variables and `value` into the slots.
+    fn outputs(&mut self, value: Option<(Value, Type)>) {
+        if let Some((value, ty)) = value {
+            self.store(value, ty, 0);
+        }
+        for index in 0..self.variables.len() {
+            let value = self.builder.use_var(Lowering::value_var(index));
+            let ty = self.variables[index].1;
+            self.store(value, ty, 1 + 2 * index);
+            let bound = self.builder.use_var(Lowering::bound_var(index));
+            self.store(bound, Type::Bool, 2 + 2 * index);
+        }
+    }
+
+    fn store(&mut self, value: Value, ty: Type, slot: usize) {
+        let value = if ty == Type::Bool { self.builder.ins().uextend(types::I64, value) } else { value };
+        self.builder.ins().store(MemFlags::trusted(), value, self.slots, Lowering::offset(slot));
+    }
+
+    /// Go on in a new block, once the current one is left.
+    fn fresh(&mut self) {
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+    }
+
+    /// Stop with OVERFLOW if `overflowed`.
+    fn check(&mut self, overflowed: Value) {
+        let builder = &mut self.builder;
+        let overflow = *self.overflow.get_or_insert_with(|| builder.create_block());
+        let next = self.builder.create_block();
+        self.builder.ins().brif(overflowed, overflow, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn operands(&mut self, l: &Node, r: &Node, ty: Type, node: &Node) -> Result<(Value, Value), String> {
+        let (l, lt) = self.expr(l)?;
+        let (r, rt) = self.expr(r)?;
+        if lt != ty || rt != ty {
+            return Err(format!("Cannot compile `{}` to native code, its operands are not {}", node,
+                               if ty == Type::Int { "integers" } else { "booleans" }));
+        }
+        Ok((l, r))
+    }
+
+    fn arithmetic(&mut self, l: &Node, r: &Node, node: &Node) -> Result<(Value, Type), String> {
+        let (l, r) = self.operands(l, r, Type::Int, node)?;
+        let (value, overflowed) = match *node {
+            Node::Add(..) => self.builder.ins().sadd_overflow(l, r),
+            Node::Subtract(..) => self.builder.ins().ssub_overflow(l, r),
+            _ => self.builder.ins().smul_overflow(l, r),
+        };
+        self.check(overflowed);
+        Ok((value, Type::Int))
+    }
+
+    fn compare(&mut self, l: &Node, r: &Node, cc: IntCC, node: &Node) -> Result<(Value, Type), String> {
+        let (l, r) = self.operands(l, r, Type::Int, node)?;
+        Ok((self.builder.ins().icmp(cc, l, r), Type::Bool))
+    }
+
+    fn expr(&mut self, node: &Node) -> Result<(Value, Type), String> {
+        match *node {
+            Node::Number(value) => Ok((self.builder.ins().iconst(types::I64, value), Type::Int)),
+            Node::Boolean(value) => Ok((self.builder.ins().iconst(types::I8, value as i64), Type::Bool)),
+            Node::Variable(ref name) => match self.names.get(name) {
+                Some(&index) => Ok((self.builder.use_var(Lowering::value_var(index)), self.variables[index].1)),
+                None if self.env.lookup(name).is_some() =>
+                    Err(format!("Cannot compile `{}` to native code, it is neither an integer nor a boolean", name)),
+                None => Err(format!("Variable {} not found", name)),
+            },
+            Node::Negate(ref value) => match self.expr(value)? {
+                (value, Type::Int) => {
+                    let zero = self.builder.ins().iconst(types::I64, 0);
+                    let (value, overflowed) = self.builder.ins().ssub_overflow(zero, value);
+                    self.check(overflowed);
+                    Ok((value, Type::Int))
+                }
+                _ => Err(unsupported(node)),
+            },
+            Node::Not(ref value) => match self.expr(value)? {
+                (value, Type::Bool) => Ok((self.builder.ins().bxor_imm(value, 1), Type::Bool)),
+                _ => Err(unsupported(node)),
+            },
+            Node::Add(ref l, ref r) | Node::Subtract(ref l, ref r) | Node::Multiply(ref l, ref r) => self.arithmetic(l, r, node),
+            Node::LT(ref l, ref r) => self.compare(l, r, IntCC::SignedLessThan, node),
+            Node::GT(ref l, ref r) => self.compare(l, r, IntCC::SignedGreaterThan, node),
+            Node::LE(ref l, ref r) => self.compare(l, r, IntCC::SignedLessThanOrEqual, node),
+            Node::GE(ref l, ref r) => self.compare(l, r, IntCC::SignedGreaterThanOrEqual, node),
+            Node::EQ(ref l, ref r) => self.compare(l, r, IntCC::Equal, node),
+            Node::NE(ref l, ref r) => self.compare(l, r, IntCC::NotEqual, node),
+            // operands have no effects, so both are evaluated
+            Node::And(ref l, ref r) => {
+                let (l, r) = self.operands(l, r, Type::Bool, node)?;
+                Ok((self.builder.ins().band(l, r), Type::Bool))
+            }
+            Node::Or(ref l, ref r) => {
+                let (l, r) = self.operands(l, r, Type::Bool, node)?;
+                Ok((self.builder.ins().bor(l, r), Type::Bool))
+            }
+            Node::Spanned(_, ref node) => self.expr(node),
+            _ => Err(unsupported(node)),
+        }
+    }
+
+    fn condition(&mut self, cond: &Node) -> Result<Value, String> {
+        match self.expr(cond)? {
+            (value, Type::Bool) => Ok(value),
+            _ => Err(format!("Cannot compile `{}` to native code, the condition is not a boolean", cond)),
+        }
+    }
+
+    fn is_statement(node: &Node) -> bool {
+        match *node {
+            Node::DoNothing | Node::Sequence(..) | Node::Assign(..) | Node::If(..) | Node::While(..) | Node::For(..) |
+                Node::Break | Node::Continue => true,
+            Node::Spanned(_, ref node) => Lowering::is_statement(node),
+            _ => false,
+        }
+    }
+
+    /// Lower `node`, the last statement of the program, returning its
+    /// value if it has one.
+    fn last(&mut self, node: &Node) -> Result<Option<(Value, Type)>, String> {
+        match *node {
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let cond = self.condition(cond)?;
+                let (then, otherwise, merge) = (self.builder.create_block(), self.builder.create_block(), self.builder.create_block());
+                self.builder.ins().brif(cond, then, &[], otherwise, &[]);
+                // the value of each branch goes to the merge as its parameter
+                self.builder.switch_to_block(then);
+                let consequence = self.last(consequence)?;
+                if let Some((value, ty)) = consequence {
+                    self.builder.append_block_param(merge, ty.ir());
+                    self.builder.ins().jump(merge, &[value]);
+                } else {
+                    self.builder.ins().jump(merge, &[]);
+                }
+                self.builder.switch_to_block(otherwise);
+                let alternative = self.last(alternative)?;
+                match (consequence, alternative) {
+                    (Some((_, l)), Some((value, r))) if l == r => { self.builder.ins().jump(merge, &[value]); }
+                    (None, None) => { self.builder.ins().jump(merge, &[]); }
+                    _ => return Err(format!("Cannot compile `{}` to native code, its branches have no value of one type", node)),
+                }
+                self.builder.switch_to_block(merge);
+                Ok(consequence.map(|(_, ty)| (self.builder.block_params(merge)[0], ty)))
+            }
+            Node::Spanned(_, ref node) => self.last(node),
+            ref node if Lowering::is_statement(node) => {
+                self.statement(node)?;
+                Ok(None)
+            }
+            ref node => self.expr(node).map(Some),
+        }
+    }
+
+    fn statement(&mut self, node: &Node) -> Result<(), String> {
+        match *node {
+            Node::DoNothing => (),
+            Node::Sequence(ref head, ref more) => {
+                self.statement(head)?;
+                self.statement(more)?;
+            }
+            Node::Assign(ref name, ref expr) => {
+                let (value, ty) = self.expr(expr)?;
+                let index = match self.names.get(name) {
+                    Some(&index) if self.variables[index].1 == ty => index,
+                    Some(_) => return Err(format!("Variable {} is assigned both an integer and a boolean", name)),
+                    None => self.declare(name, ty),
+                };
+                let bound = self.builder.ins().iconst(types::I8, 1);
+                self.builder.def_var(Lowering::value_var(index), value);
+                self.builder.def_var(Lowering::bound_var(index), bound);
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let cond = self.condition(cond)?;
+                let (then, otherwise, merge) = (self.builder.create_block(), self.builder.create_block(), self.builder.create_block());
+                self.builder.ins().brif(cond, then, &[], otherwise, &[]);
+                self.builder.switch_to_block(then);
+                self.statement(consequence)?;
+                self.builder.ins().jump(merge, &[]);
+                self.builder.switch_to_block(otherwise);
+                self.statement(alternative)?;
+                self.builder.ins().jump(merge, &[]);
+                self.builder.switch_to_block(merge);
+            }
+            Node::While(ref cond, ref body) => self.repeat(cond, body, None)?,
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.statement(init)?;
+                self.repeat(cond, body, Some(step))?;
+            }
+            Node::Break | Node::Continue => {
+                let &(exit, next) = self.loops.last().ok_or_else(|| unsupported(node))?;
+                self.builder.ins().jump(if let Node::Break = *node { exit } else { next }, &[]);
+                self.fresh();
+            }
+            Node::Spanned(_, ref node) => self.statement(node)?,
+            _ => { self.expr(node)?; }
+        }
+        Ok(())
+    }
+
+    fn repeat(&mut self, cond: &Node, body: &Node, step: Option<&Node>) -> Result<(), String> {
+        let (header, inside, exit) = (self.builder.create_block(), self.builder.create_block(), self.builder.create_block());
+        // continuing takes the step, if any, before the condition
+        let next = if step.is_some() { self.builder.create_block() } else { header };
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+        let cond = self.condition(cond)?;
+        self.builder.ins().brif(cond, inside, &[], exit, &[]);
+        self.builder.switch_to_block(inside);
+        self.loops.push((exit, next));
+        let lowered = self.statement(body);
+        self.loops.pop();
+        lowered?;
+        self.builder.ins().jump(next, &[]);
+        if let Some(step) = step {
+            self.builder.switch_to_block(next);
+            self.statement(step)?;
+            self.builder.ins().jump(header, &[]);
+        }
+        self.builder.switch_to_block(exit);
+        Ok(())
+    }
+}
+
+/// Lower `program` into `func`, which takes the address of the slots and
+/// returns 0, or OVERFLOW.
+fn lower(program: &Node, env: &Environment, func: &mut Function) -> Result<Layout, String> {
+    let mut context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(func, &mut context);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let slots = builder.block_params(entry)[0];
+    let mut lowering = Lowering {
+        builder: &mut builder,
+        slots,
+        names: HashMap::new(),
+        variables: Vec::new(),
+        env,
+        loops: Vec::new(),
+        overflow: None,
+    };
+    lowering.inputs(program);
+    let statements = reduce::statements(program);
+    let mut value = None;
+    for (index, statement) in statements.iter().enumerate() {
+        if index + 1 == statements.len() {
+            value = lowering.last(statement)?;
+        } else {
+            lowering.statement(statement)?;
+        }
+    }
+    lowering.outputs(value);
+    let ok = lowering.builder.ins().iconst(types::I32, 0);
+    lowering.builder.ins().return_(&[ok]);
+    if let Some(overflow) = lowering.overflow {
+        lowering.builder.switch_to_block(overflow);
+        let status = lowering.builder.ins().iconst(types::I32, OVERFLOW);
+        lowering.builder.ins().return_(&[status]);
+    }
+    let layout = Layout { variables: lowering.variables, value: value.map(|(_, ty)| ty) };
+    builder.seal_all_blocks();
+    builder.finalize();
+    Ok(layout)
+}
+
+/// Run `program` as native code against `env`, returning the value of its
+/// last statement.
+pub fn run(program: &Node, env: &mut Environment) -> Result<Box<Node>, String> {
+    let builder = JITBuilder::new(default_libcall_names()).map_err(|e| e.to_string())?;
+    let mut module = JITModule::new(builder);
+    let mut context = module.make_context();
+    let pointer = module.target_config().pointer_type();
+    context.func.signature.params.push(AbiParam::new(pointer));
+    context.func.signature.returns.push(AbiParam::new(types::I32));
+    let layout = lower(program, env, &mut context.func)?;
+    let id = module.declare_function("run", Linkage::Export, &context.func.signature).map_err(|e| e.to_string())?;
+    module.define_function(id, &mut context).map_err(|e| e.to_string())?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().map_err(|e| e.to_string())?;
+
+    let mut slots = vec![0i64; 1 + 2 * layout.variables.len()];
+    for (index, (name, _)) in layout.variables.iter().enumerate() {
+        match env.lookup(name).map(|value| *value) {
+            Some(Node::Number(value)) => slots[1 + 2 * index] = value,
+            Some(Node::Boolean(value)) => slots[1 + 2 * index] = value as i64,
+            _ => (),
+        }
+    }
+    // the function was compiled for this signature just above
+    let code = module.get_finalized_function(id);
+    let status = unsafe {
+        let function: extern "C" fn(*mut i64) -> i32 = mem::transmute(code);
+        function(slots.as_mut_ptr())
+    };
+    unsafe { module.free_memory() };
+    if status as i64 == OVERFLOW {
+        return Err("Integer overflow".to_string());
+    }
+    for (index, (name, ty)) in layout.variables.iter().enumerate() {
+        if slots[2 + 2 * index] != 0 {
+            env.assign(name, ty.node(slots[1 + 2 * index]));
+        }
+    }
+    Ok(layout.value.map_or_else(Node::donothing, |ty| ty.node(slots[0])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::machine::Machine;
+
+    /// The bindings `source` leaves, run by the interpreter and natively,
+    /// from an environment binding `n`.
+    fn both(source: &str) -> (String, String) {
+        let program = parser::parse(source).unwrap();
+        let mut interpreted = Machine::new_with_empty_env(program.clone());
+        let mut native = Machine::new_with_empty_env(program);
+        for machine in [&mut interpreted, &mut native] {
+            machine.environment.add("n", Node::number(27));
+        }
+        let show = |value: Box<Node>, machine: &Machine| {
+            let bindings: Vec<String> = machine.environment.iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+            format!("{}; {}", value, bindings.join(", "))
+        };
+        (show(interpreted.run().unwrap(), &interpreted), show(native.run_jit().unwrap(), &native))
+    }
+
+    #[test]
+    fn test_agrees_with_interpreter() {
+        let programs = [
+            "a = 1071; b = 462; while (b != 0) { t = b; while (not (a < b)) { a = a - b; } b = a; a = t; } a",
+            "total = 0; for (i = 0; i < 100; i = i + 1) { if (i == 3 or i == 5) { continue; } if (i * i > 50) { break; } \
+             total = total + i * -1; }",
+            "steps = 0; while (n != 1) { m = n; while (m > 1) { m = m - 2; } \
+             if (m == 1) { n = 3 * n + 1; } else { h = 0; while (h + h < n) { h = h + 1; } n = h; } steps = steps + 1; }",
+            "x = 5; big = x >= 5 and not (x <= 4) and (x != 6 or false); if (big) { x * 2 } else { x }",
+            "if (n > 100) { small = false; } n - 1",
+        ];
+        for source in programs.iter() {
+            let (interpreted, native) = both(source);
+            assert_eq!(interpreted, native, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let error = |source: &str| {
+            let mut machine = Machine::new_with_empty_env(parser::parse(source).unwrap());
+            machine.environment.add("s", Node::string("text"));
+            machine.run_jit().unwrap_err().to_string()
+        };
+        assert_eq!("Variable y not found", error("x = y;"));
+        assert_eq!("Variable x is assigned both an integer and a boolean", error("x = 1; x = true;"));
+        assert!(error("x = s;").contains("it is neither an integer nor a boolean"));
+        assert!(error("def f() { 1 } f()").contains("only integers, booleans and loops"));
+        assert!(error("if (true) { 1 } else { false }").contains("its branches have no value of one type"));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut machine = Machine::new_with_empty_env(parser::parse("x = 1; while (true) { x = x * 2; }").unwrap());
+        assert_eq!("Integer overflow", machine.run_jit().unwrap_err().to_string());
+        assert_eq!(None, machine.environment.lookup("x"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Compiling programs to JavaScript, after the denotational semantics of
+//! Understanding Computation: where the book turns every node into Ruby
+//! source for a proc from an environment to the node's meaning, `to_js`
+//! turns it into the source of a JavaScript function `e => ...`, built
+//! from the functions of its sub-expressions.
+//!
+//! The functions run on a small runtime, `RUNTIME`, that represents the
+//! values of simple code and mirrors the interpreter with its defaults:
+//! arguments passed by value and functions binding their free variables
+//! when they are made. Integers do not overflow, being BigInts, where the
+//! interpreter fails on overflow, and deep recursion is bounded by the
+//! stack of the JavaScript engine, tail calls included.
+
+use super::syntax::{Node, Pattern};
+use super::environment::{Capture, Environment};
+use super::evaluate;
+
+/// The runtime compiled programs call into, defining `$`.
+pub const RUNTIME: &str = include_str!("js_runtime.js");
+
+/// `text` as a JavaScript string literal.
+fn string(text: &str) -> String {
+    // Rust escapes are JavaScript ones, `\u{..}` included
+    format!("{:?}", text)
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else {
+        format!("({:?})", value)
+    }
+}
+
+fn strings(names: &[String]) -> String {
+    format!("[{}]", names.iter().map(|name| string(name)).collect::<Vec<_>>().join(", "))
+}
+
+fn pattern(pattern: &Pattern) -> String {
+    match *pattern {
+        Pattern::Wildcard => "{kind: \"wildcard\"}".to_string(),
+        Pattern::Bind(ref name) => format!("{{kind: \"bind\", name: {}}}", string(name)),
+        Pattern::Number(value) => format!("{{kind: \"number\", value: {}n}}", value),
+        Pattern::Boolean(value) => format!("{{kind: \"boolean\", value: {}}}", value),
+        Pattern::Str(ref value) => format!("{{kind: \"string\", value: {}}}", string(value)),
+        Pattern::Pair(ref fst, ref snd) => format!("{{kind: \"pair\", fst: {}, snd: {}}}", self::pattern(fst), self::pattern(snd)),
+        Pattern::List(ref items) => format!("{{kind: \"list\", items: [{}]}}",
+                                            items.iter().map(self::pattern).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// The scope `env` as a JavaScript expression making it again.
+fn scope(env: &Environment) -> String {
+    if env.capture == Capture::Shared {
+        panic!("Closures over shared scopes cannot be compiled to JavaScript");
+    }
+    let bindings: Vec<String> = env.iter()
+        .map(|(name, value)| format!("[{}, ({})(null)]", string(&name), value.to_js()))
+        .collect();
+    let parent = env.parent.as_ref().map_or("null".to_string(), |parent| scope(parent));
+    format!("$.scope([{}], {})", bindings.join(", "), parent)
+}
+
+/// The top-level statements of `program` as a JavaScript array of
+/// statement functions, for `$.run` and `$.main`.
+fn program(program: &Node) -> String {
+    let statements: Vec<String> = program.statements().into_iter().map(Node::to_js).collect();
+    format!("[\n    {},\n]", statements.join(",\n    "))
+}
+
+/// A script running `program` and printing its result, as `simple run`
+/// does but without checking expectations; it runs in Node.js, or in a
+/// browser with the output going to the console and the input read with
+/// `prompt`.
+pub fn compile(program: &Node) -> String {
+    format!("{}\n$.main({});\n", RUNTIME, self::program(program))
+}
+
+impl Node {
+    /// The source of a JavaScript function from an environment to what
+    /// this node evaluates to in it.
+    pub fn to_js(&self) -> String {
+        let unary = |op: &str, node: &Node| format!("e => {}(({})(e))", op, node.to_js());
+        let binary = |op: &str, l: &Node, r: &Node| format!("e => {}(({})(e), ({})(e))", op, l.to_js(), r.to_js());
+        match *self {
+            Node::Number(value) => format!("e => {}n", value),
+            Node::Float(value) => format!("e => {}", float(value)),
+            Node::Str(ref value) => format!("e => {}", string(value)),
+            Node::Boolean(value) => format!("e => {}", value),
+            Node::DoNothing => "e => $.NOTHING".to_string(),
+            Node::IsDoNothing(ref node) => format!("e => ({})(e) === $.NOTHING", node.to_js()),
+            Node::Add(ref l, ref r) => binary("$.add", l, r),
+            Node::Subtract(ref l, ref r) => format!("e => $.arithmetic(({})(e), ({})(e), (l, r) => l - r)", l.to_js(), r.to_js()),
+            Node::Multiply(ref l, ref r) => format!("e => $.arithmetic(({})(e), ({})(e), (l, r) => l * r)", l.to_js(), r.to_js()),
+            Node::Negate(ref node) => unary("$.negate", node),
+            Node::LT(ref l, ref r) => format!("e => $.compare(({})(e), ({})(e)) === -1", l.to_js(), r.to_js()),
+            Node::GT(ref l, ref r) => format!("e => $.compare(({})(e), ({})(e)) === 1", l.to_js(), r.to_js()),
+            Node::LE(ref l, ref r) => format!("e => [-1, 0].includes($.compare(({})(e), ({})(e)))", l.to_js(), r.to_js()),
+            Node::GE(ref l, ref r) => format!("e => [1, 0].includes($.compare(({})(e), ({})(e)))", l.to_js(), r.to_js()),
+            Node::EQ(ref l, ref r) => binary("$.eq", l, r),
+            Node::NE(ref l, ref r) => format!("e => !$.eq(({})(e), ({})(e))", l.to_js(), r.to_js()),
+            Node::And(ref l, ref r) => format!("e => $.cond(({})(e)) && $.cond(({})(e))", l.to_js(), r.to_js()),
+            Node::Or(ref l, ref r) => format!("e => $.cond(({})(e)) || $.cond(({})(e))", l.to_js(), r.to_js()),
+            Node::Not(ref node) => format!("e => !$.cond(({})(e))", node.to_js()),
+            Node::Variable(ref name) => format!("e => $.get(e, {})", string(name)),
+            Node::Assign(ref name, ref expr) => format!("e => $.assign(e, {}, ({})(e))", string(name), expr.to_js()),
+            Node::Global(ref name, ref expr) => format!("e => $.global(e, {}, ({})(e))", string(name), expr.to_js()),
+            Node::If(ref cond, ref consequence, ref alternative) =>
+                format!("e => $.cond(({})(e)) ? ({})(e) : ({})(e)", cond.to_js(), consequence.to_js(), alternative.to_js()),
+            Node::Sequence(..) => {
+                let statements: Vec<String> = self.statements().into_iter().map(Node::to_js).collect();
+                format!("e => $.seq(e, [{}])", statements.join(", "))
+            }
+            Node::While(ref cond, ref body) => format!("e => $.loop(e, {}, {})", cond.to_js(), body.to_js()),
+            Node::For(ref init, ref cond, ref step, ref body) =>
+                format!("e => $.forLoop(e, {}, {}, {}, {})", init.to_js(), cond.to_js(), step.to_js(), body.to_js()),
+            Node::Match(ref value, ref arms) => {
+                let arms: Vec<String> = arms.iter().map(|(p, body)| format!("[{}, {}]", pattern(p), body.to_js())).collect();
+                format!("e => $.match(e, ({})(e), [{}])", value.to_js(), arms.join(", "))
+            }
+            Node::Return(ref value) => format!("e => new $.Return(({})(e))", value.to_js()),
+            Node::Break => "e => $.BREAK".to_string(),
+            Node::Continue => "e => $.CONTINUE".to_string(),
+            Node::Pair(ref fst, ref snd) => format!("e => new $.Pair(({})(e), ({})(e))", fst.to_js(), snd.to_js()),
+            Node::Fst(ref pair) => format!("e => $.call(e, [({})(e)], new $.Builtin(\"fst\"))", pair.to_js()),
+            Node::Snd(ref pair) => format!("e => $.call(e, [({})(e)], new $.Builtin(\"snd\"))", pair.to_js()),
+            Node::Len(ref node) => format!("e => $.call(e, [({})(e)], new $.Builtin(\"len\"))", node.to_js()),
+            Node::List(ref items) => {
+                let items: Vec<String> = items.iter().map(|item| format!("({})(e)", item.to_js())).collect();
+                format!("e => [{}]", items.join(", "))
+            }
+            Node::Index(ref list, ref index) => binary("$.index", list, index),
+            Node::Map(ref entries) => {
+                let entries: Vec<String> = super::syntax::sorted(entries).into_iter()
+                    .map(|(key, value)| format!("[{}, ({})(e)]", string(key), value.to_js())).collect();
+                format!("e => new Map([{}])", entries.join(", "))
+            }
+            Node::Fun(ref name, ref params, ref body) => {
+                let mut free: Vec<String> = evaluate::get_free_vars(self).into_iter().collect();
+                free.sort();
+                format!("e => $.fun(e, {}, {}, {}, {}, {})", string(name), strings(params), strings(&free),
+                        body.to_js(), string(&self.to_string()))
+            }
+            Node::Closure(ref env, ref fun) => match **fun {
+                Node::Fun(ref name, ref params, ref body) =>
+                    format!("e => new $.Closure({}, {}, {}, {}, {})", scope(env), string(name), strings(params),
+                            body.to_js(), string(&fun.to_string())),
+                _ => panic!("Closure not contain function: {}", fun),
+            },
+            Node::Thunk(ref env, ref expr, ref memo, eager) =>
+                format!("e => new $.Thunk({}, {}, {}, {}, {})", scope(env), expr.to_js(), memo.is_some(), eager,
+                        string(&expr.to_string())),
+            Node::Delay(ref expr) =>
+                format!("e => new $.Thunk(e.clone(), {}, true, false, {})", expr.to_js(), string(&expr.to_string())),
+            Node::Call(ref callee, ref args) => {
+                let args: Vec<String> = args.iter().map(|arg| format!("({})(e)", arg.to_js())).collect();
+                format!("e => $.call(e, [{}], ({})(e))", args.join(", "), callee.to_js())
+            }
+            Node::Builtin(ref name) => format!("e => new $.Builtin({})", string(name)),
+            Node::Native(ref native) => panic!("Native {} is a host function and cannot be compiled to JavaScript", native.name),
+            Node::Compose(ref outer, ref inner) => binary("$.compose", outer, inner),
+            Node::Spanned(_, ref node) => node.to_js(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::{self, Arity};
+    use simple::builtin;
+    use simple::machine::Machine;
+
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    /// What node prints running `script`, or `None` without node.
+    fn node(script: &str) -> Option<String> {
+        let mut child = Command::new("node").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().ok()?;
+        child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", script);
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_to_js() {
+        let node = Node::add(Node::number(1), Node::variable("x"));
+        assert_eq!("e => $.add((e => 1n)(e), (e => $.get(e, \"x\"))(e))", node.to_js());
+        assert_eq!("e => (1.5)", Node::float(1.5).to_js());
+        assert_eq!("e => \"say \\\"hi\\\"\\n\"", Node::string("say \"hi\"\n").to_js());
+    }
+
+    #[test]
+    fn test_runtime_builtins() {
+        for builtin in builtin::all() {
+            let (Arity::Exactly(arity) | Arity::AtLeast(arity)) = builtin.arity;
+            assert!(RUNTIME.contains(&format!("\n        {}: [{}, ", builtin.name, arity)),
+                    "{} is not in the runtime", builtin.name);
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let program = parser::parse("print(\"sum\") def f(n) { return n + 0.5; } x = f(2); y = [divmod(-7, 2), {\"k\": 1e20}];
+            r = (x, y);").unwrap();
+        if let Some(output) = node(&compile(&program)) {
+            assert_eq!("sum\n", output);
+        }
+        let program = parser::parse("def f(n) { if (n < 1) { 0 } else { 1 } } r = f(3) + f(0);").unwrap();
+        let script = format!("{}\nconst env = new $.Env(null, true);\n$.run({}, env);\nconsole.log($.show(env.vars.get(\"r\")));",
+                             RUNTIME, super::program(&program));
+        if let Some(output) = node(&script) {
+            assert_eq!("1\n", output);
+        }
+    }
+
+    #[test]
+    fn test_agrees_with_machine() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let mut paths: Vec<_> = fs::read_dir(dir.join("conformance")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        paths.push(dir.join("streams.simple"));
+        for path in paths {
+            let program = parser::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+            let mut machine = Machine::new_with_empty_env(program.clone());
+            machine.run().unwrap();
+            let expected: Vec<String> = machine.environment.iter()
+                .filter(|(_, value)| !value.is_function())
+                .map(|(name, value)| format!("{} = {}", name, value)).collect();
+            let script = format!("{}\nconst env = new $.Env(null, true);\n$.run({}, env);\n\
+                                  for (const name of [...env.vars.keys()].sort()) {{\n\
+                                  \x20   const value = env.vars.get(name);\n\
+                                  \x20   if (!$.BUILTINS.is_function[1](value)) console.log(`${{name}} = ${{$.show(value)}}`);\n\
+                                  }}\n", RUNTIME, super::program(&program));
+            match node(&script) {
+                Some(output) => assert_eq!(expected, output.lines().collect::<Vec<_>>(), "{}", path.display()),
+                None => return,
+            }
+        }
+    }
+}
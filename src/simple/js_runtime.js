@@ -0,0 +1,760 @@
+// The runtime of simple programs compiled to JavaScript by simple::js.
+//
+// Values are represented so that they stay apart as they do in the
+// interpreter: integers are BigInts and floats are numbers, strings and
+// booleans are themselves, lists are arrays and maps are Maps. Errors
+// are thrown with the messages the interpreter panics with.
+const $ = (() => {
+    "use strict";
+
+    const NOTHING = Object.freeze({});
+    const BREAK = Object.freeze({});
+    const CONTINUE = Object.freeze({});
+
+    class Pair {
+        constructor(fst, snd) { this.fst = fst; this.snd = snd; }
+    }
+
+    class Return {
+        constructor(value) { this.value = value; }
+    }
+
+    class Builtin {
+        constructor(name) { this.name = name; }
+    }
+
+    // `body` runs in a call frame; `source` is how the function prints.
+    class Closure {
+        constructor(env, name, params, body, source) {
+            this.env = env; this.name = name; this.params = params; this.body = body; this.source = source;
+        }
+    }
+
+    class Thunk {
+        constructor(env, expr, memo, eager, source) {
+            this.env = env; this.expr = expr; this.memo = memo; this.eager = eager; this.source = source;
+            this.forced = false; this.value = undefined;
+        }
+    }
+
+    // A scope of variables, nested in `parent`, as simple::environment.
+    class Env {
+        constructor(parent, toplevel) {
+            this.vars = new Map(); this.parent = parent; this.toplevel = toplevel; this.globals = new Map();
+        }
+
+        clone() {
+            const env = new Env(this.parent, this.toplevel);
+            env.vars = new Map(this.vars);
+            env.globals = new Map(this.globals);
+            return env;
+        }
+
+        lookup(name) {
+            for (let scope = this; scope; scope = scope.parent) {
+                if (scope.vars.has(name)) {
+                    return scope.vars.get(name);
+                }
+            }
+            return undefined;
+        }
+
+        get(name) {
+            const value = this.lookup(name);
+            if (value !== undefined) {
+                return value;
+            }
+            if (Object.prototype.hasOwnProperty.call(BUILTINS, name)) {
+                return new Builtin(name);
+            }
+            throw new Error(`Variable ${name} not found`);
+        }
+    }
+
+    function scope(bindings, parent) {
+        const env = new Env(parent, parent === null);
+        for (const [name, value] of bindings) {
+            env.vars.set(name, value);
+        }
+        return env;
+    }
+
+    // How values print, as Display does for nodes.
+    function show(value) {
+        if (typeof value === "bigint" || typeof value === "boolean") {
+            return String(value);
+        }
+        if (typeof value === "number") {
+            return showFloat(value);
+        }
+        if (typeof value === "string") {
+            return quote(value);
+        }
+        if (value === NOTHING) {
+            return "do-nothing";
+        }
+        if (value instanceof Pair) {
+            return `(${show(value.fst)}, ${show(value.snd)})`;
+        }
+        if (Array.isArray(value)) {
+            return `[${value.map(show).join(", ")}]`;
+        }
+        if (value instanceof Map) {
+            return `{${[...value.keys()].sort().map(key => `${quote(key)}: ${show(value.get(key))}`).join(", ")}}`;
+        }
+        if (value instanceof Builtin) {
+            return `builtin ${value.name}`;
+        }
+        if (value instanceof Closure) {
+            return `closure ${value.source}`;
+        }
+        if (value instanceof Thunk) {
+            return value.forced ? `thunk (${value.source}) => ${show(value.value)}` : `thunk (${value.source})`;
+        }
+        return String(value);
+    }
+
+    // Floats print as Rust debug-prints them: always with a fraction or
+    // an exponent, which is used from 1e16 up and below 1e-4.
+    function showFloat(value) {
+        if (Number.isNaN(value)) {
+            return "NaN";
+        }
+        if (!Number.isFinite(value)) {
+            return value > 0 ? "inf" : "-inf";
+        }
+        if (value === 0) {
+            return Object.is(value, -0) ? "-0.0" : "0.0";
+        }
+        const magnitude = Math.abs(value);
+        if (magnitude >= 1e16 || magnitude < 1e-4) {
+            return value.toExponential().replace("e+", "e");
+        }
+        const text = String(value);
+        return text.includes(".") ? text : text + ".0";
+    }
+
+    function quote(text) {
+        let quoted = "\"";
+        for (const c of text) {
+            const code = c.codePointAt(0);
+            if (c === "\"" || c === "\\") {
+                quoted += "\\" + c;
+            } else if (c === "\n") {
+                quoted += "\\n";
+            } else if (c === "\r") {
+                quoted += "\\r";
+            } else if (c === "\t") {
+                quoted += "\\t";
+            } else if (c === "\0") {
+                quoted += "\\0";
+            } else if (code < 0x20 || code === 0x7f) {
+                quoted += `\\u{${code.toString(16)}}`;
+            } else {
+                quoted += c;
+            }
+        }
+        return quoted + "\"";
+    }
+
+    function isSignal(value) {
+        return value instanceof Return || value === BREAK || value === CONTINUE;
+    }
+
+    function cond(value) {
+        if (typeof value !== "boolean") {
+            throw new Error(`Type cannot eval to boolean ${show(value)}`);
+        }
+        return value;
+    }
+
+    function int(value) {
+        if (typeof value !== "bigint") {
+            throw new Error(`Type has no value: ${show(value)}`);
+        }
+        return value;
+    }
+
+    function float(value) {
+        if (typeof value === "bigint") {
+            return Number(value);
+        }
+        if (typeof value !== "number") {
+            throw new Error(`Type has no value: ${show(value)}`);
+        }
+        return value;
+    }
+
+    function str(value) {
+        if (typeof value !== "string") {
+            throw new Error(`Type has no string value: ${show(value)}`);
+        }
+        return value;
+    }
+
+    function list(value) {
+        if (!Array.isArray(value)) {
+            throw new Error(`Type has no list value: ${show(value)}`);
+        }
+        return value;
+    }
+
+    function map(value) {
+        if (!(value instanceof Map)) {
+            throw new Error(`Type has no map value: ${show(value)}`);
+        }
+        return value;
+    }
+
+    // Two integers give an integer; a float on either side gives a float.
+    function arithmetic(l, r, op) {
+        if (typeof l === "bigint" && typeof r === "bigint") {
+            return op(l, r);
+        }
+        return op(float(l), float(r));
+    }
+
+    // -1, 0 or 1, or undefined when either side is NaN.
+    function compare(l, r) {
+        if (typeof l !== "bigint" || typeof r !== "bigint") {
+            [l, r] = [float(l), float(r)];
+            if (Number.isNaN(l) || Number.isNaN(r)) {
+                return undefined;
+            }
+        }
+        return l < r ? -1 : l > r ? 1 : 0;
+    }
+
+    function add(l, r) {
+        if (typeof l === "string" && typeof r === "string") {
+            return l + r;
+        }
+        return arithmetic(l, r, (l, r) => l + r);
+    }
+
+    // Numbers by value, pairs, lists and maps item by item; values of
+    // different kinds, or functions, cannot be compared.
+    function eq(l, r) {
+        const number = value => typeof value === "bigint" || typeof value === "number";
+        if (number(l) && number(r)) {
+            return compare(l, r) === 0;
+        }
+        if ((typeof l === "string" || typeof l === "boolean") && typeof l === typeof r) {
+            return l === r;
+        }
+        if (l === NOTHING && r === NOTHING) {
+            return true;
+        }
+        if (l instanceof Pair && r instanceof Pair) {
+            return eq(l.fst, r.fst) && eq(l.snd, r.snd);
+        }
+        if (Array.isArray(l) && Array.isArray(r)) {
+            return l.length === r.length && l.every((item, i) => eq(item, r[i]));
+        }
+        if (l instanceof Map && r instanceof Map) {
+            return l.size === r.size && [...l].every(([key, value]) => r.has(key) && eq(value, r.get(key)));
+        }
+        throw new Error(`Cannot compare ${show(l)} and ${show(r)}`);
+    }
+
+    function negate(value) {
+        return typeof value === "number" ? -value : -int(value);
+    }
+
+    function force(value) {
+        if (!(value instanceof Thunk)) {
+            return value;
+        }
+        if (value.memo && value.forced) {
+            return value.value;
+        }
+        const result = value.expr(value.env.clone());
+        if (value.memo) {
+            value.forced = true;
+            value.value = result;
+        }
+        return result;
+    }
+
+    function get(env, name) {
+        const value = env.get(name);
+        return value instanceof Thunk && value.eager ? force(value) : value;
+    }
+
+    function checkAssignable(name) {
+        if (RESERVED.includes(name) || Object.prototype.hasOwnProperty.call(BUILTINS, name)) {
+            throw new Error(`Cannot assign to builtin ${name}`);
+        }
+    }
+
+    function assign(env, name, value) {
+        checkAssignable(name);
+        env.vars.set(name, value);
+        return NOTHING;
+    }
+
+    function global(env, name, value) {
+        checkAssignable(name);
+        if (!env.toplevel) {
+            env.globals.set(name, value);
+        }
+        env.vars.set(name, value);
+        return NOTHING;
+    }
+
+    // the value of a block is the value of its last statement
+    function seq(env, statements) {
+        let value = NOTHING;
+        for (const statement of statements) {
+            value = statement(env);
+            if (isSignal(value)) {
+                return value;
+            }
+        }
+        return value;
+    }
+
+    function loop(env, condition, body) {
+        while (cond(condition(env))) {
+            const value = body(env);
+            if (value === BREAK) {
+                break;
+            }
+            if (isSignal(value) && value !== CONTINUE) {
+                return value;
+            }
+        }
+        return NOTHING;
+    }
+
+    // Unlike a hand-written while loop, continue still runs the step.
+    function forLoop(env, init, condition, step, body) {
+        init(env);
+        while (cond(condition(env))) {
+            const value = body(env);
+            if (value === BREAK) {
+                break;
+            }
+            if (isSignal(value) && value !== CONTINUE) {
+                return value;
+            }
+            step(env);
+        }
+        return NOTHING;
+    }
+
+    function matchPattern(pattern, value, bindings) {
+        switch (pattern.kind) {
+        case "wildcard": return true;
+        case "bind": bindings.push([pattern.name, value]); return true;
+        case "number": return typeof value === "bigint" && value === pattern.value;
+        case "boolean": return typeof value === "boolean" && value === pattern.value;
+        case "string": return typeof value === "string" && value === pattern.value;
+        case "pair":
+            return value instanceof Pair && matchPattern(pattern.fst, value.fst, bindings) &&
+                matchPattern(pattern.snd, value.snd, bindings);
+        case "list":
+            return Array.isArray(value) && value.length === pattern.items.length &&
+                pattern.items.every((item, i) => matchPattern(item, value[i], bindings));
+        }
+        throw new Error(`Unknown pattern ${pattern.kind}`);
+    }
+
+    // The arm is a block scope for the names its pattern binds.
+    function match(env, value, arms) {
+        for (const [pattern, body] of arms) {
+            const bindings = [];
+            if (matchPattern(pattern, value, bindings)) {
+                const shadowed = [];
+                for (const [name, bound] of bindings) {
+                    checkAssignable(name);
+                    shadowed.push([name, env.vars.has(name), env.vars.get(name)]);
+                    env.vars.set(name, bound);
+                }
+                const result = body(env);
+                for (const [name, had, old] of shadowed.reverse()) {
+                    if (had) {
+                        env.vars.set(name, old);
+                    } else {
+                        env.vars.delete(name);
+                    }
+                }
+                return result;
+            }
+        }
+        throw new Error(`No match arm for value ${show(value)}`);
+    }
+
+    function index(items, i) {
+        items = list(items);
+        i = int(i);
+        if (i < 0n || i >= BigInt(items.length)) {
+            throw new Error(`Index ${i} out of bounds for list of length ${items.length}`);
+        }
+        return items[Number(i)];
+    }
+
+    // A function value binds the free variables of the function when it
+    // is made, as Capture::Minimal does.
+    function fun(env, name, params, free, body, source) {
+        for (const param of params) {
+            if (RESERVED.includes(param) || Object.prototype.hasOwnProperty.call(BUILTINS, param)) {
+                throw new Error(`Cannot bind builtin ${param} as a parameter`);
+            }
+        }
+        const cenv = new Env(null, true);
+        for (const var_ of free) {
+            const value = env.lookup(var_);
+            if (value !== undefined) {
+                cenv.vars.set(var_, value);
+            }
+        }
+        return new Closure(cenv, name, params, body, source);
+    }
+
+    function compose(outer, inner) {
+        const cenv = new Env(null, true);
+        cenv.vars.set("$outer", outer);
+        cenv.vars.set("$inner", inner);
+        return new Closure(cenv, "compose", ["$x"],
+            frame => call(frame, [call(frame, [frame.get("$x")], frame.get("$inner"))], frame.get("$outer")),
+            "function compose ($x) call $outer arg call $inner arg $x");
+    }
+
+    // Call `callee` with `args`, evaluated first, from `env`.
+    function call(env, args, callee) {
+        if (callee instanceof Builtin) {
+            return callBuiltin(callee.name, args);
+        }
+        if (!(callee instanceof Closure)) {
+            throw new Error(`Call on non-closure type: ${show(callee)}`);
+        }
+        if (args.length !== callee.params.length) {
+            throw new Error(`Function ${callee.name} takes ${argumentCount(callee.params.length)}, found ${args.length}`);
+        }
+        const frame = new Env(callee.env, false);
+        // anonymous functions have no name to recurse through
+        if (callee.name !== "") {
+            frame.vars.set(callee.name, callee);
+        }
+        callee.params.forEach((param, i) => frame.vars.set(param, args[i]));
+        let value = callee.body(frame);
+        if (value instanceof Return) {
+            value = value.value;
+        } else if (value === BREAK || value === CONTINUE) {
+            throw new Error(`break or continue outside of a loop in ${callee.source}`);
+        }
+        for (const [name, global] of frame.globals) {
+            (env.toplevel ? env.vars : env.globals).set(name, global);
+        }
+        return value;
+    }
+
+    function callBuiltin(name, args) {
+        const [arity, implementation] = BUILTINS[name];
+        const variadic = VARIADIC.includes(name);
+        if (args.length < arity || !variadic && args.length > arity) {
+            throw new Error(`Builtin ${name} takes ${variadic ? "at least " : ""}${argumentCount(arity)}, found ${args.length}`);
+        }
+        return implementation(...args);
+    }
+
+    // how many arguments a function takes, worded as the parser words it
+    function argumentCount(count) {
+        return count === 1 ? "1 argument" : `${count} arguments`;
+    }
+
+    // The argument at `i` of the builtin `name` checked by `test`.
+    function argument(name, i, value, expected, test) {
+        if (!test(value)) {
+            throw new Error(`Builtin ${name} expects ${expected} as argument ${i + 1}, got ${show(value)}`);
+        }
+        return value;
+    }
+
+    const isInt = value => typeof value === "bigint";
+    const integer = (name, i, value) => argument(name, i, value, "an integer", isInt);
+    const abs = value => value < 0n ? -value : value;
+
+    function gcd(a, b) {
+        [a, b] = [abs(a), abs(b)];
+        while (b !== 0n) {
+            [a, b] = [b, a % b];
+        }
+        return a;
+    }
+
+    function parseNumber(text) {
+        if (/^[+-]?\d+$/.test(text)) {
+            return BigInt(text);
+        }
+        const value = Number(text);
+        return text !== "" && !Number.isNaN(value) || text === "NaN" ? value : undefined;
+    }
+
+    function isFunction(value) {
+        return value instanceof Builtin || value instanceof Closure;
+    }
+
+    let write = text => {
+        if (typeof process !== "undefined" && process.stdout) {
+            process.stdout.write(text);
+        } else {
+            console.log(text.replace(/\n$/, ""));
+        }
+    };
+
+    let warn = text => {
+        if (typeof process !== "undefined" && process.stderr) {
+            process.stderr.write(`warning: ${text}\n`);
+        } else {
+            console.warn(`warning: ${text}`);
+        }
+    };
+
+    // A line of the standard input without its newline, or null at its end.
+    let readLine = () => {
+        if (typeof require !== "function") {
+            return prompt();
+        }
+        const fs = require("fs");
+        const bytes = [];
+        const buffer = Buffer.alloc(1);
+        for (;;) {
+            let read;
+            try {
+                read = fs.readSync(0, buffer, 0, 1);
+            } catch (e) {
+                if (e.code === "EAGAIN") {
+                    continue;
+                }
+                if (e.code !== "EOF") {
+                    throw e;
+                }
+                read = 0;
+            }
+            if (read === 0) {
+                return bytes.length > 0 ? Buffer.from(bytes).toString() : null;
+            }
+            if (buffer[0] === 10) {
+                return Buffer.from(bytes).toString();
+            }
+            bytes.push(buffer[0]);
+        }
+    };
+
+    const RESERVED = ["nothing", "isnothing", "cons_stream"];
+
+    // the builtins taking at least their arity in arguments, rather than exactly that many
+    const VARIADIC = ["compose"];
+
+    const BUILTINS = {
+        abs: [1, x => typeof x === "number" ? Math.abs(x) : abs(int(x))],
+        sign: [1, x => {
+            if (Number.isNaN(x)) {
+                throw new Error(`sign(${show(x)}) is undefined`);
+            }
+            return x > 0 ? 1n : x < 0 ? -1n : 0n;
+        }],
+        min: [2, (a, b) => isInt(a) && isInt(b) ? (a < b ? a : b) : Math.min(float(a), float(b))],
+        max: [2, (a, b) => isInt(a) && isInt(b) ? (a > b ? a : b) : Math.max(float(a), float(b))],
+        pow: [2, (base, exp) => {
+            [base, exp] = [integer("pow", 0, base), integer("pow", 1, exp)];
+            if (exp < 0n) {
+                throw new Error(`pow(${base}, ${exp}) has a negative exponent`);
+            }
+            return base ** exp;
+        }],
+        gcd: [2, (a, b) => gcd(integer("gcd", 0, a), integer("gcd", 1, b))],
+        lcm: [2, (a, b) => {
+            [a, b] = [integer("lcm", 0, a), integer("lcm", 1, b)];
+            return a === 0n || b === 0n ? 0n : abs(a / gcd(a, b) * b);
+        }],
+        divmod: [2, (a, b) => {
+            [a, b] = [int(a), int(b)];
+            if (b === 0n) {
+                throw new Error(`divmod(${a}, ${b}) divides by zero`);
+            }
+            const [q, r] = [a / b, a % b];
+            return r !== 0n && (r < 0n) !== (b < 0n) ? new Pair(q - 1n, r + b) : new Pair(q, r);
+        }],
+        pair: [2, (fst, snd) => new Pair(fst, snd)],
+        fst: [1, p => {
+            if (!(p instanceof Pair)) {
+                throw new Error(`Apply fst on non-pair type: ${show(p)}`);
+            }
+            return p.fst;
+        }],
+        snd: [1, p => {
+            if (!(p instanceof Pair)) {
+                throw new Error(`Apply snd on non-pair type: ${show(p)}`);
+            }
+            return p.snd;
+        }],
+        len: [1, value => {
+            if (typeof value === "string") {
+                return BigInt([...value].length);
+            }
+            if (Array.isArray(value)) {
+                return BigInt(value.length);
+            }
+            if (value instanceof Map) {
+                return BigInt(value.size);
+            }
+            throw new Error(`Apply len on non-string, non-list, non-map type: ${show(value)}`);
+        }],
+        push: [2, (items, item) => [...list(items), item]],
+        head: [1, items => {
+            if (list(items).length === 0) {
+                throw new Error("Apply head on empty list");
+            }
+            return items[0];
+        }],
+        tail: [1, items => {
+            if (list(items).length === 0) {
+                throw new Error("Apply tail on empty list");
+            }
+            return items.slice(1);
+        }],
+        force: [1, force],
+        stream_head: [1, s => {
+            if (!(s instanceof Pair)) {
+                throw new Error(`Apply stream_head on non-stream type: ${show(s)}`);
+            }
+            return s.fst;
+        }],
+        stream_tail: [1, s => {
+            if (!(s instanceof Pair)) {
+                throw new Error(`Apply stream_tail on non-stream type: ${show(s)}`);
+            }
+            return force(s.snd);
+        }],
+        take: [2, (stream, n) => {
+            const items = [];
+            for (let i = 0n; i < int(n) && stream !== NOTHING; i++) {
+                items.push(callBuiltin("stream_head", [stream]));
+                stream = callBuiltin("stream_tail", [stream]);
+            }
+            return items;
+        }],
+        get: [2, (entries, key) => {
+            key = str(key);
+            if (!map(entries).has(key)) {
+                throw new Error(`Key ${quote(key)} not found in ${show(entries)}`);
+            }
+            return entries.get(key);
+        }],
+        set: [3, (entries, key, value) => new Map(map(entries)).set(str(key), value)],
+        contains: [2, (entries, key) => map(entries).has(str(key))],
+        print: [1, value => {
+            write((typeof value === "string" ? value : show(value)) + "\n");
+            return NOTHING;
+        }],
+        warn: [1, value => {
+            warn(typeof value === "string" ? value : show(value));
+            return NOTHING;
+        }],
+        input: [0, () => {
+            const line = readLine();
+            if (line === null) {
+                return NOTHING;
+            }
+            const number = parseNumber(line.trim());
+            return number === undefined ? line : number;
+        }],
+        arity: [1, f => {
+            if (f instanceof Builtin && VARIADIC.includes(f.name)) {
+                throw new Error(`Builtin ${f.name} takes at least ${argumentCount(BUILTINS[f.name][0])}`);
+            }
+            if (f instanceof Builtin) {
+                return BigInt(BUILTINS[f.name][0]);
+            }
+            if (f instanceof Closure) {
+                return BigInt(f.params.length);
+            }
+            throw new Error(`Apply arity on non-function type: ${show(f)}`);
+        }],
+        name: [1, f => {
+            if (!isFunction(f)) {
+                throw new Error(`Apply name on non-function type: ${show(f)}`);
+            }
+            return f.name === "" ? NOTHING : f.name;
+        }],
+        is_function: [1, isFunction],
+        is_number: [1, value => typeof value === "bigint" || typeof value === "number"],
+        is_boolean: [1, value => typeof value === "boolean"],
+        is_string: [1, value => typeof value === "string"],
+        is_pair: [1, value => value instanceof Pair],
+        is_list: [1, value => Array.isArray(value)],
+        is_map: [1, value => value instanceof Map],
+        to_number: [2, (value, otherwise) => {
+            let converted;
+            if (typeof value === "bigint" || typeof value === "number") {
+                converted = value;
+            } else if (typeof value === "boolean") {
+                converted = value ? 1n : 0n;
+            } else if (typeof value === "string") {
+                converted = parseNumber(value.trim());
+            }
+            return converted === undefined ? otherwise : converted;
+        }],
+        to_boolean: [2, (value, otherwise) => {
+            if (typeof value === "boolean") {
+                return value;
+            }
+            if (typeof value === "bigint") {
+                return value !== 0n;
+            }
+            if (typeof value === "number" && !Number.isNaN(value)) {
+                return value !== 0;
+            }
+            if (typeof value === "string" && ["true", "false"].includes(value.trim())) {
+                return value.trim() === "true";
+            }
+            return otherwise;
+        }],
+        compose: [1, (...fs) => fs.reduceRight((inner, outer) => compose(outer, inner))],
+    };
+
+    // Run top-level statements in `env`, returning the value of the last
+    // one, or what a top-level `return` returns.
+    function run(statements, env) {
+        let last = NOTHING;
+        for (const statement of statements) {
+            last = statement(env);
+            if (last instanceof Return) {
+                return last.value;
+            }
+        }
+        return last;
+    }
+
+    // Run a program and print its value, as `simple run` does.
+    function main(statements) {
+        try {
+            const env = new Env(null, true);
+            const value = run(statements, env);
+            // a `result` variable is the result, as with `simple run`,
+            // else the value of the last statement unless do-nothing
+            const result = env.vars.has("result") ? env.vars.get("result") : value;
+            if (env.vars.has("result") || result !== NOTHING) {
+                write(show(result) + "\n");
+            }
+        } catch (e) {
+            if (typeof process === "undefined") {
+                throw e;
+            }
+            process.stderr.write(`${e.message}\n`);
+            process.exitCode = 1;
+        }
+    }
+
+    return {
+        NOTHING, BREAK, CONTINUE, Pair, Return, Builtin, Closure, Thunk, Env,
+        scope, show, cond, int, float, str, list, map, arithmetic, compare, add, eq, negate, force, get,
+        assign, global, seq, loop, forLoop, match, index, fun, compose, call, BUILTINS, run, main,
+        set write(f) { write = f; },
+        set warn(f) { warn = f; },
+        set readLine(f) { readLine = f; },
+    };
+})();
@@ -1,32 +1,1515 @@
 use super::syntax::Node;
-use super::environment::Environment;
-use super::evaluate::Evaluate;
+use super::environment::{Environment, Capture, Strategy};
+use super::evaluate::{self, Assignment, AssignHook, Evaluate, Input, Interrupted, Limit, Output, RuntimeError, StepHook, Stopped, Timeout};
+use super::subst;
+use super::reduce;
+use super::deps;
+use super::analysis;
+#[cfg(feature = "jit")]
+use super::jit;
+use super::cache::{Lookup, SharedCache};
+use super::diagnostics::Diagnostics;
+use super::numfmt::NumberFormat;
+use super::events::{self, EventSink, MachineEvent};
+use super::builtin::{self, Builtin};
+use super::convert::{FromValue, GetError, HostFn, IntoValue};
+use parser::{self, Arity, ParseError};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+/// A clone is configured as the machine is, at the same point of the same
+/// program, and shares its hooks, streams and cache.
+#[derive(Clone)]
 pub struct Machine {
     pub environment: Environment,
     expression: Box<Node>,
+    steps: usize,
+    cancellation: Option<Arc<AtomicBool>>,
+    output: Option<Output>,
+    input: Option<Input>,
+    diagnostics: Option<Diagnostics>,
+    number_format: NumberFormat,
+    assign_hook: Option<AssignHook>,
+    /// Callbacks subscribed with `on_change`, by the variable they watch.
+    observers: Vec<(String, AssignHook)>,
+    step_hook: Option<StepHook>,
+    events: Option<EventSink>,
+    /// Whether the end of the program was sent to `events` already.
+    completed: bool,
+    step_limit: Option<usize>,
+    timeout: Option<Duration>,
+    trace: Option<Output>,
+    pending: Vec<Pending>,
+    semantics: Semantics,
+    /// The program as far as it is reduced, when stepping small-step.
+    term: Option<Box<Node>>,
+    /// The step count and time at the first step, which the limits of a
+    /// program run a step at a time count from.
+    stepping: Option<(usize, Instant)>,
+    /// The value of the last top-level statement evaluated.
+    last: Box<Node>,
+    cache: Option<SharedCache>,
+}
+
+/// How the machine runs a program.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum Semantics {
+    /// Evaluate every node to its value in an environment.
+    #[default]
+    BigStep,
+    /// Rewrite the program one reduction at a time, substituting values
+    /// for variables as they are bound (see `subst`).
+    SmallStep,
+}
+
+/// What is left of the program when it is run a step at a time.
+#[derive(Debug,Clone)]
+enum Pending {
+    Statement(Box<Node>),
+    /// The condition of a loop, then its body and step while it holds.
+    Loop { cond: Box<Node>, body: Box<Node>, step: Box<Node> },
+    Body { cond: Box<Node>, body: Box<Node>, step: Box<Node> },
+}
+
+/// A part of the program and its value.
+type Evaluated = (Box<Node>, Box<Node>);
+
+/// Why a source given to `eval_many` has no value.
+#[derive(Debug)]
+pub enum EvalError {
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvalError::Parse(ref e) => write!(f, "{}", e),
+            EvalError::Runtime(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for EvalError {}
+
+/// The error an evaluation panicking with `payload` failed with, with
+/// where it was raised if the program has spans.
+pub(crate) fn failure(payload: Box<dyn Any + Send>) -> RuntimeError {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "evaluation failed".to_string(),
+        },
+    };
+    RuntimeError::Failed(match evaluate::span() {
+        Some(span) => format!("{} at {}", message, span),
+        None => message,
+    })
+}
+
+/// The machine after one step: the node it evaluated, what that came to,
+/// and the environment it left.
+#[derive(Debug,Clone,PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MachineState {
+    pub node: Box<Node>,
+    pub value: Box<Node>,
+    /// Steps taken so far, as counted by `steps_taken`.
+    pub steps: usize,
+    pub environment: Environment,
 }
 
 impl Machine {
     pub fn new(expression: Box<Node>, environment: Environment) -> Machine {
         Machine{
-            expression: expression,
-            environment: environment,
+            pending: vec![Pending::Statement(expression.clone())],
+            expression,
+            environment,
+            steps: 0,
+            cancellation: None,
+            output: None,
+            input: None,
+            diagnostics: None,
+            number_format: NumberFormat::plain(),
+            assign_hook: None,
+            observers: Vec::new(),
+            step_hook: None,
+            events: None,
+            completed: false,
+            step_limit: None,
+            timeout: None,
+            trace: None,
+            semantics: Semantics::BigStep,
+            term: None,
+            stepping: None,
+            last: Node::donothing(),
+            cache: None,
         }
     }
 
     pub fn new_with_empty_env(expression: Box<Node>) -> Machine {
-        Machine {
-            expression: expression,
-            environment: Environment::new(),
+        Machine::new(expression, Environment::new())
+    }
+
+    /// Select how function values capture their defining environment.
+    pub fn with_capture(mut self, capture: Capture) -> Machine {
+        self.environment.capture = capture;
+        self
+    }
+
+    /// Select the semantics the program runs under. Hooks on assignments
+    /// and the trace only see the big-step evaluator; limits, cancellation
+    /// and step hooks apply to both.
+    pub fn with_semantics(mut self, semantics: Semantics) -> Machine {
+        self.semantics = semantics;
+        self
+    }
+
+    /// Select when the arguments of function calls are evaluated.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Machine {
+        self.environment.strategy = strategy;
+        self
+    }
+
+    /// Stop evaluating as soon as `flag` is set, for instance from a
+    /// signal handler. The environment keeps whatever was assigned so far.
+    pub fn with_cancellation(mut self, flag: Arc<AtomicBool>) -> Machine {
+        self.cancellation = Some(flag);
+        self
+    }
+
+    /// Stop a run, or an executed statement, after `max_steps` steps.
+    pub fn with_step_limit(mut self, max_steps: usize) -> Machine {
+        self.step_limit = Some(max_steps);
+        self
+    }
+
+    /// Stop a run, or an executed statement, once it has taken `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Machine {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send what the program prints to `output` instead of stdout.
+    pub fn with_output(mut self, output: Output) -> Machine {
+        self.output = Some(output);
+        self
+    }
+
+    /// Write every node the machine evaluates, with its environment, to
+    /// `sink`. Nothing is traced otherwise.
+    pub fn with_trace(mut self, sink: Output) -> Machine {
+        self.trace = Some(sink);
+        self
+    }
+
+    /// Read what the program inputs from `input` instead of stdin.
+    pub fn with_input(mut self, input: Input) -> Machine {
+        self.input = Some(input);
+        self
+    }
+
+    /// Report the warnings of the program to `diagnostics` instead of
+    /// stderr, so that a host can show them apart from its output.
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Machine {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    /// Where the warnings of the program go, if not to stderr.
+    pub fn diagnostics(&self) -> Option<&Diagnostics> {
+        self.diagnostics.as_ref()
+    }
+
+    /// Lay out the numbers the program prints in `format`, say with the
+    /// thousands grouped as a locale writes them.
+    pub fn with_number_format(mut self, format: NumberFormat) -> Machine {
+        self.number_format = format;
+        self
+    }
+
+    /// How the numbers the program prints are laid out.
+    pub fn number_format(&self) -> NumberFormat {
+        self.number_format
+    }
+
+    /// Call `hook` before every assignment the program makes.
+    pub fn with_assign_hook(mut self, hook: AssignHook) -> Machine {
+        self.assign_hook = Some(hook);
+        self
+    }
+
+    /// Call `callback` whenever the program assigns `name`, at top level
+    /// or in a call frame, before the assignment is made, so that a front
+    /// end can follow a running program without polling it. Subscriptions
+    /// add up, and work alongside `with_assign_hook`.
+    pub fn on_change<F>(&mut self, name: &str, callback: F)
+        where F: Fn(&Assignment) + 'static {
+        self.observers.push((name.to_string(), Rc::new(callback)));
+    }
+
+    /// The hook to install while running: the event of the assignment,
+    /// the assignment hook, then the observers of the variable assigned.
+    fn assign_hooks(&self) -> Option<AssignHook> {
+        if self.observers.is_empty() && self.events.is_none() {
+            return self.assign_hook.clone();
+        }
+        let hook = self.assign_hook.clone();
+        let observers = self.observers.clone();
+        let sink = self.events.clone();
+        Some(Rc::new(move |assignment: &Assignment| {
+            if let Some(ref sink) = sink {
+                sink(&MachineEvent::Assigned { name: assignment.name.to_string() });
+            }
+            if let Some(ref hook) = hook {
+                hook(assignment);
+            }
+            for (name, observer) in &observers {
+                if name == assignment.name {
+                    observer(assignment);
+                }
+            }
+        }))
+    }
+
+    /// The hook `with_assign_hook` put in place, for one to go with it.
+    pub(crate) fn assign_hook(&self) -> Option<AssignHook> {
+        self.assign_hook.clone()
+    }
+
+    /// Call `hook` with every node the program evaluates.
+    pub fn with_step_hook(mut self, hook: StepHook) -> Machine {
+        self.step_hook = Some(hook);
+        self
+    }
+
+    /// Send what the machine does to `sink` as structured events, for a
+    /// tool to show; see `events` for what they are and how they may
+    /// change. Like assignment hooks, assignments are only sent big-step.
+    pub fn with_events(mut self, sink: EventSink) -> Machine {
+        self.events = Some(sink);
+        self
+    }
+
+    fn emit(&self, event: MachineEvent) {
+        if let Some(ref sink) = self.events {
+            sink(&event);
+        }
+    }
+
+    /// The hook to install while running: the step hook, then the events
+    /// of the node evaluated.
+    fn step_hooks(&self) -> Option<StepHook> {
+        let sink = match self.events {
+            Some(ref sink) => sink.clone(),
+            None => return self.step_hook.clone(),
+        };
+        let hook = self.step_hook.clone();
+        let small_step = self.semantics == Semantics::SmallStep;
+        Some(Rc::new(move |node: &Node| {
+            if let Some(ref hook) = hook {
+                hook(node);
+            }
+            for event in events::reduced(node, evaluate::span(), small_step) {
+                sink(&event);
+            }
+        }))
+    }
+
+    /// Tell the events the program has finished with `value`, once.
+    fn complete(&mut self, value: &Node) {
+        if !self.completed {
+            self.completed = true;
+            self.emit(MachineEvent::Completed { value: value.to_string() });
+        }
+    }
+
+    /// Expose a host function to the program under `name`. It receives
+    /// the evaluated arguments and checks their number and types itself.
+    pub fn register_native<F>(&mut self, name: &str, fun: F)
+        where F: Fn(&[Box<Node>]) -> Box<Node> + 'static {
+        self.environment.add(name, Node::native(name, fun));
+    }
+
+    /// Bind `name` to a Rust value, say `machine.set("xs", vec![1, 2, 3])`.
+    pub fn set<T: IntoValue>(&mut self, name: &str, value: T) {
+        self.environment.add(name, value.into_value());
+    }
+
+    /// The value bound to `name` as a Rust value, say
+    /// `let n: i64 = machine.get("result")?`.
+    pub fn get<T: FromValue>(&self, name: &str) -> Result<T, GetError> {
+        let value = self.environment.lookup(name).ok_or_else(|| GetError::Unbound(name.to_string()))?;
+        Ok(T::from_value(&value)?)
+    }
+
+    /// Bind `name` to a Rust closure over Rust values, say
+    /// `machine.set_fn("log", |v: Value| { ... })`, its arguments checked
+    /// and converted as those of builtins are (see `convert::HostFn`).
+    ///
+    /// The closure is called in the middle of a run, on the thread running
+    /// it, and the machine stays borrowed by the run meanwhile. So:
+    /// - to call back into simple code, it calls the function values it was
+    ///   given with `evaluate::call_value`, which count against the limits
+    ///   of the run and print to its output;
+    /// - it may make and run another machine, whose limits, hooks and
+    ///   streams stand in for this one's until it returns, though the steps
+    ///   it takes still count against this run's step limit;
+    /// - a panic in it fails the run like any evaluation error.
+    ///
+    /// The closure need not be `Send`: values are reference counted, so a
+    /// machine and its natives never leave the thread that made them. Host
+    /// state meant for machines on several threads is shared through
+    /// `Arc`, each thread registering its own closure.
+    ///
+    /// ```compile_fail
+    /// # extern crate proglang;
+    /// fn is_send<T: Send>() {}
+    /// is_send::<proglang::simple::machine::Machine>();
+    /// ```
+    pub fn set_fn<Args, F: HostFn<Args>>(&mut self, name: &str, fun: F) {
+        let owned = name.to_string();
+        self.register_native(name, move |args| {
+            builtin::check_arity(&owned, Arity::Exactly(fun.arity()), args);
+            fun.call(&owned, args)
+        });
+    }
+
+    /// Make a builtin defined outside the registry, say with `builtin!`,
+    /// callable from the program as a native.
+    pub fn register_builtin(&mut self, builtin: Builtin) {
+        self.register_native(builtin.name, builtin.fun);
+    }
+
+    /// Run the program, returning the value of its last top-level
+    /// statement, or the value it returns. Errors that would panic the
+    /// evaluator are caught and returned instead, with where they were
+    /// raised if the program has spans; the panic hook still reports them.
+    pub fn run(&mut self) -> Result<Box<Node>, RuntimeError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.try_run())) {
+            Ok(result) => Ok(result?),
+            Err(payload) => Err(failure(payload)),
         }
     }
 
-    pub fn run(&mut self) {
-        self.expression.evaluate(&mut self.environment);
+    /// Run `f`, another evaluator of the program, say, on the environment
+    /// of the machine and under its limits, cancellation and streams, as
+    /// `run` runs the program. Hooks see only what `f` evaluates through
+    /// `evaluate`.
+    pub fn run_with<T, F>(&mut self, f: F) -> Result<T, RuntimeError>
+        where F: FnOnce(&mut Environment) -> T {
+        let limits = (self.step_limit, self.timeout);
+        match panic::catch_unwind(AssertUnwindSafe(|| self.guarded(limits, f))) {
+            Ok(result) => Ok(result?),
+            Err(payload) => Err(failure(payload)),
+        }
+    }
+
+    /// Parse and evaluate each of `sources`, say the formulas of a
+    /// spreadsheet, against the environment of the machine, in order, so
+    /// that one may use what an earlier one assigned. The hooks and
+    /// streams of the machine are put in place once for the whole batch,
+    /// and its limits apply to the batch as a whole: once it is stopped,
+    /// the sources left are stopped too. One failing does not stop the
+    /// others.
+    pub fn eval_many(&mut self, sources: &[&str]) -> Vec<Result<Box<Node>, EvalError>> {
+        let semantics = self.semantics;
+        let limits = (self.step_limit, self.timeout);
+        let mut results = Vec::with_capacity(sources.len());
+        let stopped = self.guarded(limits, |environment| {
+            for source in sources {
+                let node = match parser::parse_spanned(source) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        results.push(Err(EvalError::Parse(e)));
+                        continue;
+                    }
+                };
+                evaluate::set_span(None);
+                let value = panic::catch_unwind(AssertUnwindSafe(|| match semantics {
+                    Semantics::BigStep => node.evaluate(environment),
+                    Semantics::SmallStep => subst::execute(&node, environment),
+                }));
+                match value {
+                    Ok(value) => results.push(Ok(value)),
+                    // stopping is the guard's to report, for the whole batch
+                    Err(payload) if payload.is::<Interrupted>() || payload.is::<Timeout>() => panic::resume_unwind(payload),
+                    Err(payload) => results.push(Err(EvalError::Runtime(failure(payload)))),
+                }
+            }
+        }).err();
+        evaluate::set_span(None);
+        if let Some(stopped) = stopped {
+            let left = sources.len() - results.len();
+            results.extend((0..left).map(|_| Err(EvalError::Runtime(RuntimeError::Stopped(stopped.clone())))));
+        }
+        results
+    }
+
+    /// Evaluate again what the program computed from `name`, once the host
+    /// has changed it, say with `set`: the top-level statements reading
+    /// `name`, then those reading what these assign, and so on along the
+    /// dependency graph of the program (see `deps`), in order and against
+    /// the environment as it is. The other statements are left alone, so
+    /// the program works as a formula engine. Returns the names assigned
+    /// again, sorted.
+    ///
+    /// A statement with effects would repeat them, so if one has to be
+    /// evaluated again, nothing is and the error says which.
+    pub fn invalidate(&mut self, name: &str) -> Result<Vec<String>, RuntimeError> {
+        let statements = reduce::statements(&self.expression);
+        let graph = deps::graph(&self.expression);
+        // the definitions of each statement, in the order of the graph's
+        let mut owners = Vec::with_capacity(graph.definitions.len());
+        for (index, statement) in statements.iter().enumerate() {
+            owners.extend(iter::repeat_n(index, deps::graph(statement).definitions.len()));
+        }
+        let mut affected = vec![false; statements.len()];
+        let mut changed: HashSet<&str> = HashSet::new();
+        changed.insert(name);
+        let mut dirty = vec![false; graph.definitions.len()];
+        for (index, definition) in graph.definitions.iter().enumerate() {
+            dirty[index] = definition.reads.iter().any(|read| read == name)
+                || definition.depends_on.iter().any(|&from| dirty[from]);
+            if dirty[index] {
+                affected[owners[index]] = true;
+                changed.insert(&definition.name);
+            }
+        }
+        // statements assigning nothing are affected by what they read
+        for (index, statement) in statements.iter().enumerate() {
+            if !owners.contains(&index) && evaluate::get_free_vars(statement).iter().any(|read| changed.contains(read.as_str())) {
+                affected[index] = true;
+            }
+        }
+        let again: Vec<&Node> = statements.iter().zip(&affected).filter(|&(_, &affected)| affected).map(|(statement, _)| statement).collect();
+        for statement in &again {
+            let effects = analysis::effects_in_env(statement, &self.environment);
+            if !effects.is_pure() {
+                return Err(RuntimeError::Failed(format!("Cannot evaluate `{}` again, it depends on {} and has effects: {}",
+                                                        statement.without_spans(), name, effects)));
+            }
+        }
+        for statement in &again {
+            match panic::catch_unwind(AssertUnwindSafe(|| self.try_execute(statement))) {
+                Ok(result) => { result?; }
+                Err(payload) => return Err(failure(payload)),
+            }
+        }
+        let mut names: Vec<String> = graph.definitions.iter().zip(&owners)
+            .filter(|&(_, &owner)| affected[owner])
+            .map(|(definition, _)| definition.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Run the program as native code, if it computes only with integers
+    /// and booleans (see `jit`), returning the value of its last top-level
+    /// statement. It reads and assigns the environment of the machine as
+    /// `run` does, but the hooks, limits and cancellation of the machine do
+    /// not apply.
+    #[cfg(feature = "jit")]
+    pub fn run_jit(&mut self) -> Result<Box<Node>, RuntimeError> {
+        jit::run(&self.expression, &mut self.environment).map_err(RuntimeError::Failed)
+    }
+
+    /// Like `run`, but only cancellation and running out of steps or time
+    /// are returned as an error; evaluation errors panic.
+    pub fn try_run(&mut self) -> Result<Box<Node>, Stopped> {
+        self.pending = vec![Pending::Statement(self.expression.clone())];
+        self.term = None;
+        self.stepping = None;
+        self.last = Node::donothing();
+        self.completed = false;
+        evaluate::set_span(None);
+        let cache = self.cache.clone()
+            .filter(|_| self.assign_hook.is_none() && self.observers.is_empty() && self.step_hook.is_none()
+                    && self.trace.is_none() && self.events.is_none());
+        let key = match cache {
+            Some(ref cache) => match cache.borrow_mut().lookup(&self.expression, &mut self.environment) {
+                Lookup::Hit(value) => {
+                    self.pending.clear();
+                    self.last = value.clone();
+                    return Ok(value);
+                }
+                Lookup::Miss(key) => Some(key),
+                Lookup::Uncacheable => None,
+            },
+            None => None,
+        };
+        let result = (|| { while self.advance()?.is_some() {} Ok(()) })();
+        self.stepping = None;
+        evaluate::set_span(None);
+        result?;
+        let value = self.result();
+        self.complete(&value);
+        if let (Some(cache), Some(key)) = (cache, key) {
+            cache.borrow_mut().insert(key, &self.expression, &self.environment, &value);
+        }
+        Ok(value)
+    }
+
+    /// The value of the last top-level statement, or the value returned.
+    fn result(&self) -> Box<Node> {
+        match *self.last {
+            Node::Return(ref value) => value.clone(),
+            _ => self.last.clone(),
+        }
+    }
+
+    /// What the program came to once stepped to its end: small-step, the
+    /// value it reduced to, else as `run` has it.
+    #[cfg(feature = "async")]
+    pub(crate) fn stepped_value(&self) -> Box<Node> {
+        match self.term {
+            Some(ref term) if self.semantics == Semantics::SmallStep => term.clone(),
+            _ => self.result(),
+        }
+    }
+
+    /// Remember what programs free of effects run to in `cache`, and
+    /// answer runs of them from it. A machine with hooks or a trace does
+    /// not use the cache, as they would not see answered runs.
+    pub fn with_result_cache(mut self, cache: SharedCache) -> Machine {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Run the program for at most `max_steps` steps.
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<(), Timeout> {
+        let limit = self.step_limit.replace(max_steps);
+        let result = self.try_run();
+        self.step_limit = limit;
+        match result {
+            Ok(_) => Ok(()),
+            Err(Stopped::Timeout(timeout)) => Err(timeout),
+            Err(stopped) => panic!("{}", stopped),
+        }
+    }
+
+    /// Evaluate one more statement against the environment kept by the
+    /// machine, returning its value.
+    pub fn execute(&mut self, statement: &Node) -> Box<Node> {
+        self.try_execute(statement).unwrap_or_else(|stopped| panic!("{}", stopped))
+    }
+
+    /// Like `execute`, but report cancellation and running out of steps
+    /// or time as an error rather than a panic.
+    pub fn try_execute(&mut self, statement: &Node) -> Result<Box<Node>, Stopped> {
+        let semantics = self.semantics;
+        let limits = (self.step_limit, self.timeout);
+        self.guarded(limits, |environment| match semantics {
+            Semantics::BigStep => statement.evaluate(environment),
+            Semantics::SmallStep => subst::execute(statement, environment),
+        })
+    }
+
+    /// Run `f` on the environment with at most `limits` steps and time,
+    /// and the machine's hooks and streams in place, counting the steps it
+    /// takes.
+    fn guarded<T, F>(&mut self, limits: (Option<usize>, Option<Duration>), f: F) -> Result<T, Stopped>
+        where F: FnOnce(&mut Environment) -> T {
+        let start = evaluate::steps();
+        let watched = evaluate::set_cancellation(self.cancellation.clone());
+        let budget = evaluate::set_budget(limits.0, limits.1);
+        let output = evaluate::set_output(self.output.clone());
+        let trace = evaluate::set_trace(self.trace.clone());
+        let input = evaluate::set_input(self.input.clone());
+        let diagnostics = evaluate::set_diagnostics(self.diagnostics.clone());
+        let number_format = evaluate::set_number_format(self.number_format);
+        let assign_hook = evaluate::set_assign_hook(self.assign_hooks());
+        let step_hook = evaluate::set_step_hook(self.step_hooks());
+        let environment = &mut self.environment;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(environment)));
+        evaluate::set_cancellation(watched);
+        evaluate::restore_budget(budget);
+        evaluate::set_output(output);
+        evaluate::set_trace(trace);
+        evaluate::set_input(input);
+        evaluate::set_diagnostics(diagnostics);
+        evaluate::set_number_format(number_format);
+        evaluate::set_assign_hook(assign_hook);
+        evaluate::set_step_hook(step_hook);
+        self.steps += evaluate::steps() - start;
+        match result {
+            Ok(value) => Ok(value),
+            Err(payload) => match payload.downcast::<Interrupted>() {
+                Ok(interrupted) => Err(Stopped::Interrupted(*interrupted)),
+                Err(payload) => match payload.downcast::<Timeout>() {
+                    // report the machine's limit rather than what was left of it
+                    Ok(timeout) => Err(Stopped::Timeout(Timeout {
+                        limit: match timeout.limit {
+                            Limit::Steps(_) => Limit::Steps(self.step_limit.unwrap()),
+                            Limit::Time(_) => Limit::Time(self.timeout.unwrap()),
+                        },
+                        redex: timeout.redex,
+                    })),
+                    Err(payload) => panic::resume_unwind(payload),
+                },
+            },
+        }
+    }
+
+    /// Take one step of the program: evaluate its next top-level statement,
+    /// or the condition or one iteration of the body of a top-level loop.
+    /// `None` once the program has finished. Stepping walks the program
+    /// apart from `run`, and counts the same steps as it.
+    pub fn step(&mut self) -> Option<MachineState> {
+        self.try_step().unwrap_or_else(|stopped| panic!("{}", stopped))
+    }
+
+    /// Like `step`, but report cancellation and running out of steps or
+    /// time as an error rather than a panic.
+    pub fn try_step(&mut self) -> Result<Option<MachineState>, Stopped> {
+        if self.semantics == Semantics::SmallStep {
+            return self.try_reduce();
+        }
+        match self.advance()? {
+            Some((node, value)) => Ok(Some(MachineState { node, value, steps: self.steps, environment: self.environment.clone() })),
+            None => {
+                let value = self.result();
+                self.complete(&value);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Evaluate the next part of the program, returning it with its value,
+    /// and keep the value of the last top-level statement in `last`.
+    fn advance(&mut self) -> Result<Option<Evaluated>, Stopped> {
+        loop {
+            let (node, value) = match self.pending.pop() {
+                None => return Ok(None),
+                Some(Pending::Statement(node)) => match *node {
+                    Node::Spanned(span, ref statement) => {
+                        evaluate::set_span(Some(span));
+                        self.pending.push(Pending::Statement(statement.clone()));
+                        continue;
+                    }
+                    // control nodes count as a step, like in `evaluate`
+                    Node::Sequence(..) => {
+                        self.try_enter(&node)?;
+                        // nodes nested in a chain of sequences are only counted
+                        let mut chain = vec![&*node];
+                        let mut statements = Vec::new();
+                        while let Some(part) = chain.pop() {
+                            match *part {
+                                Node::Sequence(ref head, ref more) => {
+                                    if !ptr::eq(part, &*node) {
+                                        self.steps += 1;
+                                    }
+                                    chain.push(more);
+                                    chain.push(head);
+                                }
+                                _ => statements.push(Pending::Statement(Box::new(part.clone()))),
+                            }
+                        }
+                        self.pending.extend(statements.into_iter().rev());
+                        continue;
+                    }
+                    Node::If(ref cond, ref consequence, ref alternative) => {
+                        self.try_enter(&node)?;
+                        let value = self.try_execute_step(cond)?;
+                        let branch = if value.condition() { consequence } else { alternative };
+                        self.pending.push(Pending::Statement(branch.clone()));
+                        (cond.clone(), value)
+                    }
+                    Node::While(ref cond, ref body) => {
+                        self.try_enter(&node)?;
+                        self.pending.push(Pending::Loop { cond: cond.clone(), body: body.clone(), step: Node::donothing() });
+                        continue;
+                    }
+                    Node::For(ref init, ref cond, ref step, ref body) => {
+                        self.try_enter(&node)?;
+                        let value = self.try_execute_step(init)?;
+                        self.pending.push(Pending::Loop { cond: cond.clone(), body: body.clone(), step: step.clone() });
+                        (init.clone(), value)
+                    }
+                    _ => {
+                        let value = self.try_execute_step(&node)?;
+                        if let Node::Break | Node::Continue = *value {
+                            panic!("break or continue outside of a loop");
+                        }
+                        if value.is_signal() {
+                            self.pending.clear();
+                        }
+                        self.last = value.clone();
+                        (node, value)
+                    }
+                },
+                Some(Pending::Loop { cond, body, step }) => {
+                    let value = self.try_execute_step(&cond)?;
+                    if value.condition() {
+                        self.pending.push(Pending::Body { cond: cond.clone(), body, step });
+                    } else {
+                        self.last = Node::donothing();
+                    }
+                    (cond, value)
+                }
+                Some(Pending::Body { cond, body, step }) => {
+                    let value = self.try_execute_step(&body)?;
+                    match *value {
+                        Node::Break => self.last = Node::donothing(),
+                        ref signal if signal.is_signal() && !matches!(*signal, Node::Continue) => {
+                            self.pending.clear();
+                            self.last = value.clone();
+                        }
+                        _ => {
+                            self.pending.push(Pending::Loop { cond, body: body.clone(), step: step.clone() });
+                            if !matches!(*step, Node::DoNothing) {
+                                self.pending.push(Pending::Statement(step));
+                            }
+                        }
+                    }
+                    (body, value)
+                }
+            };
+            return Ok(Some((node, value)));
+        }
+    }
+
+    /// Enter a node the machine takes apart itself, as `evaluate` would.
+    fn try_enter(&mut self, node: &Node) -> Result<(), Stopped> {
+        self.emit(MachineEvent::StepStarted { step: self.steps });
+        let limits = self.step_limits();
+        self.guarded(limits, |environment| {
+            evaluate::enter(node, environment);
+            Node::donothing()
+        }).map(|_| ())
+    }
+
+    /// Evaluate a part of the program as one step of it.
+    fn try_execute_step(&mut self, node: &Node) -> Result<Box<Node>, Stopped> {
+        self.emit(MachineEvent::StepStarted { step: self.steps });
+        let semantics = self.semantics;
+        let limits = self.step_limits();
+        self.guarded(limits, |environment| match semantics {
+            Semantics::BigStep => node.evaluate(environment),
+            Semantics::SmallStep => subst::execute(node, environment),
+        })
+    }
+
+    /// What is left of the step limit and timeout when stepping.
+    fn step_limits(&mut self) -> (Option<usize>, Option<Duration>) {
+        let steps = self.steps;
+        let (start, began) = *self.stepping.get_or_insert((steps, Instant::now()));
+        (self.step_limit.map(|max| max.saturating_sub(steps - start)),
+         self.timeout.map(|timeout| timeout.saturating_sub(began.elapsed())))
+    }
+
+    /// A single reduction of the whole program, small-step.
+    fn try_reduce(&mut self) -> Result<Option<MachineState>, Stopped> {
+        if let Some(Pending::Statement(program)) = self.pending.pop() {
+            self.term = Some(subst::close(&program, &self.environment));
+        }
+        let node = match self.term {
+            Some(ref term) if !subst::is_value(term) => term.clone(),
+            Some(ref term) => {
+                let value = term.clone();
+                self.complete(&value);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        self.emit(MachineEvent::StepStarted { step: self.steps });
+        let limits = self.step_limits();
+        let value = self.guarded(limits, |environment| {
+            let mut bindings = HashMap::new();
+            let next = subst::step_program(&node, &mut bindings).unwrap();
+            for (name, value) in bindings {
+                environment.add(&name, value);
+            }
+            next
+        })?;
+        self.term = Some(value.clone());
+        Ok(Some(MachineState { node, value, steps: self.steps, environment: self.environment.clone() }))
+    }
+
+    /// Replace the program the machine runs, keeping its environment.
+    pub fn load(&mut self, program: Box<Node>) {
+        self.pending = vec![Pending::Statement(program.clone())];
+        self.expression = program;
+        self.term = None;
+        self.stepping = None;
+        self.completed = false;
+    }
+
+    /// The program the machine runs.
+    pub fn program(&self) -> &Node {
+        &self.expression
+    }
+
+    /// Number of evaluation steps (nodes evaluated) the machine has taken.
+    pub fn steps_taken(&self) -> usize {
+        self.steps
     }
 
     pub fn get_environment(&self) -> Environment {
         self.environment.clone()
     }
 }
+
+/// The states the machine steps through.
+impl Iterator for Machine {
+    type Item = MachineState;
+
+    fn next(&mut self) -> Option<MachineState> {
+        self.step()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps_sequence() {
+        // x = 1 + 1; y = x + 3
+        let mut machine = Machine::new_with_empty_env(Node::sequence(
+            Node::assign("x", Node::add(Node::number(1), Node::number(1))),
+            Node::assign("y", Node::add(Node::variable("x"), Node::number(3)))
+        ));
+        machine.run().unwrap();
+        assert_eq!(9, machine.steps_taken());
+    }
+
+    #[test]
+    fn test_steps_while() {
+        // x = 1; while (x < 5) { x = x * 3 }
+        let mut machine = Machine::new_with_empty_env(Node::sequence(
+            Node::assign("x", Node::number(1)),
+            Node::while_node(
+                Node::lt(Node::variable("x"), Node::number(5)),
+                Node::assign("x", Node::multiply(Node::variable("x"), Node::number(3))))
+        ));
+        machine.run().unwrap();
+        assert_eq!(21, machine.steps_taken());
+    }
+
+    // def first(a, b) { a }; result = first(1, fst(0))
+    fn first_of_failing() -> Box<Node> {
+        Node::sequence(
+            Node::assign("first", Node::fun("first", &["a", "b"], Node::variable("a"))),
+            Node::assign("result", Node::call_args(Node::variable("first"), vec![
+                Node::number(1), Node::call(Node::variable("fst"), Node::number(0))])))
+    }
+
+    #[test]
+    fn test_strategy_unused_argument() {
+        for strategy in [Strategy::Name, Strategy::Need] {
+            let mut machine = Machine::new_with_empty_env(first_of_failing()).with_strategy(strategy);
+            machine.run().unwrap();
+            assert_eq!(1, machine.environment.get("result").value());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Apply fst on non-pair type")]
+    fn test_strategy_value_unused_argument() {
+        Machine::new_with_empty_env(first_of_failing()).with_strategy(Strategy::Value).run().unwrap();
+    }
+
+    #[test]
+    fn test_strategy_memoization() {
+        // def twice(x) { x + x }; result = twice(1 + 2 * 3)
+        let program = || Node::sequence(
+            Node::assign("twice", Node::fun("twice", &["x"], Node::add(Node::variable("x"), Node::variable("x")))),
+            Node::assign("result", Node::call(Node::variable("twice"),
+                Node::add(Node::number(1), Node::multiply(Node::number(2), Node::number(3))))));
+        let steps: Vec<usize> = [Strategy::Value, Strategy::Name, Strategy::Need].iter().map(|&strategy| {
+            let mut machine = Machine::new_with_empty_env(program()).with_strategy(strategy);
+            machine.run().unwrap();
+            assert_eq!(14, machine.environment.get("result").value());
+            machine.steps_taken()
+        }).collect();
+        // by name evaluates the argument on both uses, by need only once
+        assert_eq!(steps[0] + 5, steps[1]);
+        assert_eq!(steps[0], steps[2]);
+    }
+
+    #[test]
+    fn test_steps_accumulate() {
+        let mut machine = Machine::new_with_empty_env(Node::donothing());
+        machine.execute(&Node::assign("x", Node::number(1)));
+        assert_eq!(2, machine.steps_taken());
+        machine.execute(&Node::add(Node::variable("x"), Node::number(1)));
+        assert_eq!(5, machine.steps_taken());
+    }
+
+    #[test]
+    fn test_output() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // print("x is"); x = 1.5; print(x); print([x, "s"])
+        let call_print = |arg| Node::call(Node::variable("print"), arg);
+        let program = Node::sequence(call_print(Node::string("x is")), Node::sequence(
+            Node::assign("x", Node::float(1.5)), Node::sequence(
+            call_print(Node::variable("x")),
+            call_print(Node::list(vec![Node::variable("x"), Node::string("s")])))));
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = Machine::new_with_empty_env(program).with_output(buffer.clone());
+        machine.run().unwrap();
+        assert_eq!("x is\n1.5\n[1.5, \"s\"]\n", String::from_utf8(buffer.borrow().clone()).unwrap());
+    }
+
+    #[test]
+    fn test_number_format() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let program = ::parser::parse("def fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }
+            print(fact(10)) print([fact(7), 0.5]) print(\"1000000\")").unwrap();
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let format = NumberFormat::locale("en").unwrap().with_width(10);
+        let mut machine = Machine::new_with_empty_env(program).with_output(buffer.clone()).with_number_format(format);
+        machine.run().unwrap();
+        // strings are printed as they are
+        assert_eq!(" 3,628,800\n[     5,040,        0.5]\n1000000\n", String::from_utf8(buffer.borrow().clone()).unwrap());
+        assert!(evaluate::number_format().is_plain());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let program = ::parser::parse_spanned("x = 1;\nprint(x)\nwarn((\"x is\", x))").unwrap();
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let diagnostics = Diagnostics::new();
+        let mut machine = Machine::new_with_empty_env(program)
+            .with_output(buffer.clone())
+            .with_diagnostics(diagnostics.clone());
+        machine.run().unwrap();
+        // warnings are kept apart from what the program prints
+        assert_eq!("1\n", String::from_utf8(buffer.borrow().clone()).unwrap());
+        let warnings: Vec<String> = diagnostics.take().iter().map(|warning| warning.to_string()).collect();
+        assert_eq!(vec!["warning at line 3, column 1: (\"x is\", 1)".to_string()], warnings);
+    }
+
+    #[test]
+    fn test_step() {
+        let program = ::parser::parse("x = 0; if (x < 1) { y = 1; } else { y = 2; }
+            while (true) { x = x + 1; if (x == 3) { break; } }
+            for (i = 0; i < 2; i = i + 1) { y = y * 10; }").unwrap();
+        let mut stepped = Machine::new_with_empty_env(program.clone());
+        let nodes: Vec<String> = stepped.by_ref().map(|state| state.node.to_string()).collect();
+        let body = "x = x + 1; if (x = 3) break else do-nothing";
+        assert_eq!(vec!["x = 0", "x < 1", "y = 1", "true", body, "true", body, "true", body,
+                        "i = 0", "i < 2", "y = y * 10", "i = i + 1", "i < 2", "y = y * 10", "i = i + 1", "i < 2"],
+                   nodes);
+        assert!(stepped.step().is_none());
+        let mut run = Machine::new_with_empty_env(program);
+        run.run().unwrap();
+        assert_eq!(run.environment, stepped.environment);
+        assert_eq!(run.steps_taken(), stepped.steps_taken());
+        // each state holds the environment after its step
+        let mut machine = Machine::new_with_empty_env(::parser::parse("x = 1; x = x + 1;").unwrap());
+        let state = machine.step().unwrap();
+        assert_eq!((Node::donothing(), Node::number(1)), (state.value, state.environment.get("x")));
+        assert_eq!(Node::number(2), machine.next().unwrap().environment.get("x"));
+        // the step limit holds for the whole program, not for each step
+        let mut machine = Machine::new_with_empty_env(::parser::parse("x = 0; while (true) { x = x + 1; }").unwrap())
+            .with_step_limit(20);
+        let stopped = loop {
+            if let Err(stopped) = machine.try_step() {
+                break stopped;
+            }
+        };
+        assert!(stopped.to_string().starts_with("Step limit of 20 reached"));
+    }
+
+    #[test]
+    fn test_semantics() {
+        let program = ::parser::parse("def sq(x) { return x * x; } a = sq(3); b = a + 1;").unwrap();
+        let environments: Vec<Environment> = [Semantics::BigStep, Semantics::SmallStep].iter().map(|&semantics| {
+            let mut machine = Machine::new_with_empty_env(program.clone()).with_semantics(semantics);
+            machine.run().unwrap();
+            machine.environment
+        }).collect();
+        for name in ["a", "b"] {
+            assert_eq!(environments[0].get(name), environments[1].get(name));
+        }
+        // small-step, a step is one reduction of the whole program
+        let mut machine = Machine::new_with_empty_env(::parser::parse("x = 1 + 2; y = x * 2;").unwrap())
+            .with_semantics(Semantics::SmallStep);
+        let terms: Vec<String> = machine.by_ref().map(|state| state.value.to_string()).collect();
+        assert_eq!(vec!["x = 3; y = x * 2", "do-nothing; y = 3 * 2", "do-nothing; y = 6", "do-nothing; do-nothing", "do-nothing"], terms);
+        assert_eq!(Node::number(6), machine.environment.get("y"));
+        // statements see what is bound in the environment
+        let mut machine = Machine::new_with_empty_env(Node::donothing()).with_semantics(Semantics::SmallStep);
+        machine.execute(&Node::assign("x", Node::number(4)));
+        assert_eq!(Node::number(5), machine.execute(&Node::add(Node::variable("x"), Node::number(1))));
+        let mut machine = Machine::new_with_empty_env(::parser::parse("i = 0; while (true) { i = i + 1; }").unwrap())
+            .with_semantics(Semantics::SmallStep).with_step_limit(100);
+        assert!(matches!(machine.try_run(), Err(Stopped::Timeout(_))));
+    }
+
+    #[test]
+    fn test_run_value() {
+        let cases = [("x = 6; x * 7", Node::number(42)), ("x = 1; if (x > 0) { y = x; 2 } else { 3 }", Node::number(2)),
+                     ("x = 0; while (x < 3) { x = x + 1; }", Node::donothing()), ("x = 1; return x + 1; x = 5;", Node::number(2)),
+                     ("for (i = 0; true; i = i + 1) { if (i == 2) { return i; } }", Node::number(2))];
+        for semantics in [Semantics::BigStep, Semantics::SmallStep] {
+            for (source, value) in cases.iter() {
+                let mut machine = Machine::new_with_empty_env(::parser::parse(source).unwrap()).with_semantics(semantics);
+                assert_eq!(Ok(value.clone()), machine.run(), "{} {:?}", source, semantics);
+            }
+        }
+        let mut machine = Machine::new_with_empty_env(::parser::parse("x = 1; y + x").unwrap());
+        assert_eq!(Err(RuntimeError::Failed("Variable y not found".to_string())), machine.run());
+        assert_eq!(Node::number(1), machine.environment.get("x"));
+    }
+
+    #[test]
+    fn test_run_spans() {
+        let source = "x = 1;\ndef f(n) {\n  return n + y;\n}\nf(x)";
+        for semantics in [Semantics::BigStep, Semantics::SmallStep] {
+            let mut machine = Machine::new_with_empty_env(::parser::parse_spanned(source).unwrap()).with_semantics(semantics);
+            let error = machine.run().unwrap_err().to_string();
+            assert!(error.starts_with("Variable y not found at line "), "{}", error);
+        }
+        // the error points at the variable big-step, at the statement small-step
+        let mut machine = Machine::new_with_empty_env(::parser::parse_spanned(source).unwrap());
+        assert_eq!(Err(RuntimeError::Failed("Variable y not found at line 3, column 14".to_string())), machine.run());
+        let mut machine = Machine::new_with_empty_env(::parser::parse_spanned("x = 2;\nx * 21").unwrap());
+        assert_eq!(Ok(Node::number(42)), machine.run());
+    }
+
+    #[test]
+    fn test_run_signal_outside_loop() {
+        for source in ["x = 1;\nbreak; 5", "continue; 5", "if (true) { break; } 5"] {
+            for semantics in [Semantics::BigStep, Semantics::SmallStep] {
+                let mut machine = Machine::new_with_empty_env(::parser::parse_spanned(source).unwrap()).with_semantics(semantics);
+                let error = machine.run().unwrap_err().to_string();
+                assert!(error.starts_with("break or continue outside of a loop at line "), "{}", error);
+            }
+        }
+        let mut machine = Machine::new_with_empty_env(::parser::parse("while (true) { break; } 5").unwrap());
+        assert_eq!(Ok(Node::number(5)), machine.run());
+    }
+
+    #[test]
+    fn test_assign_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // x = 1; y = 2; x = x + y; def f() { global x = 10; }; f()
+        let program = Node::sequence(Node::assign("x", Node::number(1)), Node::sequence(
+            Node::assign("y", Node::number(2)), Node::sequence(
+            Node::assign("x", Node::add(Node::variable("x"), Node::variable("y"))), Node::sequence(
+            Node::assign("f", Node::fun("f", &[], Node::global("x", Node::number(10)))),
+            Node::call_args(Node::variable("f"), vec![])))));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let seen = log.clone();
+        let hook: AssignHook = Rc::new(move |assignment| if assignment.name == "x" {
+            seen.borrow_mut().push(format!("{:?} -> {} by {}", assignment.old.as_ref().map(|old| old.to_string()),
+                                           assignment.new, assignment.statement));
+        });
+        let mut machine = Machine::new_with_empty_env(program).with_assign_hook(hook);
+        machine.run().unwrap();
+        assert_eq!(vec!["None -> 1 by x = 1".to_string(),
+                        "Some(\"1\") -> 3 by x = x + y".to_string(),
+                        "None -> 10 by global x = 10".to_string()], *log.borrow());
+        // the hook is only installed while the machine runs
+        machine.environment.add("x", Node::number(0));
+        Node::assign("x", Node::number(5)).evaluate(&mut machine.environment);
+        assert_eq!(3, log.borrow().len());
+    }
+
+    #[test]
+    fn test_eval_many() {
+        let mut machine = Machine::new_with_empty_env(Node::donothing());
+        machine.set("rate", 3);
+        let results = machine.eval_many(&["base = 10;", "base * rate", "base +", "missing + 1", "base - 1"]);
+        let shown: Vec<_> = results.iter().map(|result| match *result {
+            Ok(ref value) => value.to_string(),
+            Err(EvalError::Parse(_)) => "parse error".to_string(),
+            Err(EvalError::Runtime(ref e)) => e.to_string(),
+        }).collect();
+        assert_eq!(vec!["do-nothing", "30", "parse error", "Variable missing not found at line 1, column 1", "9"], shown);
+        // the limits are the batch's
+        let mut machine = Machine::new_with_empty_env(Node::donothing()).with_step_limit(100);
+        let results = machine.eval_many(&["x = 1;", "while (true) { x = x + 1; }", "x"]);
+        assert!(results[0].is_ok());
+        for result in &results[1..] {
+            match *result {
+                Err(EvalError::Runtime(RuntimeError::Stopped(Stopped::Timeout(_)))) => (),
+                ref other => panic!("expected running out of steps, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let program = ::parser::parse("a = rate * 10; b = a + 1; c = 5; def f(x) { return x + b; }
+            d = f(1); if (c > a) { e = c; } else { e = a; } total = d + e;").unwrap();
+        let mut machine = Machine::new_with_empty_env(program);
+        machine.set("rate", 2);
+        machine.run().unwrap();
+        assert_eq!(42, machine.get::<i64>("total").unwrap());
+        let evaluated = Rc::new(std::cell::RefCell::new(0));
+        let count = evaluated.clone();
+        machine.on_change("c", move |_| *count.borrow_mut() += 1);
+        machine.set("rate", 3);
+        assert_eq!(vec!["a", "b", "d", "e", "f", "total"], machine.invalidate("rate").unwrap());
+        assert_eq!(62, machine.get::<i64>("total").unwrap());
+        assert_eq!(0, *evaluated.borrow());
+        assert_eq!(Vec::<String>::new(), machine.invalidate("unused").unwrap());
+        // a statement with effects is not run again
+        let mut machine = Machine::new_with_empty_env(::parser::parse("a = rate * 2; print(a)").unwrap())
+            .with_output(Rc::new(std::cell::RefCell::new(Vec::new())));
+        machine.set("rate", 1);
+        machine.run().unwrap();
+        machine.set("rate", 2);
+        let error = machine.invalidate("rate").unwrap_err();
+        assert_eq!("Cannot evaluate `call print arg a` again, it depends on rate and has effects: io", error.to_string());
+        assert_eq!(2, machine.get::<i64>("a").unwrap());
+    }
+
+    #[test]
+    fn test_on_change() {
+        use std::cell::RefCell;
+
+        let program = ::parser::parse("x = 1; y = 2; def bump(n) { global x = x + n; } r = bump(5); x = x * 2;").unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let hook_log = Rc::new(RefCell::new(0));
+        let seen = hook_log.clone();
+        let mut machine = Machine::new_with_empty_env(program)
+            .with_assign_hook(Rc::new(move |_: &Assignment| *seen.borrow_mut() += 1));
+        for watcher in 0..2 {
+            let seen = log.clone();
+            machine.on_change("x", move |assignment| seen.borrow_mut().push(format!("{}: {}", watcher, assignment.new)));
+        }
+        machine.run().unwrap();
+        assert_eq!(vec!["0: 1", "1: 1", "0: 6", "1: 6", "0: 12", "1: 12"], *log.borrow());
+        // x = 1, y = 2, bump, global x, r, x = x * 2
+        assert_eq!(6, *hook_log.borrow());
+    }
+
+    #[test]
+    fn test_events() {
+        use std::cell::RefCell;
+
+        let program = ::parser::parse_spanned("def double(n) { return n * 2; }\nx = double(3);").unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let seen = log.clone();
+        let mut machine = Machine::new_with_empty_env(program.clone())
+            .with_events(Rc::new(move |event: &MachineEvent| seen.borrow_mut().push(event.clone())));
+        machine.run().unwrap();
+        let log = log.borrow();
+        assert_eq!(Some(&MachineEvent::StepStarted { step: 0 }), log.first());
+        assert_eq!(Some(&MachineEvent::Completed { value: "do-nothing".to_string() }), log.last());
+        let called = log.iter().position(|event| *event == MachineEvent::Called { fun: "double".to_string() }).unwrap();
+        let assigned: Vec<_> = log.iter().enumerate()
+            .filter_map(|(at, event)| match *event {
+                MachineEvent::Assigned { ref name } => Some((at, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        // the call is made before its value is assigned
+        assert_eq!(vec!["double", "x"], assigned.iter().map(|&(_, name)| name).collect::<Vec<_>>());
+        assert!(called < assigned[1].0);
+        // reduced in the body of the function, where its statement was written
+        let span = ::simple::syntax::Span { line: 1, col: 17, end_line: 1, end_col: 30 };
+        assert!(log.contains(&MachineEvent::Reduced { rule: "multiply".to_string(), span: Some(span) }));
+        // the same events are sent on a channel, small-step too, once stepped to the end
+        let (sink, receiver) = events::channel();
+        let mut machine = Machine::new_with_empty_env(program.without_spans())
+            .with_semantics(Semantics::SmallStep)
+            .with_events(sink);
+        while machine.step().is_some() {}
+        assert!(machine.step().is_none());
+        let events: Vec<MachineEvent> = receiver.try_iter().collect();
+        assert_eq!(1, events.iter().filter(|event| **event == MachineEvent::Called { fun: "double".to_string() }).count());
+        assert_eq!(1, events.iter().filter(|event| matches!(**event, MachineEvent::Completed { .. })).count());
+        assert!(events.contains(&MachineEvent::Reduced { rule: "multiply".to_string(), span: None }));
+    }
+
+    #[test]
+    fn test_trace() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let program = Node::assign("x", Node::add(Node::number(1), Node::number(2)));
+        let mut machine = Machine::new_with_empty_env(program.clone());
+        machine.run().unwrap();
+        let sink = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = Machine::new_with_empty_env(program).with_trace(sink.clone());
+        machine.run().unwrap();
+        let trace = String::from_utf8(sink.borrow().clone()).unwrap();
+        assert_eq!(4, trace.matches("evaluate ").count());
+        assert!(trace.starts_with("evaluate x = 1 + 2 with environment \n{\n}\n\n"));
+    }
+
+    #[test]
+    fn test_input() {
+        use std::cell::RefCell;
+        use std::io::Cursor;
+        use std::rc::Rc;
+
+        // a = input(); b = input(); c = input(); d = input(); e = input()
+        let names = ["a", "b", "c", "d", "e"];
+        let program = names.iter().rev().fold(Node::donothing(), |rest, name|
+            Node::sequence(Node::assign(name, Node::call_args(Node::variable("input"), vec![])), rest));
+        let stdin = Rc::new(RefCell::new(Cursor::new("42\n 2.5 \nhello world\r\n-7")));
+        let mut machine = Machine::new_with_empty_env(program).with_input(stdin);
+        machine.run().unwrap();
+        let env = machine.get_environment();
+        assert_eq!(Node::number(42), env.get("a"));
+        assert_eq!(Node::float(2.5), env.get("b"));
+        assert_eq!(Node::string("hello world"), env.get("c"));
+        assert_eq!(Node::number(-7), env.get("d"));
+        assert_eq!(Node::donothing(), env.get("e"));
+    }
+
+    #[test]
+    fn test_register_native() {
+        // def hyp(a, b) { sqrt(a * a + b * b) }; result = hyp(3, 4.0); f = sqrt
+        let program = Node::sequence(
+            Node::assign("hyp", Node::fun("hyp", &["a", "b"], Node::call(Node::variable("sqrt"), Node::add(
+                Node::multiply(Node::variable("a"), Node::variable("a")),
+                Node::multiply(Node::variable("b"), Node::variable("b")))))),
+            Node::sequence(
+                Node::assign("result", Node::call_args(Node::variable("hyp"), vec![Node::number(3), Node::float(4.0)])),
+                Node::assign("f", Node::variable("sqrt"))));
+        for strategy in [Strategy::Value, Strategy::Need] {
+            let mut machine = Machine::new_with_empty_env(program.clone()).with_strategy(strategy);
+            machine.register_native("sqrt", |args| {
+                if args.len() != 1 {
+                    panic!("sqrt expects 1 argument, got {}", args.len());
+                }
+                Node::float(args[0].float_value().sqrt())
+            });
+            machine.run().unwrap();
+            let env = machine.get_environment();
+            assert_eq!(Node::float(5.0), env.get("result"));
+            assert_eq!("native sqrt", env.get("f").to_string());
+            assert_eq!(Some("sqrt"), env.get("f").function_name());
+        }
+    }
+
+    #[test]
+    fn test_set_get() {
+        let mut machine = Machine::new_with_empty_env(::parser::parse(
+            "result = len(xs); ends = (head(xs), fst(bounds)); found = nothing();").unwrap());
+        machine.set("xs", vec![1, 2, 3]);
+        machine.set("bounds", (0.5, Some("hi")));
+        machine.run().unwrap();
+        assert_eq!(Ok(3), machine.get::<i64>("result"));
+        assert_eq!(Ok((1, 0.5)), machine.get::<(i64, f64)>("ends"));
+        assert_eq!(Ok(Some("hi".to_string())), machine.get::<(f64, Option<String>)>("bounds").map(|bounds| bounds.1));
+        assert_eq!(Ok(None), machine.get::<Option<i64>>("found"));
+        assert_eq!(Err(GetError::Unbound("missing".to_string())), machine.get::<i64>("missing"));
+        assert_eq!("expected a string, found 3", machine.get::<String>("result").unwrap_err().to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        use serde_json;
+
+        let program = ::parser::parse_spanned("def add(a) { return fun(b) { return a + b; }; } inc = add(1);
+            m = [1.5, (\"s\", true)]; d = {\"k\": m}; match (m) { [x, _] => { r = x; } _ => { r = 0; } }").unwrap();
+        let json = serde_json::to_string(&program).unwrap();
+        assert_eq!(program, serde_json::from_str(&json).unwrap());
+        let mut machine = Machine::new_with_empty_env(program);
+        let mut last = None;
+        while let Some(state) = machine.step() {
+            last = Some(state);
+        }
+        let state = last.unwrap();
+        let back: MachineState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(state, back);
+        assert_eq!(Some(Node::float(1.5)), back.environment.lookup("r"));
+        // the closure comes back with the environment it captured
+        let mut env = back.environment;
+        assert_eq!(Node::number(3), Node::call(Node::variable("inc"), Node::number(2)).evaluate(&mut env));
+        assert!(serde_json::to_string(&Node::native("id", |args| args[0].clone())).is_err());
+    }
+
+    #[test]
+    fn test_set_fn() {
+        use simple::convert::Value;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let logged = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = Machine::new_with_empty_env(::parser::parse(
+            "log(1 + 2) log(\"done\") result = add(2, 3) * answer();").unwrap());
+        let sink = logged.clone();
+        machine.set_fn("log", move |v: Value| sink.borrow_mut().push(v.to_string()));
+        machine.set_fn("add", |a: i64, b: i64| a + b);
+        machine.set_fn("answer", || 42);
+        machine.run().unwrap();
+        assert_eq!(vec!["3".to_string(), "\"done\"".to_string()], *logged.borrow());
+        assert_eq!(Ok(210), machine.get::<i64>("result"));
+        machine.load(::parser::parse("add(1, true)").unwrap());
+        assert_eq!("Builtin add expects an integer as argument 2, got true", machine.run().unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_set_fn_reentrant() {
+        use simple::convert::Value;
+
+        let program = ::parser::parse("def loop(n) { loop(n + 1) }
+            result = twice(fun(x) { x * 2 }, 5);
+            nested = inner(4);").unwrap();
+        let mut machine = Machine::new_with_empty_env(program).with_step_limit(10_000);
+        // calling back into simple code from the host
+        machine.set_fn("twice", |f: Value, x: Value| {
+            let once = evaluate::call_value(&f, vec![x]);
+            evaluate::call_value(&f, vec![once])
+        });
+        // running another machine from the host
+        machine.set_fn("inner", |n: i64| {
+            let mut inner = Machine::new_with_empty_env(::parser::parse("n * n").unwrap()).with_step_limit(100);
+            inner.set("n", n);
+            inner.run().unwrap()
+        });
+        machine.set_fn("spin", |f: Value| evaluate::call_value(&f, vec![Node::number(0)]));
+        machine.run().unwrap();
+        assert_eq!(Ok(20), machine.get::<i64>("result"));
+        assert_eq!(Ok(16), machine.get::<i64>("nested"));
+        // callbacks are held to the limits of the run calling them
+        machine.load(::parser::parse("spin(loop)").unwrap());
+        match machine.try_run() {
+            Err(Stopped::Timeout(timeout)) => assert_eq!(Limit::Steps(10_000), timeout.limit),
+            other => panic!("expect a timeout, got {:?}", other.map(|value| value.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_set_fn_threads() {
+        use std::sync::Mutex;
+        use std::thread;
+
+        // a machine stays on its thread; host state is shared through Arc
+        let total = Arc::new(Mutex::new(0));
+        let workers: Vec<_> = (1..=4).map(|n| {
+            let total = total.clone();
+            thread::spawn(move || {
+                let mut machine = Machine::new_with_empty_env(::parser::parse("report(n * 10)").unwrap());
+                machine.set("n", n);
+                machine.set_fn("report", move |value: i64| *total.lock().unwrap() += value);
+                machine.run().unwrap();
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(100, *total.lock().unwrap());
+    }
+
+    #[test]
+    fn test_register_builtin() {
+        let program = ::parser::parse("result = hyp(3, 4.0); label = greet(\"ada\", 2);").unwrap();
+        let mut machine = Machine::new_with_empty_env(program);
+        machine.register_builtin(builtin!(fn hyp(a: f64, b: f64) -> f64 { a.hypot(b) }));
+        machine.register_builtin(builtin!(fn greet(name: String, times: i64) -> String {
+            format!("hello {}", name).repeat(times as usize)
+        }));
+        machine.run().unwrap();
+        let env = machine.get_environment();
+        assert_eq!(Node::float(5.0), env.get("result"));
+        assert_eq!(Node::string("hello adahello ada"), env.get("label"));
+        // arity and argument types are checked alike for every builtin
+        let mut machine = Machine::new_with_empty_env(::parser::parse("hyp(1)").unwrap());
+        machine.register_builtin(builtin!(fn hyp(a: f64, b: f64) -> f64 { a.hypot(b) }));
+        assert_eq!("Builtin hyp takes 2 arguments, found 1", machine.run().unwrap_err().to_string());
+        let mut machine = Machine::new_with_empty_env(::parser::parse("greet(1, 2)").unwrap());
+        machine.register_builtin(builtin!(fn greet(name: String, times: i64) -> String { name.repeat(times as usize) }));
+        assert_eq!("Builtin greet expects a string as argument 1, got 1", machine.run().unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_cancellation() {
+        use std::sync::atomic::Ordering;
+        use std::thread;
+        use std::time::Duration;
+
+        // x = 0; while (true) { x = x + 1 }
+        let program = Node::sequence(
+            Node::assign("x", Node::number(0)),
+            Node::while_node(Node::boolean(true),
+                Node::assign("x", Node::add(Node::variable("x"), Node::number(1)))));
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut machine = Machine::new_with_empty_env(program).with_cancellation(flag.clone());
+        let raiser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::Relaxed);
+        });
+        assert!(machine.try_run().is_err());
+        raiser.join().unwrap();
+        // the partial environment survives the interruption
+        assert!(machine.environment.get("x").value() > 0);
+        assert!(machine.steps_taken() > 0);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        use simple::evaluate::Limit;
+
+        // x = 0; while (1 == 1) { x = x + 1 }
+        let program = Node::sequence(
+            Node::assign("x", Node::number(0)),
+            Node::while_node(Node::eq(Node::number(1), Node::number(1)),
+                Node::assign("x", Node::add(Node::variable("x"), Node::number(1)))));
+        let mut machine = Machine::new_with_empty_env(program);
+        let timeout = machine.run_with_limit(1000).unwrap_err();
+        assert_eq!(Limit::Steps(1000), timeout.limit);
+        assert_eq!(1000, machine.steps_taken());
+        assert!(machine.environment.get("x").value() > 0);
+        // the limit is per run, and a run that fits is left alone
+        let mut machine = Machine::new_with_empty_env(Node::assign("y", Node::number(1))).with_step_limit(2);
+        machine.run().unwrap();
+        assert_eq!(Ok(()), machine.run_with_limit(2));
+        let stopped = machine.try_execute(&Node::assign("y", Node::add(Node::number(1), Node::number(2))));
+        assert_eq!("Step limit of 2 reached while evaluating 1", stopped.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_timeout() {
+        use simple::evaluate::Limit;
+        use std::time::Instant;
+
+        let program = Node::while_node(Node::boolean(true), Node::donothing());
+        let mut machine = Machine::new_with_empty_env(program).with_timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        match machine.try_run() {
+            Err(Stopped::Timeout(timeout)) => assert_eq!(Limit::Time(Duration::from_millis(50)), timeout.limit),
+            other => panic!("expect a timeout, found {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_cancellation_raised() {
+        let flag = Arc::new(AtomicBool::new(true));
+        let mut machine = Machine::new_with_empty_env(Node::donothing()).with_cancellation(flag);
+        let statement = Node::assign("x", Node::number(1));
+        assert_eq!(Err(Stopped::Interrupted(Interrupted { redex: statement.to_string() })),
+                   machine.try_execute(&statement));
+        // other panics pass through unchanged
+        let failing = panic::catch_unwind(AssertUnwindSafe(|| {
+            Machine::new_with_empty_env(Node::donothing()).execute(&Node::call(Node::variable("fst"), Node::number(0)))
+        }));
+        assert!(failing.is_err());
+    }
+}
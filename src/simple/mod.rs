@@ -1,4 +1,27 @@
 pub mod syntax;
+pub mod convert;
+#[macro_use]
+pub mod builtin;
 pub mod evaluate;
 pub mod environment;
 pub mod machine;
+pub mod reduce;
+pub mod analysis;
+pub mod alpha;
+pub mod deps;
+pub mod js;
+pub mod ruby;
+pub mod rust;
+pub mod wasm;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod subst;
+pub mod trace;
+pub mod compact;
+pub mod cache;
+#[cfg(feature = "async")]
+pub mod future;
+pub mod pool;
+pub mod diagnostics;
+pub mod numfmt;
+pub mod events;
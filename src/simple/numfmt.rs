@@ -0,0 +1,150 @@
+//! How numbers are laid out when a program prints them, so that large
+//! results such as factorials read at a glance: digits grouped in
+//! thousands as a locale writes them, and numbers padded to a width so
+//! that printed columns line up.
+
+use super::syntax::{self, Node};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct NumberFormat {
+    /// Put between groups of three digits of the whole part, if any.
+    pub grouping: Option<char>,
+    /// Separates the whole part of a float from its fraction.
+    pub decimal: char,
+    /// Pad each number with spaces on the left to at least this many
+    /// characters.
+    pub width: usize,
+}
+
+impl Default for NumberFormat {
+    fn default() -> NumberFormat {
+        NumberFormat::plain()
+    }
+}
+
+impl NumberFormat {
+    /// Numbers as they are written in programs, the default.
+    pub const fn plain() -> NumberFormat {
+        NumberFormat { grouping: None, decimal: '.', width: 0 }
+    }
+
+    /// The conventions of `locale`, say `en`, `de_DE` or `fr-CH`, or
+    /// `plain`; `None` for a locale not known.
+    pub fn locale(locale: &str) -> Option<NumberFormat> {
+        let tag = locale.split('.').next().unwrap().replace('_', "-").to_lowercase();
+        let (grouping, decimal) = match tag.as_str() {
+            "plain" | "c" | "posix" => return Some(NumberFormat::plain()),
+            "de-ch" | "fr-ch" | "it-ch" => ('\'', '.'),
+            _ => match tag.split('-').next().unwrap() {
+                "en" | "ja" | "ko" | "zh" | "he" | "th" => (',', '.'),
+                "de" | "nl" | "it" | "es" | "pt" | "id" | "tr" | "da" => ('.', ','),
+                // a narrow no-break space, so numbers do not wrap
+                "fr" | "sv" | "fi" | "nb" | "pl" | "cs" | "ru" | "uk" => ('\u{202f}', ','),
+                _ => return None,
+            },
+        };
+        Some(NumberFormat { grouping: Some(grouping), decimal, width: 0 })
+    }
+
+    pub fn with_width(mut self, width: usize) -> NumberFormat {
+        self.width = width;
+        self
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == NumberFormat::plain()
+    }
+
+    fn pad(&self, text: String) -> String {
+        format!("{:>width$}", text, width = self.width)
+    }
+
+    /// The digits of `whole`, a run of ASCII digits, grouped in thousands.
+    fn group(&self, whole: &str) -> String {
+        let separator = match self.grouping {
+            Some(separator) => separator,
+            None => return whole.to_string(),
+        };
+        let mut grouped = String::new();
+        for (index, digit) in whole.chars().enumerate() {
+            if index > 0 && (whole.len() - index).is_multiple_of(3) {
+                grouped.push(separator);
+            }
+            grouped.push(digit);
+        }
+        grouped
+    }
+
+    pub fn number(&self, value: i64) -> String {
+        let sign = if value < 0 { "-" } else { "" };
+        self.pad(format!("{}{}", sign, self.group(&value.unsigned_abs().to_string())))
+    }
+
+    /// A float as Debug writes it, keeping the decimal point so it does not
+    /// read as an integer; exponents, infinities and NaN are left as they are.
+    pub fn float(&self, value: f64) -> String {
+        let text = format!("{:?}", value);
+        let (sign, unsigned) = match text.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", &text[..]),
+        };
+        let formatted = match unsigned.find('.') {
+            Some(point) if !unsigned.contains('e') =>
+                format!("{}{}{}{}", sign, self.group(&unsigned[..point]), self.decimal, &unsigned[point + 1..]),
+            _ => text.clone(),
+        };
+        self.pad(formatted)
+    }
+
+    /// How `value` prints: as `Display` has it, but with the numbers in it,
+    /// in pairs, lists and maps too, laid out in this format.
+    pub fn value(&self, value: &Node) -> String {
+        if self.is_plain() {
+            return value.to_string();
+        }
+        match *value {
+            Node::Number(value) => self.number(value),
+            Node::Float(value) => self.float(value),
+            Node::Pair(ref fst, ref snd) => format!("({}, {})", self.value(fst), self.value(snd)),
+            Node::List(ref items) => format!("[{}]", items.iter().map(|item| self.value(item)).collect::<Vec<_>>().join(", ")),
+            Node::Map(ref entries) => format!("{{{}}}", syntax::sorted(entries).iter()
+                                              .map(|&(key, value)| format!("{:?}: {}", key, self.value(value)))
+                                              .collect::<Vec<_>>().join(", ")),
+            ref value => value.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        let en = NumberFormat::locale("en_US.UTF-8").unwrap();
+        assert_eq!("3,628,800", en.number(3628800));
+        assert_eq!("-999", en.number(-999));
+        assert_eq!("-1,000", en.number(-1000));
+        assert_eq!("-9,223,372,036,854,775,808", en.number(i64::MIN));
+        assert_eq!("1,234.5", en.float(1234.5));
+        assert_eq!("1e20", en.float(1e20));
+        let de = NumberFormat::locale("de-DE").unwrap();
+        assert_eq!("1.234,5", de.float(1234.5));
+        assert_eq!("1'234.5", NumberFormat::locale("de_CH").unwrap().float(1234.5));
+        assert_eq!("1\u{202f}000", NumberFormat::locale("fr").unwrap().number(1000));
+        assert_eq!(None, NumberFormat::locale("xx"));
+        assert_eq!(Some(NumberFormat::plain()), NumberFormat::locale("plain"));
+        // the width pads the number, separators included
+        assert_eq!("   12,345", en.with_width(9).number(12345));
+        assert_eq!("     42", NumberFormat::plain().with_width(7).number(42));
+    }
+
+    #[test]
+    fn test_values() {
+        let en = NumberFormat::locale("en").unwrap();
+        let value = Node::pair(Node::number(5040), Node::list(vec![Node::float(2500.25), Node::string("1000")]));
+        assert_eq!("(5,040, [2,500.25, \"1000\"])", en.value(&value));
+        assert_eq!(value.to_string(), NumberFormat::plain().value(&value));
+        assert_eq!("true", en.value(&Node::Boolean(true)));
+    }
+}
@@ -0,0 +1,206 @@
+//! A pool of machines set up once, for services evaluating many small
+//! programs: natives registered, limits set and a prelude run. A machine
+//! is checked out for one tenant as a copy of the one the pool keeps, and
+//! the copy is dropped when returned, so tenants never see each other's
+//! variables, programs or observers.
+//!
+//! Nodes are reference counted, so machines cannot leave the thread
+//! that made them: a service running on several threads keeps a pool per
+//! thread.
+//!
+//! A pool may keep one result cache for all its machines, so tenants
+//! running the same program against the same prelude share its result.
+//!
+//! Each engine checked out reports the warnings of its tenant to
+//! diagnostics of its own, read with `engine.diagnostics()`, unless the
+//! factory gave its machines diagnostics to report to.
+
+use super::environment::Environment;
+use super::machine::Machine;
+use super::cache::{CacheStats, ResultCache, SharedCache};
+use super::diagnostics::Diagnostics;
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+
+pub type Factory = Box<dyn Fn() -> Machine>;
+
+pub struct EnginePool {
+    factory: Factory,
+    size: usize,
+    idle: RefCell<Vec<Machine>>,
+    cache: Option<SharedCache>,
+}
+
+/// A machine checked out of a pool, returned to it when dropped.
+pub struct Engine<'a> {
+    pool: &'a EnginePool,
+    machine: Machine,
+    /// The machine as the pool keeps it, which the tenant never touches.
+    pristine: Option<Machine>,
+}
+
+/// A copy of the top-level bindings of `env` that shares no scope with
+/// it. Closures keep the scope they were made in, so under
+/// `Capture::Shared` those made by a prelude still share theirs.
+fn detach(env: &Environment) -> Environment {
+    let mut copy = env.fresh();
+    for (name, value) in env.bindings() {
+        copy.add(&name, value);
+    }
+    copy
+}
+
+impl EnginePool {
+    /// A pool keeping `size` machines made by `factory`, made up front.
+    pub fn new<F>(size: usize, factory: F) -> EnginePool
+        where F: Fn() -> Machine + 'static {
+        let idle = (0..size).map(|_| factory()).collect();
+        EnginePool { factory: Box::new(factory), size, idle: RefCell::new(idle), cache: None }
+    }
+
+    /// Keep the results of up to `capacity` runs, shared by the machines
+    /// of the pool (see `cache` for what is cached).
+    pub fn with_cache(mut self, capacity: usize) -> EnginePool {
+        self.cache = Some(ResultCache::shared(capacity));
+        self
+    }
+
+    /// Check out an idle machine, or make one when all are in use.
+    pub fn checkout(&self) -> Engine<'_> {
+        let pristine = self.idle.borrow_mut().pop().unwrap_or_else(|| (self.factory)());
+        let mut machine = pristine.clone();
+        machine.environment = detach(&pristine.environment);
+        if machine.diagnostics().is_none() {
+            machine = machine.with_diagnostics(Diagnostics::new());
+        }
+        if let Some(ref cache) = self.cache {
+            machine = machine.with_result_cache(cache.clone());
+        }
+        Engine { pool: self, machine, pristine: Some(pristine) }
+    }
+
+    /// Number of machines waiting to be checked out.
+    pub fn idle(&self) -> usize {
+        self.idle.borrow().len()
+    }
+
+    /// How the result cache has fared, if the pool keeps one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.borrow().stats())
+    }
+}
+
+impl<'a> Deref for Engine<'a> {
+    type Target = Machine;
+
+    fn deref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl<'a> DerefMut for Engine<'a> {
+    fn deref_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+impl<'a> Drop for Engine<'a> {
+    fn drop(&mut self) {
+        // whatever the tenant did to its copy goes with it
+        let machine = self.pristine.take().unwrap();
+        let mut idle = self.pool.idle.borrow_mut();
+        // machines made past the size of the pool are let go
+        if idle.len() < self.pool.size {
+            idle.push(machine);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::syntax::Node;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_pool() {
+        let pool = EnginePool::new(2, || {
+            let mut machine = Machine::new_with_empty_env(Node::donothing()).with_step_limit(10_000);
+            machine.register_native("double", |args| Node::number(args[0].value() * 2));
+            machine.execute(&parser::parse("def sq(x) { return x * x; }").unwrap());
+            machine
+        });
+        assert_eq!(2, pool.idle());
+        {
+            let mut engine = pool.checkout();
+            engine.load(parser::parse("secret = sq(4); warn(\"peek\") double(secret)").unwrap());
+            assert_eq!(Ok(Node::number(32)), engine.run());
+            assert_eq!("warning: peek", engine.diagnostics().unwrap().all()[0].to_string());
+            let (_second, _third) = (pool.checkout(), pool.checkout());
+            assert_eq!(0, pool.idle());
+        }
+        assert_eq!(2, pool.idle());
+        // the next tenant sees the prelude, not the previous tenant
+        let mut engine = pool.checkout();
+        assert_eq!(None, engine.environment.try_get("secret"));
+        assert!(engine.diagnostics().unwrap().is_empty());
+        engine.load(parser::parse("sq(3)").unwrap());
+        assert_eq!(Ok(Node::number(9)), engine.run());
+    }
+
+    #[test]
+    fn test_pool_isolation() {
+        let pool = EnginePool::new(1, || Machine::new_with_empty_env(Node::donothing()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut engine = pool.checkout();
+            let log = seen.clone();
+            engine.on_change("x", move |assignment| log.borrow_mut().push(assignment.new.to_string()));
+            engine.load(parser::parse("x = 1; x + 1").unwrap());
+            assert_eq!(Ok(Node::number(2)), engine.run());
+        }
+        assert_eq!(vec!["1"], *seen.borrow());
+        let mut engine = pool.checkout();
+        assert_eq!(Node::DoNothing, *engine.program());
+        assert_eq!(0, engine.steps_taken());
+        assert_eq!(Ok(Node::donothing()), engine.run());
+        engine.load(parser::parse("x = 5;").unwrap());
+        engine.run().unwrap();
+        // the observer of the previous tenant is gone with it
+        assert_eq!(vec!["1"], *seen.borrow());
+    }
+
+    #[test]
+    fn test_pool_diagnostics() {
+        let shared = Diagnostics::new();
+        let factory = shared.clone();
+        let pool = EnginePool::new(1, move || Machine::new_with_empty_env(Node::donothing()).with_diagnostics(factory.clone()));
+        let mut engine = pool.checkout();
+        engine.load(parser::parse("warn(\"logged\")").unwrap());
+        engine.run().unwrap();
+        assert_eq!("warning: logged", shared.all()[0].to_string());
+    }
+
+    #[test]
+    fn test_pool_cache() {
+        let pool = EnginePool::new(1, || {
+            let mut machine = Machine::new_with_empty_env(Node::donothing());
+            machine.register_native("double", |args| Node::number(args[0].value() * 2));
+            machine.execute(&parser::parse("base = 10;").unwrap());
+            machine
+        }).with_cache(8);
+        for _ in 0..3 {
+            let mut engine = pool.checkout();
+            engine.load(parser::parse("y = base * 3; y + 1").unwrap());
+            assert_eq!(Ok(Node::number(31)), engine.run());
+            assert_eq!(Node::number(30), engine.environment.get("y"));
+        }
+        // reaching a native is never cached
+        let mut engine = pool.checkout();
+        engine.load(parser::parse("double(base)").unwrap());
+        assert_eq!(Ok(Node::number(20)), engine.run());
+        assert_eq!(Some(CacheStats { hits: 2, misses: 1, uncacheable: 1, evictions: 0 }), pool.cache_stats());
+    }
+}
@@ -0,0 +1,339 @@
+use super::syntax::Node;
+
+use std::panic::{self, AssertUnwindSafe};
+
+/// Split a chain of `Sequence` nodes into its top-level statements, to
+/// take them out or put them back one by one.
+pub fn statements(node: &Node) -> Vec<Node> {
+    node.statements().into_iter().filter(|stat| **stat != Node::DoNothing).cloned().collect()
+}
+
+/// Fold statements back into a `Sequence` chain, the same shape the parser builds.
+pub fn sequence(stats: &[Node]) -> Box<Node> {
+    match stats.split_first() {
+        None => Node::donothing(),
+        Some((first, rest)) => rest.iter().fold(
+            Box::new(first.clone()), |acc, node| Node::sequence(acc, Box::new(node.clone()))),
+    }
+}
+
+/// Delta debugging (ddmin) over a statement list: returns a subsequence
+/// for which `test` still holds and from which no single chunk at the
+/// finest granularity can be removed.
+fn ddmin<F>(stats: Vec<Node>, test: &F) -> Vec<Node>
+    where F: Fn(&[Node]) -> bool {
+    let mut stats = stats;
+    let mut granularity = 2;
+    while stats.len() >= 2 {
+        let chunk = stats.len().div_ceil(granularity);
+        let mut reduced = false;
+        let mut start = 0;
+        while start < stats.len() {
+            let end = (start + chunk).min(stats.len());
+            let complement: Vec<Node> = stats[..start].iter()
+                .chain(stats[end..].iter()).cloned().collect();
+            if !complement.is_empty() && test(&complement) {
+                stats = complement;
+                granularity = (granularity - 1).max(2);
+                reduced = true;
+                break;
+            }
+            start = end;
+        }
+        if !reduced {
+            if granularity >= stats.len() {
+                break;
+            }
+            granularity = (granularity * 2).min(stats.len());
+        }
+    }
+    stats
+}
+
+/// Candidate programs one step smaller than `node`: the node replaced by
+/// one of its parts, or one of its children replaced by a smaller candidate.
+fn shrink(node: &Node) -> Vec<Box<Node>> {
+    let mut candidates = Vec::new();
+    match *node {
+        Node::Add(ref l, ref r) | Node::Subtract(ref l, ref r) |
+            Node::Multiply(ref l, ref r) | Node::LT(ref l, ref r) |
+            Node::EQ(ref l, ref r) | Node::GT(ref l, ref r) |
+            Node::LE(ref l, ref r) | Node::GE(ref l, ref r) | Node::NE(ref l, ref r) |
+            Node::And(ref l, ref r) | Node::Or(ref l, ref r) |
+            Node::Pair(ref l, ref r) | Node::Compose(ref l, ref r) |
+            Node::Index(ref l, ref r) => {
+                candidates.push(l.clone());
+                candidates.push(r.clone());
+                let rebuild = |l: Box<Node>, r: Box<Node>| match *node {
+                    Node::Add(..) => Node::add(l, r),
+                    Node::Subtract(..) => Node::subtract(l, r),
+                    Node::Multiply(..) => Node::multiply(l, r),
+                    Node::LT(..) => Node::lt(l, r),
+                    Node::EQ(..) => Node::eq(l, r),
+                    Node::GT(..) => Node::gt(l, r),
+                    Node::LE(..) => Node::le(l, r),
+                    Node::GE(..) => Node::ge(l, r),
+                    Node::NE(..) => Node::ne(l, r),
+                    Node::And(..) => Node::and(l, r),
+                    Node::Or(..) => Node::or(l, r),
+                    Node::Compose(..) => Node::compose(l, r),
+                    Node::Index(..) => Node::index(l, r),
+                    _ => Node::pair(l, r),
+                };
+                candidates.extend(shrink(l).into_iter().map(|c| rebuild(c, r.clone())));
+                candidates.extend(shrink(r).into_iter().map(|c| rebuild(l.clone(), c)));
+        }
+        Node::Sequence(ref head, ref more) => {
+            candidates.push(head.clone());
+            candidates.push(more.clone());
+            candidates.extend(shrink(head).into_iter().map(|c| Node::sequence(c, more.clone())));
+            candidates.extend(shrink(more).into_iter().map(|c| Node::sequence(head.clone(), c)));
+        }
+        Node::If(ref condition, ref consequence, ref alternative) => {
+            candidates.push(consequence.clone());
+            candidates.push(alternative.clone());
+            candidates.extend(shrink(condition).into_iter().map(
+                |c| Node::if_cond_else(c, consequence.clone(), alternative.clone())));
+            candidates.extend(shrink(consequence).into_iter().map(
+                |c| Node::if_cond_else(condition.clone(), c, alternative.clone())));
+            candidates.extend(shrink(alternative).into_iter().map(
+                |c| Node::if_cond_else(condition.clone(), consequence.clone(), c)));
+        }
+        Node::While(ref cond, ref body) => {
+            candidates.push(body.clone());
+            candidates.extend(shrink(cond).into_iter().map(|c| Node::while_node(c, body.clone())));
+            candidates.extend(shrink(body).into_iter().map(|c| Node::while_node(cond.clone(), c)));
+        }
+        Node::For(ref init, ref cond, ref step, ref body) => {
+            candidates.push(init.clone());
+            candidates.push(body.clone());
+            candidates.push(Node::while_node(cond.clone(), body.clone()));
+            candidates.extend(shrink(init).into_iter().map(
+                |c| Node::for_node(c, cond.clone(), step.clone(), body.clone())));
+            candidates.extend(shrink(cond).into_iter().map(
+                |c| Node::for_node(init.clone(), c, step.clone(), body.clone())));
+            candidates.extend(shrink(step).into_iter().map(
+                |c| Node::for_node(init.clone(), cond.clone(), c, body.clone())));
+            candidates.extend(shrink(body).into_iter().map(
+                |c| Node::for_node(init.clone(), cond.clone(), step.clone(), c)));
+        }
+        Node::IsDoNothing(ref inner) | Node::Fst(ref inner) | Node::Snd(ref inner) |
+            Node::Len(ref inner) | Node::Not(ref inner) | Node::Negate(ref inner) |
+            Node::Return(ref inner) | Node::Delay(ref inner) => {
+            candidates.push(inner.clone());
+            let rebuild = |c: Box<Node>| match *node {
+                Node::IsDoNothing(..) => Node::isdonothing(c),
+                Node::Fst(..) => Node::fst(c),
+                Node::Len(..) => Node::len(c),
+                Node::Not(..) => Node::not(c),
+                Node::Negate(..) => Node::negate(c),
+                Node::Return(..) => Node::return_node(c),
+                Node::Delay(..) => Node::delay(c),
+                _ => Node::snd(c),
+            };
+            candidates.extend(shrink(inner).into_iter().map(rebuild));
+        }
+        Node::Match(ref value, ref arms) => {
+            candidates.extend(arms.iter().map(|(_, body)| body.clone()));
+            for i in 0..arms.len() {
+                let mut fewer = arms.clone();
+                fewer.remove(i);
+                candidates.push(Node::match_node(value.clone(), fewer));
+            }
+            candidates.extend(shrink(value).into_iter().map(|c| Node::match_node(c, arms.clone())));
+            for (i, (_, body)) in arms.iter().enumerate() {
+                candidates.extend(shrink(body).into_iter().map(|c| {
+                    let mut arms = arms.clone();
+                    arms[i].1 = c;
+                    Node::match_node(value.clone(), arms)
+                }));
+            }
+        }
+        Node::List(ref items) => {
+            candidates.extend(items.iter().cloned());
+            for i in 0..items.len() {
+                let mut fewer = items.clone();
+                fewer.remove(i);
+                candidates.push(Node::list(fewer));
+            }
+            for (i, item) in items.iter().enumerate() {
+                candidates.extend(shrink(item).into_iter().map(|c| {
+                    let mut items = items.clone();
+                    items[i] = c;
+                    Node::list(items)
+                }));
+            }
+        }
+        Node::Map(ref entries) => {
+            candidates.extend(entries.values().cloned());
+            for key in entries.keys() {
+                let mut fewer = entries.clone();
+                fewer.remove(key);
+                candidates.push(Node::map(fewer));
+            }
+            for (key, value) in entries.iter() {
+                candidates.extend(shrink(value).into_iter().map(|c| {
+                    let mut entries = entries.clone();
+                    entries.insert(key.clone(), c);
+                    Node::map(entries)
+                }));
+            }
+        }
+        Node::Assign(ref name, ref expr) => {
+            candidates.push(expr.clone());
+            candidates.extend(shrink(expr).into_iter().map(|c| Node::assign(name, c)));
+        }
+        Node::Global(ref name, ref expr) => {
+            candidates.push(Node::assign(name, expr.clone()));
+            candidates.extend(shrink(expr).into_iter().map(|c| Node::global(name, c)));
+        }
+        Node::Fun(ref funname, ref params, ref body) => {
+            candidates.extend(shrink(body).into_iter().map(
+                |c| Box::new(Node::Fun(funname.clone(), params.clone(), c))));
+        }
+        Node::Call(ref closure, ref args) => {
+            candidates.extend(args.iter().cloned());
+            candidates.extend(shrink(closure).into_iter().map(|c| Node::call_args(c, args.clone())));
+            for (i, arg) in args.iter().enumerate() {
+                candidates.extend(shrink(arg).into_iter().map(|c| {
+                    let mut args = args.clone();
+                    args[i] = c;
+                    Node::call_args(closure.clone(), args)
+                }));
+            }
+        }
+        // Number, Float, Str, Boolean, Variable, DoNothing, Closure, Builtin, Native
+        _ => (),
+    }
+    if *node != Node::DoNothing {
+        candidates.push(Node::donothing());
+    }
+    candidates
+}
+
+/// Evaluate a user predicate, treating a panic inside it as "does not hold".
+fn holds<P>(predicate: &P, program: &Node) -> bool
+    where P: Fn(&Node) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(|| predicate(program))).unwrap_or(false)
+}
+
+/// Shrink `program` while `predicate` keeps holding on it, e.g. "this still
+/// panics the evaluator". Whole statements are removed first with delta
+/// debugging, then sub-expressions are greedily replaced by smaller ones
+/// until no single simplification keeps the predicate true.
+///
+/// If the predicate does not hold on `program` itself, it is returned as is.
+pub fn reduce_failing_case<P>(program: &Node, predicate: P) -> Box<Node>
+    where P: Fn(&Node) -> bool {
+    if !holds(&predicate, program) {
+        return Box::new(program.clone());
+    }
+    let stats = ddmin(statements(program), &|stats: &[Node]| holds(&predicate, &sequence(stats)));
+    let mut current = sequence(&stats);
+    'shrinking: loop {
+        for candidate in shrink(&current) {
+            if holds(&predicate, &candidate) {
+                current = candidate;
+                continue 'shrinking;
+            }
+        }
+        return current;
+    }
+}
+
+/// Run one backend, turning a panic into `None` so that "crashed" is an
+/// outcome that can be compared like any other.
+fn outcome<B, R>(backend: &B, program: &Node) -> Option<R>
+    where B: Fn(&Node) -> R {
+    panic::catch_unwind(AssertUnwindSafe(|| backend(program))).ok()
+}
+
+/// Shrink `program` to the smallest program on which the two backends
+/// still disagree. Each backend maps a program to an observable outcome
+/// (final environment, value, ...); a panic is treated as an outcome of
+/// its own. Returns `None` if the backends agree on `program`.
+pub fn minimize_divergence<A, B, R>(program: &Node, first: A, second: B) -> Option<Box<Node>>
+    where A: Fn(&Node) -> R, B: Fn(&Node) -> R, R: PartialEq {
+    let diverges = |program: &Node| outcome(&first, program) != outcome(&second, program);
+    if !diverges(program) {
+        return None;
+    }
+    Some(reduce_failing_case(program, diverges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple::environment::Environment;
+    use simple::evaluate::Evaluate;
+
+    fn run(program: &Node) -> Environment {
+        let mut env = Environment::new();
+        program.evaluate(&mut env);
+        env
+    }
+
+    #[test]
+    fn test_statements_roundtrip() {
+        let program = Node::sequence(
+            Node::sequence(Node::assign("x", Node::number(1)), Node::assign("y", Node::number(2))),
+            Node::assign("z", Node::number(3)));
+        let stats = statements(&program);
+        assert_eq!(3, stats.len());
+        assert_eq!(statements(&sequence(&stats)), stats);
+    }
+
+    #[test]
+    fn test_minimize_divergence() {
+        // a faulty backend that evaluates every multiplication as an addition
+        fn faulty(node: &Node) -> Box<Node> {
+            match *node {
+                Node::Multiply(ref l, ref r) => Node::add(faulty(l), faulty(r)),
+                Node::Assign(ref name, ref expr) => Node::assign(name, faulty(expr)),
+                Node::Sequence(ref l, ref r) => Node::sequence(faulty(l), faulty(r)),
+                _ => Box::new(node.clone()),
+            }
+        }
+        let program = sequence(&[
+            *Node::assign("a", Node::number(1)),
+            *Node::assign("b", Node::add(Node::variable("a"), Node::number(2))),
+            *Node::assign("c", Node::multiply(Node::number(3), Node::number(4))),
+            *Node::assign("d", Node::subtract(Node::variable("b"), Node::number(1))),
+        ]);
+        let minimal = minimize_divergence(&program, run, |p: &Node| run(&faulty(p))).unwrap();
+        assert_eq!(Node::assign("c", Node::multiply(Node::number(3), Node::number(4))), minimal);
+    }
+
+    #[test]
+    fn test_minimize_no_divergence() {
+        let program = Node::assign("x", Node::number(1));
+        assert_eq!(None, minimize_divergence(&program, run, run));
+    }
+
+    #[test]
+    fn test_reduce_failing_case() {
+        // fst on a number panics; everything else is noise
+        let program = sequence(&[
+            *Node::assign("x", Node::number(3)),
+            *Node::while_node(
+                Node::lt(Node::variable("x"), Node::number(5)),
+                Node::sequence(
+                    Node::assign("x", Node::add(Node::variable("x"), Node::number(1))),
+                    Node::assign("y", Node::fst(Node::add(Node::variable("x"), Node::number(1)))))),
+            *Node::assign("z", Node::number(1)),
+        ]);
+        let panics = |p: &Node| match panic::catch_unwind(AssertUnwindSafe(|| run(p))) {
+            Err(e) => e.downcast_ref::<String>().is_some_and(|m| m.contains("non-pair")),
+            Ok(_) => false,
+        };
+        let minimal = reduce_failing_case(&program, panics);
+        assert!(panics(&minimal));
+        assert_eq!(Node::fst(Node::donothing()), minimal);
+    }
+
+    #[test]
+    fn test_reduce_predicate_not_holding() {
+        let program = Node::assign("x", Node::number(1));
+        assert_eq!(Box::new(*program.clone()), reduce_failing_case(&program, |_| false));
+    }
+}
@@ -0,0 +1,301 @@
+//! The denotational semantics of Understanding Computation, chapter 2:
+//! the meaning of a node is the source of a Ruby lambda from an
+//! environment, a hash from symbols to values, written in terms of the
+//! meanings of its sub-expressions. An expression denotes a lambda giving
+//! its value, and a statement one giving the environment after it, as in
+//! the book; the nodes the book has come out as it writes them.
+//!
+//! The rest of the language follows in the same style. Lists are Ruby
+//! arrays and pairs two-element ones, maps are hashes with string keys,
+//! and do-nothing as a value is `nil`. A function is a lambda taking its
+//! arguments, whose body runs in the environment it was made in with the
+//! function and its parameters bound; `return`, `break` and `continue`
+//! throw to the enclosing call or loop. There is one environment, so
+//! `global` assigns like `=`. Builtins are looked up in the environment,
+//! which the host fills with lambdas, say `{ print: -> x { puts x } }`.
+
+use super::syntax::{Node, Pattern};
+use super::environment::Environment;
+
+use std::collections::HashMap;
+
+/// `name` as a Ruby symbol.
+fn symbol(name: &str) -> String {
+    let mut chars = name.chars();
+    let bare = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if bare { format!(":{}", name) } else { format!(":{}", string(name)) }
+}
+
+/// `text` as a Ruby string literal.
+fn string(text: &str) -> String {
+    // Rust escapes are Ruby ones, `\u{..}` included; `#` would interpolate
+    format!("{:?}", text).replace('#', "\\#")
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "Float::NAN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Float::INFINITY" } else { "-Float::INFINITY" }.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// The Ruby local a name bound by a pattern or parameter is kept in,
+/// prefixed so that it cannot hide the environment `e`.
+fn local(name: &str) -> String {
+    format!("v_{}", name)
+}
+
+/// Whether `node` is a statement, denoting a lambda that gives the
+/// environment after it rather than a value.
+fn is_statement(node: &Node) -> bool {
+    match *node {
+        Node::DoNothing | Node::Assign(..) | Node::Global(..) | Node::Sequence(..) | Node::While(..) | Node::For(..)
+            | Node::Return(_) | Node::Break | Node::Continue => true,
+        Node::If(_, ref consequence, ref alternative) => is_statement(consequence) || is_statement(alternative),
+        Node::Match(_, ref arms) => arms.iter().any(|(_, body)| is_statement(body)),
+        Node::Spanned(_, ref node) => is_statement(node),
+        _ => false,
+    }
+}
+
+/// The meaning of `node` as a statement: an expression is evaluated for
+/// what it does, leaving the environment as it was.
+fn statement(node: &Node) -> String {
+    if is_statement(node) {
+        node.to_ruby()
+    } else {
+        format!("-> e {{ ({}).call(e); e }}", node.to_ruby())
+    }
+}
+
+/// The meaning of `node` as the body of a function: a lambda giving the
+/// value of its last statement, or nothing if that is not an expression.
+fn value(node: &Node) -> String {
+    match *node {
+        _ if !is_statement(node) => node.to_ruby(),
+        Node::Sequence(..) => {
+            let statements = node.statements();
+            let (last, rest) = statements.split_last().unwrap();
+            let e = rest.iter().fold("e".to_string(), |e, node| format!("({}).call({})", statement(node), e));
+            format!("-> e {{ ({}).call({}) }}", value(last), e)
+        }
+        Node::If(ref cond, ref consequence, ref alternative) =>
+            format!("-> e {{ if ({}).call(e) then ({}).call(e) else ({}).call(e) end }}",
+                    cond.to_ruby(), value(consequence), value(alternative)),
+        Node::Match(ref matched, ref arms) => {
+            let arms: Vec<String> = arms.iter().map(|(p, body)| {
+                let names = p.bindings();
+                let scope = if names.is_empty() { "e".to_string() } else { format!("e.merge({})", bindings(&names)) };
+                format!(" in {} then ({}).call({})", pattern(p), value(body), scope)
+            }).collect();
+            format!("-> e {{ case ({}).call(e){} end }}", matched.to_ruby(), arms.concat())
+        }
+        Node::Spanned(_, ref node) => value(node),
+        Node::Return(_) => node.to_ruby(),
+        _ => format!("-> e {{ ({}).call(e); nil }}", node.to_ruby()),
+    }
+}
+
+/// Whether running `node` may break out of, or continue, the loop around
+/// it.
+fn escapes_loop(node: &Node) -> bool {
+    match *node {
+        Node::Break | Node::Continue => true,
+        // those in nested loops and functions are theirs
+        Node::While(..) | Node::For(..) | Node::Fun(..) => false,
+        _ => node.children().into_iter().any(escapes_loop),
+    }
+}
+
+/// A Ruby pattern for `case ... in`, binding the names of `pattern` to
+/// their locals.
+fn pattern(pattern: &Pattern) -> String {
+    match *pattern {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Bind(ref name) => local(name),
+        Pattern::Number(value) => value.to_string(),
+        Pattern::Boolean(value) => value.to_string(),
+        Pattern::Str(ref value) => string(value),
+        Pattern::Pair(ref fst, ref snd) => format!("[{}, {}]", self::pattern(fst), self::pattern(snd)),
+        Pattern::List(ref items) => format!("[{}]", items.iter().map(self::pattern).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// The bindings of `names` from their locals, as a hash to merge.
+fn bindings(names: &[&str]) -> String {
+    let entries: Vec<String> = names.iter().map(|name| format!("{} => {}", symbol(name), local(name))).collect();
+    format!("{{ {} }}", entries.join(", "))
+}
+
+/// The environment `env` as a Ruby hash, enclosing scopes included.
+fn environment(env: &Environment) -> String {
+    let mut scopes = Vec::new();
+    let mut scope = Some(env);
+    while let Some(env) = scope {
+        scopes.push(env);
+        scope = env.parent.as_deref();
+    }
+    let mut vars: HashMap<String, Box<Node>> = HashMap::new();
+    for env in scopes.into_iter().rev() {
+        vars.extend(env.iter());
+    }
+    let entries: Vec<String> = super::syntax::sorted(&vars).into_iter()
+        .map(|(name, value)| format!("{} => ({}).call({{}})", symbol(name), value.to_ruby()))
+        .collect();
+    format!("{{ {} }}", entries.join(", "))
+}
+
+impl Node {
+    /// The source of a Ruby lambda from an environment to the meaning of
+    /// this node in it.
+    pub fn to_ruby(&self) -> String {
+        let call = |node: &Node| format!("({}).call(e)", node.to_ruby());
+        let binary = |l: &Node, op: &str, r: &Node| format!("-> e {{ {} {} {} }}", call(l), op, call(r));
+        match *self {
+            Node::Number(value) => format!("-> e {{ {} }}", value),
+            Node::Float(value) => format!("-> e {{ {} }}", float(value)),
+            Node::Str(ref value) => format!("-> e {{ {} }}", string(value)),
+            Node::Boolean(value) => format!("-> e {{ {} }}", value),
+            Node::DoNothing => "-> e { e }".to_string(),
+            Node::IsDoNothing(ref node) => format!("-> e {{ {}.nil? }}", call(node)),
+            Node::Add(ref l, ref r) => binary(l, "+", r),
+            Node::Subtract(ref l, ref r) => binary(l, "-", r),
+            Node::Multiply(ref l, ref r) => binary(l, "*", r),
+            Node::Negate(ref node) => format!("-> e {{ -{} }}", call(node)),
+            Node::LT(ref l, ref r) => binary(l, "<", r),
+            Node::GT(ref l, ref r) => binary(l, ">", r),
+            Node::LE(ref l, ref r) => binary(l, "<=", r),
+            Node::GE(ref l, ref r) => binary(l, ">=", r),
+            Node::EQ(ref l, ref r) => binary(l, "==", r),
+            Node::NE(ref l, ref r) => binary(l, "!=", r),
+            Node::And(ref l, ref r) => binary(l, "&&", r),
+            Node::Or(ref l, ref r) => binary(l, "||", r),
+            Node::Not(ref node) => format!("-> e {{ !{} }}", call(node)),
+            Node::Variable(ref name) => format!("-> e {{ e[{}] }}", symbol(name)),
+            Node::Assign(ref name, ref expr) | Node::Global(ref name, ref expr) =>
+                format!("-> e {{ e.merge({{ {} => {} }}) }}", symbol(name), call(expr)),
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let branch = |node: &Node| if is_statement(self) { statement(node) } else { node.to_ruby() };
+                format!("-> e {{ if {} then ({}).call(e) else ({}).call(e) end }}",
+                        call(cond), branch(consequence), branch(alternative))
+            }
+            Node::Sequence(ref first, ref second) =>
+                format!("-> e {{ ({}).call(({}).call(e)) }}", statement(second), statement(first)),
+            Node::While(ref cond, ref body) if !escapes_loop(body) =>
+                format!("-> e {{ while {}; e = ({}).call(e); end; e }}", call(cond), statement(body)),
+            Node::While(ref cond, ref body) =>
+                format!("-> e {{ catch(:break) {{ while {}; e = catch(:continue) {{ ({}).call(e) }}; end; e }} }}",
+                        call(cond), statement(body)),
+            Node::For(ref init, ref cond, ref step, ref body) if !escapes_loop(body) =>
+                format!("-> e {{ e = ({}).call(e); while {}; e = ({}).call(({}).call(e)); end; e }}",
+                        statement(init), call(cond), statement(step), statement(body)),
+            Node::For(ref init, ref cond, ref step, ref body) =>
+                format!("-> e {{ e = ({}).call(e); catch(:break) {{ while {}; e = ({}).call(catch(:continue) {{ ({}).call(e) }}); end; e }} }}",
+                        statement(init), call(cond), statement(step), statement(body)),
+            Node::Match(ref value, ref arms) => {
+                let statements = is_statement(self);
+                let arms: Vec<String> = arms.iter().map(|(p, body)| {
+                    let names = p.bindings();
+                    let scope = if names.is_empty() { "e".to_string() } else { format!("e.merge({})", bindings(&names)) };
+                    if !statements {
+                        format!(" in {} then ({}).call({})", pattern(p), body.to_ruby(), scope)
+                    } else if names.is_empty() {
+                        format!(" in {} then ({}).call(e)", pattern(p), statement(body))
+                    } else {
+                        // the names the pattern binds are the arm's
+                        let symbols: Vec<String> = names.iter().map(|name| symbol(name)).collect();
+                        format!(" in {} then ({}).call({}).except({}).merge(e.slice({}))", pattern(p), statement(body), scope,
+                                symbols.join(", "), symbols.join(", "))
+                    }
+                }).collect();
+                format!("-> e {{ case {}{} end }}", call(value), arms.concat())
+            }
+            Node::Return(ref value) => format!("-> e {{ throw :return, {} }}", call(value)),
+            Node::Break => "-> e { throw :break, e }".to_string(),
+            Node::Continue => "-> e { throw :continue, e }".to_string(),
+            Node::Pair(ref fst, ref snd) => format!("-> e {{ [{}, {}] }}", call(fst), call(snd)),
+            Node::Fst(ref pair) => format!("-> e {{ {}[0] }}", call(pair)),
+            Node::Snd(ref pair) => format!("-> e {{ {}[1] }}", call(pair)),
+            Node::Len(ref node) => format!("-> e {{ {}.length }}", call(node)),
+            Node::List(ref items) => format!("-> e {{ [{}] }}", items.iter().map(|item| call(item)).collect::<Vec<_>>().join(", ")),
+            Node::Index(ref list, ref index) => format!("-> e {{ {}[{}] }}", call(list), call(index)),
+            Node::Map(ref entries) => {
+                let entries: Vec<String> = super::syntax::sorted(entries).into_iter()
+                    .map(|(key, value)| format!("{} => {}", string(key), call(value))).collect();
+                format!("-> e {{ {{ {} }} }}", entries.join(", "))
+            }
+            Node::Fun(ref name, ref params, ref body) => {
+                let locals: Vec<String> = params.iter().map(|param| local(param)).collect();
+                let mut entries = vec![format!("{} => fun", symbol(name))];
+                entries.extend(params.iter().map(|param| format!("{} => {}", symbol(param), local(param))));
+                let frame = format!("e.merge({{ {} }})", entries.join(", "));
+                format!("-> e {{ fun = lambda {{ |{}| catch(:return) {{ ({}).call({}) }} }} }}",
+                        locals.join(", "), value(body), frame)
+            }
+            Node::Closure(ref env, ref fun) => format!("-> e {{ ({}).call({}) }}", fun.to_ruby(), environment(env)),
+            Node::Thunk(ref env, ref expr, _, _) => format!("-> e {{ -> {{ ({}).call({}) }} }}", expr.to_ruby(), environment(env)),
+            Node::Delay(ref expr) => format!("-> e {{ -> {{ {} }} }}", call(expr)),
+            Node::Call(ref callee, ref args) =>
+                format!("-> e {{ {}.call({}) }}", call(callee), args.iter().map(|arg| call(arg)).collect::<Vec<_>>().join(", ")),
+            Node::Builtin(ref name) => format!("-> e {{ e[{}] }}", symbol(name)),
+            Node::Native(ref native) => panic!("Native {} is a host function and has no Ruby meaning", native.name),
+            Node::Compose(ref outer, ref inner) => binary(outer, "<<", inner),
+            Node::Spanned(_, ref node) => node.to_ruby(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    #[test]
+    fn test_book() {
+        assert_eq!("-> e { 5 }", Node::number(5).to_ruby());
+        assert_eq!("-> e { false }", Node::boolean(false).to_ruby());
+        assert_eq!("-> e { e[:x] }", Node::variable("x").to_ruby());
+        assert_eq!("-> e { (-> e { e[:x] }).call(e) + (-> e { 1 }).call(e) }",
+                   Node::add(Node::variable("x"), Node::number(1)).to_ruby());
+        assert_eq!("-> e { e.merge({ :y => (-> e { (-> e { e[:x] }).call(e) < (-> e { 3 }).call(e) }).call(e) }) }",
+                   Node::assign("y", Node::lt(Node::variable("x"), Node::number(3))).to_ruby());
+        let program = parser::parse("while (x < 5) { x = x * 3; }").unwrap();
+        assert_eq!("-> e { while (-> e { (-> e { e[:x] }).call(e) < (-> e { 5 }).call(e) }).call(e); \
+                    e = (-> e { e.merge({ :x => (-> e { (-> e { e[:x] }).call(e) * (-> e { 3 }).call(e) }).call(e) }) }).call(e); \
+                    end; e }", program.to_ruby());
+        let program = parser::parse("x = 1; y = 2;").unwrap();
+        assert_eq!("-> e { (-> e { e.merge({ :y => (-> e { 2 }).call(e) }) }).call((-> e { e.merge({ :x => (-> e { 1 }).call(e) }) }).call(e)) }",
+                   program.to_ruby());
+    }
+
+    #[test]
+    fn test_extensions() {
+        let program = parser::parse("if (x) { y = 1; } else { print(\"#{x}\") }").unwrap();
+        assert_eq!("-> e { if (-> e { e[:x] }).call(e) then (-> e { e.merge({ :y => (-> e { 1 }).call(e) }) }).call(e) \
+                    else (-> e { (-> e { (-> e { e[:print] }).call(e).call((-> e { \"\\#{x}\" }).call(e)) }).call(e); e }).call(e) end }",
+                   program.to_ruby());
+        let program = parser::parse("def f(n) { return n; }").unwrap();
+        assert_eq!("-> e { e.merge({ :f => (-> e { fun = lambda { |v_n| catch(:return) { \
+                    (-> e { throw :return, (-> e { e[:n] }).call(e) }).call(e.merge({ :f => fun, :n => v_n })) } } }).call(e) }) }",
+                   program.to_ruby());
+        // the last statement of a body gives its value, if an expression
+        let program = parser::parse("f = fun(n) { m = n; m };").unwrap();
+        assert_eq!("-> e { e.merge({ :f => (-> e { fun = lambda { |v_n| catch(:return) { \
+                    (-> e { (-> e { e[:m] }).call((-> e { e.merge({ :m => (-> e { e[:n] }).call(e) }) }).call(e)) }).call(e.merge({ :\"\" => fun, :n => v_n })) } } }).call(e) }) }",
+                   program.to_ruby());
+        assert!(parser::parse("f = fun(n) { while (n) { } };").unwrap().to_ruby().contains("}).call(e); nil }).call(e.merge("));
+        let program = parser::parse("match (p) { (a, _) => { q = a; } }").unwrap();
+        assert_eq!("-> e { case (-> e { e[:p] }).call(e) in [v_a, _] then \
+                    (-> e { e.merge({ :q => (-> e { e[:a] }).call(e) }) }).call(e.merge({ :a => v_a })).except(:a).merge(e.slice(:a)) end }",
+                   program.to_ruby());
+        let program = parser::parse("while (true) { break; }").unwrap();
+        assert_eq!("-> e { catch(:break) { while (-> e { true }).call(e); e = catch(:continue) { (-> e { throw :break, e }).call(e) }; end; e } }",
+                   program.to_ruby());
+        assert_eq!("-> e { { \"a\" => (-> e { [(-> e { 1.5 }).call(e), (-> e { e[:x] }).call(e)] }).call(e) } }",
+                   parser::parse("{\"a\": [1.5, x]}").unwrap().to_ruby());
+    }
+}
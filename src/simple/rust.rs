@@ -0,0 +1,562 @@
+//! Compiling programs to Rust, to see what the constructs of the language
+//! come to in a systems language. `compile` gives a standalone source
+//! file, which `rustc` builds into a program printing what `simple run`
+//! prints.
+//!
+//! A program whose variables each only ever hold integers or booleans,
+//! and which only prints, becomes straight-line Rust: every variable a
+//! typed local declared where it is first assigned, every loop a Rust
+//! loop. Anything else, functions say, is compiled against a runtime much
+//! like the interpreter: values are a `Value` enum, variables live in a
+//! `HashMap` environment, and every node becomes a Rust expression on it.
+//! Control flow stays Rust's own either way: `return` returns from the
+//! closure a function compiles to, and `break` and `continue` leave or
+//! go round the loop labelled for them.
+
+use super::syntax::{Node, Pattern};
+use super::environment::{Capture, Environment};
+use super::evaluate;
+use super::builtin;
+
+use std::collections::HashMap;
+
+/// The runtime of programs compiled without knowing their types.
+pub const RUNTIME: &str = include_str!("rust_runtime.rs");
+
+const HEADER: &str = "// Compiled from simple by `simple compile --target rust`.\n#![allow(unused)]\n";
+
+fn pad(indent: usize) -> String {
+    "    ".repeat(indent)
+}
+
+/// `name` as a Rust identifier.
+fn local(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false", "fn", "for",
+        "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+        "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "gen",
+        "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+    ];
+    match name {
+        "self" | "Self" | "super" | "crate" | "_" => format!("{}_", name),
+        name if KEYWORDS.contains(&name) => format!("r#{}", name),
+        name => name.to_string(),
+    }
+}
+
+/// Compile `program` to the source of a Rust program.
+pub fn compile(program: &Node) -> String {
+    match Straight::compile(program) {
+        Some(source) => format!("{}\n{}", HEADER, source),
+        None => format!("{}\n{}\n{}\nfn main() {{\n    run(program);\n}}\n", HEADER, RUNTIME, Dynamic::program(program)),
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Type {
+    Int,
+    Bool,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Int => "i64",
+            Type::Bool => "bool",
+        }
+    }
+}
+
+/// Compiling a program whose variables keep the type they are first
+/// assigned to plain Rust.
+struct Straight {
+    types: HashMap<String, Type>,
+    loops: Vec<(String, String)>,
+    labels: usize,
+}
+
+impl Straight {
+    fn compile(program: &Node) -> Option<String> {
+        let mut straight = Straight { types: HashMap::new(), loops: Vec::new(), labels: 0 };
+        let statements = program.statements();
+        let (last, init) = statements.split_last()?;
+        let mut body = String::new();
+        for statement in init {
+            body.push_str(&straight.statement(statement, true, 1)?);
+        }
+        // the result is `result`, or the value of the last statement
+        // unless it is do-nothing
+        let value = match straight.expr(last) {
+            Some((code, _)) => {
+                body.push_str(&format!("    let value = {};\n", code));
+                Some("value".to_string())
+            }
+            None => {
+                body.push_str(&straight.statement(last, true, 1)?);
+                None
+            }
+        };
+        let shown = if straight.types.contains_key("result") { Some(local("result")) } else { value };
+        let print = shown.map(|shown| format!("    println!(\"{{}}\", {});\n", shown)).unwrap_or_default();
+        Some(format!("fn main() {{\n{}{}}}\n", body, print))
+    }
+
+    /// The code of `node` as an expression and its type.
+    fn expr(&self, node: &Node) -> Option<(String, Type)> {
+        let both = |l: &Node, r: &Node, op: &str, operands: Type, result: Type| -> Option<(String, Type)> {
+            let (l, lt) = self.expr(l)?;
+            let (r, rt) = self.expr(r)?;
+            if lt != operands || rt != operands {
+                return None;
+            }
+            Some((format!("({} {} {})", l, op, r), result))
+        };
+        match *node {
+            Node::Number(value) if value < 0 => Some((format!("({})", value), Type::Int)),
+            Node::Number(value) => Some((value.to_string(), Type::Int)),
+            Node::Boolean(value) => Some((value.to_string(), Type::Bool)),
+            Node::Variable(ref name) => self.types.get(name).map(|&ty| (local(name), ty)),
+            Node::Add(ref l, ref r) => both(l, r, "+", Type::Int, Type::Int),
+            Node::Subtract(ref l, ref r) => both(l, r, "-", Type::Int, Type::Int),
+            Node::Multiply(ref l, ref r) => both(l, r, "*", Type::Int, Type::Int),
+            Node::LT(ref l, ref r) => both(l, r, "<", Type::Int, Type::Bool),
+            Node::GT(ref l, ref r) => both(l, r, ">", Type::Int, Type::Bool),
+            Node::LE(ref l, ref r) => both(l, r, "<=", Type::Int, Type::Bool),
+            Node::GE(ref l, ref r) => both(l, r, ">=", Type::Int, Type::Bool),
+            Node::EQ(ref l, ref r) | Node::NE(ref l, ref r) => {
+                let op = if let Node::EQ(..) = *node { "==" } else { "!=" };
+                let ty = self.expr(l)?.1;
+                both(l, r, op, ty, Type::Bool)
+            }
+            Node::And(ref l, ref r) => both(l, r, "&&", Type::Bool, Type::Bool),
+            Node::Or(ref l, ref r) => both(l, r, "||", Type::Bool, Type::Bool),
+            Node::Negate(ref node) => match self.expr(node)? {
+                (code, Type::Int) => Some((format!("(-{})", code), Type::Int)),
+                _ => None,
+            },
+            Node::Not(ref node) => match self.expr(node)? {
+                (code, Type::Bool) => Some((format!("(!{})", code), Type::Bool)),
+                _ => None,
+            },
+            Node::Spanned(_, ref node) => self.expr(node),
+            _ => None,
+        }
+    }
+
+    /// The code of `node` as statements, each on a line. Variables are
+    /// declared at the top level only, where they are first assigned.
+    fn statement(&mut self, node: &Node, top: bool, indent: usize) -> Option<String> {
+        let line = |code: String| Some(format!("{}{}\n", pad(indent), code));
+        match *node {
+            Node::DoNothing => Some(String::new()),
+            Node::Sequence(..) => {
+                let mut code = String::new();
+                for statement in node.statements() {
+                    code.push_str(&self.statement(statement, top, indent)?);
+                }
+                Some(code)
+            }
+            Node::Assign(ref name, ref expr) => {
+                if builtin::lookup(name).is_some() || builtin::is_reserved(name) {
+                    return None;
+                }
+                let (code, ty) = self.expr(expr)?;
+                match self.types.get(name) {
+                    Some(&declared) if declared == ty => line(format!("{} = {};", local(name), code)),
+                    Some(_) => None,
+                    None if top => {
+                        self.types.insert(name.clone(), ty);
+                        line(format!("let mut {}: {} = {};", local(name), ty.name(), code))
+                    }
+                    None => None,
+                }
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let cond = self.condition(cond)?;
+                let consequence = self.statement(consequence, false, indent + 1)?;
+                let alternative = self.statement(alternative, false, indent + 1)?;
+                if alternative.is_empty() {
+                    Some(format!("{0}if {1} {{\n{2}{0}}}\n", pad(indent), cond, consequence))
+                } else {
+                    Some(format!("{0}if {1} {{\n{2}{0}}} else {{\n{3}{0}}}\n", pad(indent), cond, consequence, alternative))
+                }
+            }
+            Node::While(ref cond, ref body) => {
+                let cond = self.condition(cond)?;
+                let label = self.label("l");
+                self.loops.push((format!("break {}", label), format!("continue {}", label)));
+                let body = self.statement(body, false, indent + 1);
+                self.loops.pop();
+                Some(format!("{0}{1}: while {2} {{\n{3}{0}}}\n", pad(indent), label, cond, body?))
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                let init = self.statement(init, top, indent)?;
+                let cond = self.condition(cond)?;
+                let (label, block) = (self.label("l"), self.label("b"));
+                self.loops.push((format!("break {}", label), format!("break {}", block)));
+                let body = self.statement(body, false, indent + 2);
+                self.loops.pop();
+                let step = self.statement(step, false, indent + 1)?;
+                // continuing still takes the step, after the body's block
+                Some(format!("{0}{1}{2}: while {3} {{\n{4}{5}: {{\n{6}{4}}}\n{7}{1}}}\n",
+                             init, pad(indent), label, cond, pad(indent + 1), block, body?, step))
+            }
+            Node::Break => self.loops.last().cloned().and_then(|(exit, _)| line(format!("{};", exit))),
+            Node::Continue => self.loops.last().cloned().and_then(|(_, next)| line(format!("{};", next))),
+            Node::Call(ref callee, ref args) if args.len() == 1 => match *callee.unspanned() {
+                Node::Variable(ref name) if name == "print" => line(format!("println!(\"{{}}\", {});", self.expr(&args[0])?.0)),
+                _ => None,
+            },
+            Node::Spanned(_, ref node) => self.statement(node, top, indent),
+            _ => self.expr(node).and_then(|(code, _)| line(format!("let _ = {};", code))),
+        }
+    }
+
+    fn condition(&self, cond: &Node) -> Option<String> {
+        match self.expr(cond)? {
+            (code, Type::Bool) => Some(code),
+            _ => None,
+        }
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("'{}{}", kind, self.labels)
+    }
+}
+
+/// Compiling any program against the runtime.
+struct Dynamic {
+    /// What `break` and `continue` come to in the loops around.
+    loops: Vec<(String, String)>,
+    labels: usize,
+    /// The builtins no parameter hides, called directly.
+    builtins: Vec<String>,
+}
+
+/// Whether the code of `node` borrows the environment mutably, outside
+/// of the functions in it.
+fn mutates(node: &Node) -> bool {
+    match *node {
+        Node::Call(..) | Node::Assign(..) | Node::Global(..) | Node::Match(..) => true,
+        Node::Fun(..) | Node::Delay(_) | Node::Closure(..) | Node::Thunk(..) => false,
+        _ => node.children().into_iter().any(mutates),
+    }
+}
+
+/// The names bound as parameters or function names anywhere in `node`.
+fn bound(node: &Node, names: &mut Vec<String>) {
+    if let Node::Fun(ref name, ref params, _) = *node {
+        names.push(name.clone());
+        names.extend(params.iter().cloned());
+    }
+    for child in node.children() {
+        bound(child, names);
+    }
+}
+
+fn pattern(pattern: &Pattern) -> String {
+    match *pattern {
+        Pattern::Wildcard => "Pattern::Wildcard".to_string(),
+        Pattern::Bind(ref name) => format!("Pattern::Bind({:?})", name),
+        Pattern::Number(value) => format!("Pattern::Int({})", value),
+        Pattern::Boolean(value) => format!("Pattern::Bool({})", value),
+        Pattern::Str(ref value) => format!("Pattern::Str({:?})", value),
+        Pattern::Pair(ref fst, ref snd) =>
+            format!("Pattern::Pair(Box::new({}), Box::new({}))", self::pattern(fst), self::pattern(snd)),
+        Pattern::List(ref items) =>
+            format!("Pattern::List(vec![{}])", items.iter().map(self::pattern).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+fn strings(names: &[String]) -> String {
+    format!("&[{}]", names.iter().map(|name| format!("{:?}", name)).collect::<Vec<_>>().join(", "))
+}
+
+impl Dynamic {
+    /// The function `program` compiles to, running it in an environment.
+    fn program(program: &Node) -> String {
+        let mut hidden = Vec::new();
+        bound(program, &mut hidden);
+        let builtins = builtin::names().into_iter().filter(|name| !hidden.iter().any(|hidden| hidden == name))
+            .map(str::to_string).collect();
+        let mut dynamic = Dynamic { loops: Vec::new(), labels: 0, builtins };
+        format!("fn program(e: &mut Env) -> Value {{\n{}\n}}\n", dynamic.block(program, 1))
+    }
+
+    /// The lines of a block running `node`, the value of its last
+    /// statement last.
+    fn block(&mut self, node: &Node, indent: usize) -> String {
+        let lines: Vec<String> = node.statements().into_iter()
+            .map(|statement| format!("{}{}", pad(indent), self.expr(statement, indent)))
+            .collect();
+        lines.join(";\n")
+    }
+
+    /// The lines of a block running `node` for what it does.
+    fn body(&mut self, node: &Node, indent: usize) -> String {
+        node.statements().into_iter()
+            .map(|statement| format!("{}{};\n", pad(indent), self.expr(statement, indent)))
+            .collect()
+    }
+
+    /// A `fn` body: the block of `node`, with no loops around it.
+    fn function(&mut self, node: &Node, indent: usize) -> String {
+        let loops = std::mem::take(&mut self.loops);
+        let block = self.block(node, indent + 1);
+        self.loops = loops;
+        format!("|e| {{\n{}\n{}}}", block, pad(indent))
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("'{}{}", kind, self.labels)
+    }
+
+    /// The environment `env` as an expression making it again.
+    fn scope(&mut self, env: &Environment, indent: usize) -> String {
+        if env.capture == Capture::Shared {
+            panic!("Closures over shared scopes cannot be compiled to Rust");
+        }
+        let bindings: Vec<String> = env.iter()
+            .map(|(name, value)| format!("({:?}, {})", name, self.expr(&value, indent)))
+            .collect();
+        let parent = match env.parent {
+            Some(ref parent) => format!("Some(Rc::new({}))", self.scope(parent, indent)),
+            None => "None".to_string(),
+        };
+        format!("scope(vec![{}], {})", bindings.join(", "), parent)
+    }
+
+    /// The code of `node` as a Rust expression of type `Value`, in a scope
+    /// where `e` is the environment.
+    fn expr(&mut self, node: &Node, indent: usize) -> String {
+        match *node {
+            Node::Number(value) => format!("Value::Int({})", value),
+            Node::Float(value) if value.is_nan() => "Value::Float(f64::NAN)".to_string(),
+            Node::Float(value) if value.is_infinite() =>
+                format!("Value::Float({}f64::INFINITY)", if value < 0.0 { "-" } else { "" }),
+            Node::Float(value) => format!("Value::Float({:?})", value),
+            Node::Str(ref value) => format!("Value::string({:?})", value),
+            Node::Boolean(value) => format!("Value::Bool({})", value),
+            Node::DoNothing => "Value::Nothing".to_string(),
+            Node::IsDoNothing(ref node) => format!("Value::Bool({}.is_nothing())", self.expr(node, indent)),
+            Node::Add(ref l, ref r) => format!("add({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            Node::Subtract(ref l, ref r) => format!("sub({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            Node::Multiply(ref l, ref r) => format!("mul({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            Node::Negate(ref node) => format!("negate({})", self.expr(node, indent)),
+            Node::LT(ref l, ref r) => format!("lt({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            Node::LE(ref l, ref r) => format!("le({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            // as the interpreter does, the right side first
+            Node::GT(ref l, ref r) => format!("{{ let r = {}; lt(r, {}) }}", self.expr(r, indent), self.expr(l, indent)),
+            Node::GE(ref l, ref r) => format!("{{ let r = {}; le(r, {}) }}", self.expr(r, indent), self.expr(l, indent)),
+            Node::EQ(ref l, ref r) => format!("eq({}, {})", self.expr(l, indent), self.expr(r, indent)),
+            Node::NE(ref l, ref r) => format!("Value::Bool(!cond(eq({}, {})))", self.expr(l, indent), self.expr(r, indent)),
+            Node::And(ref l, ref r) => format!("Value::Bool(cond({}) && cond({}))", self.expr(l, indent), self.expr(r, indent)),
+            Node::Or(ref l, ref r) => format!("Value::Bool(cond({}) || cond({}))", self.expr(l, indent), self.expr(r, indent)),
+            Node::Not(ref node) => format!("Value::Bool(!cond({}))", self.expr(node, indent)),
+            Node::Variable(ref name) => format!("e.get({:?})", name),
+            Node::Assign(ref name, ref expr) | Node::Global(ref name, ref expr) => {
+                let method = if let Node::Assign(..) = *node { "assign" } else { "global" };
+                let value = self.expr(expr, indent);
+                if mutates(expr) {
+                    format!("{{ let value = {}; e.{}({:?}, value) }}", value, method, name)
+                } else {
+                    format!("e.{}({:?}, {})", method, name, value)
+                }
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                let cond = self.expr(cond, indent);
+                let consequence = self.block(consequence, indent + 1);
+                let alternative = self.block(alternative, indent + 1);
+                format!("if cond({1}) {{\n{2}\n{0}}} else {{\n{3}\n{0}}}", pad(indent), cond, consequence, alternative)
+            }
+            Node::Sequence(..) => format!("{{\n{}\n{}}}", self.block(node, indent + 1), pad(indent)),
+            Node::While(ref cond, ref body) => {
+                let label = self.label("l");
+                let cond = self.expr(cond, indent + 1);
+                self.loops.push((format!("break {}", label), format!("continue {}", label)));
+                let body = self.body(body, indent + 2);
+                self.loops.pop();
+                format!("{{\n{1}{2}: while cond({3}) {{\n{4}{1}}}\n{1}Value::Nothing\n{0}}}",
+                        pad(indent), pad(indent + 1), label, cond, body)
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                let (label, block) = (self.label("l"), self.label("b"));
+                let init = self.expr(init, indent + 1);
+                let cond = self.expr(cond, indent + 1);
+                self.loops.push((format!("break {}", label), format!("break {}", block)));
+                let body = self.body(body, indent + 3);
+                self.loops.pop();
+                let step = self.expr(step, indent + 2);
+                // continuing still takes the step, after the body's block
+                format!("{{\n{1}{4};\n{1}{2}: while cond({5}) {{\n{6}{3}: {{\n{7}{6}}}\n{6}{8};\n{1}}}\n{1}Value::Nothing\n{0}}}",
+                        pad(indent), pad(indent + 1), label, block, init, cond, pad(indent + 2), body, step)
+            }
+            Node::Match(ref value, ref arms) => {
+                let value = self.expr(value, indent + 1);
+                let patterns: Vec<String> = arms.iter().map(|(p, _)| pattern(p)).collect();
+                let mut code = format!("{{\n{0}let value = {1};\n{0}match arm(&[{2}], &value) {{\n",
+                                       pad(indent + 1), value, patterns.join(", "));
+                for (index, (_, body)) in arms.iter().enumerate() {
+                    // the arm is a block scope for the names its pattern binds,
+                    // restored on the way out of a loop around too
+                    let shadowed = self.label("shadowed")[1..].to_string();
+                    let loops = self.loops.clone();
+                    for (exit, next) in &mut self.loops {
+                        *exit = format!("{{ e.restore({}); {} }}", shadowed, exit);
+                        *next = format!("{{ e.restore({}); {} }}", shadowed, next);
+                    }
+                    let body = self.expr(body, indent + 3);
+                    self.loops = loops;
+                    code.push_str(&format!("{0}({1}, bindings) => {{\n{2}let {4} = e.bind(bindings);\n\
+                                            {2}let value = {3};\n{2}e.restore({4});\n{2}value\n{0}}}\n",
+                                           pad(indent + 2), index, pad(indent + 3), body, shadowed));
+                }
+                code.push_str(&format!("{}_ => unreachable!(),\n{}}}\n{}}}", pad(indent + 2), pad(indent + 1), pad(indent)));
+                code
+            }
+            Node::Return(ref value) => format!("return {}", self.expr(value, indent)),
+            Node::Break | Node::Continue => {
+                let exit = self.loops.last().map(|(exit, next)| if let Node::Break = *node { exit.clone() } else { next.clone() });
+                exit.unwrap_or_else(|| "panic!(\"break or continue outside of a loop\")".to_string())
+            }
+            Node::Pair(ref fst, ref snd) => format!("Value::pair({}, {})", self.expr(fst, indent), self.expr(snd, indent)),
+            Node::Fst(ref node) => format!("call_builtin(\"fst\", vec![{}])", self.expr(node, indent)),
+            Node::Snd(ref node) => format!("call_builtin(\"snd\", vec![{}])", self.expr(node, indent)),
+            Node::Len(ref node) => format!("call_builtin(\"len\", vec![{}])", self.expr(node, indent)),
+            Node::List(ref items) => {
+                let items: Vec<String> = items.iter().map(|item| self.expr(item, indent)).collect();
+                format!("Value::list(vec![{}])", items.join(", "))
+            }
+            Node::Index(ref list, ref index) => format!("index({}, {})", self.expr(list, indent), self.expr(index, indent)),
+            Node::Map(ref entries) => {
+                let entries: Vec<String> = super::syntax::sorted(entries).into_iter()
+                    .map(|(key, value)| format!("({:?}, {})", key, self.expr(value, indent))).collect();
+                format!("Value::map(vec![{}])", entries.join(", "))
+            }
+            Node::Fun(ref name, ref params, ref body) => {
+                let mut free: Vec<String> = evaluate::get_free_vars(node).into_iter().collect();
+                free.sort();
+                format!("fun(e, {:?}, {}, {}, {}, {:?})", name, strings(params), strings(&free),
+                        self.function(body, indent), node.to_string())
+            }
+            Node::Closure(ref env, ref fun) => match **fun {
+                Node::Fun(ref name, ref params, ref body) => {
+                    let scope = self.scope(env, indent);
+                    format!("closure({}, {:?}, {}, {}, {:?})", scope, name, strings(params), self.function(body, indent),
+                            fun.to_string())
+                }
+                _ => panic!("Closure not contain function: {}", fun),
+            },
+            Node::Thunk(ref env, ref expr, ref memo, eager) => {
+                let scope = self.scope(env, indent);
+                format!("thunk({}, {}, {}, {}, {:?})", scope, self.function(expr, indent), memo.is_some(), eager, expr.to_string())
+            }
+            Node::Delay(ref expr) => format!("thunk(e.clone(), {}, true, false, {:?})", self.function(expr, indent), expr.to_string()),
+            Node::Call(ref callee, ref args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.expr(arg, indent)).collect();
+                match *callee.unspanned() {
+                    Node::Variable(ref name) | Node::Builtin(ref name) if self.builtins.contains(name) =>
+                        format!("call_builtin({:?}, vec![{}])", name, args.join(", ")),
+                    _ => format!("{{ let args = vec![{}]; let callee = {}; call(e, callee, args) }}",
+                                 args.join(", "), self.expr(callee, indent)),
+                }
+            }
+            Node::Builtin(ref name) => format!("Value::Builtin({:?})", name),
+            Node::Native(ref native) => panic!("Native {} is a host function and cannot be compiled to Rust", native.name),
+            Node::Compose(ref outer, ref inner) => format!("compose({}, {})", self.expr(outer, indent), self.expr(inner, indent)),
+            Node::Spanned(_, ref node) => self.expr(node, indent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::{self, Arity};
+    use simple::machine::Machine;
+
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// What the program `source` prints once built with rustc, or `None`
+    /// without rustc.
+    fn rustc(source: &str, name: &str) -> Option<String> {
+        let dir = env::temp_dir().join(format!("simple-rust-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (file, binary) = (dir.join("main.rs"), dir.join("main"));
+        fs::write(&file, source).unwrap();
+        let built = Command::new("rustc").arg("-o").arg(&binary).arg(&file).output().ok()?;
+        assert!(built.status.success(), "{}\n{}", String::from_utf8_lossy(&built.stderr), source);
+        let output = Command::new(&binary).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let program = parser::parse("x = 1; done = false; while (not done) { x = x * 3; if (x > 100) { done = true; } }
+            for (i = 0; i < 3; i = i + 1) { if (i == 1) { continue; } print(i) } result = x - 1;").unwrap();
+        let source = compile(&program);
+        assert!(source.contains("\nfn main() {\n    let mut x: i64 = 1;\n    let mut done: bool = false;\n\
+                                 \x20   'l1: while (!done) {\n        x = (x * 3);\n"), "{}", source);
+        assert!(!source.contains("enum Value"));
+        if let Some(output) = rustc(&source, "straight") {
+            assert_eq!("0\n2\n242\n", output);
+        }
+        // without `result`, the last statement is the result, if it has a value
+        if let Some(output) = rustc(&compile(&parser::parse("x = 2; x * 21").unwrap()), "last") {
+            assert_eq!("42\n", output);
+        }
+        assert!(!compile(&parser::parse("x = 2; print(x)").unwrap()).contains("value"));
+        // a variable first assigned in a branch needs the runtime
+        let program = parser::parse("if (true) { x = 1; } else { x = 2; } result = x;").unwrap();
+        assert!(compile(&program).contains("enum Value"));
+    }
+
+    #[test]
+    fn test_runtime_builtins() {
+        for builtin in builtin::all() {
+            let (Arity::Exactly(arity) | Arity::AtLeast(arity)) = builtin.arity;
+            assert!(RUNTIME.contains(&format!("\n    ({:?}, {}, |", builtin.name, arity)),
+                    "{} is not in the runtime", builtin.name);
+        }
+    }
+
+    #[test]
+    fn test_agrees_with_machine() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let mut paths: Vec<_> = fs::read_dir(dir.join("conformance")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        paths.sort();
+        paths.push(dir.join("streams.simple"));
+        let mut source = format!("{}\n{}\n", HEADER, RUNTIME);
+        let mut expected = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let program = parser::parse(&fs::read_to_string(path).unwrap()).unwrap();
+            let mut machine = Machine::new_with_empty_env(program.clone());
+            machine.run().unwrap();
+            expected.extend(machine.environment.iter()
+                .filter(|(_, value)| !value.is_function())
+                .map(|(name, value)| format!("{} = {}", name, value)));
+            source.push_str(&Dynamic::program(&program).replacen("fn program", &format!("fn program{}", index), 1));
+        }
+        // one program printing the bindings each leaves
+        source.push_str("\nfn main() {\n");
+        for index in 0..paths.len() {
+            source.push_str(&format!("    let mut e = Env::new(None, true);\n    program{}(&mut e);\n\
+                                      \x20   let mut names: Vec<_> = e.vars.keys().cloned().collect();\n\
+                                      \x20   names.sort();\n\
+                                      \x20   for name in names {{\n\
+                                      \x20       if !is_function(&e.vars[&name]) {{ println!(\"{{}} = {{}}\", name, e.vars[&name]); }}\n\
+                                      \x20   }}\n", index));
+        }
+        source.push_str("}\n");
+        if let Some(output) = rustc(&source, "dynamic") {
+            assert_eq!(expected, output.lines().collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,684 @@
+// The runtime of simple programs compiled to Rust by simple::rust, put at
+// the top of the programs it compiles without knowing their types.
+//
+// Values stay apart as they do in the interpreter, and errors panic with
+// the messages it panics with. Function bodies are plain `fn` pointers:
+// everything a function uses is reached through the environment.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+
+#[derive(Clone)]
+enum Value {
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+    Bool(bool),
+    Nothing,
+    Pair(Rc<(Value, Value)>),
+    List(Rc<Vec<Value>>),
+    Map(Rc<BTreeMap<String, Value>>),
+    Builtin(&'static str),
+    Closure(Rc<Closure>),
+    Thunk(Rc<Thunk>),
+}
+
+/// What a function body or a delayed expression runs, in a frame.
+type Body = fn(&mut Env) -> Value;
+
+/// `source` is how the function prints.
+struct Closure {
+    env: Rc<Env>,
+    name: &'static str,
+    params: &'static [&'static str],
+    body: Body,
+    source: &'static str,
+}
+
+struct Thunk {
+    env: Env,
+    expr: Body,
+    memo: bool,
+    eager: bool,
+    source: &'static str,
+    value: RefCell<Option<Value>>,
+}
+
+/// A scope of variables, nested in `parent`, as simple::environment.
+#[derive(Clone)]
+struct Env {
+    vars: HashMap<String, Value>,
+    parent: Option<Rc<Env>>,
+    toplevel: bool,
+    globals: HashMap<String, Value>,
+}
+
+/// A pattern of a `match` arm.
+enum Pattern {
+    Wildcard,
+    Bind(&'static str),
+    Int(i64),
+    Bool(bool),
+    Str(&'static str),
+    Pair(Box<Pattern>, Box<Pattern>),
+    List(Vec<Pattern>),
+}
+
+impl Value {
+    fn string(text: &str) -> Value {
+        Value::Str(Rc::from(text))
+    }
+
+    fn pair(fst: Value, snd: Value) -> Value {
+        Value::Pair(Rc::new((fst, snd)))
+    }
+
+    fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(items))
+    }
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(Rc::new(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect()))
+    }
+
+    fn is_nothing(&self) -> bool {
+        matches!(*self, Value::Nothing)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Str(ref value) => write!(f, "{:?}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Nothing => write!(f, "do-nothing"),
+            Value::Pair(ref pair) => write!(f, "({}, {})", pair.0, pair.1),
+            Value::List(ref items) => {
+                let items: Vec<String> = items.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(ref entries) => {
+                let entries: Vec<String> = entries.iter().map(|(key, value)| format!("{:?}: {}", key, value)).collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Value::Builtin(name) => write!(f, "builtin {}", name),
+            Value::Closure(ref closure) => write!(f, "closure {}", closure.source),
+            Value::Thunk(ref thunk) => match *thunk.value.borrow() {
+                Some(ref value) => write!(f, "thunk ({}) => {}", thunk.source, value),
+                None => write!(f, "thunk ({})", thunk.source),
+            },
+        }
+    }
+}
+
+impl Env {
+    fn new(parent: Option<Rc<Env>>, toplevel: bool) -> Env {
+        Env { vars: HashMap::new(), parent, toplevel, globals: HashMap::new() }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Value> {
+        let mut scope = Some(self);
+        while let Some(env) = scope {
+            if let Some(value) = env.vars.get(name) {
+                return Some(value.clone());
+            }
+            scope = env.parent.as_deref();
+        }
+        None
+    }
+
+    /// The value of a variable, an eager thunk forced.
+    fn get(&self, name: &'static str) -> Value {
+        let value = match self.lookup(name) {
+            Some(value) => value,
+            None if builtin(name).is_some() => Value::Builtin(name),
+            None => panic!("Variable {} not found", name),
+        };
+        match value {
+            Value::Thunk(ref thunk) if thunk.eager => force(value.clone()),
+            value => value,
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: Value) -> Value {
+        check_assignable(name);
+        self.vars.insert(name.to_string(), value);
+        Value::Nothing
+    }
+
+    fn global(&mut self, name: &str, value: Value) -> Value {
+        check_assignable(name);
+        if !self.toplevel {
+            self.globals.insert(name.to_string(), value.clone());
+        }
+        self.vars.insert(name.to_string(), value);
+        Value::Nothing
+    }
+
+    /// Bind the names a pattern matched, returning what they shadow.
+    fn bind(&mut self, bindings: Vec<(&'static str, Value)>) -> Vec<(&'static str, Option<Value>)> {
+        bindings.into_iter().map(|(name, value)| {
+            check_assignable(name);
+            (name, self.vars.insert(name.to_string(), value))
+        }).collect()
+    }
+
+    fn restore(&mut self, shadowed: Vec<(&'static str, Option<Value>)>) {
+        for (name, value) in shadowed.into_iter().rev() {
+            match value {
+                Some(value) => { self.vars.insert(name.to_string(), value); }
+                None => { self.vars.remove(name); }
+            }
+        }
+    }
+}
+
+fn scope(bindings: Vec<(&str, Value)>, parent: Option<Rc<Env>>) -> Env {
+    let mut env = Env::new(parent.clone(), parent.is_none());
+    for (name, value) in bindings {
+        env.vars.insert(name.to_string(), value);
+    }
+    env
+}
+
+fn cond(value: Value) -> bool {
+    match value {
+        Value::Bool(value) => value,
+        value => panic!("Type cannot eval to boolean {}", value),
+    }
+}
+
+fn int(value: Value) -> i64 {
+    match value {
+        Value::Int(value) => value,
+        value => panic!("Type has no value: {}", value),
+    }
+}
+
+fn float(value: Value) -> f64 {
+    match value {
+        Value::Int(value) => value as f64,
+        Value::Float(value) => value,
+        value => panic!("Type has no value: {}", value),
+    }
+}
+
+fn string(value: Value) -> Rc<str> {
+    match value {
+        Value::Str(value) => value,
+        value => panic!("Type has no string value: {}", value),
+    }
+}
+
+fn list(value: Value) -> Rc<Vec<Value>> {
+    match value {
+        Value::List(items) => items,
+        value => panic!("Type has no list value: {}", value),
+    }
+}
+
+fn map(value: Value) -> Rc<BTreeMap<String, Value>> {
+    match value {
+        Value::Map(entries) => entries,
+        value => panic!("Type has no map value: {}", value),
+    }
+}
+
+/// Two integers give an integer, failing on overflow; a float on either
+/// side gives a float.
+fn arithmetic(l: Value, r: Value, op: &str, int_op: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64) -> Value {
+    match (l, r) {
+        (Value::Int(l), Value::Int(r)) => Value::Int(int_op(l, r).unwrap_or_else(|| panic!("{} {} {} overflows", l, op, r))),
+        (l, r) => Value::Float(float_op(float(l), float(r))),
+    }
+}
+
+fn add(l: Value, r: Value) -> Value {
+    match (l, r) {
+        (Value::Str(l), Value::Str(r)) => Value::string(&format!("{}{}", l, r)),
+        (l, r) => arithmetic(l, r, "+", i64::checked_add, |l, r| l + r),
+    }
+}
+
+fn sub(l: Value, r: Value) -> Value {
+    arithmetic(l, r, "-", i64::checked_sub, |l, r| l - r)
+}
+
+fn mul(l: Value, r: Value) -> Value {
+    arithmetic(l, r, "*", i64::checked_mul, |l, r| l * r)
+}
+
+fn negate(value: Value) -> Value {
+    match value {
+        Value::Float(value) => Value::Float(-value),
+        value => {
+            let value = int(value);
+            Value::Int(value.checked_neg().unwrap_or_else(|| panic!("-({}) overflows", value)))
+        }
+    }
+}
+
+/// NaN is unordered, so every comparison involving it is false.
+fn compare(l: Value, r: Value) -> Option<Ordering> {
+    match (l, r) {
+        (Value::Int(l), Value::Int(r)) => Some(l.cmp(&r)),
+        (l, r) => float(l).partial_cmp(&float(r)),
+    }
+}
+
+fn lt(l: Value, r: Value) -> Value {
+    Value::Bool(compare(l, r) == Some(Ordering::Less))
+}
+
+fn le(l: Value, r: Value) -> Value {
+    Value::Bool(matches!(compare(l, r), Some(Ordering::Less) | Some(Ordering::Equal)))
+}
+
+/// Numbers by value, pairs, lists and maps item by item; values of
+/// different kinds, or functions, cannot be compared.
+fn equal(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Str(l), Value::Str(r)) => l == r,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::Nothing, Value::Nothing) => true,
+        (Value::Pair(l), Value::Pair(r)) => equal(&l.0, &r.0) && equal(&l.1, &r.1),
+        (Value::List(l), Value::List(r)) => l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| equal(l, r)),
+        (Value::Map(l), Value::Map(r)) =>
+            l.len() == r.len() && l.iter().all(|(key, l)| r.get(key).map_or(false, |r| equal(l, r))),
+        (Value::Int(_), Value::Int(_)) | (Value::Int(_), Value::Float(_)) |
+            (Value::Float(_), Value::Int(_)) | (Value::Float(_), Value::Float(_)) =>
+            compare(l.clone(), r.clone()) == Some(Ordering::Equal),
+        (l, r) => panic!("Cannot compare {} and {}", l, r),
+    }
+}
+
+fn eq(l: Value, r: Value) -> Value {
+    Value::Bool(equal(&l, &r))
+}
+
+fn force(value: Value) -> Value {
+    let thunk = match value {
+        Value::Thunk(thunk) => thunk,
+        value => return value,
+    };
+    if let Some(ref value) = *thunk.value.borrow() {
+        return value.clone();
+    }
+    let value = (thunk.expr)(&mut thunk.env.clone());
+    if thunk.memo {
+        *thunk.value.borrow_mut() = Some(value.clone());
+    }
+    value
+}
+
+fn check_assignable(name: &str) {
+    if RESERVED.contains(&name) || builtin(name).is_some() {
+        panic!("Cannot assign to builtin {}", name);
+    }
+}
+
+fn matches(pattern: &Pattern, value: &Value, bindings: &mut Vec<(&'static str, Value)>) -> bool {
+    match (pattern, value) {
+        (&Pattern::Wildcard, _) => true,
+        (&Pattern::Bind(name), value) => {
+            bindings.push((name, value.clone()));
+            true
+        }
+        (&Pattern::Int(expected), &Value::Int(value)) => expected == value,
+        (&Pattern::Bool(expected), &Value::Bool(value)) => expected == value,
+        (&Pattern::Str(expected), Value::Str(value)) => expected == &**value,
+        (Pattern::Pair(fst, snd), Value::Pair(pair)) => matches(fst, &pair.0, bindings) && matches(snd, &pair.1, bindings),
+        (Pattern::List(patterns), Value::List(items)) =>
+            patterns.len() == items.len() && patterns.iter().zip(items.iter()).all(|(pattern, item)| matches(pattern, item, bindings)),
+        _ => false,
+    }
+}
+
+/// The bindings of the first of `patterns` to match `value`, by index.
+fn arm(patterns: &[Pattern], value: &Value) -> (usize, Vec<(&'static str, Value)>) {
+    for (index, pattern) in patterns.iter().enumerate() {
+        let mut bindings = Vec::new();
+        if matches(pattern, value, &mut bindings) {
+            return (index, bindings);
+        }
+    }
+    panic!("No match arm for value {}", value)
+}
+
+fn index(items: Value, index: Value) -> Value {
+    let items = list(items);
+    let index = int(index);
+    match usize::try_from(index).ok().and_then(|i| items.get(i)) {
+        Some(item) => item.clone(),
+        None => panic!("Index {} out of bounds for list of length {}", index, items.len()),
+    }
+}
+
+/// A function value binds the free variables of the function when it is
+/// made, as Capture::Minimal does.
+fn fun(env: &Env, name: &'static str, params: &'static [&'static str], free: &[&str], body: Body,
+       source: &'static str) -> Value {
+    if let Some(param) = params.iter().find(|param| RESERVED.contains(param) || builtin(param).is_some()) {
+        panic!("Cannot bind builtin {} as a parameter", param);
+    }
+    let mut cenv = Env::new(None, true);
+    for var in free {
+        if let Some(value) = env.lookup(var) {
+            cenv.vars.insert(var.to_string(), value);
+        }
+    }
+    closure(cenv, name, params, body, source)
+}
+
+fn closure(env: Env, name: &'static str, params: &'static [&'static str], body: Body, source: &'static str) -> Value {
+    Value::Closure(Rc::new(Closure { env: Rc::new(env), name, params, body, source }))
+}
+
+fn thunk(env: Env, expr: Body, memo: bool, eager: bool, source: &'static str) -> Value {
+    Value::Thunk(Rc::new(Thunk { env, expr, memo, eager, source, value: RefCell::new(None) }))
+}
+
+fn compose(outer: Value, inner: Value) -> Value {
+    let env = scope(vec![("$outer", outer), ("$inner", inner)], None);
+    closure(env, "compose", &["$x"], |frame| {
+        let x = frame.get("$x");
+        let inner = frame.get("$inner");
+        let y = call(frame, inner, vec![x]);
+        let outer = frame.get("$outer");
+        call(frame, outer, vec![y])
+    }, "function compose ($x) call $outer arg call $inner arg $x")
+}
+
+/// Call `callee` with `args` from `env`.
+fn call(env: &mut Env, callee: Value, args: Vec<Value>) -> Value {
+    let closure = match callee {
+        Value::Builtin(name) => return call_builtin(name, args),
+        Value::Closure(closure) => closure,
+        callee => panic!("Call on non-closure type: {}", callee),
+    };
+    if args.len() != closure.params.len() {
+        panic!("Function {} takes {}, found {}", closure.name, arguments(closure.params.len()), args.len());
+    }
+    let mut frame = Env::new(Some(closure.env.clone()), false);
+    // anonymous functions have no name to recurse through
+    if !closure.name.is_empty() {
+        frame.vars.insert(closure.name.to_string(), Value::Closure(closure.clone()));
+    }
+    for (param, arg) in closure.params.iter().zip(args) {
+        frame.vars.insert(param.to_string(), arg);
+    }
+    let value = (closure.body)(&mut frame);
+    for (name, value) in frame.globals {
+        if env.toplevel { &mut env.vars } else { &mut env.globals }.insert(name, value);
+    }
+    value
+}
+
+fn call_builtin(name: &str, args: Vec<Value>) -> Value {
+    let &(name, arity, implementation) = builtin(name).unwrap();
+    let variadic = VARIADIC.contains(&name);
+    if args.len() < arity || !variadic && args.len() > arity {
+        let least = if variadic { "at least " } else { "" };
+        panic!("Builtin {} takes {}{}, found {}", name, least, arguments(arity), args.len());
+    }
+    implementation(args)
+}
+
+/// How many arguments a function takes, worded as the parser words it.
+fn arguments(count: usize) -> String {
+    if count == 1 { "1 argument".to_string() } else { format!("{} arguments", count) }
+}
+
+fn builtin(name: &str) -> Option<&'static (&'static str, usize, fn(Vec<Value>) -> Value)> {
+    BUILTINS.iter().find(|builtin| builtin.0 == name)
+}
+
+fn integer(name: &str, i: usize, value: &Value) -> i64 {
+    match *value {
+        Value::Int(value) => value,
+        ref value => panic!("Builtin {} expects an integer as argument {}, got {}", name, i + 1, value),
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    i64::try_from(a).unwrap_or_else(|_| panic!("gcd result {} overflows", a))
+}
+
+fn parse_number(text: &str) -> Option<Value> {
+    match text.parse::<i64>() {
+        Ok(value) => Some(Value::Int(value)),
+        Err(_) => text.parse::<f64>().ok().map(Value::Float),
+    }
+}
+
+fn is_function(value: &Value) -> bool {
+    matches!(*value, Value::Builtin(_) | Value::Closure(_))
+}
+
+fn stream_head(stream: Value) -> Value {
+    match stream {
+        Value::Pair(pair) => pair.0.clone(),
+        stream => panic!("Apply stream_head on non-stream type: {}", stream),
+    }
+}
+
+fn stream_tail(stream: Value) -> Value {
+    match stream {
+        Value::Pair(pair) => force(pair.1.clone()),
+        stream => panic!("Apply stream_tail on non-stream type: {}", stream),
+    }
+}
+
+const RESERVED: &[&str] = &["nothing", "isnothing", "cons_stream"];
+
+/// The builtins taking at least their arity in arguments, rather than
+/// exactly that many.
+const VARIADIC: &[&str] = &["compose"];
+
+type Builtin = (&'static str, usize, fn(Vec<Value>) -> Value);
+
+const BUILTINS: &[Builtin] = &[
+    ("abs", 1, |args| match args[0] {
+        Value::Float(value) => Value::Float(value.abs()),
+        ref value => Value::Int(int(value.clone()).abs()),
+    }),
+    ("sign", 1, |args| match args[0] {
+        Value::Float(value) if value.is_nan() => panic!("sign({}) is undefined", args[0]),
+        Value::Float(value) => Value::Int(if value > 0.0 { 1 } else if value < 0.0 { -1 } else { 0 }),
+        ref value => Value::Int(int(value.clone()).signum()),
+    }),
+    ("min", 2, |args| match (&args[0], &args[1]) {
+        (&Value::Int(a), &Value::Int(b)) => Value::Int(a.min(b)),
+        (a, b) => Value::Float(float(a.clone()).min(float(b.clone()))),
+    }),
+    ("max", 2, |args| match (&args[0], &args[1]) {
+        (&Value::Int(a), &Value::Int(b)) => Value::Int(a.max(b)),
+        (a, b) => Value::Float(float(a.clone()).max(float(b.clone()))),
+    }),
+    ("pow", 2, |args| {
+        let (base, exp) = (integer("pow", 0, &args[0]), integer("pow", 1, &args[1]));
+        if exp < 0 {
+            panic!("pow({}, {}) has a negative exponent", base, exp);
+        }
+        let result = if exp > u32::MAX as i64 { None } else { base.checked_pow(exp as u32) };
+        Value::Int(result.unwrap_or_else(|| panic!("pow({}, {}) overflows", base, exp)))
+    }),
+    ("gcd", 2, |args| Value::Int(gcd(integer("gcd", 0, &args[0]), integer("gcd", 1, &args[1])))),
+    ("lcm", 2, |args| {
+        let (a, b) = (integer("lcm", 0, &args[0]), integer("lcm", 1, &args[1]));
+        if a == 0 || b == 0 {
+            return Value::Int(0);
+        }
+        let lcm = (a / gcd(a, b)).checked_mul(b).and_then(i64::checked_abs);
+        Value::Int(lcm.unwrap_or_else(|| panic!("lcm({}, {}) overflows", a, b)))
+    }),
+    ("divmod", 2, |args| {
+        let (a, b) = (int(args[0].clone()), int(args[1].clone()));
+        if b == 0 {
+            panic!("divmod({}, {}) divides by zero", a, b);
+        }
+        let (q, r) = match (a.checked_div(b), a.checked_rem(b)) {
+            (Some(q), Some(r)) => (q, r),
+            _ => panic!("divmod({}, {}) overflows", a, b),
+        };
+        if r != 0 && (r < 0) != (b < 0) {
+            Value::pair(Value::Int(q - 1), Value::Int(r + b))
+        } else {
+            Value::pair(Value::Int(q), Value::Int(r))
+        }
+    }),
+    ("pair", 2, |args| Value::pair(args[0].clone(), args[1].clone())),
+    ("fst", 1, |args| match args[0] {
+        Value::Pair(ref pair) => pair.0.clone(),
+        ref value => panic!("Apply fst on non-pair type: {}", value),
+    }),
+    ("snd", 1, |args| match args[0] {
+        Value::Pair(ref pair) => pair.1.clone(),
+        ref value => panic!("Apply snd on non-pair type: {}", value),
+    }),
+    ("len", 1, |args| match args[0] {
+        Value::Str(ref value) => Value::Int(value.chars().count() as i64),
+        Value::List(ref items) => Value::Int(items.len() as i64),
+        Value::Map(ref entries) => Value::Int(entries.len() as i64),
+        ref value => panic!("Apply len on non-string, non-list, non-map type: {}", value),
+    }),
+    ("push", 2, |args| {
+        let mut items = (*list(args[0].clone())).clone();
+        items.push(args[1].clone());
+        Value::list(items)
+    }),
+    ("head", 1, |args| match list(args[0].clone()).first() {
+        Some(item) => item.clone(),
+        None => panic!("Apply head on empty list"),
+    }),
+    ("tail", 1, |args| {
+        let items = list(args[0].clone());
+        if items.is_empty() {
+            panic!("Apply tail on empty list");
+        }
+        Value::list(items[1..].to_vec())
+    }),
+    ("force", 1, |args| force(args[0].clone())),
+    ("stream_head", 1, |args| stream_head(args[0].clone())),
+    ("stream_tail", 1, |args| stream_tail(args[0].clone())),
+    ("take", 2, |args| {
+        let mut stream = args[0].clone();
+        let mut items = Vec::new();
+        for _ in 0..int(args[1].clone()).max(0) {
+            if stream.is_nothing() {
+                break;
+            }
+            items.push(stream_head(stream.clone()));
+            stream = stream_tail(stream);
+        }
+        Value::list(items)
+    }),
+    ("get", 2, |args| {
+        let (entries, key) = (map(args[0].clone()), string(args[1].clone()));
+        match entries.get(&*key) {
+            Some(value) => value.clone(),
+            None => panic!("Key {:?} not found in {}", key, args[0]),
+        }
+    }),
+    ("set", 3, |args| {
+        let mut entries = (*map(args[0].clone())).clone();
+        entries.insert(string(args[1].clone()).to_string(), args[2].clone());
+        Value::Map(Rc::new(entries))
+    }),
+    ("contains", 2, |args| Value::Bool(map(args[0].clone()).contains_key(&*string(args[1].clone())))),
+    ("print", 1, |args| {
+        match args[0] {
+            Value::Str(ref text) => println!("{}", text),
+            ref value => println!("{}", value),
+        }
+        Value::Nothing
+    }),
+    ("warn", 1, |args| {
+        match args[0] {
+            Value::Str(ref text) => eprintln!("warning: {}", text),
+            ref value => eprintln!("warning: {}", value),
+        }
+        Value::Nothing
+    }),
+    ("input", 0, |_| {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) => Value::Nothing,
+            Ok(_) => {
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                parse_number(line.trim()).unwrap_or_else(|| Value::string(line))
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }),
+    ("arity", 1, |args| match args[0] {
+        Value::Builtin(name) if VARIADIC.contains(&name) =>
+            panic!("Builtin {} takes at least {}", name, arguments(builtin(name).unwrap().1)),
+        Value::Builtin(name) => Value::Int(builtin(name).unwrap().1 as i64),
+        Value::Closure(ref closure) => Value::Int(closure.params.len() as i64),
+        ref value => panic!("Apply arity on non-function type: {}", value),
+    }),
+    ("name", 1, |args| match args[0] {
+        Value::Builtin(name) => Value::string(name),
+        Value::Closure(ref closure) if closure.name.is_empty() => Value::Nothing,
+        Value::Closure(ref closure) => Value::string(closure.name),
+        ref value => panic!("Apply name on non-function type: {}", value),
+    }),
+    ("is_function", 1, |args| Value::Bool(is_function(&args[0]))),
+    ("is_number", 1, |args| Value::Bool(matches!(args[0], Value::Int(_) | Value::Float(_)))),
+    ("is_boolean", 1, |args| Value::Bool(matches!(args[0], Value::Bool(_)))),
+    ("is_string", 1, |args| Value::Bool(matches!(args[0], Value::Str(_)))),
+    ("is_pair", 1, |args| Value::Bool(matches!(args[0], Value::Pair(_)))),
+    ("is_list", 1, |args| Value::Bool(matches!(args[0], Value::List(_)))),
+    ("is_map", 1, |args| Value::Bool(matches!(args[0], Value::Map(_)))),
+    ("to_number", 2, |args| match args[0] {
+        Value::Int(_) | Value::Float(_) => args[0].clone(),
+        Value::Bool(value) => Value::Int(value as i64),
+        Value::Str(ref text) => parse_number(text.trim()).unwrap_or_else(|| args[1].clone()),
+        _ => args[1].clone(),
+    }),
+    ("to_boolean", 2, |args| match args[0] {
+        Value::Bool(value) => Value::Bool(value),
+        Value::Int(value) => Value::Bool(value != 0),
+        Value::Float(value) if !value.is_nan() => Value::Bool(value != 0.0),
+        Value::Str(ref text) if text.trim() == "true" || text.trim() == "false" => Value::Bool(text.trim() == "true"),
+        _ => args[1].clone(),
+    }),
+    ("compose", 1, |mut args| {
+        let last = args.pop().unwrap();
+        args.into_iter().rev().fold(last, |inner, outer| compose(outer, inner))
+    }),
+];
+
+/// Run `program` in a fresh environment, printing its result as `simple
+/// run` does, and errors as the interpreter reports them.
+fn run(program: fn(&mut Env) -> Value) {
+    std::panic::set_hook(Box::new(|info| {
+        match info.payload().downcast_ref::<String>() {
+            Some(message) => eprintln!("{}", message),
+            None => eprintln!("{}", info.payload().downcast_ref::<&str>().unwrap_or(&"evaluation failed")),
+        }
+    }));
+    let mut env = Env::new(None, true);
+    let value = program(&mut env);
+    // a `result` variable is the result, else the value of the last
+    // statement unless do-nothing
+    match env.vars.get("result") {
+        Some(result) => println!("{}", result),
+        None if value.is_nothing() => (),
+        None => println!("{}", value),
+    }
+}
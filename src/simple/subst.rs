@@ -0,0 +1,865 @@
+//! A small-step semantics without environments: a variable is replaced by
+//! its value as soon as it is bound, the way the lambda calculus is
+//! usually presented, instead of being looked up when it is used.
+//!
+//! `x = v; rest` steps to `rest` with `v` substituted for `x`, calling a
+//! function substitutes its arguments into its body, and substitution
+//! renames the parameters of a function when they would capture a free
+//! variable of the substituted value. Loops are unrolled one iteration at
+//! a time, so that the assignments of one iteration are substituted into
+//! the next. An unrolled iteration has its `break`s and `continue`s
+//! rewritten away: the statements after one are moved into the branches
+//! that do not leave the iteration, and it becomes nothing, or the loop
+//! again. Those outside a loop stop the program like `return`.
+//!
+//! `delay` has no counterpart here; `check` rejects the programs using it.
+
+use super::syntax::{Node, Pattern};
+use super::environment::Environment;
+use super::evaluate::{self, Evaluate};
+use super::builtin;
+use super::trace::Trace;
+use super::machine::{Machine, Semantics};
+use super::reduce;
+use parser::Arity;
+
+use std::collections::{HashMap, HashSet};
+use std::slice;
+
+/// Whether `node` is fully reduced. A pending `return`, `break` or
+/// `continue` counts as a value so that it stops the statements around
+/// it; those of loops the program unrolls never get there.
+pub fn is_value(node: &Node) -> bool {
+    match *node {
+        Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Boolean(_) | Node::DoNothing |
+            Node::Break | Node::Continue | Node::Fun(..) | Node::Closure(..) | Node::Builtin(_) | Node::Native(_) => true,
+        Node::Pair(ref l, ref r) => is_value(l) && is_value(r),
+        Node::List(ref items) => items.iter().all(|item| is_value(item)),
+        Node::Map(ref entries) => entries.values().all(|value| is_value(value)),
+        Node::Return(ref value) => is_value(value),
+        _ => false,
+    }
+}
+
+/// Whether running `node` may assign `name` in the enclosing scope.
+fn may_assign(node: &Node, name: &str) -> bool {
+    match *node {
+        Node::Assign(ref var, _) | Node::Global(ref var, _) if var == name => true,
+        // an arm whose pattern binds `name` only assigns its own binding
+        Node::Match(ref matched, ref arms) => may_assign(matched, name) ||
+            arms.iter().any(|(pattern, body)| !pattern.bindings().contains(&name) && may_assign(body, name)),
+        Node::Fun(..) => false,
+        _ => node.children().into_iter().any(|child| may_assign(child, name)),
+    }
+}
+
+/// Every name occurring in `node`, bound or free.
+fn names(node: &Node, found: &mut HashSet<String>) {
+    match *node {
+        Node::Variable(ref name) | Node::Assign(ref name, _) | Node::Global(ref name, _) => {
+            found.insert(name.clone());
+        }
+        Node::Fun(ref funname, ref params, _) => {
+            found.insert(funname.clone());
+            found.extend(params.iter().cloned());
+        }
+        Node::Match(_, ref arms) => {
+            for (pattern, _) in arms {
+                found.extend(pattern.bindings().into_iter().map(|name| name.to_string()));
+            }
+        }
+        _ => (),
+    }
+    for child in node.children() {
+        names(child, found);
+    }
+}
+
+/// `name` with a suffix that source code cannot write, so that it is
+/// distinct from every name in `taken`.
+fn fresh(name: &str, taken: &HashSet<String>) -> String {
+    (1..).map(|n| format!("{}'{}", name, n)).find(|candidate| !taken.contains(candidate)).unwrap()
+}
+
+/// Replace the free occurrences of `name` in `node` by `value`.
+pub fn subst(node: &Node, name: &str, value: &Node) -> Box<Node> {
+    let s = |node: &Node| subst(node, name, value);
+    match *node {
+        Node::Variable(ref var) if var == name => Box::new(value.clone()),
+        Node::Fun(ref funname, ref params, ref body) => {
+            if funname == name || params.iter().any(|param| param == name) {
+                return Box::new(node.clone());
+            }
+            // rename the binders that would capture a free variable of the value
+            let free = evaluate::get_free_vars(value);
+            let mut taken = free.clone();
+            names(body, &mut taken);
+            taken.insert(name.to_string());
+            let mut body = body.clone();
+            let mut binders = Vec::new();
+            for binder in Some(funname).into_iter().chain(params) {
+                if free.contains(binder) {
+                    let renamed = fresh(binder, &taken);
+                    taken.insert(renamed.clone());
+                    body = subst(&body, binder, &Node::Variable(renamed.clone()));
+                    binders.push(renamed);
+                } else {
+                    binders.push(binder.clone());
+                }
+            }
+            let funname = binders.remove(0);
+            Box::new(Node::Fun(funname, binders, subst(&body, name, value)))
+        }
+        Node::Sequence(ref head, ref more) => {
+            if may_assign(head, name) {
+                Node::sequence(subst_rebinding(head, name, value), more.clone())
+            } else {
+                Node::sequence(s(head), s(more))
+            }
+        }
+        // a `continue` goes on to the loop itself, which unrolling again
+        // would never finish substituting into
+        Node::While(_, ref body) if may_assign(body, name) && leaves_iteration(body) =>
+            subst_rebinding(node, name, value),
+        Node::While(ref cond, ref body) if may_assign(body, name) => s(&unroll(cond, body)),
+        Node::For(..) if may_assign(node, name) => s(&desugar_for(node)),
+        Node::Match(ref matched, ref arms) => Node::match_node(s(matched), arms.iter().map(|(pattern, body)| {
+            if pattern.bindings().contains(&name) {
+                (pattern.clone(), body.clone())
+            } else {
+                (pattern.clone(), s(body))
+            }
+        }).collect()),
+        _ => map_children(node, &s),
+    }
+}
+
+/// Rename the free occurrences of `from` in `node`, both as a variable and
+/// as an assignment target, to `to`, a name that does not occur in `node`.
+fn rename(node: &Node, from: &str, to: &str) -> Box<Node> {
+    let r = |node: &Node| rename(node, from, to);
+    match *node {
+        Node::Variable(ref var) if var == from => Node::variable(to),
+        Node::Assign(ref var, ref expr) if var == from => Node::assign(to, r(expr)),
+        Node::Fun(ref funname, ref params, ref body) => {
+            if funname == from || params.iter().any(|param| param == from) {
+                Box::new(node.clone())
+            } else {
+                Box::new(Node::Fun(funname.clone(), params.clone(), r(body)))
+            }
+        }
+        Node::Match(ref matched, ref arms) => Node::match_node(r(matched), arms.iter().map(|(pattern, body)| {
+            if pattern.bindings().contains(&from) {
+                (pattern.clone(), body.clone())
+            } else {
+                (pattern.clone(), r(body))
+            }
+        }).collect()),
+        _ => map_children(node, &r),
+    }
+}
+
+/// Substitute into a statement that may assign `name`, the rest of the
+/// program after it left alone. Every path through the statement either
+/// assigns `name` itself or starts with `name = value`, so that the
+/// binding reaches the rest of the program once the statement is taken
+/// apart.
+fn subst_rebinding(node: &Node, name: &str, value: &Node) -> Box<Node> {
+    let rebind = |body: &Node| Node::sequence(Node::assign(name, Box::new(value.clone())), Box::new(body.clone()));
+    match *node {
+        Node::Assign(ref var, ref expr) => Node::assign(var, subst(expr, name, value)),
+        Node::Global(ref var, ref expr) => Node::global(var, subst(expr, name, value)),
+        Node::Sequence(ref head, ref more) => {
+            if may_assign(head, name) {
+                Node::sequence(subst_rebinding(head, name, value), more.clone())
+            } else {
+                Node::sequence(subst(head, name, value), subst_rebinding(more, name, value))
+            }
+        }
+        Node::If(ref cond, ref consequence, ref alternative) =>
+            Node::if_cond_else(subst(cond, name, value), rebind(consequence), rebind(alternative)),
+        Node::While(ref cond, ref body) => subst_rebinding(&unroll(cond, body), name, value),
+        Node::For(..) => subst_rebinding(&desugar_for(node), name, value),
+        Node::Match(ref matched, ref arms) => Node::match_node(
+            subst(matched, name, value), arms.iter().map(|(pattern, body)| {
+                if pattern.bindings().contains(&name) {
+                    (pattern.clone(), body.clone())
+                } else {
+                    (pattern.clone(), rebind(body))
+                }
+            }).collect()),
+        _ => subst(node, name, value),
+    }
+}
+
+/// One iteration of a while loop followed by the loop itself.
+fn unroll(cond: &Node, body: &Node) -> Box<Node> {
+    let again = Node::while_node(Box::new(cond.clone()), Box::new(body.clone()));
+    Node::if_cond_else(Box::new(cond.clone()), iteration(body, again.clone(), &Node::DoNothing, &again), Node::donothing())
+}
+
+/// A for loop as a while loop, whose body goes on to the step on
+/// `continue` as well as at its end.
+fn desugar_for(node: &Node) -> Box<Node> {
+    match *node {
+        Node::For(ref init, ref cond, ref step, ref body) => Node::sequence(
+            init.clone(), Node::while_node(cond.clone(), iteration(body, step.clone(), &Node::Break, step))),
+        _ => unreachable!(),
+    }
+}
+
+/// Whether `node` has a `break` or `continue` of the loop it is in.
+fn leaves_iteration(node: &Node) -> bool {
+    match *node {
+        Node::Break | Node::Continue => true,
+        Node::While(..) | Node::For(..) | Node::Fun(..) => false,
+        _ => node.children().into_iter().any(leaves_iteration),
+    }
+}
+
+/// The statement `node` of a loop body followed by `rest`, the remainder
+/// of the iteration, with its `break`s replaced by `breaking` and its
+/// `continue`s by `continuing`.
+fn iteration(node: &Node, rest: Box<Node>, breaking: &Node, continuing: &Node) -> Box<Node> {
+    if !leaves_iteration(node) {
+        return Node::sequence(Box::new(node.clone()), rest);
+    }
+    match *node {
+        Node::Break => Box::new(breaking.clone()),
+        Node::Continue => Box::new(continuing.clone()),
+        Node::Sequence(ref head, ref more) =>
+            iteration(head, iteration(more, rest, breaking, continuing), breaking, continuing),
+        Node::If(ref cond, ref consequence, ref alternative) => Node::if_cond_else(cond.clone(),
+            iteration(consequence, rest.clone(), breaking, continuing), iteration(alternative, rest, breaking, continuing)),
+        Node::Match(ref matched, ref arms) => {
+            // the rest is out of the scope of the names an arm binds
+            let free = evaluate::get_free_vars(&rest);
+            let mut taken = HashSet::new();
+            names(node, &mut taken);
+            names(&rest, &mut taken);
+            Node::match_node(matched.clone(), arms.iter().map(|(pattern, body)| {
+                let (mut pattern, mut body) = (pattern.clone(), body.clone());
+                for name in pattern.clone().bindings().into_iter().filter(|name| free.contains(*name)) {
+                    let renamed = fresh(name, &taken);
+                    taken.insert(renamed.clone());
+                    pattern = rename_pattern(&pattern, name, &renamed);
+                    body = rename(&body, name, &renamed);
+                }
+                (pattern, iteration(&body, rest.clone(), breaking, continuing))
+            }).collect())
+        }
+        _ => Node::sequence(Box::new(node.clone()), rest),
+    }
+}
+
+fn rename_pattern(pattern: &Pattern, from: &str, to: &str) -> Pattern {
+    match *pattern {
+        Pattern::Bind(ref name) if name == from => Pattern::Bind(to.to_string()),
+        Pattern::Pair(ref l, ref r) =>
+            Pattern::Pair(Box::new(rename_pattern(l, from, to)), Box::new(rename_pattern(r, from, to))),
+        Pattern::List(ref items) => Pattern::List(items.iter().map(|item| rename_pattern(item, from, to)).collect()),
+        _ => pattern.clone(),
+    }
+}
+
+/// Refuse programs the substitution semantics cannot run: those with a
+/// `delay`, which would have to be remembered apart from the program.
+pub fn check(node: &Node) -> Result<(), String> {
+    match *node {
+        Node::Delay(_) => Err(format!("{} is not supported by the substitution semantics", node)),
+        _ => node.children().into_iter().try_for_each(check),
+    }
+}
+
+/// Rebuild `node` with `f` applied to each of its sub-expressions, for the
+/// nodes that bind nothing.
+fn map_children<F>(node: &Node, f: &F) -> Box<Node>
+    where F: Fn(&Node) -> Box<Node> {
+    let mut node = node.clone();
+    match node {
+        Node::Add(ref mut l, ref mut r) | Node::Subtract(ref mut l, ref mut r) |
+            Node::Multiply(ref mut l, ref mut r) | Node::LT(ref mut l, ref mut r) |
+            Node::EQ(ref mut l, ref mut r) | Node::GT(ref mut l, ref mut r) |
+            Node::LE(ref mut l, ref mut r) | Node::GE(ref mut l, ref mut r) | Node::NE(ref mut l, ref mut r) |
+            Node::And(ref mut l, ref mut r) | Node::Or(ref mut l, ref mut r) |
+            Node::Sequence(ref mut l, ref mut r) | Node::While(ref mut l, ref mut r) |
+            Node::Pair(ref mut l, ref mut r) | Node::Compose(ref mut l, ref mut r) |
+            Node::Index(ref mut l, ref mut r) => {
+                *l = f(l);
+                *r = f(r);
+        }
+        Node::Not(ref mut inner) | Node::Negate(ref mut inner) | Node::IsDoNothing(ref mut inner) |
+            Node::Return(ref mut inner) | Node::Delay(ref mut inner) |
+            Node::Fst(ref mut inner) | Node::Snd(ref mut inner) | Node::Len(ref mut inner) |
+            Node::Assign(_, ref mut inner) | Node::Global(_, ref mut inner) => {
+            *inner = f(inner);
+        }
+        Node::If(ref mut cond, ref mut consequence, ref mut alternative) => {
+            *cond = f(cond);
+            *consequence = f(consequence);
+            *alternative = f(alternative);
+        }
+        Node::For(ref mut init, ref mut cond, ref mut step, ref mut body) => {
+            *init = f(init);
+            *cond = f(cond);
+            *step = f(step);
+            *body = f(body);
+        }
+        Node::Call(ref mut closure, ref mut args) => {
+            *closure = f(closure);
+            for arg in args.iter_mut() {
+                *arg = f(arg);
+            }
+        }
+        Node::List(ref mut items) => {
+            for item in items.iter_mut() {
+                *item = f(item);
+            }
+        }
+        Node::Map(ref mut entries) => {
+            for value in entries.values_mut() {
+                *value = f(value);
+            }
+        }
+        Node::Match(ref mut matched, ref mut arms) => {
+            *matched = f(matched);
+            for arm in arms.iter_mut() {
+                arm.1 = f(&arm.1);
+            }
+        }
+        // Number, Float, Str, Boolean, Variable, DoNothing, Builtin, Native, Break,
+        // Continue, Fun, Closure, Thunk
+        _ => (),
+    }
+    Box::new(node)
+}
+
+/// Reduce the first sub-expression of `children` that is not a value yet.
+fn step_first(reducer: &mut Reducer, children: &mut [&mut Box<Node>]) -> bool {
+    for child in children.iter_mut() {
+        if !is_value(child) {
+            let next = reducer.step(child, false);
+            **child = next;
+            return true;
+        }
+    }
+    false
+}
+
+/// What reducing a whole program comes to.
+#[derive(Debug,PartialEq)]
+pub struct Outcome {
+    pub value: Box<Node>,
+    /// The last value assigned to each top-level variable.
+    pub bindings: HashMap<String, Box<Node>>,
+    pub steps: usize,
+}
+
+struct Reducer {
+    bindings: HashMap<String, Box<Node>>,
+    steps: usize,
+    trace: Option<Trace>,
+}
+
+impl Reducer {
+    fn run(&mut self, node: &Node, toplevel: bool) -> Box<Node> {
+        let mut node = Box::new(node.clone());
+        while !is_value(&node) {
+            if let (true, Some(trace)) = (toplevel, self.trace.as_mut()) {
+                trace.step(&node);
+            }
+            node = self.step(&node, toplevel);
+        }
+        node
+    }
+
+    /// Record a top-level assignment before its value is substituted away.
+    /// The names `fresh` makes belong to the scope they were made for, not
+    /// to the program.
+    fn bind(&mut self, name: &str, value: &Node, toplevel: bool) {
+        if toplevel && !name.contains('\'') {
+            if let Some(trace) = self.trace.as_mut() {
+                trace.assign(name, value);
+            }
+            self.bindings.insert(name.to_string(), Box::new(value.clone()));
+        }
+    }
+
+    fn step(&mut self, node: &Node, toplevel: bool) -> Box<Node> {
+        self.steps += 1;
+        evaluate::count_step(node);
+        let mut next = node.clone();
+        match next {
+            Node::Variable(ref name) => match builtin::lookup(name) {
+                Some(_) => return Node::builtin(name),
+                None => panic!("Variable {} not found", name),
+            },
+            Node::Sequence(ref head, ref more) if is_value(head) => {
+                if head.is_signal() {
+                    return head.clone();
+                }
+                // the value of a sequence is the value of its last statement
+                if is_value(more) {
+                    return more.clone();
+                }
+                if let Node::Sequence(..) = **more {
+                    return more.clone();
+                }
+                // the last statement is reduced in place, as the book writes it
+                return Node::sequence(Node::donothing(), self.step(more, toplevel));
+            }
+            Node::Sequence(ref head, ref more) => return match **head {
+                Node::Sequence(ref first, ref second) =>
+                    Node::sequence(first.clone(), Node::sequence(second.clone(), more.clone())),
+                Node::Assign(ref name, ref value) | Node::Global(ref name, ref value) if is_value(value) => {
+                    self.bind(name, value, toplevel);
+                    Node::sequence(Node::donothing(), subst(more, name, value))
+                }
+                Node::If(ref cond, ref consequence, ref alternative) if is_value(cond) => {
+                    let branch = if cond.condition() { consequence } else { alternative };
+                    Node::sequence(branch.clone(), more.clone())
+                }
+                Node::While(ref cond, ref body) => Node::sequence(unroll(cond, body), more.clone()),
+                Node::For(..) => Node::sequence(desugar_for(head), more.clone()),
+                Node::Match(ref matched, ref arms) if is_value(matched) =>
+                    Node::sequence(select_arm(matched, arms), more.clone()),
+                _ => Node::sequence(self.step(head, toplevel), more.clone()),
+            },
+            Node::Assign(ref name, ref value) | Node::Global(ref name, ref value) if is_value(value) => {
+                self.bind(name, value, toplevel);
+                return Node::donothing();
+            }
+            Node::If(ref cond, ref consequence, ref alternative) if is_value(cond) => {
+                return if cond.condition() { consequence.clone() } else { alternative.clone() };
+            }
+            Node::While(ref cond, ref body) => return unroll(cond, body),
+            Node::For(..) => return desugar_for(node),
+            Node::Match(ref matched, ref arms) if is_value(matched) => return select_arm(matched, arms),
+            Node::Delay(_) | Node::Thunk(..) | Node::Closure(..) =>
+                panic!("{} is not supported by the substitution semantics", node),
+            _ => (),
+        }
+        let stepped = match next {
+            Node::Add(ref mut l, ref mut r) | Node::Subtract(ref mut l, ref mut r) |
+                Node::Multiply(ref mut l, ref mut r) | Node::LT(ref mut l, ref mut r) |
+                Node::EQ(ref mut l, ref mut r) | Node::GT(ref mut l, ref mut r) |
+                Node::LE(ref mut l, ref mut r) | Node::GE(ref mut l, ref mut r) | Node::NE(ref mut l, ref mut r) |
+                Node::Pair(ref mut l, ref mut r) | Node::Compose(ref mut l, ref mut r) |
+                Node::Index(ref mut l, ref mut r) => step_first(self, &mut [l, r]),
+            Node::And(ref mut l, ref mut r) | Node::Or(ref mut l, ref mut r) => {
+                if !is_value(l) {
+                    step_first(self, &mut [l])
+                } else {
+                    // decide on the left operand alone when it short-circuits
+                    let short = match *node { Node::And(..) => !l.condition(), _ => l.condition() };
+                    if short {
+                        return Box::new(Node::Boolean(l.condition()));
+                    }
+                    step_first(self, &mut [r])
+                }
+            }
+            Node::Not(ref mut inner) | Node::Negate(ref mut inner) | Node::IsDoNothing(ref mut inner) |
+                Node::Return(ref mut inner) | Node::Fst(ref mut inner) | Node::Snd(ref mut inner) |
+                Node::Len(ref mut inner) | Node::Assign(_, ref mut inner) | Node::Global(_, ref mut inner) =>
+                step_first(self, &mut [inner]),
+            Node::If(ref mut cond, _, _) | Node::Match(ref mut cond, _) => step_first(self, &mut [cond]),
+            Node::Call(ref mut closure, ref mut args) => {
+                // arguments first, like the big-step evaluator
+                let mut children: Vec<&mut Box<Node>> = args.iter_mut().collect();
+                children.push(closure);
+                step_first(self, &mut children)
+            }
+            Node::List(ref mut items) => step_first(self, &mut items.iter_mut().collect::<Vec<_>>()),
+            Node::Map(ref mut entries) => {
+                let mut keys: Vec<String> = entries.keys().cloned().collect();
+                keys.sort();
+                let mut children: Vec<&mut Box<Node>> = Vec::new();
+                // sorted key order, matching the big-step evaluator
+                let mut values: HashMap<&String, &mut Box<Node>> = entries.iter_mut().collect();
+                for key in &keys {
+                    children.push(values.remove(key).unwrap());
+                }
+                step_first(self, &mut children)
+            }
+            _ => false,
+        };
+        if stepped {
+            return Box::new(next);
+        }
+        self.reduce(node)
+    }
+
+    /// Reduce a node whose sub-expressions are all values.
+    fn reduce(&mut self, node: &Node) -> Box<Node> {
+        match *node {
+            Node::Call(ref closure, ref args) => match **closure {
+                Node::Builtin(ref name) => builtin::call(name, args),
+                Node::Native(ref native) => (native.fun)(args),
+                Node::Fun(ref funname, ref params, ref body) => {
+                    // functions are values here, so their parameters are checked by the call
+                    evaluate::check_params(params);
+                    if args.len() != params.len() {
+                        panic!("Function {} takes {}, found {}", funname, Arity::Exactly(params.len()), args.len());
+                    }
+                    let mut body = body.clone();
+                    for (param, arg) in params.iter().zip(args) {
+                        body = subst(&body, param, arg);
+                    }
+                    if !funname.is_empty() && !params.contains(funname) {
+                        body = subst(&body, funname, closure);
+                    }
+                    // the body runs on its own so that a return stops at the call
+                    match *self.run(&body, false) {
+                        Node::Return(value) => value,
+                        Node::Break | Node::Continue => panic!("break or continue outside of a loop in {}", closure),
+                        value => Box::new(value),
+                    }
+                }
+                // made by a builtin such as compose: its environment is
+                // substituted in to call it as a plain function
+                Node::Closure(ref cenv, ref fun) => {
+                    let fun = cenv.bindings().into_iter()
+                        .fold(Box::new((**fun).clone()), |fun, (name, value)| subst(&fun, &name, &value));
+                    self.reduce(&Node::Call(fun, args.clone()))
+                }
+                _ => panic!("Closure not contain function: {}", closure),
+            },
+            Node::Compose(ref outer, ref inner) => Node::fun("compose", &["$x"], Node::call(
+                outer.clone(), Node::call(inner.clone(), Node::variable("$x")))),
+            Node::Fst(ref pair) => builtin::call("fst", slice::from_ref(pair)),
+            Node::Snd(ref pair) => builtin::call("snd", slice::from_ref(pair)),
+            Node::Len(ref value) => builtin::call("len", slice::from_ref(value)),
+            Node::Index(ref list, ref index) => {
+                let items = list.list_value();
+                let index = index.value();
+                match items.get(index as usize) {
+                    Some(item) if index >= 0 => item.clone(),
+                    _ => panic!("Index {} out of bounds for list of length {}", index, items.len()),
+                }
+            }
+            // the remaining operators only see numbers, strings and booleans
+            _ => node.evaluate(&mut Environment::new()),
+        }
+    }
+}
+
+/// The body of the first arm of a `match` that fits `value`, with the
+/// names its pattern binds substituted. They are scoped to the arm, so
+/// a name the arm assigns again is first renamed apart from the program.
+fn select_arm(value: &Node, arms: &[(Pattern, Box<Node>)]) -> Box<Node> {
+    for (pattern, body) in arms {
+        let mut bindings = Vec::new();
+        if evaluate::match_pattern(pattern, value, &mut bindings) {
+            let mut taken = HashSet::new();
+            names(body, &mut taken);
+            let mut body = body.clone();
+            for (name, value) in bindings {
+                if may_assign(&body, &name) {
+                    let renamed = fresh(&name, &taken);
+                    taken.insert(renamed.clone());
+                    body = rename(&body, &name, &renamed);
+                    body = Node::sequence(Node::assign(&renamed, value), body);
+                } else {
+                    body = subst(&body, &name, &value);
+                }
+            }
+            return body;
+        }
+    }
+    panic!("No match arm for value {}", value)
+}
+
+/// Take a single reduction step, or `None` if `node` is already a value.
+pub fn step(node: &Node) -> Option<Box<Node>> {
+    let node = node.without_spans();
+    if is_value(&node) {
+        return None;
+    }
+    Some(Reducer{ bindings: HashMap::new(), steps: 0, trace: None }.step(&node, false))
+}
+
+/// Take a single reduction step of a whole program, adding the top-level
+/// assignments it makes to `bindings`, or `None` if it is a value. The
+/// program is one `close` gave, without spans.
+pub fn step_program(node: &Node, bindings: &mut HashMap<String, Box<Node>>) -> Option<Box<Node>> {
+    if is_value(node) {
+        return None;
+    }
+    let mut reducer = Reducer{ bindings: HashMap::new(), steps: 0, trace: None };
+    let next = reducer.step(node, true);
+    bindings.extend(reducer.bindings);
+    Some(next)
+}
+
+/// `node` with the values bound in `env` substituted for its free
+/// variables and its spans taken out, so that it can be reduced on its
+/// own.
+pub fn close(node: &Node, env: &Environment) -> Box<Node> {
+    let mut bindings = env.bindings();
+    bindings.sort_by(|(l, _), (r, _)| l.cmp(r));
+    bindings.into_iter().fold(node.without_spans(), |node, (name, value)| subst(&node, &name, &value))
+}
+
+/// Reduce `statement` against `env`, binding its top-level assignments
+/// there as they are made, and return its value.
+pub fn execute(statement: &Node, env: &mut Environment) -> Box<Node> {
+    let mut node = close(statement, env);
+    let mut bindings = HashMap::new();
+    while let Some(next) = step_program(&node, &mut bindings) {
+        for (name, value) in bindings.drain() {
+            env.add(&name, value);
+        }
+        node = next;
+    }
+    node
+}
+
+/// One step of a derivation: the term reduced to and the top-level
+/// assignments the step made, sorted by name.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Reduction {
+    pub node: Box<Node>,
+    pub assigned: Vec<(String, Box<Node>)>,
+}
+
+/// The derivation of a program, one `Reduction` for each step until it is
+/// a value, the way Understanding Computation prints them.
+///
+/// ```
+/// use proglang::simple::syntax::Node;
+/// use proglang::simple::subst::ReductionTrace;
+///
+/// let program = Node::add(Node::number(1), Node::multiply(Node::number(2), Node::number(3)));
+/// let terms: Vec<_> = ReductionTrace::new(&program).map(|step| step.node.to_string()).collect();
+/// assert_eq!(vec!["1 + 6", "7"], terms);
+/// ```
+pub struct ReductionTrace {
+    node: Option<Box<Node>>,
+}
+
+impl ReductionTrace {
+    pub fn new(program: &Node) -> ReductionTrace {
+        ReductionTrace { node: Some(program.without_spans()) }
+    }
+
+    /// The derivation of `program` run against `env`, its free variables
+    /// bound there.
+    pub fn with_environment(program: &Node, env: &Environment) -> ReductionTrace {
+        ReductionTrace { node: Some(close(program, env)) }
+    }
+}
+
+impl Iterator for ReductionTrace {
+    type Item = Reduction;
+
+    fn next(&mut self) -> Option<Reduction> {
+        let mut bindings = HashMap::new();
+        let next = step_program(self.node.as_ref()?, &mut bindings);
+        self.node = next.clone();
+        let mut assigned: Vec<_> = bindings.into_iter().collect();
+        assigned.sort_by(|(l, _), (r, _)| l.cmp(r));
+        next.map(|node| Reduction { node, assigned })
+    }
+}
+
+/// Reduce `program` to a value.
+pub fn run(program: &Node) -> Outcome {
+    run_traced_if(program, false).0
+}
+
+/// Reduce `program` to a value, recording the top-level terms it steps
+/// through and the assignments it makes.
+pub fn run_traced(program: &Node) -> (Outcome, Trace) {
+    let (outcome, trace) = run_traced_if(program, true);
+    (outcome, trace.unwrap_or_default())
+}
+
+fn run_traced_if(program: &Node, traced: bool) -> (Outcome, Option<Trace>) {
+    let trace = if traced { Some(Trace::new()) } else { None };
+    let mut reducer = Reducer{ bindings: HashMap::new(), steps: 0, trace };
+    let value = reducer.run(&program.without_spans(), true);
+    (Outcome{ value, bindings: reducer.bindings, steps: reducer.steps }, reducer.trace)
+}
+
+/// Steps either semantics may take on a program `divergence` tries.
+const DIVERGENCE_STEPS: usize = 100_000;
+
+/// The smallest program on which this semantics and the environment
+/// machine still disagree, on the values the program leaves in its
+/// variables or on whether it fails; `None` when they agree on `program`.
+pub fn divergence(program: &Node) -> Option<Box<Node>> {
+    let program = program.without_spans();
+    reduce::minimize_divergence(&program, |program| settle(program, Semantics::BigStep),
+                                |program| settle(program, Semantics::SmallStep))
+}
+
+/// The values `program` leaves in its variables under `semantics`, or
+/// `None` when it fails. Functions are left out: they are closures in
+/// one semantics and plain terms in the other.
+fn settle(program: &Node, semantics: Semantics) -> Option<Vec<(String, Box<Node>)>> {
+    let mut machine = Machine::new(Box::new(program.clone()), Environment::new())
+        .with_semantics(semantics).with_step_limit(DIVERGENCE_STEPS);
+    machine.run().ok()?;
+    let mut values: Vec<_> = machine.get_environment().bindings().into_iter()
+        .filter(|(_, value)| !matches!(**value, Node::Closure(..) | Node::Fun(..)))
+        .collect();
+    values.sort_by(|(l, _), (r, _)| l.cmp(r));
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_subst_capture_avoiding() {
+        // (fun(y) { x + y })[x := y] must not capture the free y
+        let fun = Node::fun("", &["y"], Node::add(Node::variable("x"), Node::variable("y")));
+        let result = subst(&fun, "x", &Node::Variable("y".to_string()));
+        let expected = Node::fun("", &["y'1"], Node::add(Node::variable("y"), Node::variable("y'1")));
+        assert_eq!(expected, result);
+        assert!(result.alpha_eq(&Node::fun("", &["z"], Node::add(Node::variable("y"), Node::variable("z")))));
+        // a parameter of the same name shadows the substituted variable
+        let shadow = Node::fun("", &["x"], Node::variable("x"));
+        assert_eq!(shadow, subst(&shadow, "x", &Node::Number(1)));
+    }
+
+    #[test]
+    fn test_subst_stops_at_reassignment() {
+        // (x = x + 1; x)[x := 1] is x = 1 + 1; x
+        let program = Node::sequence(Node::assign("x", Node::add(Node::variable("x"), Node::number(1))),
+                                     Node::variable("x"));
+        let expected = Node::sequence(Node::assign("x", Node::add(Node::number(1), Node::number(1))),
+                                      Node::variable("x"));
+        assert_eq!(expected, subst(&program, "x", &Node::Number(1)));
+    }
+
+    #[test]
+    fn test_step() {
+        let program = Node::multiply(Node::add(Node::number(1), Node::number(2)), Node::number(3));
+        let once = step(&program).unwrap();
+        assert_eq!(Node::multiply(Node::number(3), Node::number(3)), once);
+        assert_eq!(Node::number(9), step(&once).unwrap());
+        assert_eq!(None, step(&Node::Number(9)));
+    }
+
+    #[test]
+    fn test_subst_equality() {
+        let outcome = run(&parser::parse("flag = false; a = flag == false; b = (1, [true]) != (1, [true]);").unwrap());
+        assert_eq!(Some(&Node::boolean(true)), outcome.bindings.get("a"));
+        assert_eq!(Some(&Node::boolean(false)), outcome.bindings.get("b"));
+    }
+
+    #[test]
+    fn test_subst_compose() {
+        let outcome = run(&parser::parse("def inc(x) { return x + 1; } def dbl(x) { return x * 2; }
+            c = compose; r = c(inc, dbl)(3) + (inc >> dbl)(3);").unwrap());
+        assert_eq!(Some(&Node::number(15)), outcome.bindings.get("r"));
+    }
+
+    #[test]
+    #[should_panic(expected = "4611686018427387904 * 2 overflows")]
+    fn test_subst_overflow() {
+        run(&parser::parse("x = 4611686018427387904; y = x * 2;").unwrap());
+    }
+
+    #[test]
+    fn test_subst_loop_signals() {
+        let program = parser::parse("s = 0; i = 0;
+            while (true) { i = i + 1; if (i > 6) { break; } if (i == 2) { continue; } s = s + i; }
+            t = 0; for (j = 0; j < 4; j = j + 1) { match (j) { 1 => { continue; } k => { t = t + k; } } }").unwrap();
+        let outcome = run(&program);
+        assert_eq!((Some(&Node::number(19)), Some(&Node::number(5))), (outcome.bindings.get("s"), outcome.bindings.get("t")));
+        assert_eq!(Some(&Node::number(4)), outcome.bindings.get("j"));
+        // a body run on its own stops with its signal, for the machine to act on
+        let mut env = Environment::new();
+        env.add("i", Node::number(3));
+        assert_eq!(Node::break_node(), execute(&parser::parse("if (i > 2) { break; } i = 0;").unwrap(), &mut env));
+        assert_eq!(Node::number(3), env.get("i"));
+        // the rest of an iteration sees the variable an arm's pattern shadows
+        let outcome = run(&parser::parse("x = 1; n = 0;
+            while (n < 2) { n = n + 1; match (5) { x => { if (x > n) { continue; } } } x = x + 1; }").unwrap());
+        assert_eq!(Some(&Node::number(1)), outcome.bindings.get("x"));
+        assert!(check(&program).is_ok());
+        assert!(check(&parser::parse("s = delay 1 + 2;").unwrap()).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a loop")]
+    fn test_subst_break_outside_loop() {
+        run(&parser::parse("def f() { break; } f()").unwrap());
+    }
+
+    #[test]
+    fn test_reduction_trace() {
+        let program = parser::parse("x = 1 + 2; y = x * 3;").unwrap();
+        let derivation: Vec<_> = ReductionTrace::new(&program).map(|step| {
+            let assigned: Vec<_> = step.assigned.iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+            (step.node.to_string(), assigned.join(", "))
+        }).collect();
+        let expected = [("x = 3; y = x * 3", ""), ("do-nothing; y = 3 * 3", "x = 3"), ("do-nothing; y = 9", ""),
+            ("do-nothing; do-nothing", "y = 9"), ("do-nothing", "")];
+        assert_eq!(expected.iter().map(|&(node, assigned)| (node.to_string(), assigned.to_string())).collect::<Vec<_>>(), derivation);
+        let mut env = Environment::new();
+        env.add("x", Node::number(4));
+        let terms: Vec<_> = ReductionTrace::with_environment(&parser::parse("x + 1").unwrap(), &env).collect();
+        assert_eq!(1, terms.len());
+        assert_eq!(Node::number(5), terms[0].node);
+        assert!(ReductionTrace::new(&Node::number(5)).next().is_none());
+    }
+
+    #[test]
+    fn test_native() {
+        // y = double(x + 1), with a host function substituted for double
+        let program = Node::assign("y", Node::call(Node::variable("double"),
+            Node::add(Node::variable("x"), Node::number(1))));
+        let double = Node::native("double", |args| Node::number(args[0].value() * 2));
+        let program = subst(&subst(&program, "double", &double), "x", &Node::Number(20));
+        assert_eq!(Some(&Node::number(42)), run(&program).bindings.get("y"));
+    }
+
+    /// The conformance programs: every `.simple` file in examples/conformance.
+    fn conformance_suite() -> Vec<(String, Box<Node>)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/conformance");
+        let mut programs: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        programs.sort();
+        programs.into_iter().map(|path| {
+            let source = fs::read_to_string(&path).unwrap();
+            (path.display().to_string(), parser::parse(&source).unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn test_subst_agrees_with_environment_machine() {
+        for (name, program) in conformance_suite() {
+            let outcome = run(&program);
+            let mut machine = Machine::new_with_empty_env(program.clone());
+            machine.run().unwrap();
+            // functions are closures in one semantics and plain terms in the other
+            let mut values = 0;
+            for (var, value) in machine.environment.bindings() {
+                if let Node::Closure(..) = *value {
+                    continue;
+                }
+                values += 1;
+                assert_eq!(Some(&value), outcome.bindings.get(&var), "{}: {}, down to {:?}", name, var, divergence(&program));
+            }
+            assert!(values > 0, "{} assigns no values", name);
+            assert_eq!(None, divergence(&program), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_divergence() {
+        assert_eq!(None, divergence(&parser::parse("x = 1; def f(n) { return n * 2; } y = f(x);").unwrap()));
+        // only the big-step machine runs a delayed expression
+        let program = parser::parse("a = 1; s = delay (a + 1); b = a * 3;").unwrap();
+        let minimized = divergence(&program).unwrap();
+        assert!(check(&minimized).is_err(), "{}", minimized);
+        assert!(!minimized.to_string().contains("b = "), "{}", minimized);
+    }
+}
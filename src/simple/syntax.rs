@@ -1,60 +1,227 @@
-use super::environment::Environment;
+use super::environment::{Capture, Environment};
+use super::builtin;
 
-use std::fmt::Display;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
 use std::fmt::Result;
 use std::fmt::Formatter;
 
+/// The shape a `match` arm tests its value against.
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Pattern {
+    /// `_` matches anything and binds nothing.
+    Wildcard,
+    /// A name matches anything and binds it.
+    Bind(String),
+    Number(i64),
+    Boolean(bool),
+    Str(String),
+    Pair(Box<Pattern>, Box<Pattern>),
+    /// Matches lists of exactly this many items.
+    List(Vec<Pattern>),
+}
+
+impl Pattern {
+    /// The names this pattern binds, left to right.
+    pub fn bindings(&self) -> Vec<&str> {
+        match *self {
+            Pattern::Bind(ref name) => vec![name],
+            Pattern::Pair(ref l, ref r) => {
+                let mut names = l.bindings();
+                names.extend(r.bindings());
+                names
+            }
+            Pattern::List(ref items) => items.iter().flat_map(|item| item.bindings()).collect(),
+            Pattern::Wildcard | Pattern::Number(_) | Pattern::Boolean(_) | Pattern::Str(_) => vec![],
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Bind(ref name) => write!(f, "{}", name),
+            Pattern::Number(value) => write!(f, "{}", value),
+            Pattern::Boolean(value) => write!(f, "{}", value),
+            Pattern::Str(ref value) => write!(f, "{:?}", value),
+            Pattern::Pair(ref l, ref r) => write!(f, "({}, {})", l, r),
+            Pattern::List(ref items) => write!(f, "[{}]",
+                                               items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+/// The host side of a native function: evaluated arguments in, value out.
+pub type NativeFn = dyn Fn(&[Box<Node>]) -> Box<Node>;
+
+/// A function of the host program, callable from simple code.
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub fun: Rc<NativeFn>,
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Native({:?})", self.name)
+    }
+}
+
+/// Two natives are equal only if they share the same host function.
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        self.name == other.name && Rc::ptr_eq(&self.fun, &other.fun)
+    }
+}
+
+/// Where a node was written: the line and column it starts at and those
+/// just past its end, counting from 1.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+/// The slot a memoized thunk remembers its value in once forced.
+pub type Memo = Rc<RefCell<Option<Box<Node>>>>;
+
+/// With the `serde` feature, nodes serialize with their variant names as
+/// tags; natives are host functions and fail to serialize.
 #[derive(Debug,PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Node {
     Number(i64),
+    Float(f64),
+    Str(String),
     Add(Box<Node>, Box<Node>),
     Subtract(Box<Node>, Box<Node>),
     Multiply(Box<Node>, Box<Node>),
+    Negate(Box<Node>),
     Boolean(bool),
     LT(Box<Node>, Box<Node>),
     EQ(Box<Node>, Box<Node>),
     GT(Box<Node>, Box<Node>),
+    LE(Box<Node>, Box<Node>),
+    GE(Box<Node>, Box<Node>),
+    NE(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
     Variable(String),
     DoNothing,
     IsDoNothing(Box<Node>),
     Assign(String, Box<Node>),
+    Global(String, Box<Node>),
     If(Box<Node>, Box<Node>, Box<Node>),
     Sequence(Box<Node>, Box<Node>),
     While(Box<Node>, Box<Node>),
+    Match(Box<Node>, Vec<(Pattern, Box<Node>)>),
+    For(Box<Node>, Box<Node>, Box<Node>, Box<Node>),
+    Return(Box<Node>),
+    Break,
+    Continue,
     Pair(Box<Node>, Box<Node>),
     Fst(Box<Node>),
     Snd(Box<Node>),
-    Fun(String, String, Box<Node>),
-    Closure(Environment, Box<Node>),
-    Call(Box<Node>, Box<Node>),
+    Len(Box<Node>),
+    List(Vec<Box<Node>>),
+    Index(Box<Node>, Box<Node>),
+    Map(HashMap<String, Box<Node>>),
+    Fun(String, Vec<String>, Box<Node>),
+    Closure(Rc<Environment>, Rc<Node>),
+    /// An unevaluated expression with the environment to evaluate it in,
+    /// its memo slot if memoized, and whether it is forced as soon as a
+    /// variable bound to it is read (a lazily passed argument) rather
+    /// than only by `force` (a `delay` expression).
+    Thunk(Rc<Environment>, Rc<Node>, Option<Memo>, bool),
+    Delay(Box<Node>),
+    Call(Box<Node>, Vec<Box<Node>>),
+    Builtin(String),
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Native(Native),
+    Compose(Box<Node>, Box<Node>),
+    /// A statement, call or variable and where it was written, kept by
+    /// `parser::parse_spanned` so errors can point into the source.
+    Spanned(Span, Box<Node>),
 }
 
+#[allow(clippy::should_implement_trait)]
 impl Node {
     pub fn number(value: i64) -> Box<Node> { Box::new(Node::Number(value)) }
+    pub fn float(value: f64) -> Box<Node> { Box::new(Node::Float(value)) }
+    pub fn string(value: &str) -> Box<Node> { Box::new(Node::Str(value.to_string())) }
     pub fn add(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::Add(left, right)) }
     pub fn subtract(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::Subtract(left, right)) }
     pub fn multiply(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::Multiply(left, right)) }
+    pub fn negate(node: Box<Node>) -> Box<Node> { Box::new(Node::Negate(node)) }
     pub fn boolean(value: bool) -> Box<Node> { Box::new(Node::Boolean(value)) }
     pub fn lt(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::LT(left, right)) }
     pub fn eq(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::EQ(left, right)) }
     pub fn gt(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::GT(left, right)) }
+    pub fn le(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::LE(left, right)) }
+    pub fn ge(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::GE(left, right)) }
+    pub fn ne(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::NE(left, right)) }
+    pub fn and(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::And(left, right)) }
+    pub fn or(left: Box<Node>, right: Box<Node>) -> Box<Node> { Box::new(Node::Or(left, right)) }
+    pub fn not(node: Box<Node>) -> Box<Node> { Box::new(Node::Not(node)) }
     pub fn variable(name: &str) -> Box<Node> { Box::new(Node::Variable(name.to_string())) }
     pub fn donothing() -> Box<Node> { Box::new(Node::DoNothing) }
     pub fn isdonothing(node: Box<Node>) -> Box<Node> { Box::new(Node::IsDoNothing(node)) }
     pub fn assign(name: &str, expr: Box<Node>) -> Box<Node> { Box::new(Node::Assign(name.to_string(), expr)) }
+    pub fn global(name: &str, expr: Box<Node>) -> Box<Node> { Box::new(Node::Global(name.to_string(), expr)) }
     pub fn if_cond_else(condition: Box<Node>, consequence: Box<Node>, alternative: Box<Node>) -> Box<Node> {
         Box::new(Node::If(condition, consequence, alternative))
     }
     pub fn sequence(head: Box<Node>, more: Box<Node>) -> Box<Node> { Box::new(Node::Sequence(head, more)) }
     pub fn while_node(cond: Box<Node>, body: Box<Node>) -> Box<Node> { Box::new(Node::While(cond, body)) }
+    pub fn match_node(value: Box<Node>, arms: Vec<(Pattern, Box<Node>)>) -> Box<Node> { Box::new(Node::Match(value, arms)) }
+    pub fn for_node(init: Box<Node>, cond: Box<Node>, step: Box<Node>, body: Box<Node>) -> Box<Node> {
+        Box::new(Node::For(init, cond, step, body))
+    }
+    pub fn return_node(value: Box<Node>) -> Box<Node> { Box::new(Node::Return(value)) }
+    pub fn break_node() -> Box<Node> { Box::new(Node::Break) }
+    pub fn continue_node() -> Box<Node> { Box::new(Node::Continue) }
     pub fn pair(fst: Box<Node>, snd: Box<Node>) -> Box<Node> { Box::new(Node::Pair(fst, snd)) }
     pub fn fst(pair: Box<Node>) -> Box<Node> { Box::new(Node::Fst(pair)) }
     pub fn snd(pair: Box<Node>) -> Box<Node> { Box::new(Node::Snd(pair)) }
-    pub fn fun(funname: &str, argname: &str, body: Box<Node>) -> Box<Node> {
-        Box::new(Node::Fun(funname.to_string(), argname.to_string(), body))
+    pub fn len(node: Box<Node>) -> Box<Node> { Box::new(Node::Len(node)) }
+    pub fn list(items: Vec<Box<Node>>) -> Box<Node> { Box::new(Node::List(items)) }
+    pub fn map(entries: HashMap<String, Box<Node>>) -> Box<Node> { Box::new(Node::Map(entries)) }
+    pub fn index(list: Box<Node>, index: Box<Node>) -> Box<Node> { Box::new(Node::Index(list, index)) }
+    pub fn fun(funname: &str, params: &[&str], body: Box<Node>) -> Box<Node> {
+        Box::new(Node::Fun(funname.to_string(), params.iter().map(|param| param.to_string()).collect(), body))
+    }
+    pub fn closure(env: Environment, fun: Box<Node>) -> Box<Node> { Box::new(Node::Closure(Rc::new(env), Rc::from(fun))) }
+    pub fn thunk(env: Environment, expr: Box<Node>, memoize: bool, on_read: bool) -> Box<Node> {
+        let memo = if memoize { Some(Rc::new(RefCell::new(None))) } else { None };
+        Box::new(Node::Thunk(Rc::new(env), Rc::from(expr), memo, on_read))
     }
-    pub fn closure(env: Environment, fun: Box<Node>) -> Box<Node> { Box::new(Node::Closure(env, fun)) }
-    pub fn call(closure: Box<Node>, arg: Box<Node>) -> Box<Node> { Box::new(Node::Call(closure, arg)) }
+    pub fn delay(expr: Box<Node>) -> Box<Node> { Box::new(Node::Delay(expr)) }
+    pub fn call(closure: Box<Node>, arg: Box<Node>) -> Box<Node> { Box::new(Node::Call(closure, vec![arg])) }
+    pub fn call_args(closure: Box<Node>, args: Vec<Box<Node>>) -> Box<Node> { Box::new(Node::Call(closure, args)) }
+    pub fn builtin(name: &str) -> Box<Node> { Box::new(Node::Builtin(name.to_string())) }
+    pub fn native<F>(name: &str, fun: F) -> Box<Node>
+        where F: Fn(&[Box<Node>]) -> Box<Node> + 'static {
+        Box::new(Node::Native(Native { name: name.to_string(), fun: Rc::new(fun) }))
+    }
+    pub fn compose(outer: Box<Node>, inner: Box<Node>) -> Box<Node> { Box::new(Node::Compose(outer, inner)) }
+    pub fn spanned(span: Span, node: Box<Node>) -> Box<Node> { Box::new(Node::Spanned(span, node)) }
 
     pub fn value(&self) -> i64 {
         match *self {
@@ -63,6 +230,77 @@ impl Node {
         }
     }
 
+    /// The value of a number as a float, promoting integers.
+    pub fn float_value(&self) -> f64 {
+        match *self {
+            Node::Number(value) => { value as f64 },
+            Node::Float(value) => { value },
+            _ => panic!("Type has no value: {}", *self)
+        }
+    }
+
+    pub fn string_value(&self) -> &str {
+        match *self {
+            Node::Str(ref value) => { value },
+            _ => panic!("Type has no string value: {}", *self)
+        }
+    }
+
+    pub fn map_value(&self) -> &HashMap<String, Box<Node>> {
+        match *self {
+            Node::Map(ref entries) => { entries },
+            _ => panic!("Type has no map value: {}", *self)
+        }
+    }
+
+    pub fn list_value(&self) -> &[Box<Node>] {
+        match *self {
+            Node::List(ref items) => { items },
+            _ => panic!("Type has no list value: {}", *self)
+        }
+    }
+
+    /// The definition behind a function value: a closure's function, or
+    /// the function itself where functions are plain terms.
+    fn function(&self) -> Option<(&str, &[String])> {
+        match *self {
+            Node::Fun(ref name, ref params, _) => Some((name, params)),
+            Node::Closure(_, ref fun) => fun.function(),
+            _ => None,
+        }
+    }
+
+    /// Whether this value can be called.
+    pub fn is_function(&self) -> bool {
+        matches!(*self, Node::Builtin(_) | Node::Native(_)) || self.function().is_some()
+    }
+
+    /// The name a function value was defined with, `None` for anonymous
+    /// functions and values that are not functions.
+    pub fn function_name(&self) -> Option<&str> {
+        match *self {
+            Node::Builtin(ref name) => Some(name),
+            Node::Native(ref native) => Some(&native.name),
+            _ => self.function().map(|(name, _)| name).filter(|name| !name.is_empty()),
+        }
+    }
+
+    /// How many arguments a function value takes, `None` for natives,
+    /// which check their own arguments, builtins taking any number, and
+    /// values that are not functions.
+    pub fn arity(&self) -> Option<usize> {
+        match *self {
+            Node::Builtin(ref name) => builtin::lookup(name).and_then(|builtin| builtin.arity.exact()),
+            _ => self.function().map(|(_, params)| params.len()),
+        }
+    }
+
+    /// Whether this value is a control-flow signal (a pending `return`,
+    /// `break` or `continue`) that enclosing statements must pass on unchanged.
+    pub fn is_signal(&self) -> bool {
+        matches!(*self, Node::Return(_) | Node::Break | Node::Continue)
+    }
+
     pub fn condition(&self) -> bool {
         match *self {
             Node::Boolean(b) => { b },
@@ -70,35 +308,196 @@ impl Node {
         }
     }
 
+    /// The direct sub-expressions of this node, in evaluation order.
+    pub fn children(&self) -> Vec<&Node> {
+        match *self {
+            Node::Add(ref l, ref r) | Node::Subtract(ref l, ref r) |
+                Node::Multiply(ref l, ref r) | Node::LT(ref l, ref r) |
+                Node::EQ(ref l, ref r) | Node::GT(ref l, ref r) |
+                Node::LE(ref l, ref r) | Node::GE(ref l, ref r) | Node::NE(ref l, ref r) |
+                Node::And(ref l, ref r) | Node::Or(ref l, ref r) |
+                Node::Sequence(ref l, ref r) | Node::While(ref l, ref r) |
+                Node::Pair(ref l, ref r) | Node::Compose(ref l, ref r) |
+                Node::Index(ref l, ref r) => vec![l, r],
+            Node::List(ref items) => items.iter().map(|item| &**item).collect(),
+            Node::Match(ref value, ref arms) => {
+                let mut children: Vec<&Node> = vec![value];
+                children.extend(arms.iter().map(|(_, body)| &**body));
+                children
+            }
+            Node::Map(ref entries) => sorted(entries).into_iter().map(|(_, value)| value).collect(),
+            Node::Call(ref closure, ref args) => {
+                let mut children: Vec<&Node> = vec![closure];
+                children.extend(args.iter().map(|arg| &**arg));
+                children
+            }
+            Node::Not(ref node) | Node::Negate(ref node) | Node::IsDoNothing(ref node) |
+                Node::Return(ref node) | Node::Delay(ref node) |
+                Node::Fst(ref node) | Node::Snd(ref node) | Node::Len(ref node) |
+                Node::Assign(_, ref node) | Node::Global(_, ref node) |
+                Node::Fun(_, _, ref node) | Node::Spanned(_, ref node) => vec![node],
+            Node::Closure(_, ref fun) | Node::Thunk(_, ref fun, _, _) => vec![fun],
+            Node::If(ref condition, ref consequence, ref alternative) => vec![condition, consequence, alternative],
+            Node::For(ref init, ref cond, ref step, ref body) => vec![init, cond, body, step],
+            Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Boolean(_) |
+                Node::Variable(_) | Node::DoNothing | Node::Builtin(_) | Node::Native(_) |
+                Node::Break | Node::Continue => vec![],
+        }
+    }
+
+    /// The sub-expressions of this node written in it, to rewrite them in
+    /// place. Those of closures and thunks are shared and left out.
+    pub fn children_mut(&mut self) -> Vec<&mut Box<Node>> {
+        match *self {
+            Node::Add(ref mut l, ref mut r) | Node::Subtract(ref mut l, ref mut r) |
+                Node::Multiply(ref mut l, ref mut r) | Node::LT(ref mut l, ref mut r) |
+                Node::EQ(ref mut l, ref mut r) | Node::GT(ref mut l, ref mut r) |
+                Node::LE(ref mut l, ref mut r) | Node::GE(ref mut l, ref mut r) | Node::NE(ref mut l, ref mut r) |
+                Node::And(ref mut l, ref mut r) | Node::Or(ref mut l, ref mut r) |
+                Node::Sequence(ref mut l, ref mut r) | Node::While(ref mut l, ref mut r) |
+                Node::Pair(ref mut l, ref mut r) | Node::Compose(ref mut l, ref mut r) |
+                Node::Index(ref mut l, ref mut r) => vec![l, r],
+            Node::List(ref mut items) => items.iter_mut().collect(),
+            Node::Match(ref mut value, ref mut arms) => {
+                let mut children = vec![value];
+                children.extend(arms.iter_mut().map(|(_, body)| body));
+                children
+            }
+            Node::Map(ref mut entries) => entries.values_mut().collect(),
+            Node::Call(ref mut closure, ref mut args) => {
+                let mut children = vec![closure];
+                children.extend(args.iter_mut());
+                children
+            }
+            Node::Not(ref mut node) | Node::Negate(ref mut node) | Node::IsDoNothing(ref mut node) |
+                Node::Return(ref mut node) | Node::Delay(ref mut node) |
+                Node::Fst(ref mut node) | Node::Snd(ref mut node) | Node::Len(ref mut node) |
+                Node::Assign(_, ref mut node) | Node::Global(_, ref mut node) |
+                Node::Fun(_, _, ref mut node) | Node::Spanned(_, ref mut node) => vec![node],
+            Node::If(ref mut condition, ref mut consequence, ref mut alternative) => vec![condition, consequence, alternative],
+            Node::For(ref mut init, ref mut cond, ref mut step, ref mut body) => vec![init, cond, body, step],
+            Node::Closure(..) | Node::Thunk(..) |
+                Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Boolean(_) |
+                Node::Variable(_) | Node::DoNothing | Node::Builtin(_) | Node::Native(_) |
+                Node::Break | Node::Continue => vec![],
+        }
+    }
+
+    /// The node under the spans of `parser::parse_spanned` wrapped around
+    /// this one, to look at its shape without copying it.
+    pub fn unspanned(&self) -> &Node {
+        let mut node = self;
+        while let Node::Spanned(_, ref inner) = *node {
+            node = inner;
+        }
+        node
+    }
+
+    /// The statements of a sequence, flattened, through the spans around
+    /// the sequences in it.
+    pub fn statements(&self) -> Vec<&Node> {
+        match *self {
+            Node::Sequence(ref head, ref more) => {
+                let mut all = head.statements();
+                all.extend(more.statements());
+                all
+            }
+            Node::Spanned(_, ref inner) if matches!(**inner, Node::Sequence(..)) => inner.statements(),
+            _ => vec![self],
+        }
+    }
+
+    /// This node with the spans of `parser::parse_spanned` taken out, for
+    /// passes that look for particular shapes of nodes.
+    pub fn without_spans(&self) -> Box<Node> {
+        match *self {
+            Node::Spanned(_, ref node) => node.without_spans(),
+            _ => {
+                let mut node = Box::new(self.clone());
+                for child in node.children_mut() {
+                    *child = child.without_spans();
+                }
+                node
+            }
+        }
+    }
+
     fn prettyprint(&self, indent: usize) -> String {
         let prefix = " ".repeat(indent);
         match *self {
             Node::Number(value) => format!("{}", value),
+            // Debug keeps the decimal point, so 1.0 does not read back as an integer
+            Node::Float(value) => format!("{:?}", value),
+            Node::Str(ref value) => format!("{:?}", value),
             Node::Add(ref l, ref r) => format!("{0} + {1}", l, r),
             Node::Subtract(ref l, ref r) => format!("{0} - {1}", l, r),
             Node::Multiply(ref l, ref r) => format!("{0} * {1}", l, r),
+            Node::Negate(ref node) => format!("-({0})", node),
             Node::Boolean(value) => format!("{}", value),
             Node::LT(ref l, ref r) => format!("{0} < {1}", l, r),
             Node::EQ(ref l, ref r) => format!("{0} = {1}", l, r),
             Node::GT(ref l, ref r) => format!("{0} > {1}", l, r),
-            Node::Variable(ref name) => format!("{}", name),
-            Node::DoNothing => format!("do-nothing"),
+            Node::LE(ref l, ref r) => format!("{0} <= {1}", l, r),
+            Node::GE(ref l, ref r) => format!("{0} >= {1}", l, r),
+            Node::NE(ref l, ref r) => format!("{0} != {1}", l, r),
+            Node::And(ref l, ref r) => format!("{0} and {1}", l, r),
+            Node::Or(ref l, ref r) => format!("{0} or {1}", l, r),
+            Node::Not(ref node) => format!("not {0}", node),
+            Node::Variable(ref name) => name.to_string(),
+            Node::DoNothing => "do-nothing".to_string(),
             Node::IsDoNothing(ref node) => format!("is-do-nothing({0})", node),
             Node::Assign(ref name, ref expr) => format!("{0} = {1}", name, expr),
+            Node::Global(ref name, ref expr) => format!("global {0} = {1}", name, expr),
             Node::If(ref condition, ref consequence, ref alternative) => format!("if ({0}) {1} else {2}", condition, consequence, alternative),
             Node::Sequence(ref head, ref more) => format!("{0}; {1}", head, more),
             Node::While(ref cond, ref body) => format!("while ({0}) {1}", cond, body),
-            Node::Pair(ref fst, ref snd) => format!("pair ({0}, {1})", fst, snd),
+            Node::Match(ref value, ref arms) => format!("match ({0}) {{ {1} }}", value, arms.iter()
+                                                        .map(|(pattern, body)| format!("{} => {}", pattern, body))
+                                                        .collect::<Vec<_>>().join("; ")),
+            Node::For(ref init, ref cond, ref step, ref body) => format!("for ({0}; {1}; {2}) {3}", init, cond, step, body),
+            Node::Return(ref value) => format!("return {0}", value),
+            Node::Break => "break".to_string(),
+            Node::Continue => "continue".to_string(),
+            Node::Pair(ref fst, ref snd) => format!("({0}, {1})", fst, snd),
             Node::Fst(ref pair) => format!("fst ({0})", pair),
             Node::Snd(ref pair) => format!("snd ({0})", pair),
-            Node::Fun(ref fname, ref argname, ref body) => format!("function {0} ({1}) {2}", fname, argname, body),
+            Node::Len(ref node) => format!("len ({0})", node),
+            Node::List(ref items) => format!("[{}]", items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(", ")),
+            Node::Index(ref list, ref index) => format!("{0}[{1}]", list, index),
+            Node::Map(ref entries) => format!("{{{}}}", sorted(entries).iter()
+                                              .map(|&(key, value)| format!("{:?}: {}", key, value)).collect::<Vec<_>>().join(", ")),
+            Node::Fun(ref fname, ref params, ref body) if fname.is_empty() => format!("fun ({0}) {1}", params.join(", "), body),
+            Node::Fun(ref fname, ref params, ref body) => format!("function {0} ({1}) {2}", fname, params.join(", "), body),
+            // a shared scope may hold the closure itself
+            Node::Closure(ref env, ref fun) if env.capture == Capture::Shared =>
+                format!("closure {0}, shared env", fun.prettyprint(indent+1)),
             Node::Closure(ref env, ref fun) => format!("closure {0}, env \n{1}{2}",
                                                        fun.prettyprint(indent+1), prefix, env.prettyprint(indent+1)),
-            Node::Call(ref closure, ref arg) => format!("call {0} arg {1}", closure.prettyprint(indent+1), arg),
+            Node::Call(ref closure, ref args) if args.is_empty() => format!("call {0}", closure.prettyprint(indent+1)),
+            Node::Call(ref closure, ref args) => format!("call {0} arg {1}", closure.prettyprint(indent+1),
+                                                         args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().join(", ")),
+            // the captured environment is left out: it may be large or hold the thunk itself
+            Node::Thunk(_, ref expr, ref memo, _) => match memo.as_ref().and_then(|memo| memo.borrow().clone()) {
+                Some(value) => format!("thunk ({0}) => {1}", expr, value),
+                None => format!("thunk ({0})", expr),
+            },
+            Node::Delay(ref expr) => format!("delay {0}", expr),
+            Node::Builtin(ref name) => format!("builtin {}", name),
+            Node::Native(ref native) => format!("native {}", native.name),
+            Node::Compose(ref outer, ref inner) => format!("compose ({0}, {1})", outer, inner),
+            Node::Spanned(_, ref node) => node.prettyprint(indent),
         }
     }
 }
 
+/// The entries of a map ordered by key, so that maps print and evaluate
+/// the same way every time.
+pub fn sorted(entries: &HashMap<String, Box<Node>>) -> Vec<(&str, &Node)> {
+    let mut entries: Vec<_> = entries.iter().map(|(key, value)| (key.as_str(), &**value)).collect();
+    entries.sort_by_key(|&(key, _)| key);
+    entries
+}
+
 impl Display for Node {
     fn fmt(&self, f: &mut Formatter) -> Result {
         write!(f, "{}", self.prettyprint(0))
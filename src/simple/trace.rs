@@ -0,0 +1,632 @@
+//! Recorded runs of a program. A trace lists the steps a backend took and
+//! the top-level assignments it made, one event per line, so that two runs
+//! can be saved and compared with `diff`. Assignments are what the runs
+//! are aligned on: backends differ in how finely they step, not in what
+//! they assign.
+//!
+//! A trace may be saved as JSON too, for other tools to read, and either
+//! form read back and stepped through with `Replay`, which needs nothing
+//! but the trace: a long run recorded once can be looked into anywhere.
+
+use super::syntax::Node;
+use super::machine::Machine;
+use super::evaluate::RuntimeError;
+use super::subst;
+use super::deps::quote;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
+
+/// The version of the JSON form of traces, bumped when it changes.
+pub const JSON_VERSION: u32 = 1;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Event {
+    /// The term a backend is about to reduce, printed.
+    Step(String),
+    /// A top-level variable and the value assigned to it, printed.
+    Assign(String, String),
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Trace {
+    pub events: Vec<Event>,
+}
+
+/// Where two traces first make different assignments. The positions are
+/// indices into the events of each trace; `None` when a trace ended first.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Divergence {
+    /// How many assignments the traces agree on.
+    pub agreed: usize,
+    pub left: Option<usize>,
+    pub right: Option<usize>,
+}
+
+/// Values printed so that both backends agree: functions are closures in
+/// one and plain terms in the other, so only their name is kept.
+fn show(value: &Node) -> String {
+    if value.is_function() {
+        match value.function_name() {
+            Some(name) => format!("function {}", name),
+            None => "function".to_string(),
+        }
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('\t', "\\t")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, if c == '\\' { chars.next() } else { None }) {
+            ('\\', Some('n')) => result.push('\n'),
+            ('\\', Some('t')) => result.push('\t'),
+            ('\\', Some(c)) => result.push(c),
+            (c, _) => result.push(c),
+        }
+    }
+    result
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { events: Vec::new() }
+    }
+
+    pub fn step(&mut self, node: &Node) {
+        self.events.push(Event::Step(node.to_string()));
+    }
+
+    pub fn assign(&mut self, name: &str, value: &Node) {
+        self.events.push(Event::Assign(name.to_string(), show(value)));
+    }
+
+    /// Run `program` on the environment machine, recording every node it
+    /// evaluates.
+    pub fn machine(program: &Node) -> Trace {
+        let (trace, result) = Trace::record(Machine::new_with_empty_env(Box::new(program.clone())));
+        result.unwrap_or_else(|e| panic!("{}", e));
+        trace
+    }
+
+    /// Run the program of `machine` on it, as it is configured, recording
+    /// every node it evaluates, up to where it fails if it does.
+    pub fn record(machine: Machine) -> (Trace, Result<Box<Node>, RuntimeError>) {
+        let trace = Rc::new(RefCell::new(Trace::new()));
+        let (steps, assignments) = (trace.clone(), trace.clone());
+        let hook = machine.assign_hook();
+        let mut machine = machine
+            .with_step_hook(Rc::new(move |node| steps.borrow_mut().step(node)))
+            .with_assign_hook(Rc::new(move |assignment| {
+                if let Some(ref hook) = hook {
+                    hook(assignment);
+                }
+                if assignment.toplevel {
+                    assignments.borrow_mut().assign(assignment.name, assignment.new);
+                }
+            }));
+        let result = machine.run();
+        let trace = trace.borrow().clone();
+        (trace, result)
+    }
+
+    /// Reduce `program` by substitution, recording the whole term before
+    /// every step.
+    pub fn subst(program: &Node) -> Trace {
+        subst::run_traced(program).1
+    }
+
+    /// Reduce the program of `machine` by substitution, under the limits
+    /// and cancellation of the machine, recording the whole term before
+    /// every step. A run that fails records nothing.
+    pub fn record_subst(mut machine: Machine) -> (Trace, Result<Box<Node>, RuntimeError>) {
+        let program = machine.program().clone();
+        match machine.run_with(|_| subst::run_traced(&program)) {
+            Ok((outcome, trace)) => (trace, Ok(outcome.value)),
+            Err(e) => (Trace::new(), Err(e)),
+        }
+    }
+
+    /// Read a trace in the format it is displayed in.
+    pub fn parse(text: &str) -> Result<Trace, String> {
+        let mut trace = Trace::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let event = match fields[..] {
+                ["step", node] => Event::Step(unescape(node)),
+                ["assign", name, value] => Event::Assign(unescape(name), unescape(value)),
+                _ => return Err(format!("line {}: expect a step or an assignment, found {}", lineno + 1, line)),
+            };
+            trace.events.push(event);
+        }
+        Ok(trace)
+    }
+
+    /// The trace as JSON: `{"version": 1, "events": [...]}`, each event
+    /// an object naming its kind under `event`, a `step` with its `node`
+    /// and an `assign` with its `name` and `value`.
+    pub fn to_json(&self) -> String {
+        let events: Vec<String> = self.events.iter().map(|event| match *event {
+            Event::Step(ref node) => format!("\n  {{\"event\": \"step\", \"node\": {}}}", quote(node)),
+            Event::Assign(ref name, ref value) =>
+                format!("\n  {{\"event\": \"assign\", \"name\": {}, \"value\": {}}}", quote(name), quote(value)),
+        }).collect();
+        format!("{{\"version\": {}, \"events\": [{}\n]}}\n", JSON_VERSION, events.join(","))
+    }
+
+    /// Read a trace in the form `to_json` writes.
+    pub fn parse_json(text: &str) -> Result<Trace, String> {
+        let json = Json::parse(text)?;
+        match json.get("version") {
+            Some(&Json::Number(version)) if version == f64::from(JSON_VERSION) => (),
+            _ => return Err(format!("expect a trace of version {}", JSON_VERSION)),
+        }
+        let events = match json.get("events") {
+            Some(Json::Array(events)) => events,
+            _ => return Err("expect a list of events".to_string()),
+        };
+        let mut trace = Trace::new();
+        for (index, event) in events.iter().enumerate() {
+            let field = |key| match event.get(key) {
+                Some(Json::Str(text)) => Some(text.clone()),
+                _ => None,
+            };
+            let event = match (field("event").as_deref(), field("node"), field("name"), field("value")) {
+                (Some("step"), Some(node), _, _) => Event::Step(node),
+                (Some("assign"), _, Some(name), Some(value)) => Event::Assign(name, value),
+                _ => return Err(format!("event {}: expect a step or an assignment", index + 1)),
+            };
+            trace.events.push(event);
+        }
+        Ok(trace)
+    }
+
+    /// Read a trace saved in either form.
+    pub fn load(path: &str) -> Result<Trace, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Error in reading {}: {}", path, e))?;
+        let trace = if text.trim_start().starts_with('{') { Trace::parse_json(&text) } else { Trace::parse(&text) };
+        trace.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_string()).map_err(|e| format!("Error in writing {}: {}", path, e))
+    }
+
+    pub fn save_json(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_json()).map_err(|e| format!("Error in writing {}: {}", path, e))
+    }
+
+    /// Positions of the assignments in the trace.
+    fn assignments(&self) -> Vec<usize> {
+        self.events.iter().enumerate()
+            .filter(|&(_, event)| matches!(*event, Event::Assign(..)))
+            .map(|(index, _)| index).collect()
+    }
+
+    /// Where `self` and `other` first make different assignments, or
+    /// `None` if they make the same ones in the same order.
+    pub fn diff(&self, other: &Trace) -> Option<Divergence> {
+        let (left, right) = (self.assignments(), other.assignments());
+        let mut agreed = 0;
+        loop {
+            let (l, r) = (left.get(agreed).cloned(), right.get(agreed).cloned());
+            match (l, r) {
+                (None, None) => return None,
+                (Some(l), Some(r)) if self.events[l] == other.events[r] => agreed += 1,
+                _ => return Some(Divergence { agreed, left: l, right: r }),
+            }
+        }
+    }
+
+    /// The last step taken before the event at `index`.
+    pub fn step_before(&self, index: usize) -> Option<&str> {
+        self.events[..index].iter().rev().filter_map(|event| match *event {
+            Event::Step(ref node) => Some(node.as_str()),
+            Event::Assign(..) => None,
+        }).next()
+    }
+}
+
+/// A position among the steps of a trace, moved forward, back or to any
+/// step, with what had been assigned by then.
+#[derive(Debug,Clone)]
+pub struct Replay<'a> {
+    trace: &'a Trace,
+    /// Positions of the steps among the events.
+    steps: Vec<usize>,
+    /// The step replayed, counted from 0.
+    at: usize,
+}
+
+impl<'a> Replay<'a> {
+    /// A replay at the first step of `trace`.
+    pub fn new(trace: &'a Trace) -> Replay<'a> {
+        let steps = trace.events.iter().enumerate()
+            .filter(|&(_, event)| matches!(*event, Event::Step(_)))
+            .map(|(index, _)| index).collect();
+        Replay { trace, steps, at: 0 }
+    }
+
+    /// How many steps the trace has.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The number of the step replayed, counted from 1.
+    pub fn position(&self) -> usize {
+        self.at + 1
+    }
+
+    /// The term evaluated at the step replayed, if the trace has steps.
+    pub fn step(&self) -> Option<&str> {
+        match self.trace.events[*self.steps.get(self.at)?] {
+            Event::Step(ref node) => Some(node),
+            Event::Assign(..) => None,
+        }
+    }
+
+    /// Move to the next step, returning false at the last.
+    pub fn forward(&mut self) -> bool {
+        let moved = self.at + 1 < self.len();
+        if moved {
+            self.at += 1;
+        }
+        moved
+    }
+
+    /// Move to the previous step, returning false at the first.
+    pub fn backward(&mut self) -> bool {
+        let moved = self.at > 0;
+        if moved {
+            self.at -= 1;
+        }
+        moved
+    }
+
+    /// Move to step `step`, counted from 1.
+    pub fn jump(&mut self, step: usize) -> Result<(), String> {
+        if step == 0 || step > self.len() {
+            return Err(format!("expect a step from 1 to {}, found {}", self.len(), step));
+        }
+        self.at = step - 1;
+        Ok(())
+    }
+
+    /// The assignments made after the step replayed, up to the next one.
+    pub fn assignments(&self) -> Vec<(&str, &str)> {
+        let start = self.steps.get(self.at).map_or(0, |&index| index + 1);
+        self.trace.events[start..].iter().take_while(|event| matches!(**event, Event::Assign(..)))
+            .filter_map(|event| match *event {
+                Event::Assign(ref name, ref value) => Some((name.as_str(), value.as_str())),
+                Event::Step(_) => None,
+            }).collect()
+    }
+
+    /// The values of the variables once the step replayed and the
+    /// assignments after it are done.
+    pub fn variables(&self) -> BTreeMap<&str, &str> {
+        let end = self.steps.get(self.at + 1).cloned().unwrap_or(self.trace.events.len());
+        self.trace.events[..end].iter().filter_map(|event| match *event {
+            Event::Assign(ref name, ref value) => Some((name.as_str(), value.as_str())),
+            Event::Step(_) => None,
+        }).collect()
+    }
+}
+
+/// Just enough of JSON to read traces back.
+#[derive(Debug,Clone,PartialEq)]
+enum Json {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut reader = JsonReader { chars: text.chars().peekable() };
+        let json = reader.value()?;
+        reader.skip_whitespace();
+        match reader.chars.next() {
+            None => Ok(json),
+            Some(c) => Err(format!("unexpected {:?} after the JSON", c)),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|field| field.0 == key).map(|field| &field.1),
+            _ => None,
+        }
+    }
+}
+
+struct JsonReader<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> JsonReader<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expect {:?} in JSON, found {:?}", expected, c)),
+            None => Err(format!("expect {:?} in JSON, found the end", expected)),
+        }
+    }
+
+    fn word(&mut self, word: &str) -> Result<(), String> {
+        if word.chars().all(|expected| self.chars.next() == Some(expected)) {
+            Ok(())
+        } else {
+            Err(format!("expect {} in JSON", word))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('{') => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.items('}', |reader| {
+                    reader.expect('"')?;
+                    let key = reader.string()?;
+                    reader.expect(':')?;
+                    fields.push((key, reader.value()?));
+                    Ok(())
+                })?;
+                Ok(Json::Object(fields))
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.items(']', |reader| {
+                    items.push(reader.value()?);
+                    Ok(())
+                })?;
+                Ok(Json::Array(items))
+            }
+            Some('"') => {
+                self.chars.next();
+                self.string().map(Json::Str)
+            }
+            Some('t') => self.word("true").map(|_| Json::Boolean(true)),
+            Some('f') => self.word("false").map(|_| Json::Boolean(false)),
+            Some('n') => self.word("null").map(|_| Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_digit() && !"+-.eE".contains(c) {
+                        break;
+                    }
+                    number.push(c);
+                    self.chars.next();
+                }
+                number.parse().map(Json::Number).map_err(|_| format!("expect a number in JSON, found {}", number))
+            }
+            Some(c) => Err(format!("unexpected {:?} in JSON", c)),
+            None => Err("unexpected end of JSON".to_string()),
+        }
+    }
+
+    /// Read the items of an array or object, its opening bracket read, up
+    /// to `close`.
+    fn items<F>(&mut self, close: char, mut item: F) -> Result<(), String>
+        where F: FnMut(&mut Self) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&close) {
+            self.chars.next();
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => (),
+                Some(c) if c == close => return Ok(()),
+                _ => return Err(format!("expect , or {} in JSON", close)),
+            }
+        }
+    }
+
+    /// The rest of a string, its opening quote read.
+    fn string(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.code_point()?,
+                        Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                        _ => return Err("bad escape in a JSON string".to_string()),
+                    };
+                    string.push(c);
+                }
+                Some(c) => string.push(c),
+                None => return Err("unterminated JSON string".to_string()),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, read with the low surrogate after
+    /// it if it is a high one.
+    fn code_point(&mut self) -> Result<char, String> {
+        let high = self.hex()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.word("\\u")?;
+            let low = self.hex()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err("bad escape in a JSON string".to_string());
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| "bad escape in a JSON string".to_string())
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits: String = self.chars.by_ref().take(4).collect();
+        match u32::from_str_radix(&digits, 16) {
+            Ok(code) if digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) => Ok(code),
+            _ => Err(format!("expect four hex digits in JSON, found {}", digits)),
+        }
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Event::Step(ref node) => write!(f, "step\t{}", escape(node)),
+            Event::Assign(ref name, ref value) => write!(f, "assign\t{}\t{}", escape(name), escape(value)),
+        }
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::evaluate::Stopped;
+
+    #[test]
+    fn test_trace_roundtrip() {
+        let mut trace = Trace::new();
+        trace.step(&Node::string("a\tb\\n"));
+        trace.assign("x", &Node::list(vec![Node::number(1), Node::string("line\nbreak")]));
+        trace.assign("f", &Node::fun("f", &["x"], Node::variable("x")));
+        let text = trace.to_string();
+        assert_eq!(3, text.lines().count());
+        assert_eq!(Ok(trace), Trace::parse(&text));
+        assert!(Trace::parse("jump\tx").is_err());
+    }
+
+    #[test]
+    fn test_trace_json() {
+        let mut trace = Trace::new();
+        trace.step(&Node::string("say \"hi\"\n"));
+        trace.assign("x", &Node::list(vec![Node::number(1), Node::string("tab\there")]));
+        let json = trace.to_json();
+        assert!(json.starts_with("{\"version\": 1, \"events\": [\n  {\"event\": \"step\", \"node\": "));
+        assert_eq!(Ok(trace), Trace::parse_json(&json));
+        assert_eq!(Ok(Trace::new()), Trace::parse_json(&Trace::new().to_json()));
+        // written by another tool
+        let other = r#"{"events":[{"value":"\u00e9\ud83d\ude00","name":"s","event":"assign","at":null}],"version":1.0}"#;
+        assert_eq!(vec![Event::Assign("s".to_string(), "é😀".to_string())], Trace::parse_json(other).unwrap().events);
+        assert!(Trace::parse_json(r#"{"version": 2, "events": []}"#).is_err());
+        assert!(Trace::parse_json(r#"{"version": 1, "events": [{"event": "jump"}]}"#).is_err());
+        assert!(Trace::parse_json(r#"{"version": 1, "events": [}"#).is_err());
+        assert!(Trace::parse_json(r#"{"version": 1, "events": []} x"#).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let trace = Trace::machine(&parser::parse("a = 1; b = a + 2; a = b * 2;").unwrap());
+        let mut replay = Replay::new(&trace);
+        assert_eq!(trace.events.len() - 3, replay.len());
+        assert_eq!(1, replay.position());
+        assert!(!replay.backward());
+        assert!(replay.variables().is_empty());
+        // on to the step after which `a` is first assigned
+        while replay.assignments().is_empty() {
+            assert!(replay.forward());
+        }
+        assert_eq!(vec![("a", "1")], replay.assignments());
+        assert_eq!(Some(&"1"), replay.variables().get("a"));
+        assert!(replay.backward());
+        assert!(replay.variables().is_empty());
+        replay.jump(replay.len()).unwrap();
+        assert!(!replay.forward());
+        assert_eq!(vec![("a", "6")], replay.assignments());
+        assert_eq!(vec![("a", "6"), ("b", "3")], replay.variables().into_iter().collect::<Vec<_>>());
+        assert!(replay.backward());
+        assert!(replay.jump(0).is_err() && replay.jump(replay.len() + 1).is_err());
+        let empty = Trace::new();
+        let replay = Replay::new(&empty);
+        assert!(replay.is_empty());
+        assert_eq!(None, replay.step());
+        assert!(replay.assignments().is_empty());
+    }
+
+    #[test]
+    fn test_trace_diff() {
+        let program = parser::parse("def sq(x) { y = x * x; return y; }
+            a = sq(3);
+            b = a + 1;").unwrap();
+        let machine = Trace::machine(&program);
+        let subst = Trace::subst(&program);
+        // the backends step differently but assign the same
+        assert_ne!(machine.events.len(), subst.events.len());
+        assert_eq!(None, machine.diff(&subst));
+        let changed = Trace::machine(&parser::parse("def sq(x) { y = x * x; return y; }
+            a = sq(3);
+            b = a - 1;").unwrap());
+        let divergence = machine.diff(&changed).unwrap();
+        assert_eq!(2, divergence.agreed);
+        let (left, right) = (divergence.left.unwrap(), divergence.right.unwrap());
+        assert_eq!(Event::Assign("b".to_string(), "10".to_string()), machine.events[left]);
+        assert_eq!(Event::Assign("b".to_string(), "8".to_string()), changed.events[right]);
+        // the assignment follows evaluating the last operand of a - 1
+        assert_eq!(Some("1"), changed.step_before(right));
+        let mut shorter = machine.clone();
+        shorter.events.truncate(left);
+        assert_eq!(Some(Divergence { agreed: 2, left: None, right: Some(left) }), shorter.diff(&machine));
+    }
+
+    #[test]
+    fn test_record_errors() {
+        let failing = || Machine::new_with_empty_env(parser::parse("x = 1; y = x + z;").unwrap());
+        let (trace, result) = Trace::record(failing());
+        assert_eq!(Some(&Event::Assign("x".to_string(), "1".to_string())),
+            trace.events.iter().find(|event| matches!(event, Event::Assign(..))));
+        match result {
+            Err(RuntimeError::Failed(message)) => assert!(message.starts_with("Variable z not found")),
+            other => panic!("expected a failure, got {:?}", other.map(|node| node.to_string())),
+        }
+        assert!(matches!(Trace::record_subst(failing()).1, Err(RuntimeError::Failed(_))));
+        let endless = || Machine::new_with_empty_env(parser::parse("while (true) { }").unwrap()).with_step_limit(100);
+        let (trace, result) = Trace::record(endless());
+        assert!(!trace.events.is_empty());
+        assert!(matches!(result, Err(RuntimeError::Stopped(Stopped::Timeout(_)))));
+        assert!(matches!(Trace::record_subst(endless()).1, Err(RuntimeError::Stopped(Stopped::Timeout(_)))));
+    }
+}
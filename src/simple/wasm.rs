@@ -0,0 +1,377 @@
+//! Compiling programs to WebAssembly, encoded by hand. Only the part of
+//! the language a machine word holds is compiled: integers, booleans,
+//! arithmetic, comparisons and logic, assignments, `if`, `while` and `for`
+//! with `break` and `continue`.
+//!
+//! The module has a single function, exported as `run`, taking nothing and
+//! returning the value of `result`. Each variable is a local of it, an
+//! `i64` for an integer or an `i32` for a boolean, so a variable keeps the
+//! type it is first assigned; it is zero until then. Arithmetic wraps
+//! around on overflow, where the interpreter fails.
+
+use super::syntax::Node;
+use super::compact::put_varint;
+
+use std::collections::HashMap;
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// section ids
+const TYPE: u8 = 1;
+const FUNCTION: u8 = 3;
+const EXPORT: u8 = 7;
+const CODE: u8 = 10;
+
+// instructions
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0B;
+const BR: u8 = 0x0C;
+const BR_IF: u8 = 0x0D;
+const DROP: u8 = 0x1A;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQZ: u8 = 0x45;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const I64_GT_S: u8 = 0x55;
+const I64_LE_S: u8 = 0x57;
+const I64_GE_S: u8 = 0x59;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const I64_ADD: u8 = 0x7C;
+const I64_SUB: u8 = 0x7D;
+const I64_MUL: u8 = 0x7E;
+
+/// The block type of blocks leaving nothing on the stack.
+const EMPTY: u8 = 0x40;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Type {
+    Int,
+    Bool,
+}
+
+impl Type {
+    fn encoding(self) -> u8 {
+        match self {
+            Type::Int => 0x7E,
+            Type::Bool => 0x7F,
+        }
+    }
+}
+
+/// Write `value` as a signed LEB128 varint.
+fn put_signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    put_bytes(out, contents);
+}
+
+fn unsupported(node: &Node) -> String {
+    format!("Cannot compile `{}` to WebAssembly, only integers, booleans and loops", node)
+}
+
+struct Compiler {
+    locals: Vec<Type>,
+    names: HashMap<String, u32>,
+    code: Vec<u8>,
+    /// The blocks open around the code being compiled.
+    depth: u32,
+    /// The depths of the blocks `break` and `continue` leave, of each loop
+    /// around.
+    loops: Vec<(u32, u32)>,
+}
+
+impl Compiler {
+    fn local(&mut self, op: u8, index: u32) {
+        self.code.push(op);
+        put_varint(&mut self.code, index as u64);
+    }
+
+    fn open(&mut self, op: u8) {
+        self.code.extend_from_slice(&[op, EMPTY]);
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.code.push(END);
+        self.depth -= 1;
+    }
+
+    /// Branch to the block open at `depth`.
+    fn branch(&mut self, op: u8, depth: u32) {
+        self.code.push(op);
+        put_varint(&mut self.code, (self.depth - depth) as u64);
+    }
+
+    fn operands(&mut self, l: &Node, r: &Node, ty: Type, node: &Node) -> Result<(), String> {
+        if self.expr(l)? != ty || self.expr(r)? != ty {
+            return Err(format!("Cannot compile `{}` to WebAssembly, its operands are not {}", node,
+                               if ty == Type::Int { "integers" } else { "booleans" }));
+        }
+        Ok(())
+    }
+
+    /// Compile `node` to code leaving its value on the stack, returning
+    /// its type.
+    fn expr(&mut self, node: &Node) -> Result<Type, String> {
+        let (ty, op) = match *node {
+            Node::Number(value) => {
+                self.code.push(I64_CONST);
+                put_signed(&mut self.code, value);
+                return Ok(Type::Int);
+            }
+            Node::Boolean(value) => {
+                self.code.extend_from_slice(&[I32_CONST, value as u8]);
+                return Ok(Type::Bool);
+            }
+            Node::Variable(ref name) => {
+                let index = *self.names.get(name)
+                    .ok_or_else(|| format!("Variable {} is read before it is assigned", name))?;
+                self.local(LOCAL_GET, index);
+                return Ok(self.locals[index as usize]);
+            }
+            Node::Negate(ref value) => {
+                self.code.extend_from_slice(&[I64_CONST, 0]);
+                if self.expr(value)? != Type::Int {
+                    return Err(unsupported(node));
+                }
+                (Type::Int, I64_SUB)
+            }
+            Node::Not(ref value) => {
+                if self.expr(value)? != Type::Bool {
+                    return Err(unsupported(node));
+                }
+                (Type::Bool, I32_EQZ)
+            }
+            Node::Add(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Int, I64_ADD) }
+            Node::Subtract(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Int, I64_SUB) }
+            Node::Multiply(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Int, I64_MUL) }
+            Node::LT(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_LT_S) }
+            Node::GT(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_GT_S) }
+            Node::LE(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_LE_S) }
+            Node::GE(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_GE_S) }
+            // operands have no effects, so both are evaluated
+            Node::And(ref l, ref r) => { self.operands(l, r, Type::Bool, node)?; (Type::Bool, I32_AND) }
+            Node::Or(ref l, ref r) => { self.operands(l, r, Type::Bool, node)?; (Type::Bool, I32_OR) }
+            Node::EQ(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_EQ) }
+            Node::NE(ref l, ref r) => { self.operands(l, r, Type::Int, node)?; (Type::Bool, I64_NE) }
+            Node::Spanned(_, ref node) => return self.expr(node),
+            _ => return Err(unsupported(node)),
+        };
+        self.code.push(op);
+        Ok(ty)
+    }
+
+    fn condition(&mut self, cond: &Node) -> Result<(), String> {
+        match self.expr(cond)? {
+            Type::Bool => Ok(()),
+            Type::Int => Err(format!("Cannot compile `{}` to WebAssembly, the condition is not a boolean", cond)),
+        }
+    }
+
+    /// Compile `node` to code leaving the stack as it was.
+    fn statement(&mut self, node: &Node) -> Result<(), String> {
+        match *node {
+            Node::DoNothing => (),
+            Node::Sequence(ref head, ref more) => {
+                self.statement(head)?;
+                self.statement(more)?;
+            }
+            Node::Assign(ref name, ref expr) => {
+                let ty = self.expr(expr)?;
+                let index = match self.names.get(name) {
+                    Some(&index) if self.locals[index as usize] == ty => index,
+                    Some(_) => return Err(format!("Variable {} is assigned both an integer and a boolean", name)),
+                    None => {
+                        let index = self.locals.len() as u32;
+                        self.locals.push(ty);
+                        self.names.insert(name.clone(), index);
+                        index
+                    }
+                };
+                self.local(LOCAL_SET, index);
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                self.condition(cond)?;
+                self.open(IF);
+                self.statement(consequence)?;
+                self.code.push(ELSE);
+                self.statement(alternative)?;
+                self.close();
+            }
+            Node::While(ref cond, ref body) => self.repeat(cond, body, None)?,
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.statement(init)?;
+                self.repeat(cond, body, Some(step))?;
+            }
+            Node::Break | Node::Continue => {
+                let &(exit, next) = self.loops.last().ok_or_else(|| unsupported(node))?;
+                self.branch(BR, if let Node::Break = *node { exit } else { next });
+            }
+            Node::Spanned(_, ref node) => self.statement(node)?,
+            _ => {
+                self.expr(node)?;
+                self.code.push(DROP);
+            }
+        }
+        Ok(())
+    }
+
+    /// A loop: a block to break out of around a loop to go round, where a
+    /// step after the body has the body in a block of its own to continue
+    /// out of.
+    fn repeat(&mut self, cond: &Node, body: &Node, step: Option<&Node>) -> Result<(), String> {
+        self.open(BLOCK);
+        let exit = self.depth;
+        self.open(LOOP);
+        let again = self.depth;
+        self.condition(cond)?;
+        self.code.push(I32_EQZ);
+        self.branch(BR_IF, exit);
+        match step {
+            Some(step) => {
+                self.open(BLOCK);
+                self.loops.push((exit, self.depth));
+                self.statement(body)?;
+                self.loops.pop();
+                self.close();
+                self.statement(step)?;
+            }
+            None => {
+                self.loops.push((exit, again));
+                self.statement(body)?;
+                self.loops.pop();
+            }
+        }
+        self.branch(BR, again);
+        self.close();
+        self.close();
+        Ok(())
+    }
+}
+
+/// Compile `program` to a WebAssembly module exporting `run`, which runs it
+/// and returns its `result`.
+pub fn compile(program: &Node) -> Result<Vec<u8>, String> {
+    let mut compiler = Compiler { locals: Vec::new(), names: HashMap::new(), code: Vec::new(), depth: 0, loops: Vec::new() };
+    compiler.statement(program)?;
+    let result = *compiler.names.get("result").ok_or("The program assigns no `result` to return")?;
+    compiler.local(LOCAL_GET, result);
+    compiler.code.push(END);
+
+    let types = [1, 0x60, 0, 1, compiler.locals[result as usize].encoding()];
+    let mut body = Vec::new();
+    put_varint(&mut body, compiler.locals.len() as u64);
+    for ty in &compiler.locals {
+        body.extend_from_slice(&[1, ty.encoding()]);
+    }
+    body.extend(compiler.code);
+    let mut code = vec![1];
+    put_bytes(&mut code, &body);
+    let mut export = vec![1];
+    put_bytes(&mut export, b"run");
+    export.extend_from_slice(&[0, 0]);
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(VERSION);
+    section(&mut out, TYPE, &types);
+    section(&mut out, FUNCTION, &[1, 0]);
+    section(&mut out, EXPORT, &export);
+    section(&mut out, CODE, &code);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+    use simple::machine::Machine;
+
+    use std::process::Command;
+
+    /// What `run` of `module` returns under node, or `None` without node.
+    fn node(module: &[u8]) -> Option<String> {
+        let bytes: Vec<String> = module.iter().map(|byte| byte.to_string()).collect();
+        let script = format!("WebAssembly.instantiate(new Uint8Array([{}])).then(m => console.log(String(m.instance.exports.run())))",
+                             bytes.join(","));
+        let output = Command::new("node").arg("-e").arg(script).output().ok()?;
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        Some(String::from_utf8(output.stdout).unwrap().trim().to_string())
+    }
+
+    #[test]
+    fn test_encoding() {
+        let module = compile(&parser::parse("result = 1 + -200;").unwrap()).unwrap();
+        assert_eq!(vec![0, b'a', b's', b'm', 1, 0, 0, 0,
+                        TYPE, 5, 1, 0x60, 0, 1, 0x7E,
+                        FUNCTION, 2, 1, 0,
+                        EXPORT, 7, 1, 3, b'r', b'u', b'n', 0, 0,
+                        CODE, 19, 1, 17, 1, 1, 0x7E,
+                        I64_CONST, 1, I64_CONST, 0, I64_CONST, 0xC8, 1, I64_SUB, I64_ADD, LOCAL_SET, 0,
+                        LOCAL_GET, 0, END],
+                   module);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let error = |source: &str| compile(&parser::parse(source).unwrap()).unwrap_err();
+        assert_eq!("The program assigns no `result` to return", error("x = 1;"));
+        assert_eq!("Variable y is read before it is assigned", error("result = y;"));
+        assert_eq!("Variable x is assigned both an integer and a boolean", error("x = 1; x = true; result = x;"));
+        assert!(error("result = 1.5;").starts_with("Cannot compile `1.5` to WebAssembly"));
+        assert!(error("def f() { 1 } result = f();").contains("only integers, booleans and loops"));
+        assert!(error("if (1) { result = 1; }").contains("the condition is not a boolean"));
+        assert!(error("result = true == false;").contains("its operands are not integers"));
+    }
+
+    #[test]
+    fn test_agrees_with_machine() {
+        let programs = [
+            "a = 1071; b = 462; while (b != 0) { t = b; while (not (a < b)) { a = a - b; } b = a; a = t; } result = a;",
+            "total = 0; for (i = 0; i < 100; i = i + 1) { if (i == 3 or i == 5) { continue; } if (i * i > 50) { break; } \
+             total = total + i * -1; } result = total;",
+            "n = 27; steps = 0; while (n != 1) { odd = false; m = n; while (m > 1) { m = m - 2; } \
+             if (m == 1) { n = 3 * n + 1; } else { h = 0; while (h + h < n) { h = h + 1; } n = h; } steps = steps + 1; } \
+             result = steps;",
+            "x = 5; result = x >= 5 and not (x <= 4) and (x != 6 or false);",
+        ];
+        for source in programs.iter() {
+            let program = parser::parse(source).unwrap();
+            let module = compile(&program).unwrap();
+            let mut machine = Machine::new_with_empty_env(program);
+            machine.run().unwrap();
+            // booleans come back as 0 or 1
+            let expected = match *machine.environment.get("result") {
+                Node::Boolean(value) => (value as i32).to_string(),
+                ref value => value.to_string(),
+            };
+            if let Some(result) = node(&module) {
+                assert_eq!(expected, result, "{}", source);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Wall-clock time spent in the named phases of a run, in the order they ran.
+#[derive(Debug,Default,Clone)]
+pub struct Timings {
+    phases: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings::default()
+    }
+
+    /// Run `f` as the phase `name`, recording how long it took.
+    pub fn time<T, F>(&mut self, name: &str, f: F) -> T
+        where F: FnOnce() -> T {
+        let start = Instant::now();
+        let value = f();
+        self.record(name, start.elapsed());
+        value
+    }
+
+    /// Add time measured elsewhere; time recorded twice under one name adds up.
+    pub fn record(&mut self, name: &str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(phase, _)| phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((name.to_string(), elapsed)),
+        }
+    }
+
+    pub fn phases(&self) -> &[(String, Duration)] {
+        &self.phases
+    }
+
+    pub fn get(&self, name: &str) -> Option<Duration> {
+        self.phases.iter().find(|(phase, _)| phase == name).map(|&(_, elapsed)| elapsed)
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|&(_, elapsed)| elapsed).sum()
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let width = self.phases.iter().map(|(phase, _)| phase.len()).max().unwrap_or(0).max(5);
+        for (phase, elapsed) in &self.phases {
+            writeln!(f, "{:width$}  {:>10.3} ms", phase, elapsed.as_secs_f64() * 1000.0, width = width)?;
+        }
+        write!(f, "{:width$}  {:>10.3} ms", "total", self.total().as_secs_f64() * 1000.0, width = width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings() {
+        let mut timings = Timings::new();
+        assert_eq!(3, timings.time("parse", || 1 + 2));
+        timings.record("execution", Duration::from_millis(2));
+        timings.record("execution", Duration::from_millis(3));
+        assert_eq!(Some(Duration::from_millis(5)), timings.get("execution"));
+        assert_eq!(None, timings.get("analysis"));
+        assert_eq!(vec!["parse", "execution"],
+                   timings.phases().iter().map(|(phase, _)| phase.as_str()).collect::<Vec<_>>());
+        assert!(timings.total() >= Duration::from_millis(5));
+        let report = timings.to_string();
+        assert!(report.contains("execution       5.000 ms"), "{}", report);
+        assert!(report.ends_with(" ms") && report.contains("total"));
+    }
+}
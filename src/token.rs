@@ -0,0 +1,229 @@
+//! Splitting source into tokens, for tools that work on the text of a
+//! program rather than its syntax: highlighting, say. The tokenizer never
+//! fails: what the grammar does not take is an `Error` token, and the
+//! tokens, whitespace and comments included, put back together make the
+//! source again.
+
+use simple::builtin;
+
+use std::fmt;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum TokenKind {
+    Keyword,
+    /// A variable, or a name being defined.
+    Identifier,
+    /// A name of a builtin function.
+    Builtin,
+    Number,
+    Float,
+    Boolean,
+    Str,
+    Operator,
+    /// Brackets, commas, semicolons and colons.
+    Punctuation,
+    Comment,
+    Whitespace,
+    Error,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TokenKind::Keyword => "keyword",
+            TokenKind::Identifier => "identifier",
+            TokenKind::Builtin => "builtin",
+            TokenKind::Number => "number",
+            TokenKind::Float => "float",
+            TokenKind::Boolean => "boolean",
+            TokenKind::Str => "string",
+            TokenKind::Operator => "operator",
+            TokenKind::Punctuation => "punctuation",
+            TokenKind::Comment => "comment",
+            TokenKind::Whitespace => "whitespace",
+            TokenKind::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Where `text` starts in the source, in bytes.
+    pub start: usize,
+}
+
+const KEYWORDS: &[&str] = &[
+    "if", "else", "while", "for", "match", "return", "break", "continue", "global",
+    "def", "function", "fun", "and", "or", "not", "delay",
+];
+
+/// Operators, the longest first so that `<=` is not read as `<`.
+const OPERATORS: &[&str] = &["==", "<=", ">=", "!=", ">>", "=>", "+", "-", "*", "<", ">", "="];
+
+const PUNCTUATION: &[char] = &['(', ')', '{', '}', '[', ']', ',', ';', ':'];
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// How long the token at the start of `rest` is, and what kind.
+fn next(rest: &str) -> (TokenKind, usize) {
+    let c = rest.chars().next().unwrap();
+    let end = |found: Option<usize>| found.unwrap_or(rest.len());
+    if c.is_whitespace() {
+        return (TokenKind::Whitespace, end(rest.find(|c: char| !c.is_whitespace())));
+    }
+    if rest.starts_with("//") {
+        return (TokenKind::Comment, end(rest.find('\n')));
+    }
+    if let Some(inside) = rest.strip_prefix("/*") {
+        return (TokenKind::Comment, end(inside.find("*/").map(|n| n + 4)));
+    }
+    if c == '"' {
+        let mut escaped = false;
+        for (pos, c) in rest.char_indices().skip(1) {
+            match c {
+                '"' if !escaped => return (TokenKind::Str, pos + 1),
+                '\\' => escaped = !escaped,
+                _ => escaped = false,
+            }
+        }
+        // not closed
+        return (TokenKind::Error, rest.len());
+    }
+    if c.is_ascii_digit() {
+        return number(rest);
+    }
+    if c.is_ascii_alphabetic() || c == '_' {
+        let len = end(rest.find(|c: char| !is_word(c)));
+        let word = &rest[..len];
+        let kind = if KEYWORDS.contains(&word) {
+            TokenKind::Keyword
+        } else if word == "true" || word == "false" {
+            TokenKind::Boolean
+        } else if builtin::lookup(word).is_some() {
+            TokenKind::Builtin
+        } else {
+            TokenKind::Identifier
+        };
+        return (kind, len);
+    }
+    if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+        return (TokenKind::Operator, op.len());
+    }
+    if PUNCTUATION.contains(&c) {
+        return (TokenKind::Punctuation, 1);
+    }
+    (TokenKind::Error, c.len_utf8())
+}
+
+/// A number as the grammar has it, a float if it has a fraction or an
+/// exponent.
+fn number(rest: &str) -> (TokenKind, usize) {
+    let bytes = rest.as_bytes();
+    let digits = |from: usize| from + bytes[from..].iter().take_while(|b| b.is_ascii_digit()).count();
+    let mut len = digits(0);
+    let mut kind = TokenKind::Number;
+    if bytes.get(len) == Some(&b'.') && bytes.get(len + 1).is_some_and(u8::is_ascii_digit) {
+        len = digits(len + 1);
+        kind = TokenKind::Float;
+    }
+    if let Some(b'e') | Some(b'E') = bytes.get(len) {
+        let sign = match bytes.get(len + 1) {
+            Some(b'+') | Some(b'-') => 1,
+            _ => 0,
+        };
+        if bytes.get(len + 1 + sign).is_some_and(u8::is_ascii_digit) {
+            len = digits(len + 1 + sign);
+            kind = TokenKind::Float;
+        }
+    }
+    (kind, len)
+}
+
+/// The tokens of `source`, in order.
+pub fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < source.len() {
+        let (kind, len) = next(&source[start..]);
+        tokens.push(Token { kind, text: &source[start..start + len], start });
+        start += len;
+    }
+    tokens
+}
+
+/// Whether `source` leaves a block or a `/*` comment open, so that more
+/// of it is to come, as when the REPL reads a program line by line.
+/// Braces in strings and comments do not count.
+pub fn is_open(source: &str) -> bool {
+    let mut depth = 0usize;
+    for token in tokenize(source) {
+        match (token.kind, token.text) {
+            (TokenKind::Punctuation, "{") => depth += 1,
+            (TokenKind::Punctuation, "}") => depth = depth.saturating_sub(1),
+            (TokenKind::Comment, text) if text.starts_with("/*") && !text[2..].ends_with("*/") => return true,
+            _ => (),
+        }
+    }
+    depth > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(source).into_iter()
+            .filter(|token| token.kind != TokenKind::Whitespace)
+            .map(|token| (token.kind, token.text))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(vec![
+            (TokenKind::Keyword, "def"), (TokenKind::Identifier, "f"), (TokenKind::Punctuation, "("),
+            (TokenKind::Identifier, "x_1"), (TokenKind::Punctuation, ")"), (TokenKind::Punctuation, "{"),
+            (TokenKind::Keyword, "return"), (TokenKind::Builtin, "len"), (TokenKind::Punctuation, "("),
+            (TokenKind::Identifier, "x_1"), (TokenKind::Punctuation, ")"), (TokenKind::Operator, ">="),
+            (TokenKind::Float, "2.5e-3"), (TokenKind::Keyword, "and"), (TokenKind::Boolean, "true"),
+            (TokenKind::Punctuation, ";"), (TokenKind::Punctuation, "}"),
+        ], kinds("def f(x_1) { return len(x_1) >= 2.5e-3 and true; }"));
+        assert_eq!(vec![(TokenKind::Number, "12"), (TokenKind::Error, "."), (TokenKind::Identifier, "e")], kinds("12.e"));
+        // keywords and builtins only as whole words
+        assert_eq!(vec![(TokenKind::Identifier, "iffy"), (TokenKind::Identifier, "length")], kinds("iffy length"));
+    }
+
+    #[test]
+    fn test_strings_and_comments() {
+        assert_eq!(vec![
+            (TokenKind::Identifier, "s"), (TokenKind::Operator, "="), (TokenKind::Str, "\"a \\\" // b\""),
+            (TokenKind::Punctuation, ";"), (TokenKind::Comment, "// note"),
+            (TokenKind::Comment, "/* two\nlines */"), (TokenKind::Identifier, "x"),
+        ], kinds("s = \"a \\\" // b\"; // note\n/* two\nlines */ x"));
+        assert_eq!(vec![(TokenKind::Error, "\"open")], kinds("\"open"));
+        assert_eq!(vec![(TokenKind::Error, "@"), (TokenKind::Error, "é")], kinds("@ é"));
+    }
+
+    #[test]
+    fn test_is_open() {
+        assert!(is_open("while (true) {\n  x = 1;"));
+        assert!(is_open("def f() { if (x) { return 1; }"));
+        assert!(is_open("x = 1; /* more"));
+        assert!(!is_open("def f() { return 1; }"));
+        assert!(!is_open("x = \"{\";"));
+        assert!(!is_open("x = 1; // {\n/* { */"));
+        assert!(!is_open("}"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "x = 1;  // one\n\tif (x != 2) { print(\"ok\") }\n/* open";
+        let tokens = tokenize(source);
+        assert_eq!(source, tokens.iter().map(|token| token.text).collect::<String>());
+        assert!(tokens.iter().all(|token| &source[token.start..token.start + token.text.len()] == token.text));
+    }
+}
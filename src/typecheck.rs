@@ -0,0 +1,560 @@
+//! Find type errors without running a program: a boolean added to a
+//! number, a pair used as a condition, a number called as a function.
+//!
+//! The checker is gradual: a type it cannot tell from the program text,
+//! say of a parameter or of what a builtin returns, is `Unknown`, and an
+//! unknown type is never an error. So every error reported is one the
+//! program runs into if it gets that far.
+
+use simple::syntax::{Node, Span};
+use simple::builtin;
+use lattice::{merge, Join};
+use parser::Arity;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Type {
+    Number,
+    Float,
+    Boolean,
+    String,
+    Pair(Box<Type>, Box<Type>),
+    /// A list with items of the given type.
+    List(Box<Type>),
+    Map,
+    /// A function taking this many arguments, when known, and returning
+    /// values of the given type.
+    Function(Option<usize>, Box<Type>),
+    Nothing,
+    Unknown,
+}
+
+impl Type {
+    /// The type of both `self` and `other`, `Unknown` where they differ.
+    pub fn join(&self, other: &Type) -> Type {
+        match (self, other) {
+            (Type::Pair(l, r), Type::Pair(ol, or)) => Type::Pair(Box::new(l.join(ol)), Box::new(r.join(or))),
+            (Type::List(item), Type::List(other)) => Type::List(Box::new(item.join(other))),
+            (Type::Function(arity, ret), Type::Function(other, oret)) =>
+                Type::Function(if arity == other { *arity } else { None }, Box::new(ret.join(oret))),
+            _ if self == other => self.clone(),
+            _ => Type::Unknown,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(*self, Type::Number | Type::Float)
+    }
+
+    /// What a value of this type is, for messages.
+    fn article(&self) -> &'static str {
+        match *self {
+            Type::Number => "a number",
+            Type::Float => "a float",
+            Type::Boolean => "a boolean",
+            Type::String => "a string",
+            Type::Pair(..) => "a pair",
+            Type::List(_) => "a list",
+            Type::Map => "a map",
+            Type::Function(..) => "a function",
+            Type::Nothing => "nothing",
+            Type::Unknown => "a value of unknown type",
+        }
+    }
+}
+
+impl Join for Type {
+    fn join(&self, other: &Type) -> Type {
+        Type::join(self, other)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Number => write!(f, "number"),
+            Type::Float => write!(f, "float"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::String => write!(f, "string"),
+            Type::Pair(ref l, ref r) => write!(f, "pair({}, {})", l, r),
+            Type::List(ref item) => write!(f, "list({})", item),
+            Type::Map => write!(f, "map"),
+            Type::Function(arity, ref ret) => {
+                write!(f, "function")?;
+                if let Some(arity) = arity {
+                    write!(f, "({})", arity)?;
+                }
+                match **ret {
+                    Type::Unknown => Ok(()),
+                    ref ret => write!(f, " -> {}", ret),
+                }
+            }
+            Type::Nothing => write!(f, "nothing"),
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct TypeError {
+    pub message: String,
+    /// Where the error is, when the program was parsed with
+    /// `parser::parse_spanned`.
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "type error at {}: {}", span, self.message),
+            None => write!(f, "type error: {}", self.message),
+        }
+    }
+}
+
+/// The types of the variables in scope.
+type Scope = HashMap<String, Type>;
+
+/// Whether a function body ends in a statement rather than an expression
+/// giving the value of the function, and if so whether it is a `return`.
+fn statement_body(body: &Node) -> Option<bool> {
+    match *body.unspanned() {
+        Node::Sequence(_, ref last) => statement_body(last),
+        Node::Return(_) => Some(true),
+        Node::Assign(..) | Node::Global(..) | Node::While(..) | Node::For(..) => Some(false),
+        _ => None,
+    }
+}
+
+struct Checker {
+    scope: Scope,
+    /// The types returned so far in the function being checked.
+    returns: Vec<Type>,
+    errors: Vec<TypeError>,
+    /// Off while the types of a loop settle, so each error is reported once.
+    report: bool,
+    span: Option<Span>,
+}
+
+impl Checker {
+    fn error(&mut self, message: String) {
+        if self.report {
+            self.errors.push(TypeError { message, span: self.span });
+        }
+    }
+
+    /// The type of an operand of `op`, which takes values `accepts` holds
+    /// for; `Unknown` after reporting a value it does not take.
+    fn operand(&mut self, node: &Node, op: &str, accepts: fn(&Type) -> bool, expected: &str) -> Type {
+        let found = self.check(node);
+        if found == Type::Unknown || accepts(&found) {
+            return found;
+        }
+        self.error(format!("`{}` is {}, but `{}` takes {}", node, found.article(), op, expected));
+        Type::Unknown
+    }
+
+    fn numbers(&mut self, l: &Node, r: &Node, op: &str) -> (Type, Type) {
+        (self.operand(l, op, Type::is_numeric, "numbers"), self.operand(r, op, Type::is_numeric, "numbers"))
+    }
+
+    /// Operands that may be both numbers or both strings.
+    fn numbers_or_strings(&mut self, node: &Node, l: &Node, r: &Node, op: &str, verb: &str) -> (Type, Type) {
+        let accepts = |found: &Type| found.is_numeric() || *found == Type::String;
+        let l = self.operand(l, op, accepts, "numbers or strings");
+        let r = self.operand(r, op, accepts, "numbers or strings");
+        if l != Type::Unknown && r != Type::Unknown && l.is_numeric() != r.is_numeric() {
+            self.error(format!("cannot {} {} and {} in `{}`", verb, l.article(), r.article(), node));
+            return (Type::Unknown, Type::Unknown);
+        }
+        (l, r)
+    }
+
+    /// Operands of `==` or `!=`: values of one kind, numbers of either,
+    /// and not functions.
+    fn comparable(&mut self, node: &Node, l: &Node, r: &Node, op: &str) {
+        let accepts = |found: &Type| !matches!(*found, Type::Function(..));
+        let l = self.operand(l, op, accepts, "values other than functions");
+        let r = self.operand(r, op, accepts, "values other than functions");
+        let kind = |found: &Type| if found.is_numeric() { None } else { Some(mem::discriminant(found)) };
+        if l != Type::Unknown && r != Type::Unknown && kind(&l) != kind(&r) {
+            self.error(format!("cannot compare {} and {} in `{}`", l.article(), r.article(), node));
+        }
+    }
+
+    fn condition(&mut self, cond: &Node) {
+        let found = self.check(cond);
+        if found != Type::Unknown && found != Type::Boolean {
+            self.error(format!("the condition `{}` is {}, not a boolean", cond, found.article()));
+        }
+    }
+
+    /// Check a loop the first time round, with the types from before it,
+    /// and again once the types of the variables it assigns settle: a
+    /// variable whose type changes from one iteration to the next is
+    /// `Unknown` from then on.
+    fn repeat<F: Fn(&mut Checker)>(&mut self, iteration: F) {
+        let start = self.errors.len();
+        iteration(self);
+        let first = self.errors.len();
+        let report = self.report;
+        self.report = false;
+        loop {
+            let before = self.scope.clone();
+            iteration(self);
+            let mut settled = true;
+            for (name, found) in mem::replace(&mut self.scope, before) {
+                match self.scope.get(&name) {
+                    Some(known) if *known == found || *known == Type::Unknown => continue,
+                    Some(_) => { self.scope.insert(name, Type::Unknown); }
+                    None => { self.scope.insert(name, found); }
+                }
+                settled = false;
+            }
+            if settled {
+                break;
+            }
+        }
+        self.report = report;
+        let settled = self.scope.clone();
+        iteration(self);
+        // the loop may stop before any iteration
+        self.scope = merge(&settled, &self.scope);
+        for error in self.errors.split_off(first) {
+            if !self.errors[start..].contains(&error) {
+                self.errors.push(error);
+            }
+        }
+    }
+
+    fn function(&mut self, name: &str, params: &[String], body: &Node) -> Type {
+        let mut scope = self.scope.clone();
+        if !name.is_empty() {
+            scope.insert(name.to_string(), Type::Function(Some(params.len()), Box::new(Type::Unknown)));
+        }
+        for param in params {
+            scope.insert(param.clone(), Type::Unknown);
+        }
+        let outer = mem::replace(&mut self.scope, scope);
+        let outer_returns = mem::take(&mut self.returns);
+        let value = self.check(body);
+        self.scope = outer;
+        let mut returns = mem::replace(&mut self.returns, outer_returns);
+        match statement_body(body) {
+            // a body ending in an expression has its value
+            None => returns.push(value),
+            Some(false) => returns.push(Type::Nothing),
+            Some(true) => (),
+        }
+        let ret = returns.iter().skip(1).fold(returns[0].clone(), |ret, found| ret.join(found));
+        Type::Function(Some(params.len()), Box::new(ret))
+    }
+
+    /// The type of `fst` or `snd` of `pair`.
+    fn project(&mut self, pair: &Node, op: &str) -> Type {
+        match self.operand(pair, op, |found| matches!(*found, Type::Pair(..)), "a pair") {
+            Type::Pair(l, r) => if op == "fst" { *l } else { *r },
+            _ => Type::Unknown,
+        }
+    }
+
+    fn call(&mut self, closure: &Node, args: &[Box<Node>]) -> Type {
+        // the builtins whose types are evident, unless a variable hides them
+        if let Node::Variable(ref name) = *closure.unspanned() {
+            if !self.scope.contains_key(name) {
+                match (&name[..], args) {
+                    ("fst", [pair]) | ("snd", [pair]) => return self.project(pair, name),
+                    ("pair", [l, r]) => return Type::Pair(Box::new(self.check(l)), Box::new(self.check(r))),
+                    ("len", [value]) => { self.check(value); return Type::Number; }
+                    _ => (),
+                }
+            }
+        }
+        let callee = self.check(closure);
+        for arg in args {
+            self.check(arg);
+        }
+        match callee {
+            Type::Function(arity, ret) => {
+                if let Some(arity) = arity {
+                    if arity != args.len() {
+                        self.error(format!("`{}` takes {}, found {}", closure, Arity::Exactly(arity), args.len()));
+                    }
+                }
+                *ret
+            }
+            Type::Unknown => Type::Unknown,
+            other => {
+                self.error(format!("cannot call `{}`, it is {}", closure, other.article()));
+                Type::Unknown
+            }
+        }
+    }
+
+    /// The type of the value of `node`: for a sequence, that of its last
+    /// statement.
+    fn check(&mut self, node: &Node) -> Type {
+        match *node {
+            Node::Number(_) => Type::Number,
+            Node::Float(_) => Type::Float,
+            Node::Str(_) => Type::String,
+            Node::Boolean(_) => Type::Boolean,
+            Node::DoNothing | Node::Break | Node::Continue => Type::Nothing,
+            Node::Add(ref l, ref r) => match self.numbers_or_strings(node, l, r, "+", "add") {
+                (Type::String, Type::String) => Type::String,
+                (l, r) => arithmetic(&l, &r),
+            },
+            Node::Subtract(ref l, ref r) => { let (l, r) = self.numbers(l, r, "-"); arithmetic(&l, &r) }
+            Node::Multiply(ref l, ref r) => { let (l, r) = self.numbers(l, r, "*"); arithmetic(&l, &r) }
+            Node::Negate(ref inner) => self.operand(inner, "-", Type::is_numeric, "a number"),
+            Node::LT(ref l, ref r) => { self.numbers(l, r, "<"); Type::Boolean }
+            Node::GT(ref l, ref r) => { self.numbers(l, r, ">"); Type::Boolean }
+            Node::LE(ref l, ref r) => { self.numbers(l, r, "<="); Type::Boolean }
+            Node::GE(ref l, ref r) => { self.numbers(l, r, ">="); Type::Boolean }
+            Node::EQ(ref l, ref r) => { self.comparable(node, l, r, "=="); Type::Boolean }
+            Node::NE(ref l, ref r) => { self.comparable(node, l, r, "!="); Type::Boolean }
+            Node::And(ref l, ref r) | Node::Or(ref l, ref r) => {
+                let op = if let Node::And(..) = *node { "and" } else { "or" };
+                let boolean = |found: &Type| *found == Type::Boolean;
+                self.operand(l, op, boolean, "booleans");
+                self.operand(r, op, boolean, "booleans");
+                Type::Boolean
+            }
+            Node::Not(ref inner) => {
+                self.operand(inner, "not", |found| *found == Type::Boolean, "a boolean");
+                Type::Boolean
+            }
+            Node::IsDoNothing(ref inner) => { self.check(inner); Type::Boolean }
+            Node::Variable(ref name) => match self.scope.get(name) {
+                Some(known) => known.clone(),
+                None => match builtin::lookup(name) {
+                    Some(builtin) => Type::Function(builtin.arity.exact(), Box::new(Type::Unknown)),
+                    None => Type::Unknown,
+                },
+            },
+            Node::Assign(ref name, ref value) | Node::Global(ref name, ref value) => {
+                let found = self.check(value);
+                self.scope.insert(name.clone(), found);
+                Type::Nothing
+            }
+            Node::If(ref cond, ref consequence, ref alternative) => {
+                self.condition(cond);
+                let before = self.scope.clone();
+                let consequence = self.check(consequence);
+                let after = mem::replace(&mut self.scope, before);
+                let alternative = self.check(alternative);
+                self.scope = merge(&after, &self.scope);
+                consequence.join(&alternative)
+            }
+            Node::Sequence(ref head, ref more) => {
+                self.check(head);
+                self.check(more)
+            }
+            Node::While(ref cond, ref body) => {
+                self.repeat(|checker| {
+                    checker.condition(cond);
+                    checker.check(body);
+                });
+                Type::Nothing
+            }
+            Node::For(ref init, ref cond, ref step, ref body) => {
+                self.check(init);
+                self.repeat(|checker| {
+                    checker.condition(cond);
+                    checker.check(body);
+                    checker.check(step);
+                });
+                Type::Nothing
+            }
+            Node::Match(ref value, ref arms) => {
+                self.check(value);
+                let before = self.scope.clone();
+                let mut found: Option<(Type, Scope)> = None;
+                for (pattern, body) in arms {
+                    self.scope = before.clone();
+                    for name in pattern.bindings() {
+                        self.scope.insert(name.to_string(), Type::Unknown);
+                    }
+                    let value = self.check(body);
+                    // the names a pattern binds go out of scope with its arm
+                    for name in pattern.bindings() {
+                        match before.get(name) {
+                            Some(known) => self.scope.insert(name.to_string(), known.clone()),
+                            None => self.scope.remove(name),
+                        };
+                    }
+                    found = Some(match found {
+                        Some((other, scope)) => (other.join(&value), merge(&scope, &self.scope)),
+                        None => (value, self.scope.clone()),
+                    });
+                }
+                match found {
+                    Some((value, scope)) => { self.scope = scope; value }
+                    None => { self.scope = before; Type::Unknown }
+                }
+            }
+            Node::Return(ref value) => {
+                let found = self.check(value);
+                self.returns.push(found.clone());
+                found
+            }
+            Node::Pair(ref l, ref r) => Type::Pair(Box::new(self.check(l)), Box::new(self.check(r))),
+            Node::Fst(ref pair) => self.project(pair, "fst"),
+            Node::Snd(ref pair) => self.project(pair, "snd"),
+            Node::Len(ref inner) => { self.check(inner); Type::Number }
+            Node::List(ref items) => {
+                let items: Vec<Type> = items.iter().map(|item| self.check(item)).collect();
+                let item = match items.split_first() {
+                    Some((first, rest)) => rest.iter().fold(first.clone(), |item, found| item.join(found)),
+                    None => Type::Unknown,
+                };
+                Type::List(Box::new(item))
+            }
+            Node::Index(ref list, ref index) => {
+                let list = self.operand(list, "[]", |found| matches!(*found, Type::List(_)), "a list");
+                self.operand(index, "[]", |found| *found == Type::Number, "a number");
+                match list {
+                    Type::List(item) => *item,
+                    _ => Type::Unknown,
+                }
+            }
+            Node::Map(ref entries) => {
+                for value in entries.values() {
+                    self.check(value);
+                }
+                Type::Map
+            }
+            Node::Fun(ref name, ref params, ref body) => self.function(name, params, body),
+            Node::Call(ref closure, ref args) => self.call(closure, args),
+            Node::Compose(ref outer, ref inner) => {
+                let function = |found: &Type| matches!(*found, Type::Function(..));
+                let outer = self.operand(outer, ".", function, "functions");
+                self.operand(inner, ".", function, "functions");
+                match outer {
+                    Type::Function(_, ret) => Type::Function(Some(1), ret),
+                    _ => Type::Function(Some(1), Box::new(Type::Unknown)),
+                }
+            }
+            Node::Delay(ref expr) => { self.check(expr); Type::Unknown }
+            Node::Closure(..) | Node::Builtin(_) | Node::Native(_) =>
+                Type::Function(node.arity(), Box::new(Type::Unknown)),
+            Node::Thunk(..) => Type::Unknown,
+            Node::Spanned(span, ref inner) => {
+                let outer = self.span.replace(span);
+                let found = self.check(inner);
+                self.span = outer;
+                found
+            }
+        }
+    }
+}
+
+/// The type of an arithmetic result: integers stay integers, and a float
+/// on either side makes a float.
+fn arithmetic(l: &Type, r: &Type) -> Type {
+    match (l, r) {
+        (Type::Number, Type::Number) => Type::Number,
+        (Type::Float, _) | (_, Type::Float) if l.is_numeric() && r.is_numeric() => Type::Float,
+        _ => Type::Unknown,
+    }
+}
+
+/// Check `program`, giving the type of the value of its last statement,
+/// or every type error found in it.
+pub fn typecheck(program: &Node) -> Result<Type, Vec<TypeError>> {
+    let mut checker = Checker { scope: Scope::new(), returns: Vec::new(), errors: Vec::new(), report: true, span: None };
+    let found = checker.check(program);
+    if checker.errors.is_empty() {
+        Ok(found)
+    } else {
+        Err(checker.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn check(source: &str) -> Result<Type, Vec<String>> {
+        typecheck(&parser::parse(source).unwrap())
+            .map_err(|errors| errors.iter().map(|e| e.message.clone()).collect())
+    }
+
+    #[test]
+    fn test_types() {
+        assert_eq!(Ok(Type::Number), check("x = 1; y = x * 2; y + 1"));
+        assert_eq!(Ok(Type::Float), check("x = 1; x + 0.5"));
+        assert_eq!(Ok(Type::String), check("s = \"a\"; s + \"b\""));
+        assert_eq!(Ok(Type::Boolean), check("x = 1; x < 2 and not (x == 3)"));
+        assert_eq!(Ok(Type::Boolean), check("flag = true; flag == false"));
+        assert_eq!(Ok(Type::Boolean), check("(1, [true]) != (1.5, [false])"));
+        assert_eq!(Ok(Type::Number), check("p = (1, (true, \"s\")); fst(p)"));
+        assert_eq!("pair(boolean, string)", check("p = (1, (true, \"s\")); snd(p)").unwrap().to_string());
+        assert_eq!("number", check("xs = [1, 2, 3]; xs[1]").unwrap().to_string());
+        assert_eq!(Ok(Type::Nothing), check("x = 1;"));
+        // what is not evident from the program is unknown, never an error
+        assert_eq!(Ok(Type::Unknown), check("def f(x) { return x; } f(1) + 1"));
+        assert_eq!(Ok(Type::Unknown), check("input() + 1"));
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!("function(2) -> number", check("def size(a, b) { return len(a) + 1; } size").unwrap().to_string());
+        assert_eq!(Ok(Type::Number), check("def size(a, b) { len(a) * 2 } size([1], 2)"));
+        assert_eq!("function(2)", check("def add(a, b) { a + b } add").unwrap().to_string());
+        assert_eq!(Ok(Type::Nothing), check("def f(a) { x = a; } f(1)"));
+        assert_eq!(Ok(Type::Number), check("def f(a) { x = len(a); x * 2 } f([1])"));
+        assert_eq!(Ok(Type::Unknown), check("def f(a) { if (a > 0) { return 1; } } f(1)"));
+        // recursive calls are of unknown type while the function is checked
+        assert_eq!(Ok(Type::Unknown), check("def fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } } fact(5)"));
+        assert_eq!(Ok(Type::Boolean), check("f = fun(x) { x < 1 }; f(0)"));
+        assert_eq!(Err(vec!["`f` takes 2 arguments, found 1".to_string()]), check("def f(a, b) { a } f(1)"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(vec!["`true` is a boolean, but `+` takes numbers or strings".to_string()]), check("1 + true"));
+        assert_eq!(Err(vec!["cannot add a string and a number in `\"a\" + 1`".to_string()]), check("\"a\" + 1"));
+        assert_eq!(Err(vec!["the condition `p` is a pair, not a boolean".to_string()]),
+                   check("p = (1, 2); if (p) { x = 1; }"));
+        assert_eq!(Err(vec!["cannot call `n`, it is a number".to_string()]), check("n = 3; n(1)"));
+        assert_eq!(Err(vec!["`x` is a number, but `fst` takes a pair".to_string()]), check("x = 1; fst(x)"));
+        assert_eq!(Err(vec!["cannot compare a boolean and a number in `b != 1`".to_string()]), check("b = true; b != 1"));
+        assert_eq!(Err(vec!["`f` is a function, but `!=` takes values other than functions".to_string()]),
+                   check("def f() { 1 } f != 1"));
+        // every error is reported, each once, errors in loops included
+        let errors = check("i = 0; while (i) { i = i + true; } s = \"a\" - 1;").unwrap_err();
+        assert_eq!(vec![
+            "the condition `i` is a number, not a boolean".to_string(),
+            "`true` is a boolean, but `+` takes numbers or strings".to_string(),
+            "`\"a\"` is a string, but `-` takes numbers".to_string(),
+        ], errors);
+    }
+
+    #[test]
+    fn test_flow() {
+        // a variable may hold different types on different paths
+        assert_eq!(Ok(Type::Unknown), check("if (true) { x = 1; } else { x = true; } x"));
+        assert!(check("if (true) { x = 1; } else { x = true; } x + 1").is_ok());
+        // a loop that changes the type of a variable leaves it unknown
+        assert_eq!(Ok(Type::Unknown), check("x = 1; i = 0; while (i < 3) { x = (x, i); i = i + 1; } x"));
+        assert_eq!(Err(vec!["cannot call `x`, it is a number".to_string()]),
+                   check("x = 1; i = 0; while (i < 3) { x() x = fun() { 1 }; i = i + 1; }"));
+        assert_eq!(Ok(Type::Number), check("i = 0; for (j = 0; j < 3; j = j + 1) { i = i + j; } i"));
+        // the names a pattern binds are unknown, and only in their arm
+        assert_eq!(Err(vec!["cannot call `x`, it is a boolean".to_string()]),
+                   check("x = true; match ((1, 2)) { (x, y) => { x() } _ => { x() } }"));
+    }
+
+    #[test]
+    fn test_spans() {
+        let program = parser::parse_spanned("x = 1;\ny = x + false;").unwrap();
+        let errors = typecheck(&program).unwrap_err();
+        assert_eq!("type error at line 2, column 1: `false` is a boolean, but `+` takes numbers or strings",
+                   errors[0].to_string());
+    }
+}